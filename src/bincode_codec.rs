@@ -0,0 +1,34 @@
+//!
+//! A ready-made codec for any type that implements rustc-serialize's `Encodable` and `Decodable`
+//!
+//! Packets are encoded with bincode, so there's no need to hand-roll a serializer/deserializer pair.
+//!
+use std::time::duration::Duration;
+use rustc_serialize::{Encodable, Decodable};
+use bincode;
+use bincode::SizeLimit;
+use shared::ConnectionConfig;
+
+/**
+ * Turn raw data into a packet using bincode
+ */
+pub fn deserializer<T: Decodable>(message: &Vec<u8>) -> Option<T> {
+    bincode::decode(message.as_slice()).ok()
+}
+
+/**
+ * Turn a packet into raw data using bincode
+ */
+pub fn serializer<T: Encodable>(packet: &T) -> Option<Vec<u8>> {
+    bincode::encode(packet, SizeLimit::Infinite).ok()
+}
+
+impl <T: Encodable + Decodable> ConnectionConfig <T> {
+
+    /**
+     * Create a new ConnectionConfig object which uses the bincode codec
+     */
+    pub fn with_bincode(protocol_id: u32, timeout_period: Duration) -> ConnectionConfig<T> {
+        ConnectionConfig::new(protocol_id, timeout_period, deserializer::<T>, serializer::<T>)
+    }
+}
@@ -0,0 +1,329 @@
+//!
+//! Bit-level reading and writing, for squeezing packets into as few bytes as possible
+//!
+//! A BitWriter and BitReader can be used directly inside the serializer and deserializer hooks on
+//! a ConnectionConfig:
+//!
+//! ```ignore
+//! fn serializer(packet: &Move) -> Option<Vec<u8>> {
+//!     let mut writer = BitWriter::new();
+//!     match writer.write_ranged(packet.player as i64, 0, 31) {
+//!         Ok(()) => writer.write_quantized(packet.x as f64, -100.0, 100.0, 12),
+//!         Err(_) => return None
+//!     }
+//!     Some(writer.into_bytes())
+//! }
+//!
+//! fn deserializer(raw: &Vec<u8>) -> Option<Move> {
+//!     let mut reader = BitReader::new(raw.as_slice());
+//!     match (reader.read_ranged(0, 31), reader.read_quantized(-100.0, 100.0, 12)) {
+//!         (Ok(player), Ok(x)) if reader.finish().is_ok() => Some(Move { player: player as u8, x: x as f32 }),
+//!         _ => None
+//!     }
+//! }
+//! ```
+//!
+use std::num::{Int, Float};
+
+/**
+ * Reasons that reading from a BitReader, or writing a bounded value to a BitWriter, can fail
+ */
+#[derive(Clone, Show, PartialEq, Copy)]
+pub enum BitError {
+    ///Tried to read past the end of the data
+    EndOfData,
+    ///A bounded value was outside of the range it should have been written with, or too large to write
+    OutOfRange,
+    ///A variable-length integer didn't fit into 64 bits
+    VarintOverflow,
+    ///There was data left over once everything had been read
+    TrailingData,
+    ///The data couldn't be turned into the type asked for, such as invalid UTF-8
+    InvalidData
+}
+
+pub type BitResult<T> = Result<T, BitError>;
+
+/**
+ * How many bits are needed to store any value between min and max, inclusive
+ */
+pub fn bits_for_range(min: i64, max: i64) -> usize {
+    assert!(min <= max, "Range minimum must not be larger than its maximum");
+    let range = (max as u64).wrapping_sub(min as u64);
+    64 - range.leading_zeros() as usize
+}
+
+/**
+ * Map a float in the range min..max onto an integer of the given bit width
+ *
+ * Values outside of the range are clamped
+ */
+pub fn quantize(value: f64, min: f64, max: f64, bits: usize) -> u64 {
+    assert!(bits > 0 && bits < 64, "Quantized values must use between 1 and 63 bits");
+    let steps = ((1u64 << bits) - 1) as f64;
+    let clamped = value.max(min).min(max);
+    ((clamped - min) / (max - min) * steps).round() as u64
+}
+
+/**
+ * Turn a quantized integer back into a float in the range min..max
+ */
+pub fn dequantize(value: u64, min: f64, max: f64, bits: usize) -> f64 {
+    assert!(bits > 0 && bits < 64, "Quantized values must use between 1 and 63 bits");
+    let steps = ((1u64 << bits) - 1) as f64;
+    min + (value as f64 / steps) * (max - min)
+}
+
+/**
+ * Writes values into a buffer using only as many bits as asked for
+ */
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    length: usize
+}
+
+impl BitWriter {
+
+    /**
+     * Create an empty BitWriter
+     */
+    pub fn new() -> BitWriter {
+        BitWriter {
+            bytes: vec![],
+            length: 0
+        }
+    }
+
+    /**
+     * Write the lowest `bits` bits of a value, most significant bit first
+     */
+    pub fn write_bits(&mut self, value: u64, bits: usize) {
+        assert!(bits <= 64, "Can't write more than 64 bits at once");
+        for i in (0..bits).rev() {
+            if self.length % 8 == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= bit << (7 - self.length % 8);
+            self.length += 1;
+        }
+    }
+
+    /**
+     * Write a single bit
+     */
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(if value { 1 } else { 0 }, 1);
+    }
+
+    /**
+     * Write an unsigned integer using `bits` bits, failing with OutOfRange if it doesn't fit or `bits` is over 64
+     */
+    pub fn write_unsigned(&mut self, value: u64, bits: usize) -> BitResult<()> {
+        if bits > 64 || (bits < 64 && value >> bits != 0) {
+            return Err(BitError::OutOfRange);
+        }
+        self.write_bits(value, bits);
+        Ok(())
+    }
+
+    /**
+     * Write a signed integer as a `bits` bit two's complement value, failing with OutOfRange if it doesn't fit
+     *
+     * A sign needs at least one bit, and no more than 64 can be written, so any other width fails too.
+     */
+    pub fn write_signed(&mut self, value: i64, bits: usize) -> BitResult<()> {
+        if bits == 0 || bits > 64 {
+            return Err(BitError::OutOfRange);
+        }
+        if bits < 64 && (value < -(1i64 << (bits - 1)) || value >= 1i64 << (bits - 1)) {
+            return Err(BitError::OutOfRange);
+        }
+        self.write_bits(value as u64, bits);
+        Ok(())
+    }
+
+    /**
+     * Write an integer between min and max, using as few bits as that range needs
+     *
+     * Fails with OutOfRange, writing nothing, if the value is outside of the range
+     */
+    pub fn write_ranged(&mut self, value: i64, min: i64, max: i64) -> BitResult<()> {
+        if value < min || value > max {
+            return Err(BitError::OutOfRange);
+        }
+        self.write_bits((value as u64).wrapping_sub(min as u64), bits_for_range(min, max));
+        Ok(())
+    }
+
+    /**
+     * Write a float in the range min..max as a fixed-point value of the given bit width
+     *
+     * Values outside of the range are clamped
+     */
+    pub fn write_quantized(&mut self, value: f64, min: f64, max: f64, bits: usize) {
+        self.write_bits(quantize(value, min, max, bits), bits);
+    }
+
+    /**
+     * Write an unsigned integer in groups of 7 bits, so small values take up less room
+     */
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let group = value & 0x7F;
+            value >>= 7;
+            self.write_bool(value != 0);
+            self.write_bits(group, 7);
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    /**
+     * Write a signed integer as a varint, so small negative values also take up less room
+     */
+    pub fn write_signed_varint(&mut self, value: i64) {
+        self.write_varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    /**
+     * How many bits have been written so far
+     */
+    pub fn bits_written(&self) -> usize {
+        self.length
+    }
+
+    /**
+     * Get the written data, padded out to a whole number of bytes with zeroes
+     */
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/**
+ * Reads back values written by a BitWriter
+ *
+ * Every read checks its input, so malformed or malicious data results in an error rather than a panic
+ */
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl <'a> BitReader <'a> {
+
+    /**
+     * Create a BitReader over some raw data
+     */
+    pub fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes: bytes,
+            position: 0
+        }
+    }
+
+    /**
+     * Read a value that was written with `bits` bits
+     */
+    pub fn read_bits(&mut self, bits: usize) -> BitResult<u64> {
+        assert!(bits <= 64, "Can't read more than 64 bits at once");
+        if bits > self.bits_remaining() {
+            return Err(BitError::EndOfData);
+        }
+        let mut value = 0u64;
+        for _ in 0..bits {
+            let bit = (self.bytes[self.position / 8] >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    /**
+     * Read a single bit
+     */
+    pub fn read_bool(&mut self) -> BitResult<bool> {
+        self.read_bits(1).map(|bit| bit == 1)
+    }
+
+    /**
+     * Read an integer that was written with `write_signed`, failing with OutOfRange for a width it couldn't have been written with
+     */
+    pub fn read_signed(&mut self, bits: usize) -> BitResult<i64> {
+        if bits == 0 || bits > 64 {
+            return Err(BitError::OutOfRange);
+        }
+        let shift = 64 - bits;
+        self.read_bits(bits).map(|raw| ((raw << shift) as i64) >> shift)
+    }
+
+    /**
+     * Read an integer that was written with `write_ranged`
+     */
+    pub fn read_ranged(&mut self, min: i64, max: i64) -> BitResult<i64> {
+        let raw = try!(self.read_bits(bits_for_range(min, max)));
+        if raw > (max as u64).wrapping_sub(min as u64) {
+            Err(BitError::OutOfRange)
+        } else {
+            Ok((min as u64).wrapping_add(raw) as i64)
+        }
+    }
+
+    /**
+     * Read a float that was written with `write_quantized`
+     */
+    pub fn read_quantized(&mut self, min: f64, max: f64, bits: usize) -> BitResult<f64> {
+        self.read_bits(bits).map(|raw| dequantize(raw, min, max, bits))
+    }
+
+    /**
+     * Read an integer that was written with `write_varint`
+     */
+    pub fn read_varint(&mut self) -> BitResult<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let more = try!(self.read_bool());
+            let group = try!(self.read_bits(7));
+            if shift >= 64 || (shift > 0 && group >> (64 - shift) != 0) {
+                return Err(BitError::VarintOverflow);
+            }
+            value |= group << shift;
+            shift += 7;
+            if !more {
+                return Ok(value);
+            }
+        }
+    }
+
+    /**
+     * Read an integer that was written with `write_signed_varint`
+     */
+    pub fn read_signed_varint(&mut self) -> BitResult<i64> {
+        self.read_varint().map(|raw| ((raw >> 1) as i64) ^ -((raw & 1) as i64))
+    }
+
+    /**
+     * How many bits are left unread, including any padding
+     */
+    pub fn bits_remaining(&self) -> usize {
+        self.bytes.len() * 8 - self.position
+    }
+
+    /**
+     * Check that everything has been read, leaving nothing but zeroed padding
+     */
+    pub fn finish(&self) -> BitResult<()> {
+        let remaining = self.bits_remaining();
+        if remaining >= 8 {
+            return Err(BitError::TrailingData);
+        }
+        if remaining > 0 && self.bytes[self.bytes.len() - 1] & ((1u8 << remaining) - 1) != 0 {
+            return Err(BitError::TrailingData);
+        }
+        Ok(())
+    }
+}
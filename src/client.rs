@@ -1,18 +1,28 @@
 use std::old_io::net::udp::UdpSocket;
 use std::old_io::net::ip::SocketAddr;
-use std::old_io::{IoResult, IoError, OtherIoError, TimedOut};
+use std::old_io::{TimedOut, EndOfFile};
 use std::old_io::Timer;
 use std::sync::mpsc::{Sender, Receiver, TryRecvError, channel, Select};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::Thread;
 use std::time::duration::Duration;
-use packet::{Packet, PacketType, TaskCommand};
-use shared::{ConnectionConfig, SequenceManager};
-use time::now;
+use packet::{TaskCommand, MAX_PACKET_SIZE};
+use shared::ConnectionConfig;
+use error::{Error, NetResult};
+use transport::Transport;
+use relay::RelayTransport;
+use conditioner::LinkConditioner;
+use clock::SharedClock;
+use protocol::{ClientProtocol, ClientEvent, Datagram, handshake_result};
+use queue::{QueueSender, QueueReceiver, QueueSendError, QueueStats, SharedSender, queue, refuse_block};
+use worker::{WorkerStep, Waker, wait_for, woken, deadline_after, conditioned, READ_TIMEOUT_MS, WAKE_CHECK_MS};
+use time::precise_time_ns;
 
 
 /**
  * The current state of a connection
  */
+#[derive(Clone, Copy, PartialEq, Show)]
 pub enum ConnectionState {
     Disconnected,
     Connecting,
@@ -24,84 +34,127 @@ pub enum ConnectionState {
  */
 pub enum PollFailResult {
     Empty,
-    Disconnected
+    Disconnected,
+    ///Something went wrong in the background, such as a failed send
+    Failed(Error)
 }
 
-fn reader_process(mut reader: UdpSocket, send: Sender<Packet>, recv: Receiver<TaskCommand>, target_addr: SocketAddr, protocol_id: u32, timeout_period: Duration) {
-    let mut buf = [0; 1024];
-    reader.set_timeout(Some(1000));
+/**
+ * The receiving half of a connection, run on its own thread or pumped by hand
+ *
+ * It only moves datagrams, stamped with when they arrived. Making sense of them is up to the protocol.
+ */
+struct Reader<N> {
+    transport: N,
+    send: QueueSender<(Vec<u8>, SocketAddr, u64)>,
+    errors: Sender<Error>,
+    commands: Receiver<TaskCommand>,
+    ///Hung up when this worker goes, however it goes
+    finished: Sender<()>,
+    target_addr: SocketAddr,
+    clock: SharedClock
+}
 
-    let mut expires = now().to_timespec().sec + timeout_period.num_seconds();
+impl <N: Transport> Reader<N> {
 
-    loop {
-        match reader.recv_from(&mut buf) {
+    /**
+     * Pass on a single datagram, or notice there's nothing to pass on
+     */
+    fn step(&mut self) -> WorkerStep {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        match self.transport.recv_from(&mut buf) {
+            //An empty datagram only means a hang up on a stream. From anything else it could be forged
+            Ok((0, _)) if !self.transport.is_stream() => WorkerStep::Busy,
             Ok((amt, src)) => {
-                if src == target_addr {
-                    match Packet::deserialize(buf.slice_to(amt)) {
-                        Ok(packet) => {
-                            if packet.protocol_id == protocol_id {
-                                match send.send(packet) {
-                                    Ok(()) => {
-                                        expires = now().to_timespec().sec + timeout_period.num_seconds();
-                                    },
-                                    Err(_) => {
-                                        //Other end hung up, we should give up
-                                        break;
-                                    }
-                                }
-                            }
-                        },
-                        Err(_) => ()
-                    }
+                match self.send.send((buf.slice_to(amt).to_vec(), src, self.clock.now_ms())) {
+                    //Once the server has hung up, there's nothing more to read
+                    Ok(()) => if amt == 0 && src == self.target_addr { WorkerStep::Stop } else { WorkerStep::Busy },
+                    Err(QueueSendError::Full) => {
+                        match self.errors.send(Error::QueueFull) {
+                            Ok(()) => WorkerStep::Busy,
+                            Err(_) => WorkerStep::Stop
+                        }
+                    },
+                    Err(QueueSendError::Closed) => WorkerStep::Stop
                 }
             },
             Err(e) => {
                 match e.kind {
                     TimedOut => {
-                        match recv.try_recv() {
-                            Ok(TaskCommand::Disconnect) => {
-                                break;
-                            },
-                            Err(TryRecvError::Disconnected) => {
-                                break;
-                            },
-                            Err(TryRecvError::Empty) => {
-                                //Keep going
-                            }
+                        match self.commands.try_recv() {
+                            Ok(TaskCommand::Disconnect) => WorkerStep::Stop,
+                            Err(TryRecvError::Disconnected) => WorkerStep::Stop,
+                            Err(TryRecvError::Empty) => WorkerStep::Idle
                         }
                     },
-                    _ => ()
+                    //The transport has closed, so nothing more can ever arrive
+                    EndOfFile => WorkerStep::Stop,
+                    _ => {
+                        match self.errors.send(Error::Io(e)) {
+                            Ok(()) => WorkerStep::Idle,
+                            Err(_) => WorkerStep::Stop
+                        }
+                    }
                 }
             }
-        };
-        if now().to_timespec().sec > expires {
-            //FIXME: Need a nicer way of ignoring failure for this
-            //FIXME: Bad sequence ID!
-            match send.send(Packet::disconnect(protocol_id, 0)) {
-                _ => break
-            }
         }
     }
 }
 
-fn writer_process(mut writer: UdpSocket, recv: Receiver<Packet>, target_addr: SocketAddr) {
-    for msg in recv.iter() {
-        match msg.serialize() {
-            Ok(msg) => {
-                match writer.send_to(msg.as_slice(), target_addr) {
-                    Ok(()) => (),
-                    Err(e) => println!("Error sending data - {}", e)
-                }
-            },
-            Err(_) => ()
+fn reader_process<N: Transport>(mut reader: Reader<N>) {
+    reader.transport.set_timeout(Some(READ_TIMEOUT_MS));
+    while reader.step() != WorkerStep::Stop {}
+}
+
+/**
+ * The sending half of a connection, run on its own thread or pumped by hand
+ */
+struct Writer<N> {
+    transport: N,
+    recv: QueueReceiver<Datagram>,
+    errors: Sender<Error>,
+    finished: Sender<()>
+}
+
+impl <N: Transport> Writer<N> {
+
+    /**
+     * Put a datagram on the wire, returning false if nobody is listening for our errors any more
+     */
+    fn write(&mut self, (data, target_addr): Datagram) -> bool {
+        match self.transport.send_to(data.as_slice(), target_addr) {
+            Ok(()) => true,
+            Err(e) => self.errors.send(Error::Io(e)).is_ok()
+        }
+    }
+
+    /**
+     * Send a single queued datagram, if there is one
+     */
+    fn step(&mut self) -> WorkerStep {
+        match self.recv.try_recv() {
+            Ok(datagram) => if self.write(datagram) { WorkerStep::Busy } else { WorkerStep::Stop },
+            Err(TryRecvError::Empty) => WorkerStep::Idle,
+            Err(TryRecvError::Disconnected) => WorkerStep::Stop
+        }
+    }
+}
+
+fn writer_process<N: Transport>(mut writer: Writer<N>) {
+    loop {
+        match writer.recv.recv() {
+            Ok(datagram) => if !writer.write(datagram) { break },
+            Err(_) => break
         }
     }
 }
 
 /**
- * Clientside implementation of UDP networking
+ * Clientside implementation of networking, over UDP unless another Transport is given
+ *
+ * This drives a `ClientProtocol`, which holds the connection's actual state.
  */
-pub struct Client <T> {
+pub struct Client <T, N: Transport = UdpSocket> {
     ///The socket we should use locally
     pub addr: SocketAddr,
     ///The socket of the server we intent to connect to
@@ -113,10 +166,18 @@ pub struct Client <T> {
     pub connection_state: ConnectionState,
 
     reader_send: Sender<TaskCommand>,
-    reader_receive: Receiver<Packet>,
-    writer_send: Sender<Packet>,
-
-    sequence_manager: SequenceManager
+    reader_receive: QueueReceiver<(Vec<u8>, SocketAddr, u64)>,
+    writer_send: SharedSender<Datagram>,
+    error_receive: Receiver<Error>,
+    transport: N,
+    pumped_reader: Option<Reader<LinkConditioner<N>>>,
+    pumped_writer: Option<Writer<LinkConditioner<N>>>,
+    waker: Waker,
+    wakes: Receiver<()>,
+    finished: Receiver<()>,
+    shut_down: bool,
+
+    protocol: Arc<Mutex<ClientProtocol>>
 }
 
 /**
@@ -142,114 +203,321 @@ impl ClientConnectionConfig {
     }
 }
 
-impl <T> Client <T> {
+impl <T> Client <T, UdpSocket> {
 
     /**
      * Connect our Client to a target Server.
      * Will block until either a valid connection is made, or we give up
      */
-    pub fn connect(addr: SocketAddr, target_addr: SocketAddr, config: ConnectionConfig<T>, client_connection_config: ClientConnectionConfig) -> IoResult<Client<T>> {
-         match UdpSocket::bind(addr) {
-            Ok(reader) => {
-                let writer = reader.clone();
+    pub fn connect(addr: SocketAddr, target_addr: SocketAddr, config: ConnectionConfig<T>, client_connection_config: ClientConnectionConfig) -> NetResult<Client<T, UdpSocket>> {
+        let socket = try!(UdpSocket::bind(addr));
+        Client::connect_with(socket, target_addr, config, client_connection_config)
+    }
 
-                let (reader_send, reader_task_receive) = channel();
-                let (reader_task_send, reader_receive) = channel();
+    /**
+     * Start connecting our Client to a target Server, without any background threads.
+     *
+     * The socket never blocks, and this returns straight away in the Connecting state. Call `pump`
+     * every frame to send, receive and carry the connection attempt along, then `poll` as normal.
+     */
+    pub fn connect_nonblocking(addr: SocketAddr, target_addr: SocketAddr, config: ConnectionConfig<T>, client_connection_config: ClientConnectionConfig) -> NetResult<Client<T, UdpSocket>> {
+        let socket = try!(UdpSocket::bind(addr));
+        Client::connect_unthreaded(socket, target_addr, config, client_connection_config)
+    }
+}
 
-                let protocol_id = config.protocol_id;
-                let timeout_period = config.timeout_period;
+impl <T> Client <T, RelayTransport<UdpSocket>> {
 
-                Thread::spawn(move || {
-                    reader_process(reader, reader_task_send, reader_task_receive, target_addr, protocol_id, timeout_period);
-                });
+    /**
+     * Connect our Client to a Server hosting through a relay with the same session token.
+     * Will block until either a valid connection is made, or we give up
+     */
+    pub fn connect_via_relay(addr: SocketAddr, relay_addr: SocketAddr, token: u64, config: ConnectionConfig<T>, client_connection_config: ClientConnectionConfig) -> NetResult<Client<T, RelayTransport<UdpSocket>>> {
+        let socket = try!(UdpSocket::bind(addr));
+        let transport = try!(RelayTransport::with_clock(socket, relay_addr, token, config.clock.clone()));
+        Client::connect_with(transport, relay_addr, config, client_connection_config)
+    }
+}
+
+impl <T, N: Transport> Client <T, N> {
 
-                let (writer_send, writer_task_receive) = channel();
+    /**
+     * Connect our Client to a target Server over any Transport.
+     * Will block until either a valid connection is made, or we give up
+     */
+    pub fn connect_with(transport: N, target_addr: SocketAddr, config: ConnectionConfig<T>, client_connection_config: ClientConnectionConfig) -> NetResult<Client<T, N>> {
+        let (mut client, reader, writer) = try!(Client::build(transport, target_addr, config));
 
-                Thread::spawn(move || {
-                    writer_process(writer, writer_task_receive, target_addr);
-                });
+        Thread::spawn(move || {
+            reader_process(reader);
+        });
 
-                let mut client = Client {
-                    addr: addr,
-                    target_addr: target_addr,
-                    reader_send: reader_send,
-                    reader_receive: reader_receive,
-                    writer_send: writer_send,
-                    connection_state: ConnectionState::Disconnected,
-                    config: config,
-                    sequence_manager: SequenceManager::new()
-                };
+        Thread::spawn(move || {
+            writer_process(writer);
+        });
 
-                if client.connection_dance(client_connection_config.max_connect_retries, client_connection_config.connect_attempt_timeout) {
-                    Ok(client)
-                } else {
-                    Err(IoError {
-                        kind: OtherIoError,
-                        desc: "Failed to connect",
-                        detail: None
-                    })
+        try!(client.connection_dance(client_connection_config.max_connect_retries, client_connection_config.connect_attempt_timeout));
+        Ok(client)
+    }
+
+    /**
+     * Start connecting to a target Server without any background threads.
+     *
+     * This returns straight away, in the Connecting state. Nothing is sent or received until
+     * `pump` is called, which also carries the connection attempt along. Neither queue may be set to block.
+     */
+    pub fn connect_unthreaded(transport: N, target_addr: SocketAddr, config: ConnectionConfig<T>, client_connection_config: ClientConnectionConfig) -> NetResult<Client<T, N>> {
+        try!(refuse_block(config.inbound_queue, "inbound"));
+        try!(refuse_block(config.outbound_queue, "outbound"));
+        let (mut client, mut reader, writer) = try!(Client::build(transport, target_addr, config));
+        reader.transport.set_timeout(Some(0));
+
+        client.pumped_reader = Some(reader);
+        client.pumped_writer = Some(writer);
+        let now = client.config.clock.now_ms();
+        let event = client.protocol().connect(client_connection_config.max_connect_retries, client_connection_config.connect_attempt_timeout, now);
+        match event {
+            Some(event) => {
+                client.connection_state = client.protocol().state();
+                try!(client.finish_handshake(event));
+            },
+            None => client.connection_state = client.protocol().state()
+        }
+        try!(client.flush());
+        Ok(client)
+    }
+
+    /**
+     * Set up a Client and the workers which will carry its traffic
+     */
+    fn build(mut transport: N, target_addr: SocketAddr, config: ConnectionConfig<T>) -> NetResult<(Client<T, N>, Reader<LinkConditioner<N>>, Writer<LinkConditioner<N>>)> {
+        let addr = try!(transport.local_addr());
+
+        let (reader_send, reader_task_receive) = channel();
+        let (reader_task_send, reader_receive) = queue(config.inbound_queue);
+        let (error_send, error_receive) = channel();
+        let (writer_send, writer_task_receive) = queue(config.outbound_queue);
+        let (waker, wakes) = Waker::new();
+        let (finished_send, finished) = channel();
+
+        let reader = Reader {
+            transport: try!(conditioned(&transport, &config, &finished_send)),
+            send: reader_task_send,
+            errors: error_send.clone(),
+            commands: reader_task_receive,
+            finished: finished_send.clone(),
+            target_addr: target_addr,
+            clock: config.clock.clone()
+        };
+
+        let writer = Writer {
+            transport: try!(conditioned(&transport, &config, &finished_send)),
+            recv: writer_task_receive,
+            errors: error_send,
+            finished: finished_send
+        };
+
+        let client = Client {
+            addr: addr,
+            target_addr: target_addr,
+            reader_send: reader_send,
+            reader_receive: reader_receive,
+            writer_send: SharedSender::new(writer_send),
+            error_receive: error_receive,
+            transport: transport,
+            pumped_reader: None,
+            pumped_writer: None,
+            waker: waker,
+            wakes: wakes,
+            finished: finished,
+            shut_down: false,
+            connection_state: ConnectionState::Disconnected,
+            protocol: Arc::new(Mutex::new(ClientProtocol::new(config.protocol_id, target_addr, config.timeout_period))),
+            config: config
+        };
+        Ok((client, reader, writer))
+    }
+
+    /**
+     * Do any waiting network work on the calling thread, for Clients made with connect_unthreaded or
+     * connect_nonblocking. Clients with their own threads have nothing to do here.
+     *
+     * Everything queued is sent, everything which has arrived is read, and any connection
+     * attempt is carried along. Fails if we were trying to connect, and have given up.
+     */
+    pub fn pump(&mut self) -> NetResult<()> {
+        self.pump_writer();
+        let stopped = match self.pumped_reader {
+            Some(ref mut reader) => {
+                let mut step = reader.step();
+                while step == WorkerStep::Busy {
+                    step = reader.step();
                 }
-            }
-            Err(e) => Err(e)
+                step == WorkerStep::Stop
+            },
+            None => false
+        };
+        if stopped {
+            //Just as if a reader thread had finished
+            self.pumped_reader = None;
         }
+        let result = self.step_handshake();
+        self.pump_writer();
+        result
+    }
+
+    fn pump_writer(&mut self) {
+        match self.pumped_writer {
+            Some(ref mut writer) => {
+                while writer.step() == WorkerStep::Busy {}
+            },
+            None => ()
+        }
+    }
+
+    fn protocol(&self) -> MutexGuard<ClientProtocol> {
+        self.protocol.lock().unwrap()
     }
 
     /**
-     * A blocking connection request
+     * Hand everything the protocol wants sent to the writer
      */
-    fn connection_dance(&mut self, max_attempts: u32, timeout: Duration) -> bool {
-        self.connection_state = ConnectionState::Connecting;
-        let mut timer = Timer::new().unwrap();
-        let mut attempts = 0u32;
+    fn flush(&mut self) -> NetResult<()> {
+        //A turn taken before letting go of the protocol, so a ClientSender can't get in between
+        let (turn, datagrams) = {
+            let mut protocol = self.protocol();
+            (self.writer_send.turn(), protocol.drain_outgoing())
+        };
+        try!(turn.send_all(datagrams));
+        Ok(())
+    }
 
-        while attempts < max_attempts && match self.connection_state { ConnectionState::Connecting => true, _ => false } {
-            self.writer_send.send(Packet::connect(self.config.protocol_id, self.sequence_manager.next_sequence_id()));
+    /**
+     * Feed the protocol something the reader passed on
+     */
+    fn receive(&mut self, data: &[u8], src: SocketAddr, received: u64) -> Option<ClientEvent> {
+        //The reader only passes on empty datagrams from streams, to tell us they've hung up
+        if data.len() == 0 {
+            self.protocol().hang_up(src)
+        } else {
+            self.protocol().handle(data, src, received)
+        }
+    }
 
-            let timeout = timer.oneshot(timeout);
+    /**
+     * The reader has gone, so nothing more can arrive
+     */
+    fn reader_died(&mut self) {
+        self.protocol().disconnect();
+        self.connection_state = ConnectionState::Disconnected;
+        match self.flush() {
+            _ => ()
+        }
+    }
+
+    /**
+     * Feed the protocol anything which has arrived while connecting, and let it ask again when it's due
+     */
+    fn step_handshake(&mut self) -> NetResult<()> {
+        match self.connection_state {
+            ConnectionState::Connecting => (),
+            _ => return Ok(())
+        };
+
+        loop {
+            match self.reader_receive.try_recv() {
+                Ok((data, src, received)) => {
+                    let event = self.receive(data.as_slice(), src, received);
+                    match event {
+                        Some(event) => {
+                            self.connection_state = self.protocol().state();
+                            return self.finish_handshake(event);
+                        },
+                        None => ()
+                    }
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.reader_died();
+                    return Err(Error::ThreadDied);
+                }
+            }
+        }
+
+        let event = self.protocol().update(self.config.clock.now_ms());
+        self.connection_state = self.protocol().state();
+        try!(self.flush());
+        match event {
+            Some(event) => self.finish_handshake(event),
+            None => Ok(())
+        }
+    }
 
-            //FIXME: Replace with the select! macro when it starts working
-            let sel = Select::new();
-            let mut reader = sel.handle(&self.reader_receive);
-            let mut timeout = sel.handle(&timeout);
-            unsafe { reader.add(); timeout.add(); }
-            let ret = sel.wait();
-            if ret == reader.id() {
+    /**
+     * A blocking connection request
+     */
+    fn connection_dance(&mut self, max_attempts: u32, timeout: Duration) -> NetResult<()> {
+        let mut timer = try!(Timer::new());
+        let mut event = self.protocol().connect(max_attempts, timeout, self.config.clock.now_ms());
+
+        while event.is_none() {
+            try!(self.flush());
+            let now = self.config.clock.now_ms();
+            let wait = match self.protocol().next_deadline() {
+                Some(deadline) if deadline > now => deadline - now,
+                _ => 0
+            };
+            let timeout = timer.oneshot(Duration::milliseconds(wait as i64));
+
+            let (arrived, timed_out) = {
+                let arrivals = self.reader_receive.selectable();
+                //FIXME: Replace with the select! macro when it starts working
+                let sel = Select::new();
+                let mut reader = sel.handle(&*arrivals);
+                let mut timeout = sel.handle(&timeout);
+                unsafe { reader.add(); timeout.add(); }
+                let ret = sel.wait();
+                (ret == reader.id(), ret == timeout.id())
+            };
+            if arrived {
                 match self.reader_receive.recv() {
-                    Ok(packet) => {
-                        match packet.packet_type {
-                            PacketType::Accept => {
-                                self.connection_state = ConnectionState::Connected;
-                            }
-                            PacketType::Reject => {
-                                self.connection_state = ConnectionState::Disconnected;
-                            }
-                            PacketType::Disconnect => {
-                                self.connection_state = ConnectionState::Disconnected;
-                            }
-                            _ => (),
-                        }
+                    Ok((data, src, received)) => {
+                        event = self.receive(data.as_slice(), src, received);
                     },
                     Err(_) => {
-                        panic!("Reader disconnected while connection dancing."); //FIXME: There's gotta be a better way to do this
+                        self.reader_died();
+                        return Err(Error::ThreadDied);
                     }
                 }
-            } else if ret == timeout.id() {
-                let _ = timeout.recv();
-                attempts += 1;
+            } else if timed_out {
+                match timeout.recv() {
+                    _ => event = self.protocol().update(self.config.clock.now_ms())
+                }
             } else {
                 unreachable!();
             }
         }
 
-        match self.connection_state {
-            ConnectionState::Connecting => {
-                self.connection_state = ConnectionState::Disconnected;
-                false
+        self.connection_state = self.protocol().state();
+        try!(self.flush());
+        self.finish_handshake(event.unwrap())
+    }
+
+    /**
+     * Turn the end of a handshake into a result
+     *
+     * A handshake which timed out may well have done so because sending failed, in which case that
+     * failure is the more useful thing to report.
+     */
+    fn finish_handshake(&mut self, event: ClientEvent) -> NetResult<()> {
+        match handshake_result(event) {
+            Err(Error::TimedOut) => {
+                match self.error_receive.try_recv() {
+                    Ok(e) => Err(e),
+                    Err(_) => Err(Error::TimedOut)
+                }
             },
-            ConnectionState::Disconnected => false,
-            ConnectionState::Connected => {
-                true
-            }
+            result => result
         }
     }
 
@@ -258,58 +526,355 @@ impl <T> Client <T> {
      */
     pub fn poll(&mut self) -> Result<T, PollFailResult> {
         match self.connection_state {
-            ConnectionState::Connected => {
-                let mut result = Err(PollFailResult::Empty);
-                loop {
-                    match self.reader_receive.try_recv() {
-                        Ok(value) => {
-                            match value.packet_type {
-                                PacketType::Disconnect => {
-                                    self.connection_state = ConnectionState::Disconnected;
-                                    result = Err(PollFailResult::Disconnected);
+            ConnectionState::Connected => (),
+            _ => return Err(PollFailResult::Disconnected)
+        }
+
+        match self.error_receive.try_recv() {
+            Ok(e) => return Err(PollFailResult::Failed(e)),
+            Err(_) => ()
+        };
+
+        let mut result = Err(PollFailResult::Empty);
+        loop {
+            match self.reader_receive.try_recv() {
+                Ok((data, src, received)) => {
+                    let event = self.receive(data.as_slice(), src, received);
+                    match event {
+                        Some(ClientEvent::Message(payload)) => {
+                            match (self.config.packet_deserializer)(&payload) {
+                                Some(deserialized) => {
+                                    result = Ok(deserialized);
                                     break;
                                 },
-                                PacketType::Message => {
-                                    //Are we expecting this packet?
-                                    if self.sequence_manager.packet_is_newer(value.sequence_id) {
-                                        self.sequence_manager.set_newest_packet(value.sequence_id);
-                                        match (self.config.packet_deserializer)(&value.packet_content.unwrap()) {
-                                            Some(deserialized) => {
-                                                result = Ok(deserialized);
-                                                break;
-                                            },
-                                            None => ()
-                                        }
-                                    }
-                                },
-                                _ => ()
+                                None => ()
                             }
                         },
-                        _ => break
-                    };
+                        Some(ClientEvent::Disconnected) => {
+                            result = Err(PollFailResult::Disconnected);
+                            break;
+                        },
+                        _ => ()
+                    }
+                },
+                Err(TryRecvError::Disconnected) => {
+                    self.reader_died();
+                    return Err(PollFailResult::Failed(Error::ThreadDied));
+                },
+                Err(TryRecvError::Empty) => {
+                    //Everything which arrived has been seen, so now see whether the server has gone quiet
+                    let event = self.protocol().update(self.config.clock.now_ms());
+                    match event {
+                        Some(ClientEvent::Disconnected) => result = Err(PollFailResult::Disconnected),
+                        _ => ()
+                    }
+                    break;
                 }
-                result
+            };
+        }
+        self.connection_state = self.protocol().state();
+        match self.flush() {
+            Ok(()) => result,
+            Err(e) => Err(PollFailResult::Failed(e))
+        }
+    }
+
+    /**
+     * Like `poll`, but if nothing is waiting, block until something arrives or the timeout passes
+     *
+     * Fails with Empty if the timeout passes, or if we're woken through a `Waker`. Clients made to be
+     * pumped are pumped while waiting.
+     */
+    pub fn poll_timeout(&mut self, timeout: Duration) -> Result<T, PollFailResult> {
+        self.poll_until(Some(deadline_after(timeout)))
+    }
+
+    /**
+     * Like `poll`, but if nothing is waiting, block until something arrives, the server times out,
+     * or we're woken through a `Waker`
+     */
+    pub fn wait(&mut self) -> Result<T, PollFailResult> {
+        self.poll_until(None)
+    }
+
+    /**
+     * Something which can end a `wait` or `poll_timeout` early, from another thread
+     */
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+
+    fn poll_until(&mut self, deadline: Option<u64>) -> Result<T, PollFailResult> {
+        loop {
+            match self.poll() {
+                Err(PollFailResult::Empty) => (),
+                result => return result
+            }
+
+            //Don't sleep past the point where the server should be given up on
+            let now = precise_time_ns();
+            let left = match deadline {
+                Some(deadline) if deadline <= now => return Err(PollFailResult::Empty),
+                Some(deadline) => Some((deadline - now) / 1000000 + 1),
+                None => None
+            };
+            let clock_now = self.config.clock.now_ms();
+            let wait_ms = match (left, self.protocol().next_deadline()) {
+                (Some(left), Some(next)) if next > clock_now => Some(::std::cmp::min(left, next - clock_now)),
+                (None, Some(next)) if next > clock_now => Some(next - clock_now),
+                (left, _) => left
+            };
+
+            match self.block(wait_ms) {
+                Ok(true) => return Err(PollFailResult::Empty),
+                Ok(false) => (),
+                Err(e) => return Err(PollFailResult::Failed(e))
+            }
+        }
+    }
+
+    /**
+     * Wait for the reader, or the transport itself if we're pumped. Returns true if we were woken
+     */
+    fn block(&mut self, wait_ms: Option<u64>) -> NetResult<bool> {
+        //Anything waiting to go out shouldn't have to wait with us
+        self.pump_writer();
+        let stopped = match self.pumped_reader {
+            Some(ref mut reader) => {
+                //Nothing can wake the transport, so it's waited on a little at a time, looking for wakes in between
+                let slice = match wait_ms {
+                    Some(ms) if ms < WAKE_CHECK_MS => ms,
+                    _ => WAKE_CHECK_MS
+                };
+                reader.transport.set_timeout(Some(slice));
+                let step = reader.step();
+                reader.transport.set_timeout(Some(0));
+                step == WorkerStep::Stop
             },
-            _ => Err(PollFailResult::Disconnected)
+            None => return wait_for(&*self.reader_receive.selectable(), &self.error_receive, &self.wakes, wait_ms).map_err(|e| Error::Io(e))
+        };
+        if stopped {
+            self.pumped_reader = None;
         }
+        try!(self.pump());
+        Ok(woken(&self.wakes))
     }
 
     /**
-     * Send a packet to the server
+     * Say goodbye to the server, send everything still queued, and stop our threads
+     *
+     * Returns the first error any of that ran into. A reader thread can take up to a second to notice
+     * it should stop, so this can block for that long. Dropping a Client says goodbye too, but doesn't
+     * wait for anything or report what went wrong.
      */
-    pub fn send(&mut self, packet: &T) {
-        match self.writer_send.send(Packet::message(self.config.protocol_id, self.sequence_manager.next_sequence_id(), (self.config.packet_serializer)(packet))) {
-            _ => () //FIXME: We shouldn't discard errors here
+    pub fn shutdown(mut self) -> NetResult<()> {
+        self.shut_down = true;
+        self.protocol().disconnect();
+        self.connection_state = ConnectionState::Disconnected;
+        let flushed = self.flush();
+        match self.reader_send.send(TaskCommand::Disconnect) {
+            _ => () //The reader may have gone already
+        }
+
+        //With nobody left to queue anything, the writer stops once everything queued has gone out
+        self.writer_send.close();
+        //A reader waiting for room in a full inbound queue would never notice it should stop
+        self.reader_receive.close();
+        self.pump_writer();
+        self.pumped_reader = None;
+        self.pumped_writer = None;
+        while self.finished.recv().is_ok() {}
+
+        try!(flushed);
+        match self.error_receive.try_recv() {
+            Ok(e) => Err(e),
+            Err(_) => Ok(())
         }
     }
+
+    /**
+     * How full our queues are, and how much they've had to throw away
+     */
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            inbound: self.reader_receive.depth(),
+            outbound: self.writer_send.depth(),
+            inbound_dropped: self.reader_receive.dropped(),
+            outbound_dropped: self.writer_send.dropped()
+        }
+    }
+
+    /**
+     * Ask the transport which address we're really using
+     *
+     * This can differ from addr, such as when binding to port 0
+     */
+    pub fn local_addr(&mut self) -> NetResult<SocketAddr> {
+        self.transport.local_addr().map_err(|e| Error::Io(e))
+    }
+
+    /**
+     * Send a packet to the server
+     */
+    pub fn send(&mut self, packet: &T) -> NetResult<()> {
+        let payload = try!((self.config.packet_serializer)(packet).ok_or(Error::Serialization));
+        try!(self.protocol().send(payload));
+        self.flush()
+    }
+
+    /**
+     * Split into a sender, which can be cloned and handed to other threads, and a receiver
+     *
+     * Both halves share the one connection, so sequence numbers and acks stay in step whichever sends.
+     */
+    pub fn split(self) -> (ClientSender<T>, ClientReceiver<T, N>) {
+        let sender = ClientSender {
+            protocol: self.protocol.clone(),
+            writer_send: self.writer_send.clone(),
+            packet_serializer: self.config.packet_serializer
+        };
+        (sender, ClientReceiver { client: self })
+    }
 }
 
 #[unsafe_destructor]
-impl<T> Drop for Client<T> {
+impl<T, N: Transport> Drop for Client<T, N> {
 
     fn drop(&mut self) {
-        match (self.reader_send.send(TaskCommand::Disconnect),  self.writer_send.send(Packet::disconnect(self.config.protocol_id, self.sequence_manager.next_sequence_id()))) {
-            _ => () //FIXME: This is a bad way of discarding errors
+        if self.shut_down {
+            return;
+        }
+        self.protocol().disconnect();
+        match (self.flush(), self.reader_send.send(TaskCommand::Disconnect)) {
+            _ => () //Nothing more can be done if the threads have already gone
+        }
+        //Without threads of our own, the goodbye has to go out now
+        self.pump_writer();
+    }
+}
+
+/**
+ * The sending half of a split Client, which can be cloned and used from any thread
+ *
+ * A Client made to be pumped only puts anything on the wire when its receiver is pumped.
+ */
+pub struct ClientSender<T> {
+    protocol: Arc<Mutex<ClientProtocol>>,
+    writer_send: SharedSender<Datagram>,
+    packet_serializer: fn(&T) -> Option<Vec<u8>>
+}
+
+impl <T> ClientSender<T> {
+
+    /**
+     * Send a packet to the server
+     *
+     * Fails with NotConnected once the receiver has seen the connection go.
+     */
+    pub fn send(&self, packet: &T) -> NetResult<()> {
+        let payload = try!((self.packet_serializer)(packet).ok_or(Error::Serialization));
+        let (turn, datagrams) = {
+            let mut protocol = self.protocol.lock().unwrap();
+            try!(protocol.send(payload));
+            //A turn taken before letting go of the protocol keeps packets from several threads in sequence order
+            (self.writer_send.turn(), protocol.drain_outgoing())
+        };
+        try!(turn.send_all(datagrams));
+        Ok(())
+    }
+
+    /**
+     * The state of the connection, as of the last time the receiver looked
+     */
+    pub fn state(&self) -> ConnectionState {
+        self.protocol.lock().unwrap().state()
+    }
+}
+
+impl <T> Clone for ClientSender<T> {
+
+    fn clone(&self) -> ClientSender<T> {
+        ClientSender {
+            protocol: self.protocol.clone(),
+            writer_send: self.writer_send.clone(),
+            packet_serializer: self.packet_serializer
         }
     }
 }
+
+/**
+ * The receiving half of a split Client, which also keeps the connection alive
+ *
+ * Dropping it says goodbye to the server, just as dropping the Client would.
+ */
+pub struct ClientReceiver<T, N: Transport = UdpSocket> {
+    client: Client<T, N>
+}
+
+impl <T, N: Transport> ClientReceiver<T, N> {
+
+    /**
+     * Pop the last event off of our comms queue, if any. See `Client::poll`
+     */
+    pub fn poll(&mut self) -> Result<T, PollFailResult> {
+        self.client.poll()
+    }
+
+    /**
+     * Like `poll`, but blocking until something arrives or the timeout passes. See `Client::poll_timeout`
+     */
+    pub fn poll_timeout(&mut self, timeout: Duration) -> Result<T, PollFailResult> {
+        self.client.poll_timeout(timeout)
+    }
+
+    /**
+     * Like `poll`, but blocking until something arrives. See `Client::wait`
+     */
+    pub fn wait(&mut self) -> Result<T, PollFailResult> {
+        self.client.wait()
+    }
+
+    /**
+     * Something which can end a `wait` or `poll_timeout` early, from another thread
+     */
+    pub fn waker(&self) -> Waker {
+        self.client.waker()
+    }
+
+    /**
+     * Do any waiting network work on the calling thread, for Clients made to be pumped. See `Client::pump`
+     */
+    pub fn pump(&mut self) -> NetResult<()> {
+        self.client.pump()
+    }
+
+    /**
+     * What's the current state of our connection
+     */
+    pub fn state(&self) -> ConnectionState {
+        self.client.connection_state
+    }
+
+    /**
+     * How full our queues are, and how much they've had to throw away
+     */
+    pub fn queue_stats(&self) -> QueueStats {
+        self.client.queue_stats()
+    }
+
+    /**
+     * Ask the transport which address we're really using
+     */
+    pub fn local_addr(&mut self) -> NetResult<SocketAddr> {
+        self.client.local_addr()
+    }
+
+    /**
+     * Say goodbye to the server and stop our threads. See `Client::shutdown`
+     *
+     * Any senders still held fail from then on.
+     */
+    pub fn shutdown(self) -> NetResult<()> {
+        self.client.shutdown()
+    }
+}
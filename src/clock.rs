@@ -0,0 +1,88 @@
+//!
+//! Where Clients and Servers get the time from
+//!
+//! Timeouts are measured against a `Clock` held in `ConnectionConfig`. Normally this is the
+//! system clock, but a `ManualClock` can be swapped in so time only moves when a test says so.
+//!
+use std::sync::{Arc, Mutex};
+use time::precise_time_ns;
+
+/**
+ * A source of the current time
+ */
+pub trait Clock: Send + Sync {
+    /// Milliseconds since some fixed point
+    fn now_ms(&self) -> u64;
+}
+
+/**
+ * A Clock which can be shared between a connection and its background threads
+ */
+pub type SharedClock = Arc<Box<Clock + Send + Sync>>;
+
+/**
+ * The real time, from the system's monotonic clock
+ */
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        precise_time_ns() / 1000000
+    }
+}
+
+/**
+ * A shared handle on the system clock, used by default
+ */
+pub fn system_clock() -> SharedClock {
+    Arc::new(Box::new(SystemClock) as Box<Clock + Send + Sync>)
+}
+
+/**
+ * A clock which only moves when told to
+ *
+ * Clones share the same time, so one can be handed to a connection and the other kept to move it.
+ */
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<u64>>
+}
+
+impl ManualClock {
+
+    /**
+     * Create a clock stopped at the given time
+     */
+    pub fn new(start_ms: u64) -> ManualClock {
+        ManualClock {
+            now: Arc::new(Mutex::new(start_ms))
+        }
+    }
+
+    /**
+     * Move time forwards
+     */
+    pub fn advance(&self, ms: u64) {
+        *self.now.lock().unwrap() += ms;
+    }
+
+    /**
+     * Jump to an exact time
+     */
+    pub fn set(&self, now_ms: u64) {
+        *self.now.lock().unwrap() = now_ms;
+    }
+
+    /**
+     * A shared handle on this clock, for a ConnectionConfig
+     */
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(Box::new(self.clone()) as Box<Clock + Send + Sync>)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,475 @@
+//!
+//! Simulate a bad network, to reproduce what players see on poor connections
+//!
+//! A `LinkConditioner` wraps any Transport and applies `LinkConditions` to everything passing
+//! through it in either direction: latency, jitter, random and bursty loss, duplication,
+//! reordering and a bandwidth cap. All of the randomness comes from a seeded generator, so a run
+//! can be repeated exactly.
+//!
+//! Clients and Servers apply conditions from `ConnectionConfig::link_conditions` by themselves,
+//! timing delays by the config's clock, and wait for anything still delayed when they shut down.
+//!
+use std::old_io::{IoResult, IoError, TimedOut, Timer};
+use std::old_io::net::ip::SocketAddr;
+use std::sync::mpsc::{Sender, Receiver, channel, Select};
+use std::thread::Thread;
+use std::time::duration::Duration;
+use std::cmp::{min, max};
+use std::cell::Cell;
+use packet::MAX_PACKET_SIZE;
+use transport::Transport;
+use clock::{SharedClock, system_clock};
+
+/**
+ * How badly a link should behave
+ */
+#[derive(Clone, Copy, Show, PartialEq)]
+pub struct LinkConditions {
+    ///Delay added to every packet, in milliseconds
+    pub latency_ms: u64,
+    ///Up to this many milliseconds are randomly added to or taken from the latency
+    pub jitter_ms: u64,
+    ///Chance of any single packet going missing, from 0 to 1
+    pub loss: f64,
+    ///Chance of a packet starting a burst of losses, from 0 to 1
+    pub burst_chance: f64,
+    ///How many packets in a row a burst loses
+    pub burst_length: u32,
+    ///Chance of a packet arriving twice, from 0 to 1
+    pub duplicate: f64,
+    ///Chance of a packet being held back behind later ones, from 0 to 1
+    pub reorder: f64,
+    ///How long a reordered packet is held back for, in milliseconds
+    pub reorder_delay_ms: u64,
+    ///The most bytes per second the link can carry, if limited
+    pub bandwidth: Option<u64>,
+    ///Seed for the random choices, so runs can be reproduced
+    pub seed: u64
+}
+
+impl LinkConditions {
+
+    /**
+     * A link which doesn't change anything
+     */
+    pub fn perfect() -> LinkConditions {
+        LinkConditions {
+            latency_ms: 0,
+            jitter_ms: 0,
+            loss: 0.0,
+            burst_chance: 0.0,
+            burst_length: 0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay_ms: 0,
+            bandwidth: None,
+            seed: 0
+        }
+    }
+
+    /**
+     * Look up a named preset: lan, wifi, bad_wifi or bad_4g
+     */
+    pub fn preset(name: &str) -> Option<LinkConditions> {
+        let perfect = LinkConditions::perfect();
+        match name {
+            "perfect" => Some(perfect),
+            "lan" => Some(LinkConditions { latency_ms: 1, ..perfect }),
+            "wifi" => Some(LinkConditions {
+                latency_ms: 5,
+                jitter_ms: 3,
+                loss: 0.005,
+                burst_chance: 0.001,
+                burst_length: 3,
+                reorder: 0.001,
+                reorder_delay_ms: 10,
+                ..perfect
+            }),
+            "bad_wifi" => Some(LinkConditions {
+                latency_ms: 20,
+                jitter_ms: 15,
+                loss: 0.03,
+                burst_chance: 0.01,
+                burst_length: 5,
+                duplicate: 0.005,
+                reorder: 0.01,
+                reorder_delay_ms: 30,
+                bandwidth: Some(250000),
+                ..perfect
+            }),
+            "bad_4g" => Some(LinkConditions {
+                latency_ms: 80,
+                jitter_ms: 40,
+                loss: 0.05,
+                burst_chance: 0.02,
+                burst_length: 8,
+                duplicate: 0.01,
+                reorder: 0.02,
+                reorder_delay_ms: 60,
+                bandwidth: Some(50000),
+                ..perfect
+            }),
+            _ => None
+        }
+    }
+}
+
+/**
+ * A small xorshift generator, so results don't depend on the platform's random numbers
+ */
+struct Random {
+    state: u64
+}
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random { state: if seed == 0 { 0x9E3779B97F4A7C15 } else { seed } }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state
+    }
+
+    ///A number from 0 up to, but not including, 1
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+
+/**
+ * Packets waiting for their delivery time
+ */
+struct DelayLine {
+    held: Vec<(u64, Vec<u8>, SocketAddr)>
+}
+
+impl DelayLine {
+    fn new() -> DelayLine {
+        DelayLine { held: vec![] }
+    }
+
+    fn push(&mut self, due: u64, data: Vec<u8>, addr: SocketAddr) {
+        self.held.push((due, data, addr));
+    }
+
+    fn next_due(&self) -> Option<u64> {
+        self.held.iter().map(|&(due, _, _)| due).min()
+    }
+
+    ///Take the earliest packet which is due, keeping packets due at the same time in order
+    fn pop_due(&mut self, now: u64) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut earliest = None;
+        for (i, &(due, _, _)) in self.held.iter().enumerate() {
+            if due <= now && earliest.map_or(true, |(_, best)| due < best) {
+                earliest = Some((i, due));
+            }
+        }
+        earliest.map(|(i, _)| {
+            let (_, data, addr) = self.held.remove(i);
+            (data, addr)
+        })
+    }
+}
+
+/**
+ * Decides the fate of each packet in one direction
+ *
+ * This is the model behind a LinkConditioner, without any of the waiting, so it can be driven
+ * by a virtual clock as well as a real one.
+ */
+pub struct LinkModel {
+    conditions: LinkConditions,
+    random: Random,
+    burst_remaining: u32,
+    ///When the link has finished sending everything so far, in microseconds so small packets still add up
+    free_at_us: u64
+}
+
+impl LinkModel {
+
+    /**
+     * A model of a link with the given conditions, using its own seed
+     */
+    pub fn new(conditions: LinkConditions, seed: u64) -> LinkModel {
+        LinkModel {
+            conditions: conditions,
+            random: Random::new(seed),
+            burst_remaining: 0,
+            free_at_us: 0
+        }
+    }
+
+    /**
+     * When each copy of a packet sent at `now` should be delivered. Lost packets have no copies
+     */
+    pub fn schedule(&mut self, len: usize, now: u64) -> Vec<u64> {
+        if self.burst_remaining > 0 {
+            self.burst_remaining -= 1;
+            return vec![];
+        }
+        if self.random.chance(self.conditions.burst_chance) {
+            self.burst_remaining = if self.conditions.burst_length > 0 { self.conditions.burst_length - 1 } else { 0 };
+            return vec![];
+        }
+        if self.random.chance(self.conditions.loss) {
+            return vec![];
+        }
+
+        //Packets queue up behind each other on a limited link
+        let sent = match self.conditions.bandwidth {
+            Some(bytes_per_second) if bytes_per_second > 0 => {
+                let start = max(now * 1000, self.free_at_us);
+                self.free_at_us = start + (len as u64 * 1000000) / bytes_per_second;
+                //A packet hasn't arrived until the last of it has
+                (self.free_at_us + 999) / 1000
+            },
+            _ => now
+        };
+
+        let copies = if self.random.chance(self.conditions.duplicate) { 2 } else { 1 };
+        (0..copies).map(|_| {
+            let jitter = self.conditions.jitter_ms as i64;
+            let offset = if jitter > 0 { (self.random.next_u64() % (2 * jitter as u64 + 1)) as i64 - jitter } else { 0 };
+            let mut delay = max(0, self.conditions.latency_ms as i64 + offset) as u64;
+            if self.random.chance(self.conditions.reorder) {
+                delay += self.conditions.reorder_delay_ms;
+            }
+            sent + delay
+        }).collect()
+    }
+}
+
+fn delay_process<N: Transport>(mut inner: N, recv: Receiver<(u64, Vec<u8>, SocketAddr)>, clock: SharedClock) {
+    let mut line = DelayLine::new();
+    let mut timer = match Timer::new() {
+        Ok(timer) => timer,
+        Err(_) => return
+    };
+    let mut open = true;
+
+    loop {
+        let now = clock.now_ms();
+        loop {
+            match line.pop_due(now) {
+                Some((data, addr)) => {
+                    match inner.send_to(data.as_slice(), addr) {
+                        _ => () //Lost on the wire, as far as anyone can tell
+                    }
+                },
+                None => break
+            }
+        }
+
+        match (line.next_due(), open) {
+            (None, false) => break,
+            (None, true) => {
+                match recv.recv() {
+                    Ok((due, data, addr)) => line.push(due, data, addr),
+                    Err(_) => open = false
+                }
+            },
+            (Some(due), false) => {
+                timer.sleep(Duration::milliseconds((due - now) as i64));
+            },
+            (Some(due), true) => {
+                let timeout = timer.oneshot(Duration::milliseconds((due - now) as i64));
+
+                //FIXME: Replace with the select! macro when it starts working
+                let sel = Select::new();
+                let mut incoming = sel.handle(&recv);
+                let mut timeout = sel.handle(&timeout);
+                unsafe { incoming.add(); timeout.add(); }
+                if sel.wait() == incoming.id() {
+                    match recv.recv() {
+                        Ok((due, data, addr)) => line.push(due, data, addr),
+                        Err(_) => open = false
+                    }
+                }
+            }
+        }
+    }
+}
+
+/**
+ * A Transport which makes another Transport behave like a worse network
+ *
+ * With no conditions, everything is passed straight through.
+ */
+pub struct LinkConditioner<N> {
+    inner: N,
+    conditions: Option<LinkConditions>,
+    seed: u64,
+    clones: Cell<u64>,
+    incoming: LinkModel,
+    outgoing: LinkModel,
+    arrivals: DelayLine,
+    departures: Option<Sender<(u64, Vec<u8>, SocketAddr)>>,
+    clock: SharedClock,
+    ///Held by the delay thread until it stops
+    finished: Option<Sender<()>>,
+    timeout_ms: Option<u64>
+}
+
+impl <N: Transport> LinkConditioner<N> {
+
+    /**
+     * Wrap a transport, applying the given conditions in both directions
+     */
+    pub fn new(inner: N, conditions: Option<LinkConditions>) -> LinkConditioner<N> {
+        LinkConditioner::with_clock(inner, conditions, system_clock())
+    }
+
+    /**
+     * Wrap a transport, timing delays by the given clock
+     */
+    pub fn with_clock(inner: N, conditions: Option<LinkConditions>, clock: SharedClock) -> LinkConditioner<N> {
+        let seed = conditions.map_or(0, |conditions| conditions.seed);
+        LinkConditioner::with_seed(inner, conditions, seed, clock)
+    }
+
+    /**
+     * Have the thread sending delayed packets hold on to `finished` until everything delayed has gone,
+     * so whoever holds the receiving end can wait for it
+     */
+    pub fn set_finished(&mut self, finished: Sender<()>) {
+        self.finished = Some(finished);
+    }
+
+    fn with_seed(inner: N, conditions: Option<LinkConditions>, seed: u64, clock: SharedClock) -> LinkConditioner<N> {
+        let mut random = Random::new(seed);
+        let perfect = LinkConditions::perfect();
+        let incoming = LinkModel::new(conditions.unwrap_or(perfect), random.next_u64());
+        let outgoing = LinkModel::new(conditions.unwrap_or(perfect), random.next_u64());
+        LinkConditioner {
+            inner: inner,
+            conditions: conditions,
+            seed: seed,
+            clones: Cell::new(0),
+            incoming: incoming,
+            outgoing: outgoing,
+            arrivals: DelayLine::new(),
+            departures: None,
+            clock: clock,
+            finished: None,
+            timeout_ms: None
+        }
+    }
+}
+
+impl <N: Transport> Transport for LinkConditioner<N> {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        if self.conditions.is_none() {
+            return self.inner.send_to(buf, addr);
+        }
+
+        if self.departures.is_none() {
+            let inner = try!(self.inner.try_clone());
+            let (departures, departures_receive) = channel();
+            let clock = self.clock.clone();
+            let finished = self.finished.clone();
+            Thread::spawn(move || {
+                delay_process(inner, departures_receive, clock);
+                drop(finished);
+            });
+            self.departures = Some(departures);
+        }
+
+        let now = self.clock.now_ms();
+        for due in self.outgoing.schedule(buf.len(), now).into_iter() {
+            match self.departures {
+                Some(ref departures) => {
+                    match departures.send((due, buf.to_vec(), addr)) {
+                        _ => ()
+                    }
+                },
+                None => ()
+            }
+        }
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        if self.conditions.is_none() {
+            return self.inner.recv_from(buf);
+        }
+
+        let deadline = self.timeout_ms.map(|timeout_ms| self.clock.now_ms() + timeout_ms);
+        let mut scratch = [0; MAX_PACKET_SIZE];
+        loop {
+            let now = self.clock.now_ms();
+            match self.arrivals.pop_due(now) {
+                Some((data, src)) => {
+                    let mut amt = 0;
+                    for (dst, byte) in buf.iter_mut().zip(data.iter()) {
+                        *dst = *byte;
+                        amt += 1;
+                    }
+                    return Ok((amt, src));
+                },
+                None => ()
+            }
+
+            //Wait on the real transport until the next held packet is due, or we run out of time
+            let mut wait = self.arrivals.next_due().map(|due| due - now);
+            match deadline {
+                Some(deadline) => {
+                    if now >= deadline {
+                        return Err(IoError {
+                            kind: TimedOut,
+                            desc: "Timed out",
+                            detail: None
+                        });
+                    }
+                    wait = Some(wait.map_or(deadline - now, |wait| min(wait, deadline - now)));
+                },
+                None => ()
+            }
+            self.inner.set_timeout(wait.map(|wait| max(wait, 1)));
+
+            match self.inner.recv_from(&mut scratch) {
+                //Hang ups aren't part of the simulation
+                Ok((0, src)) => return Ok((0, src)),
+                Ok((amt, src)) => {
+                    for due in self.incoming.schedule(amt, now).into_iter() {
+                        self.arrivals.push(due, scratch.slice_to(amt).to_vec(), src);
+                    }
+                },
+                Err(ref e) if e.kind == TimedOut => (),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+        if self.conditions.is_none() {
+            self.inner.set_timeout(timeout_ms);
+        }
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn try_clone(&self) -> IoResult<LinkConditioner<N>> {
+        //Each handle gets its own generator, derived so that runs are still repeatable
+        self.clones.set(self.clones.get() + 1);
+        let seed = Random::new(self.seed ^ self.clones.get()).next_u64();
+        let mut clone = LinkConditioner::with_seed(try!(self.inner.try_clone()), self.conditions, seed, self.clock.clone());
+        clone.finished = self.finished.clone();
+        clone.timeout_ms = self.timeout_ms;
+        Ok(clone)
+    }
+
+    fn is_stream(&self) -> bool {
+        self.inner.is_stream()
+    }
+}
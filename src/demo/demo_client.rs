@@ -8,16 +8,46 @@ use std::time::duration::Duration;
 use std::sync::mpsc::{channel};
 use std::thread::Thread;
 
-use string_telephone::{ConnectionConfig, ClientConnectionConfig, Client, PollFailResult};
+use string_telephone::{ConnectionConfig, ClientConnectionConfig, Client, PollFailResult, NetResult, Transport, discover};
+use string_telephone::tcp::TcpClientTransport;
 
 mod demo_shared;
 
 fn main () {
 
-    let settings = ConnectionConfig::new(121, Duration::seconds(10), demo_shared::deserializer, demo_shared::serializer);
+    let args = std::os::args();
+    let mut settings = ConnectionConfig::new(121, Duration::seconds(10), demo_shared::deserializer, demo_shared::serializer);
+    settings.link_conditions = demo_shared::link_conditions(&args);
     let client_settings = ClientConnectionConfig::new(3, Duration::seconds(5));
+    let mut target_addr = SocketAddr {ip: Ipv4Addr(127, 0, 0, 1), port: 6666};
 
-    match Client::connect(SocketAddr {ip: Ipv4Addr(0, 0, 0, 0), port: 0}, SocketAddr {ip: Ipv4Addr(127, 0, 0, 1), port: 6666}, settings, client_settings) {
+    if args.iter().any(|arg| arg.as_slice() == "--find") {
+        match discover(121, Duration::seconds(1)) {
+            Ok(found) => {
+                for server in found.iter() {
+                    println!("Found {} ({}ms) - {}", server.addr, server.ping_ms, String::from_utf8_lossy(server.info.as_slice()));
+                }
+                match found.first() {
+                    Some(server) => target_addr = server.addr,
+                    None => println!("No servers found")
+                }
+            },
+            Err(e) => println!("Couldn't search - {}", e)
+        }
+    }
+
+    if args.iter().any(|arg| arg.as_slice() == "--tcp") {
+        match TcpClientTransport::connect(target_addr) {
+            Ok(transport) => chat(Client::connect_with(transport, target_addr, settings, client_settings)),
+            Err(e) => println!("Error {}", e)
+        }
+    } else {
+        chat(Client::connect(SocketAddr {ip: Ipv4Addr(0, 0, 0, 0), port: 0}, target_addr, settings, client_settings))
+    }
+}
+
+fn chat<N: Transport>(connection: NetResult<Client<String, N>>) {
+    match connection {
         Ok(ref mut connection) => {
             println!("Connected!");
 
@@ -42,12 +72,18 @@ fn main () {
                         println!("Timed out");
                         break
                     },
+                    Err(PollFailResult::Failed(e)) => {
+                        println!("Error {}", e)
+                    },
                     _ => ()
                 };
                 
                 match recv.try_recv() {
                     Ok(text) => {
-                        connection.send(&text);
+                        match connection.send(&text) {
+                            Ok(()) => (),
+                            Err(e) => println!("Couldn't send - {}", e)
+                        }
                     },
                     Err(_) => ()
                 }
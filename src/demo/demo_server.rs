@@ -4,26 +4,56 @@ extern crate core;
 use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
 use std::time::duration::Duration;
 
-use string_telephone::{ConnectionConfig, Server, PacketOrCommand};
+use string_telephone::{ConnectionConfig, Server, PacketOrCommand, NetResult, Transport};
+use string_telephone::tcp::TcpServerTransport;
 
 mod demo_shared;
 
 fn main () {
-    let settings = ConnectionConfig::new(121, Duration::seconds(10), demo_shared::deserializer, demo_shared::serializer);
+    let args = std::os::args();
+    let mut settings = ConnectionConfig::new(121, Duration::seconds(10), demo_shared::deserializer, demo_shared::serializer);
+    settings.link_conditions = demo_shared::link_conditions(&args);
+    let addr = SocketAddr {ip: Ipv4Addr(0, 0, 0, 0), port: 6666};
+    let discoverable = args.iter().any(|arg| arg.as_slice() == "--discoverable");
+    let unthreaded = args.iter().any(|arg| arg.as_slice() == "--unthreaded");
 
-    match Server::new(SocketAddr {ip: Ipv4Addr(0, 0, 0, 0), port: 6666}, settings) {
+    if args.iter().any(|arg| arg.as_slice() == "--tcp") {
+        match TcpServerTransport::bind(addr) {
+            Ok(transport) => serve(Server::with_transport(transport, settings), discoverable),
+            Err(e) => println!("{}", e)
+        }
+    } else if unthreaded {
+        serve(Server::new_nonblocking(addr, settings), discoverable)
+    } else {
+        serve(Server::new(addr, settings), discoverable)
+    }
+}
+
+fn serve<N: Transport>(server: NetResult<Server<String, N>>, discoverable: bool) {
+    match server {
         Ok(ref mut server) => {
+            if discoverable {
+                match server.enable_discovery(b"String Telephone demo".to_vec()) {
+                    Ok(()) => (),
+                    Err(e) => println!("Couldn't enable discovery - {}", e)
+                }
+            }
             loop {
-                loop {
-                    match server.poll() {
-                        Some((PacketOrCommand::UserPacket(packet), _)) => {
-                            server.send_to_all(&packet);
-                        },
-                        Some(_) => {
-                            println!("PACKET");
-                        },
-                        None => break
-                    }
+                //Sleep until there's something to do, rather than spinning, but wake now and then to cull
+                match server.poll_timeout(Duration::seconds(1)) {
+                    Some((PacketOrCommand::UserPacket(packet), _)) => {
+                        match server.send_to_all(&packet) {
+                            Ok(()) => (),
+                            Err(e) => println!("Couldn't send - {}", e)
+                        }
+                    },
+                    Some((PacketOrCommand::Failed(e), addr)) => {
+                        println!("Error with {} - {}", addr, e);
+                    },
+                    Some(_) => {
+                        println!("PACKET");
+                    },
+                    None => ()
                 };
                 let culled = server.cull();
                 if culled.len() > 0 {
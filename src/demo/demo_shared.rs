@@ -1,4 +1,5 @@
 use core::borrow::Cow;
+use string_telephone::LinkConditions;
 
 pub fn deserializer(message: &Vec<u8>) -> Option<String> {
     match String::from_utf8_lossy(message.as_slice()) {
@@ -7,6 +8,22 @@ pub fn deserializer(message: &Vec<u8>) -> Option<String> {
     }
 }
 
-pub fn serializer(packet: &String) -> Vec<u8> {
-    packet.clone().into_bytes()
+pub fn serializer(packet: &String) -> Option<Vec<u8>> {
+    Some(packet.clone().into_bytes())
+}
+
+/**
+ * Pick up any link conditions asked for with --link <preset>
+ */
+pub fn link_conditions(args: &Vec<String>) -> Option<LinkConditions> {
+    match args.iter().position(|arg| arg.as_slice() == "--link") {
+        Some(index) if index + 1 < args.len() => {
+            let conditions = LinkConditions::preset(args[index + 1].as_slice());
+            if conditions.is_none() {
+                println!("Unknown link preset {} - try lan, wifi, bad_wifi or bad_4g", args[index + 1]);
+            }
+            conditions
+        },
+        _ => None
+    }
 }
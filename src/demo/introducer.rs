@@ -0,0 +1,28 @@
+extern crate string_telephone;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+
+use string_telephone::punch::{Introducer, INTRODUCER_PORT};
+
+fn main () {
+    let args = std::os::args();
+    let port = match args.iter().position(|arg| arg.as_slice() == "--port") {
+        Some(index) if index + 1 < args.len() => args[index + 1].parse().unwrap_or(INTRODUCER_PORT),
+        _ => INTRODUCER_PORT
+    };
+    let addr = SocketAddr {ip: Ipv4Addr(0, 0, 0, 0), port: port};
+
+    match Introducer::bind(addr, 121) {
+        Ok(ref mut introducer) => {
+            println!("Introducing on {}", addr);
+            loop {
+                match introducer.step(1000) {
+                    Ok(Some(session)) => println!("Introduced the peers in {}", session),
+                    Ok(None) => (),
+                    Err(e) => println!("{}", e)
+                }
+            }
+        },
+        Err(e) => println!("{}", e)
+    }
+}
@@ -0,0 +1,43 @@
+extern crate string_telephone;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::Timer;
+use std::time::duration::Duration;
+
+use string_telephone::master::{MasterServer, MasterEvent, MASTER_PORT, DEFAULT_EXPIRY_SECONDS};
+
+/**
+ * Pick up a number given as --name <number>
+ */
+fn numeric_arg(args: &Vec<String>, name: &str) -> Option<i64> {
+    match args.iter().position(|arg| arg.as_slice() == name) {
+        Some(index) if index + 1 < args.len() => args[index + 1].parse(),
+        _ => None
+    }
+}
+
+fn main () {
+    let args = std::os::args();
+    let port = numeric_arg(&args, "--port").map_or(MASTER_PORT, |port| port as u16);
+    let expiry = numeric_arg(&args, "--expiry").unwrap_or(DEFAULT_EXPIRY_SECONDS);
+    let addr = SocketAddr {ip: Ipv4Addr(0, 0, 0, 0), port: port};
+
+    match MasterServer::bind(addr, Duration::seconds(expiry)) {
+        Ok(ref mut master) => {
+            println!("Master server listening on {}", addr);
+            let mut timer = Timer::new().unwrap();
+            loop {
+                for event in master.poll().iter() {
+                    match *event {
+                        MasterEvent::Registered(ref listed) => println!("Listed {} - {} on {} ({}/{})", listed.addr, listed.listing.name, listed.listing.map, listed.listing.players, listed.listing.max_players),
+                        MasterEvent::Unregistered(addr) => println!("Unlisted {}", addr),
+                        MasterEvent::Expired(addr) => println!("Expired {}", addr),
+                        MasterEvent::Listed(addr, count) => println!("Sent {} servers to {}", count, addr)
+                    }
+                }
+                timer.sleep(Duration::milliseconds(10));
+            }
+        },
+        Err(e) => println!("{}", e)
+    }
+}
@@ -0,0 +1,39 @@
+extern crate string_telephone;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+
+use string_telephone::relay::{Relay, RELAY_PORT};
+
+/**
+ * Pick up a number given as --name <number>
+ */
+fn numeric_arg(args: &Vec<String>, name: &str) -> Option<i64> {
+    match args.iter().position(|arg| arg.as_slice() == name) {
+        Some(index) if index + 1 < args.len() => args[index + 1].parse(),
+        _ => None
+    }
+}
+
+fn main () {
+    let args = std::os::args();
+    let port = numeric_arg(&args, "--port").map_or(RELAY_PORT, |port| port as u16);
+    let addr = SocketAddr {ip: Ipv4Addr(0, 0, 0, 0), port: port};
+
+    match Relay::bind(addr) {
+        Ok(ref mut relay) => {
+            match numeric_arg(&args, "--bandwidth") {
+                Some(bandwidth) => relay.set_bandwidth(bandwidth as usize),
+                None => ()
+            }
+            println!("Relaying on {}", addr);
+            loop {
+                match relay.step(1000) {
+                    Ok(Some(token)) => println!("Relaying session {}", token),
+                    Ok(None) => (),
+                    Err(e) => println!("{}", e)
+                }
+            }
+        },
+        Err(e) => println!("{}", e)
+    }
+}
@@ -0,0 +1,201 @@
+//!
+//! Finding servers on the local network, without typing in addresses
+//!
+//! A Server which has called `enable_discovery` listens on `DISCOVERY_PORT` for probes carrying
+//! its `protocol_id`, and answers each with the port it's really serving on and a small blob of
+//! information chosen by the game. `discover` broadcasts a probe and gathers up the answers.
+//!
+//! Only one server per machine can answer on a given discovery port.
+//!
+//! As with queries, anyone can send a probe from a forged address, so answers are rate limited and
+//! never more than `MAX_AMPLIFICATION` times the size of the probe. `discover` pads its probes so
+//! any answer which fits in a packet can be sent.
+//!
+use std::old_io::{IoResult, TimedOut};
+use std::old_io::net::ip::{SocketAddr, Ipv4Addr};
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::BufReader;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, channel};
+use std::thread::Thread;
+use std::time::duration::Duration;
+use packet::{Packet, PacketType, MAX_PACKET_SIZE, HEADER_SIZE};
+use error::{Error, NetResult};
+use transport::Transport;
+use query::{RateLimiter, within_amplification, MIN_QUERY_SIZE, DEFAULT_QUERIES_PER_ADDRESS, DEFAULT_QUERIES_TOTAL};
+use time::precise_time_ns;
+
+///The port discovery probes are sent to, unless another is chosen
+pub const DISCOVERY_PORT: u16 = 47777;
+
+///The most info a server can hand out, after the header and its port
+pub const MAX_DISCOVERY_INFO: usize = MAX_PACKET_SIZE - HEADER_SIZE - 2;
+
+/**
+ * A server which answered a probe
+ */
+#[derive(Clone, Show, PartialEq)]
+pub struct DiscoveredServer {
+    ///Where to connect to
+    pub addr: SocketAddr,
+    ///Whatever the server chose to tell us
+    pub info: Vec<u8>,
+    ///How long the answer took, in milliseconds
+    pub ping_ms: u64
+}
+
+/**
+ * The running half of a discovery responder, held by the Server which started it
+ */
+pub struct Responder {
+    info: Arc<Mutex<Vec<u8>>>,
+    running: Arc<AtomicBool>,
+    ///Hung up when the responder thread finishes
+    finished: Receiver<()>
+}
+
+impl Responder {
+
+    /**
+     * Start answering probes on a transport, pointing anyone who asks at `game_port`
+     */
+    pub fn start<N: Transport>(transport: N, protocol_id: u32, game_port: u16, info: Vec<u8>) -> NetResult<Responder> {
+        if info.len() > MAX_DISCOVERY_INFO {
+            return Err(Error::PayloadTooLarge);
+        }
+        let (finished_send, finished) = channel();
+        let responder = Responder {
+            info: Arc::new(Mutex::new(info)),
+            running: Arc::new(AtomicBool::new(true)),
+            finished: finished
+        };
+        let info = responder.info.clone();
+        let running = responder.running.clone();
+        Thread::spawn(move || {
+            respond_process(transport, protocol_id, game_port, info, running);
+            drop(finished_send);
+        });
+        Ok(responder)
+    }
+
+    /**
+     * Change what we tell anyone who asks, such as when the player count changes
+     */
+    pub fn set_info(&self, info: Vec<u8>) -> NetResult<()> {
+        if info.len() > MAX_DISCOVERY_INFO {
+            return Err(Error::PayloadTooLarge);
+        }
+        *self.info.lock().unwrap() = info;
+        Ok(())
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        //The responder notices at its next timeout, so this can take up to a second
+        self.running.store(false, Ordering::SeqCst);
+        while self.finished.recv().is_ok() {}
+    }
+}
+
+fn respond_process<N: Transport>(mut transport: N, protocol_id: u32, game_port: u16, info: Arc<Mutex<Vec<u8>>>, running: Arc<AtomicBool>) {
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let mut limiter = RateLimiter::new(DEFAULT_QUERIES_PER_ADDRESS, DEFAULT_QUERIES_TOTAL);
+    transport.set_timeout(Some(1000));
+    while running.load(Ordering::SeqCst) {
+        match transport.recv_from(&mut buf) {
+            Ok((amt, src)) => {
+                if !running.load(Ordering::SeqCst) {
+                    //We were stopped while waiting
+                    break;
+                }
+                match Packet::deserialize(buf.slice_to(amt)) {
+                    Ok(ref packet) if packet.protocol_id == protocol_id && packet.packet_type == PacketType::DiscoveryProbe => {
+                        let mut content = vec![];
+                        match content.write_be_u16(game_port) {
+                            _ => ()
+                        }
+                        content.push_all(info.lock().unwrap().as_slice());
+                        match Packet::discovery_reply(protocol_id, packet.sequence_id, content).serialize() {
+                            //Anyone who wants an answer can ask again, so a refusal needs no more than this
+                            Ok(ref reply) if !within_amplification(amt, reply.len()) => (),
+                            Ok(_) if !limiter.allow(&src.ip, precise_time_ns() / 1000000) => (),
+                            Ok(reply) => {
+                                match transport.send_to(reply.as_slice(), src) {
+                                    _ => () //Whoever asked can always ask again
+                                }
+                            },
+                            Err(_) => ()
+                        }
+                    },
+                    _ => () //Not for us
+                }
+            },
+            Err(_) => ()
+        }
+    }
+}
+
+fn read_reply(packet: &Packet, src: SocketAddr) -> IoResult<(SocketAddr, Vec<u8>)> {
+    let content = packet.packet_content.clone().unwrap_or(vec![]);
+    let mut r = BufReader::new(content.as_slice());
+    let port = try!(r.read_be_u16());
+    let info = try!(r.read_to_end());
+    Ok((SocketAddr { ip: src.ip, port: port }, info))
+}
+
+/**
+ * Broadcast a probe on the local network, and gather every answer which arrives before the timeout
+ */
+pub fn discover(protocol_id: u32, timeout: Duration) -> NetResult<Vec<DiscoveredServer>> {
+    let mut socket = try!(UdpSocket::bind(SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 0 }));
+    try!(socket.set_broadcast(true));
+    discover_with(socket, SocketAddr { ip: Ipv4Addr(255, 255, 255, 255), port: DISCOVERY_PORT }, protocol_id, timeout)
+}
+
+/**
+ * Send a probe to any address over any Transport, such as a multicast group, and gather the answers
+ */
+pub fn discover_with<N: Transport>(mut transport: N, target: SocketAddr, protocol_id: u32, timeout: Duration) -> NetResult<Vec<DiscoveredServer>> {
+    //Tell our answers apart from any to an earlier probe
+    let sequence_id = (precise_time_ns() & 0xFFFF) as u16;
+    let probe = try!(Packet::discovery_probe(protocol_id, sequence_id, MIN_QUERY_SIZE - HEADER_SIZE).serialize().map_err(|_| Error::Serialization));
+    let sent_at = precise_time_ns() / 1000000;
+    let deadline = sent_at + timeout.num_milliseconds() as u64;
+    try!(transport.send_to(probe.as_slice(), target));
+
+    let mut found: Vec<DiscoveredServer> = vec![];
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+        let now = precise_time_ns() / 1000000;
+        if now >= deadline {
+            break;
+        }
+        transport.set_timeout(Some(deadline - now));
+        match transport.recv_from(&mut buf) {
+            Ok((amt, src)) => {
+                match Packet::deserialize(buf.slice_to(amt)) {
+                    Ok(ref packet) if packet.protocol_id == protocol_id && packet.packet_type == PacketType::DiscoveryReply && packet.sequence_id == sequence_id => {
+                        match read_reply(packet, src) {
+                            Ok((addr, info)) => {
+                                if !found.iter().any(|server| server.addr == addr) {
+                                    found.push(DiscoveredServer {
+                                        addr: addr,
+                                        info: info,
+                                        ping_ms: precise_time_ns() / 1000000 - sent_at
+                                    });
+                                }
+                            },
+                            Err(_) => ()
+                        }
+                    },
+                    _ => ()
+                }
+            },
+            Err(ref e) if e.kind == TimedOut => break,
+            Err(e) => return Err(Error::Io(e))
+        }
+    }
+    Ok(found)
+}
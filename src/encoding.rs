@@ -0,0 +1,202 @@
+//!
+//! Compact encoding of packets at the bit level
+//!
+//! Types implementing `NetEncode` can be used with a ConnectionConfig without writing a
+//! serializer/deserializer pair by hand. The `string_telephone_derive` plugin's `#[derive(NetMessage)]`
+//! will generate the implementation.
+//!
+use std::mem;
+use std::cmp;
+use std::time::duration::Duration;
+use bits::{BitWriter, BitReader, BitResult, BitError};
+use shared::ConnectionConfig;
+use packet::MAX_PACKET_SIZE;
+
+/**
+ * The longest list that will be decoded
+ *
+ * Items can take no bits at all, so the data left can't bound a length on its own. Anything taking at
+ * least a bit couldn't have had more than this many items fit into a packet anyway.
+ */
+pub const MAX_DECODED_LENGTH: u64 = (MAX_PACKET_SIZE * 8) as u64;
+
+/**
+ * A type which can write itself into, and read itself from, a bit stream
+ */
+pub trait NetEncode: Sized {
+    /// Write this value out, failing if it can't be represented
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()>;
+    /// Read a value back in
+    fn decode(reader: &mut BitReader) -> BitResult<Self>;
+}
+
+macro_rules! unsigned_encode {
+    ($t:ty, $bits:expr) => (
+        impl NetEncode for $t {
+            fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+                writer.write_bits(*self as u64, $bits);
+                Ok(())
+            }
+
+            fn decode(reader: &mut BitReader) -> BitResult<$t> {
+                reader.read_bits($bits).map(|value| value as $t)
+            }
+        }
+    )
+}
+
+macro_rules! signed_encode {
+    ($t:ty, $unsigned:ty, $bits:expr) => (
+        impl NetEncode for $t {
+            fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+                writer.write_bits(*self as $unsigned as u64, $bits);
+                Ok(())
+            }
+
+            fn decode(reader: &mut BitReader) -> BitResult<$t> {
+                reader.read_bits($bits).map(|value| value as $unsigned as $t)
+            }
+        }
+    )
+}
+
+unsigned_encode!(u8, 8);
+unsigned_encode!(u16, 16);
+unsigned_encode!(u32, 32);
+unsigned_encode!(u64, 64);
+signed_encode!(i8, u8, 8);
+signed_encode!(i16, u16, 16);
+signed_encode!(i32, u32, 32);
+signed_encode!(i64, u64, 64);
+
+impl NetEncode for bool {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        writer.write_bool(*self);
+        Ok(())
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<bool> {
+        reader.read_bool()
+    }
+}
+
+impl NetEncode for f32 {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        let raw: u32 = unsafe { mem::transmute(*self) };
+        writer.write_bits(raw as u64, 32);
+        Ok(())
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<f32> {
+        reader.read_bits(32).map(|raw| unsafe { mem::transmute::<u32, f32>(raw as u32) })
+    }
+}
+
+impl NetEncode for f64 {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        let raw: u64 = unsafe { mem::transmute(*self) };
+        writer.write_bits(raw, 64);
+        Ok(())
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<f64> {
+        reader.read_bits(64).map(|raw| unsafe { mem::transmute::<u64, f64>(raw) })
+    }
+}
+
+impl NetEncode for String {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        writer.write_varint(self.len() as u64);
+        for byte in self.as_bytes().iter() {
+            writer.write_bits(*byte as u64, 8);
+        }
+        Ok(())
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<String> {
+        let bytes: Vec<u8> = try!(NetEncode::decode(reader));
+        String::from_utf8(bytes).map_err(|_| BitError::InvalidData)
+    }
+}
+
+impl <T: NetEncode> NetEncode for Vec<T> {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        writer.write_varint(self.len() as u64);
+        for item in self.iter() {
+            try!(item.encode(writer));
+        }
+        Ok(())
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<Vec<T>> {
+        let length = try!(reader.read_varint());
+        if length > MAX_DECODED_LENGTH {
+            return Err(BitError::InvalidData);
+        }
+        //Only reserve what could really be there, so a bogus length can't cost much
+        let mut items = Vec::with_capacity(cmp::min(length as usize, reader.bits_remaining()));
+        for _ in 0..length {
+            items.push(try!(NetEncode::decode(reader)));
+        }
+        Ok(items)
+    }
+}
+
+impl <T: NetEncode> NetEncode for Option<T> {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        match *self {
+            Some(ref value) => {
+                writer.write_bool(true);
+                value.encode(writer)
+            },
+            None => {
+                writer.write_bool(false);
+                Ok(())
+            }
+        }
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<Option<T>> {
+        if try!(reader.read_bool()) {
+            NetEncode::decode(reader).map(|value| Some(value))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/**
+ * Turn raw data into a packet
+ *
+ * Anything other than zeroed byte padding left over after decoding is treated as malformed
+ */
+pub fn deserializer<T: NetEncode>(message: &Vec<u8>) -> Option<T> {
+    let mut reader = BitReader::new(message.as_slice());
+    match NetEncode::decode(&mut reader) {
+        Ok(packet) => reader.finish().ok().map(|_| packet),
+        Err(_) => None
+    }
+}
+
+/**
+ * Turn a packet into raw data
+ *
+ * Packets holding values which don't fit their encoding, such as too large for their bit width, give None
+ */
+pub fn serializer<T: NetEncode>(packet: &T) -> Option<Vec<u8>> {
+    let mut writer = BitWriter::new();
+    match packet.encode(&mut writer) {
+        Ok(()) => Some(writer.into_bytes()),
+        Err(_) => None
+    }
+}
+
+impl <T: NetEncode> ConnectionConfig <T> {
+
+    /**
+     * Create a new ConnectionConfig object which uses the type's own NetEncode implementation
+     */
+    pub fn with_encoding(protocol_id: u32, timeout_period: Duration) -> ConnectionConfig<T> {
+        ConnectionConfig::new(protocol_id, timeout_period, deserializer::<T>, serializer::<T>)
+    }
+}
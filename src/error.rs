@@ -0,0 +1,83 @@
+use std::old_io::IoError;
+use queue::QueueSendError;
+use std::error::{Error as StdError, FromError};
+use std::fmt;
+
+/**
+ * Everything that can go wrong with a connection
+ */
+#[derive(Clone, Show, PartialEq)]
+pub enum Error {
+    ///An underlying socket operation failed
+    Io(IoError),
+    ///We aren't connected, or the address given isn't connected to us
+    NotConnected,
+    ///The packet is too large to fit into a single datagram
+    PayloadTooLarge,
+    ///A packet couldn't be turned into raw data
+    Serialization,
+    ///A background reader or writer thread has stopped unexpectedly
+    ThreadDied,
+    ///The remote end refused our connection, giving a reason
+    Rejected(String),
+    ///The remote end didn't answer in time
+    TimedOut,
+    ///A queue was full, and set to refuse anything more
+    QueueFull,
+    ///The configuration asks for something this kind of Client or Server can't do
+    InvalidConfig(String)
+}
+
+/**
+ * The result of any fallible networking operation
+ */
+pub type NetResult<T> = Result<T, Error>;
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) => e.desc,
+            Error::NotConnected => "Not connected",
+            Error::PayloadTooLarge => "Payload too large to send",
+            Error::Serialization => "Failed to serialize a packet",
+            Error::ThreadDied => "Background thread died",
+            Error::Rejected(_) => "Connection rejected",
+            Error::TimedOut => "Timed out",
+            Error::QueueFull => "Queue full",
+            Error::InvalidConfig(_) => "Invalid configuration"
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        match *self {
+            Error::Io(ref e) => e.detail.clone(),
+            Error::Rejected(ref reason) => Some(reason.clone()),
+            Error::InvalidConfig(ref reason) => Some(reason.clone()),
+            _ => None
+        }
+    }
+}
+
+impl fmt::String for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.detail() {
+            Some(ref detail) if detail.len() > 0 => write!(f, "{} - {}", self.description(), detail),
+            _ => write!(f, "{}", self.description())
+        }
+    }
+}
+
+impl FromError<IoError> for Error {
+    fn from_error(err: IoError) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl FromError<QueueSendError> for Error {
+    fn from_error(err: QueueSendError) -> Error {
+        match err {
+            QueueSendError::Full => Error::QueueFull,
+            QueueSendError::Closed => Error::ThreadDied
+        }
+    }
+}
@@ -0,0 +1,404 @@
+//!
+//! One thread serving many sockets, woken by readiness instead of timeouts
+//!
+//! Normally every Client and Server runs its own reader and writer threads, each waking once a
+//! second to check whether it should stop. An `EventLoop` instead watches every socket bound through
+//! it from a single thread with `poll(2)`, passing datagrams on the moment they arrive. Clients and
+//! Servers made by the loop have no threads of their own, so `pump` them and then `poll` as normal,
+//! using `wait` to sleep until there's something to do.
+//!
+//! Only available on unix, with the `event_loop` feature.
+//!
+use std::old_io::{IoResult, TimedOut};
+use std::old_io::net::ip::{SocketAddr, Ipv4Addr};
+use std::old_io::net::udp::UdpSocket;
+use std::os::unix::AsRawFd;
+use std::os::errno;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::thread::Thread;
+use std::collections::BTreeMap;
+use std::time::duration::Duration;
+use libc::{c_int, c_short, c_ulong, EINTR};
+use packet::MAX_PACKET_SIZE;
+use transport::Transport;
+use inbox::{Inbox, recv_timeout};
+use queue::{QueueLimit, QueuePolicy, refuse_block};
+use worker::WorkerStep;
+use shared::ConnectionConfig;
+use client::{Client, ClientConnectionConfig};
+use server::Server;
+use error::NetResult;
+use time::precise_time_ns;
+
+///The most datagrams read from one socket before the others get a turn
+const DRAIN_LIMIT: usize = 64;
+
+const POLLIN: c_short = 0x1;
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short
+}
+
+extern {
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+/**
+ * A socket being watched, and where its datagrams go
+ */
+struct Endpoint {
+    socket: UdpSocket,
+    inbox: Sender<(Vec<u8>, SocketAddr)>,
+    ///The same inbox the transport receives from, so the oldest datagram can be thrown away for a new one
+    waiting_inbox: Arc<Inbox>,
+    limit: Option<QueueLimit>,
+    ///Datagrams passed on which the transport hasn't picked up yet
+    waiting: Arc<AtomicUsize>,
+    dropped: Arc<AtomicUsize>
+}
+
+impl Endpoint {
+
+    /**
+     * Read whatever is waiting on the socket, up to DRAIN_LIMIT datagrams
+     */
+    fn drain(&mut self, buf: &mut [u8]) -> WorkerStep {
+        let mut step = WorkerStep::Idle;
+        //poll(2) keeps reporting a socket until it's empty, so anything past the limit is read next time round
+        for _ in (0..DRAIN_LIMIT) {
+            match self.socket.recv_from(buf) {
+                Ok((amt, src)) => {
+                    if !self.pass_on(buf.slice_to(amt).to_vec(), src) {
+                        return WorkerStep::Stop;
+                    }
+                    step = WorkerStep::Busy;
+                },
+                Err(ref e) if e.kind == TimedOut => break,
+                //Such as an ICMP error left over from an earlier send. Anything queued behind it is still worth reading
+                Err(_) => ()
+            }
+        }
+        step
+    }
+
+    /**
+     * Hand a datagram to the transport, keeping to the inbound queue's limit. Returns false if nobody is receiving any more
+     */
+    fn pass_on(&mut self, data: Vec<u8>, src: SocketAddr) -> bool {
+        match self.limit {
+            Some(limit) if self.waiting.load(Ordering::SeqCst) >= limit.capacity => {
+                match limit.policy {
+                    QueuePolicy::DropOldest => {
+                        let mut scratch = [0; MAX_PACKET_SIZE];
+                        match recv_timeout(&*self.waiting_inbox, Some(0), &mut scratch) {
+                            Ok(_) => {
+                                self.waiting.fetch_sub(1, Ordering::SeqCst);
+                            },
+                            Err(_) => () //The transport got there first
+                        }
+                        self.dropped.fetch_add(1, Ordering::SeqCst);
+                    },
+                    //The loop can't wait for room or hand anyone an error, so whatever just arrived goes
+                    _ => {
+                        self.dropped.fetch_add(1, Ordering::SeqCst);
+                        return true;
+                    }
+                }
+            },
+            _ => ()
+        }
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        self.inbox.send((data, src)).is_ok()
+    }
+}
+
+struct Shared {
+    endpoints: Mutex<BTreeMap<usize, Endpoint>>,
+    next_token: AtomicUsize,
+    running: AtomicBool,
+    ///The loop also watches a socket of its own, so it can be woken to notice new sockets or a shutdown
+    wake_socket: Mutex<UdpSocket>,
+    wake_addr: SocketAddr,
+    ///Bumped whenever anything arrives, so waiters can tell
+    arrivals: Mutex<u64>,
+    arrived: Condvar
+}
+
+impl Shared {
+    fn notify(&self) {
+        *self.arrivals.lock().unwrap() += 1;
+        self.arrived.notify_all();
+    }
+
+    fn wake(&self) {
+        match self.wake_socket.lock().unwrap().send_to(&[0], self.wake_addr) {
+            _ => () //If the thread has already gone, there's nothing to wake
+        }
+    }
+}
+
+fn poll_process(mut wake_socket: UdpSocket, shared: Arc<Shared>) {
+    let mut buf = [0; MAX_PACKET_SIZE];
+    wake_socket.set_timeout(Some(0));
+    while shared.running.load(Ordering::SeqCst) {
+        //Gathered afresh every time round, as sockets come and go
+        let mut fds = vec![PollFd { fd: wake_socket.as_raw_fd(), events: POLLIN, revents: 0 }];
+        let tokens: Vec<usize> = {
+            let endpoints = shared.endpoints.lock().unwrap();
+            for endpoint in endpoints.values() {
+                fds.push(PollFd { fd: endpoint.socket.as_raw_fd(), events: POLLIN, revents: 0 });
+            }
+            endpoints.keys().map(|token| *token).collect()
+        };
+
+        if unsafe { poll(fds.as_mut_ptr(), fds.len() as c_ulong, -1) } < 0 {
+            if errno() as c_int == EINTR {
+                continue;
+            }
+            break;
+        }
+
+        if fds[0].revents != 0 {
+            //We're only ever woken to look again, so what was sent doesn't matter
+            while wake_socket.recv_from(&mut buf).is_ok() {}
+        }
+
+        let mut received = false;
+        {
+            let mut endpoints = shared.endpoints.lock().unwrap();
+            let mut closed = vec![];
+            for (token, fd) in tokens.iter().zip(fds.iter().skip(1)) {
+                if fd.revents == 0 {
+                    continue;
+                }
+                //A socket which has since gone has nothing to read
+                match endpoints.get_mut(token) {
+                    Some(endpoint) => {
+                        match endpoint.drain(&mut buf) {
+                            WorkerStep::Busy => received = true,
+                            WorkerStep::Idle => (),
+                            WorkerStep::Stop => closed.push(*token)
+                        }
+                    },
+                    None => ()
+                }
+            }
+            for token in closed.iter() {
+                endpoints.remove(token);
+            }
+        }
+        if received {
+            shared.notify();
+        }
+    }
+
+    //Let anyone still waiting on a socket know it's gone
+    shared.endpoints.lock().unwrap().clear();
+    shared.notify();
+}
+
+/**
+ * A poller thread, and every socket it watches
+ */
+pub struct EventLoop {
+    shared: Arc<Shared>,
+    seen: Mutex<u64>
+}
+
+impl EventLoop {
+
+    /**
+     * Start a poller thread with nothing to watch yet
+     */
+    pub fn new() -> NetResult<EventLoop> {
+        let mut wake_socket = try!(UdpSocket::bind(SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 }));
+        let wake_addr = try!(wake_socket.socket_name());
+        let shared = Arc::new(Shared {
+            endpoints: Mutex::new(BTreeMap::new()),
+            next_token: AtomicUsize::new(0),
+            running: AtomicBool::new(true),
+            wake_socket: Mutex::new(wake_socket.clone()),
+            wake_addr: wake_addr,
+            arrivals: Mutex::new(0),
+            arrived: Condvar::new()
+        });
+
+        let thread_shared = shared.clone();
+        Thread::spawn(move || {
+            poll_process(wake_socket, thread_shared);
+        });
+
+        Ok(EventLoop {
+            shared: shared,
+            seen: Mutex::new(0)
+        })
+    }
+
+    /**
+     * Bind a UDP socket to be watched by this loop
+     *
+     * At most `limit` datagrams wait to be picked up, if given. The loop can't wait for room, so a
+     * limit set to block is refused, and one set to fail drops whatever arrives once it's full.
+     */
+    pub fn bind(&self, addr: SocketAddr, limit: Option<QueueLimit>) -> NetResult<EventLoopTransport> {
+        try!(refuse_block(limit, "inbound"));
+        let socket = try!(UdpSocket::bind(addr));
+        let mut watched = socket.clone();
+        watched.set_timeout(Some(0));
+
+        let (inbox_send, inbox_receive) = channel();
+        let inbox = Arc::new(Inbox::new(inbox_receive));
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let token = self.shared.next_token.fetch_add(1, Ordering::SeqCst);
+        self.shared.endpoints.lock().unwrap().insert(token, Endpoint {
+            socket: watched,
+            inbox: inbox_send,
+            waiting_inbox: inbox.clone(),
+            limit: limit,
+            waiting: waiting.clone(),
+            dropped: dropped.clone()
+        });
+        //The loop only watches what it knew about when it last looked
+        self.shared.wake();
+
+        Ok(EventLoopTransport {
+            socket: socket,
+            inbox: inbox,
+            timeout_ms: None,
+            token: token,
+            waiting: waiting,
+            dropped: dropped,
+            shared: self.shared.clone(),
+            handles: Arc::new(AtomicUsize::new(1))
+        })
+    }
+
+    /**
+     * Start a Server on this loop, without any threads of its own
+     */
+    pub fn server<T>(&self, addr: SocketAddr, config: ConnectionConfig<T>) -> NetResult<Server<T, EventLoopTransport>> {
+        let transport = try!(self.bind(addr, config.inbound_queue));
+        Server::with_transport_unthreaded(transport, config)
+    }
+
+    /**
+     * Start connecting a Client on this loop, without any threads of its own. It will be Connecting until pumped
+     */
+    pub fn client<T>(&self, addr: SocketAddr, target_addr: SocketAddr, config: ConnectionConfig<T>, client_connection_config: ClientConnectionConfig) -> NetResult<Client<T, EventLoopTransport>> {
+        let transport = try!(self.bind(addr, config.inbound_queue));
+        Client::connect_unthreaded(transport, target_addr, config, client_connection_config)
+    }
+
+    /**
+     * Sleep until something arrives on any of this loop's sockets, or the timeout passes
+     *
+     * Returns straight away if anything has arrived since the last wait. Returns false if we
+     * timed out, in which case there may still be retries or timeouts for `pump` to deal with.
+     */
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let start = precise_time_ns();
+        let mut seen = self.seen.lock().unwrap();
+        let mut arrivals = self.shared.arrivals.lock().unwrap();
+        loop {
+            if *arrivals != *seen {
+                *seen = *arrivals;
+                return true;
+            }
+            //Counted down from the timeout rather than up to a deadline, so a very long one can't overflow
+            let remaining = timeout - Duration::nanoseconds((precise_time_ns() - start) as i64);
+            if remaining <= Duration::zero() {
+                return false;
+            }
+            arrivals = match self.shared.arrived.wait_timeout(arrivals, remaining) {
+                Ok((arrivals, _)) => arrivals,
+                Err(_) => return false
+            };
+        }
+    }
+}
+
+impl Drop for EventLoop {
+
+    fn drop(&mut self) {
+        self.shared.running.store(false, Ordering::SeqCst);
+        self.shared.wake();
+    }
+}
+
+/**
+ * A UDP socket watched by an EventLoop
+ *
+ * Receiving picks up whatever the loop has read, so a waiting `recv_from` returns as soon as a datagram
+ * arrives. Once the loop stops, receiving fails straight away.
+ */
+pub struct EventLoopTransport {
+    socket: UdpSocket,
+    inbox: Arc<Inbox>,
+    timeout_ms: Option<u64>,
+    token: usize,
+    waiting: Arc<AtomicUsize>,
+    dropped: Arc<AtomicUsize>,
+    shared: Arc<Shared>,
+    handles: Arc<AtomicUsize>
+}
+
+impl EventLoopTransport {
+
+    /**
+     * How many datagrams the loop has thrown away for lack of room
+     */
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+impl Transport for EventLoopTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        self.socket.send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        let received = try!(recv_timeout(&*self.inbox, self.timeout_ms, buf));
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        Ok(received)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket.socket_name()
+    }
+
+    fn try_clone(&self) -> IoResult<EventLoopTransport> {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(EventLoopTransport {
+            socket: self.socket.clone(),
+            inbox: self.inbox.clone(),
+            timeout_ms: self.timeout_ms,
+            token: self.token,
+            waiting: self.waiting.clone(),
+            dropped: self.dropped.clone(),
+            shared: self.shared.clone(),
+            handles: self.handles.clone()
+        })
+    }
+}
+
+impl Drop for EventLoopTransport {
+
+    fn drop(&mut self) {
+        //The last handle stops the loop watching
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.endpoints.lock().unwrap().remove(&self.token);
+            self.shared.wake();
+        }
+    }
+}
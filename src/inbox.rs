@@ -0,0 +1,112 @@
+//!
+//! Channel-backed receiving, shared by the transports which don't sit directly on a socket
+//!
+use std::old_io::{IoResult, IoError, TimedOut, EndOfFile, Timer};
+use std::old_io::net::ip::SocketAddr;
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, TryRecvError, Select};
+use std::time::duration::Duration;
+
+struct Waiting {
+    receiver: Receiver<(Vec<u8>, SocketAddr)>,
+    ///Made the first time a wait needs one, then kept for every wait after
+    timer: Option<Timer>,
+    ///Once everyone sending has gone, nothing more can ever arrive
+    closed: bool
+}
+
+/**
+ * Datagrams waiting to be picked up, along with who sent them
+ */
+pub struct Inbox {
+    waiting: Mutex<Waiting>
+}
+
+impl Inbox {
+
+    /**
+     * Wrap the receiving end of a channel of datagrams
+     */
+    pub fn new(receiver: Receiver<(Vec<u8>, SocketAddr)>) -> Inbox {
+        Inbox {
+            waiting: Mutex::new(Waiting {
+                receiver: receiver,
+                timer: None,
+                closed: false
+            })
+        }
+    }
+}
+
+fn timed_out() -> IoError {
+    IoError {
+        kind: TimedOut,
+        desc: "Timed out",
+        detail: None
+    }
+}
+
+fn closed() -> IoError {
+    IoError {
+        kind: EndOfFile,
+        desc: "Transport closed",
+        detail: None
+    }
+}
+
+/**
+ * Wait on an inbox, giving up after the timeout
+ *
+ * Fails with EndOfFile once everyone sending to the inbox has gone, and every time after that.
+ */
+pub fn recv_timeout(inbox: &Inbox, timeout_ms: Option<u64>, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+    let mut guard = inbox.waiting.lock().unwrap();
+    let waiting = &mut *guard;
+    if waiting.closed {
+        return Err(closed());
+    }
+
+    let received = match timeout_ms {
+        None => waiting.receiver.recv().ok(),
+        //Nothing to wait for, so no timer either
+        Some(0) => {
+            match waiting.receiver.try_recv() {
+                Ok(received) => Some(received),
+                Err(TryRecvError::Empty) => return Err(timed_out()),
+                Err(TryRecvError::Disconnected) => None
+            }
+        },
+        Some(ms) => {
+            if waiting.timer.is_none() {
+                waiting.timer = Some(try!(Timer::new()));
+            }
+            let timeout = waiting.timer.as_mut().unwrap().oneshot(Duration::milliseconds(ms as i64));
+
+            //FIXME: Replace with the select! macro when it starts working
+            let sel = Select::new();
+            let mut incoming = sel.handle(&waiting.receiver);
+            let mut timeout = sel.handle(&timeout);
+            unsafe { incoming.add(); timeout.add(); }
+            if sel.wait() == incoming.id() {
+                waiting.receiver.recv().ok()
+            } else {
+                return Err(timed_out());
+            }
+        }
+    };
+
+    match received {
+        Some((data, src)) => {
+            let mut amt = 0;
+            for (dst, byte) in buf.iter_mut().zip(data.iter()) {
+                *dst = *byte;
+                amt += 1;
+            }
+            Ok((amt, src))
+        },
+        None => {
+            waiting.closed = true;
+            Err(closed())
+        }
+    }
+}
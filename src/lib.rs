@@ -4,24 +4,100 @@
 //! Simple abstractions for networking for video games
 //!
 #![feature(unsafe_destructor)]
+#![cfg_attr(feature = "event_loop", feature(libc))]
 #![crate_name = "string_telephone"]
 #![crate_type="lib"]
 
 extern crate time;
+#[cfg(feature = "bincode_codec")]
+extern crate "rustc-serialize" as rustc_serialize;
+#[cfg(feature = "bincode_codec")]
+extern crate bincode;
+#[cfg(all(unix, feature = "event_loop"))]
+extern crate libc;
 
+pub use error::{Error, NetResult};
 pub use packet::*;
 pub use shared::*;
 pub use client::*;
 pub use server::*;
+pub use registry::{NetMessage, Message, MessageRegistry};
+pub use encoding::NetEncode;
+pub use bits::{BitWriter, BitReader, BitError};
+pub use transport::Transport;
+pub use worker::Waker;
+pub use queue::{QueuePolicy, QueueLimit, QueueStats};
+pub use schedule::Scheduling;
+pub use tcp::{TcpClientTransport, TcpServerTransport};
+pub use loopback::{LoopbackNetwork, LoopbackTransport};
+pub use conditioner::{LinkConditions, LinkConditioner, LinkModel};
+pub use clock::{Clock, SystemClock, ManualClock, SharedClock};
+pub use sim::{Simulation, SimTransport};
+pub use multi::MultiTransport;
+pub use discovery::{discover, discover_with, DiscoveredServer, DISCOVERY_PORT};
+pub use query::{query_server, query_server_with, QueryRequest, QueryHandler, ServerInfo};
+pub use punch::{Introducer, punch_through};
+pub use relay::{Relay, RelayTransport};
+pub use protocol::{ClientProtocol, ServerProtocol, ClientEvent, ServerEvent, Datagram};
+pub use master::{MasterServer, ServerListing, ListedServer, ListFilter, Registration, register, fetch_server_list};
+#[cfg(all(unix, feature = "event_loop"))]
+pub use event_loop::{EventLoop, EventLoopTransport};
 
+pub mod error;
 pub mod packet;
 pub mod shared;
 pub mod client;
 pub mod server;
+pub mod registry;
+pub mod bits;
+pub mod encoding;
+pub mod transport;
+pub mod tcp;
+pub mod loopback;
+pub mod conditioner;
+pub mod clock;
+pub mod sim;
+pub mod multi;
+pub mod discovery;
+pub mod query;
+pub mod master;
+pub mod punch;
+pub mod relay;
+pub mod protocol;
+#[cfg(feature = "bincode_codec")]
+pub mod bincode_codec;
+#[cfg(all(unix, feature = "event_loop"))]
+pub mod event_loop;
+
+mod inbox;
+mod worker;
+mod queue;
+mod schedule;
 
 #[cfg(test)]
 mod tests {
     mod test_shared;
     mod test_client;
     mod test_server;
+    mod test_registry;
+    mod test_bits;
+    mod test_encoding;
+    mod test_transport;
+    mod test_tcp;
+    mod test_loopback;
+    mod test_conditioner;
+    mod test_sim;
+    mod test_multi;
+    mod test_discovery;
+    mod test_query;
+    mod test_master;
+    mod test_punch;
+    mod test_relay;
+    mod test_protocol;
+    mod test_queue;
+    mod test_schedule;
+    #[cfg(feature = "bincode_codec")]
+    mod test_bincode_codec;
+    #[cfg(all(unix, feature = "event_loop"))]
+    mod test_event_loop;
 }
@@ -0,0 +1,166 @@
+//!
+//! An in-memory Transport, for running a Client and Server in the same process
+//!
+//! A `LoopbackNetwork` hands out transports bound to virtual addresses. Datagrams are passed
+//! straight along channels, so no sockets or real ports are involved. As with UDP, anything sent
+//! to an address nobody is bound to is silently dropped, and anything sent to 255.255.255.255
+//! reaches everyone else bound to that port.
+//!
+use std::old_io::{IoResult, IoError, OtherIoError};
+use std::old_io::net::ip::{SocketAddr, IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::collections::BTreeMap;
+use transport::Transport;
+use inbox::{Inbox, recv_timeout};
+
+///The first port handed out when binding to port 0
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+///Sending here reaches every endpoint on the same port
+const BROADCAST: IpAddr = Ipv4Addr(255, 255, 255, 255);
+
+struct Hub {
+    bound: BTreeMap<String, Sender<(Vec<u8>, SocketAddr)>>,
+    next_port: u16
+}
+
+/**
+ * A virtual network, connecting every transport bound from it
+ */
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    hub: Arc<Mutex<Hub>>
+}
+
+impl LoopbackNetwork {
+
+    /**
+     * Create an empty network
+     */
+    pub fn new() -> LoopbackNetwork {
+        LoopbackNetwork {
+            hub: Arc::new(Mutex::new(Hub {
+                bound: BTreeMap::new(),
+                next_port: FIRST_EPHEMERAL_PORT
+            }))
+        }
+    }
+
+    /**
+     * Bind a transport to a virtual address. Port 0 picks any free port
+     */
+    pub fn bind(&self, addr: SocketAddr) -> IoResult<LoopbackTransport> {
+        let mut hub = self.hub.lock().unwrap();
+
+        let addr = if addr.port == 0 {
+            let mut candidate = SocketAddr { ip: addr.ip, port: hub.next_port };
+            while hub.bound.contains_key(&format!("{}", candidate)) {
+                candidate.port = if candidate.port == 65535 { FIRST_EPHEMERAL_PORT } else { candidate.port + 1 };
+            }
+            hub.next_port = if candidate.port == 65535 { FIRST_EPHEMERAL_PORT } else { candidate.port + 1 };
+            candidate
+        } else {
+            addr
+        };
+
+        let key = format!("{}", addr);
+        if hub.bound.contains_key(&key) {
+            return Err(IoError {
+                kind: OtherIoError,
+                desc: "Address already in use",
+                detail: Some(key)
+            });
+        }
+
+        let (inbox_send, inbox_receive) = channel();
+        hub.bound.insert(key, inbox_send);
+
+        Ok(LoopbackTransport {
+            network: self.clone(),
+            addr: addr,
+            inbox: Arc::new(Inbox::new(inbox_receive)),
+            handles: Arc::new(AtomicUsize::new(1)),
+            timeout_ms: None
+        })
+    }
+
+    /**
+     * Is anything bound to this address?
+     */
+    pub fn is_bound(&self, addr: &SocketAddr) -> bool {
+        self.hub.lock().unwrap().bound.contains_key(&format!("{}", addr))
+    }
+}
+
+/**
+ * One endpoint on a LoopbackNetwork
+ */
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    addr: SocketAddr,
+    inbox: Arc<Inbox>,
+    handles: Arc<AtomicUsize>,
+    timeout_ms: Option<u64>
+}
+
+impl Transport for LoopbackTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        let hub = self.network.hub.lock().unwrap();
+        if addr.ip == BROADCAST {
+            //Everyone else bound to the port hears a broadcast, whatever their address
+            let me = format!("{}", self.addr);
+            let port = format!(":{}", addr.port);
+            for (key, target) in hub.bound.iter() {
+                if key.ends_with(port.as_slice()) && *key != me {
+                    match target.send((buf.to_vec(), self.addr)) {
+                        _ => ()
+                    }
+                }
+            }
+            return Ok(());
+        }
+        match hub.bound.get(&format!("{}", addr)) {
+            Some(target) => {
+                match target.send((buf.to_vec(), self.addr)) {
+                    _ => () //Like UDP, nobody listening is no concern of the sender
+                }
+            },
+            None => ()
+        };
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        recv_timeout(&*self.inbox, self.timeout_ms, buf)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn try_clone(&self) -> IoResult<LoopbackTransport> {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(LoopbackTransport {
+            network: self.network.clone(),
+            addr: self.addr,
+            inbox: self.inbox.clone(),
+            handles: self.handles.clone(),
+            timeout_ms: self.timeout_ms
+        })
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        //The last handle frees up the address
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.network.hub.lock().unwrap().bound.remove(&format!("{}", self.addr));
+        }
+    }
+}
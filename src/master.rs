@@ -0,0 +1,625 @@
+//!
+//! A master server, keeping a list of game servers for players to browse
+//!
+//! Game servers `register` with a master and heartbeat to it over a normal connection, and
+//! players `fetch_server_list` to find them. A listing is dropped when its server unregisters,
+//! disconnects or stops heartbeating for longer than the master's expiry time.
+//!
+//! The `master_server` binary runs a `MasterServer` on its own, so a game can host its own list.
+//! A whole list can take several packets to send, so like queries, lists are only handed out so
+//! many times a second to any one address, and to everyone.
+//!
+use std::old_io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::Timer;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{Sender, Receiver, TryRecvError, channel};
+use std::thread::Thread;
+use std::time::duration::Duration;
+use bits::{BitWriter, BitReader, BitResult, BitError};
+use encoding::NetEncode;
+use shared::ConnectionConfig;
+use client::{Client, ClientConnectionConfig, PollFailResult};
+use server::{Server, PacketOrCommand};
+use packet::{PacketType, MAX_PACKET_SIZE, HEADER_SIZE};
+use query::RateLimiter;
+use error::{Error, NetResult};
+use transport::Transport;
+use time::precise_time_ns;
+
+///The protocol ID spoken between masters and everyone else
+pub const MASTER_PROTOCOL_ID: u32 = 0x4D535452;
+
+///The port masters listen on, unless another is chosen
+pub const MASTER_PORT: u16 = 27950;
+
+///How long a master keeps a listing without hearing from its server, by default
+pub const DEFAULT_EXPIRY_SECONDS: i64 = 30;
+
+///How often registered servers check in
+pub const HEARTBEAT_SECONDS: i64 = 10;
+
+///The longest name or map a listing can have, in bytes
+pub const MAX_LISTING_TEXT: usize = 64;
+
+///How many lists a second are handed out to any one address, by default
+pub const DEFAULT_LISTS_PER_ADDRESS: u32 = 2;
+
+///How many lists a second are handed out in total, by default
+pub const DEFAULT_LISTS_TOTAL: u32 = 100;
+
+///The Listing tag, page number and page count at the front of every page, in bits
+const LISTING_OVERHEAD_BITS: usize = 3 + 16 + 16;
+
+/**
+ * What a game server tells the master about itself
+ */
+#[derive(Clone, Show, PartialEq)]
+pub struct ServerListing {
+    ///The protocol ID of the game itself, so one master can list several games
+    pub protocol_id: u32,
+    ///The port players should connect to
+    pub port: u16,
+    pub name: String,
+    pub map: String,
+    pub players: u16,
+    pub max_players: u16
+}
+
+/**
+ * A listing, along with where the server can be found
+ */
+#[derive(Clone, Show, PartialEq)]
+pub struct ListedServer {
+    pub addr: SocketAddr,
+    pub listing: ServerListing
+}
+
+/**
+ * Which servers a player is interested in
+ */
+#[derive(Clone, Show, PartialEq)]
+pub struct ListFilter {
+    ///Only list servers for this game
+    pub protocol_id: u32,
+    ///Only list servers on this map
+    pub map: Option<String>,
+    ///Leave out servers with no free slots
+    pub not_full: bool,
+    ///Leave out servers with nobody playing
+    pub not_empty: bool
+}
+
+impl ListFilter {
+
+    /**
+     * A filter listing every server for a game
+     */
+    pub fn new(protocol_id: u32) -> ListFilter {
+        ListFilter {
+            protocol_id: protocol_id,
+            map: None,
+            not_full: false,
+            not_empty: false
+        }
+    }
+
+    /**
+     * Does a listing pass the filter?
+     */
+    pub fn matches(&self, listing: &ServerListing) -> bool {
+        listing.protocol_id == self.protocol_id
+            && self.map.as_ref().map_or(true, |map| *map == listing.map)
+            && !(self.not_full && listing.players >= listing.max_players)
+            && !(self.not_empty && listing.players == 0)
+    }
+}
+
+/**
+ * Everything said between a master and the servers and players talking to it
+ */
+#[derive(Clone, Show, PartialEq)]
+pub enum MasterMessage {
+    ///A game server adding or updating its listing
+    Register(ServerListing),
+    ///A game server saying it's still there, which the master echoes back
+    Heartbeat,
+    ///A game server taking its listing down
+    Unregister,
+    ///A player asking for servers
+    List(ListFilter),
+    ///One page of the answer, numbered from 0
+    Listing(u16, u16, Vec<ListedServer>)
+}
+
+impl NetEncode for ServerListing {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        try!(self.protocol_id.encode(writer));
+        try!(self.port.encode(writer));
+        try!(self.name.encode(writer));
+        try!(self.map.encode(writer));
+        try!(self.players.encode(writer));
+        self.max_players.encode(writer)
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<ServerListing> {
+        Ok(ServerListing {
+            protocol_id: try!(NetEncode::decode(reader)),
+            port: try!(NetEncode::decode(reader)),
+            name: try!(NetEncode::decode(reader)),
+            map: try!(NetEncode::decode(reader)),
+            players: try!(NetEncode::decode(reader)),
+            max_players: try!(NetEncode::decode(reader))
+        })
+    }
+}
+
+fn encode_ip(ip: &IpAddr, writer: &mut BitWriter) -> BitResult<()> {
+    match *ip {
+        Ipv4Addr(a, b, c, d) => {
+            writer.write_bool(false);
+            for part in [a, b, c, d].iter() {
+                try!(part.encode(writer));
+            }
+        },
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            writer.write_bool(true);
+            for part in [a, b, c, d, e, f, g, h].iter() {
+                try!(part.encode(writer));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode_ip(reader: &mut BitReader) -> BitResult<IpAddr> {
+    if try!(reader.read_bool()) {
+        let mut parts = [0u16; 8];
+        for part in parts.iter_mut() {
+            *part = try!(NetEncode::decode(reader));
+        }
+        Ok(Ipv6Addr(parts[0], parts[1], parts[2], parts[3], parts[4], parts[5], parts[6], parts[7]))
+    } else {
+        let mut parts = [0u8; 4];
+        for part in parts.iter_mut() {
+            *part = try!(NetEncode::decode(reader));
+        }
+        Ok(Ipv4Addr(parts[0], parts[1], parts[2], parts[3]))
+    }
+}
+
+impl NetEncode for ListedServer {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        try!(encode_ip(&self.addr.ip, writer));
+        try!(self.addr.port.encode(writer));
+        self.listing.encode(writer)
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<ListedServer> {
+        let ip = try!(decode_ip(reader));
+        let port = try!(NetEncode::decode(reader));
+        Ok(ListedServer {
+            addr: SocketAddr { ip: ip, port: port },
+            listing: try!(NetEncode::decode(reader))
+        })
+    }
+}
+
+impl NetEncode for ListFilter {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        try!(self.protocol_id.encode(writer));
+        try!(self.map.encode(writer));
+        try!(self.not_full.encode(writer));
+        self.not_empty.encode(writer)
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<ListFilter> {
+        Ok(ListFilter {
+            protocol_id: try!(NetEncode::decode(reader)),
+            map: try!(NetEncode::decode(reader)),
+            not_full: try!(NetEncode::decode(reader)),
+            not_empty: try!(NetEncode::decode(reader))
+        })
+    }
+}
+
+impl NetEncode for MasterMessage {
+    fn encode(&self, writer: &mut BitWriter) -> BitResult<()> {
+        match *self {
+            MasterMessage::Register(ref listing) => {
+                writer.write_bits(0, 3);
+                listing.encode(writer)
+            },
+            MasterMessage::Heartbeat => {
+                writer.write_bits(1, 3);
+                Ok(())
+            },
+            MasterMessage::Unregister => {
+                writer.write_bits(2, 3);
+                Ok(())
+            },
+            MasterMessage::List(ref filter) => {
+                writer.write_bits(3, 3);
+                filter.encode(writer)
+            },
+            MasterMessage::Listing(page, pages, ref servers) => {
+                writer.write_bits(4, 3);
+                try!(page.encode(writer));
+                try!(pages.encode(writer));
+                servers.encode(writer)
+            }
+        }
+    }
+
+    fn decode(reader: &mut BitReader) -> BitResult<MasterMessage> {
+        match try!(reader.read_bits(3)) {
+            0 => Ok(MasterMessage::Register(try!(NetEncode::decode(reader)))),
+            1 => Ok(MasterMessage::Heartbeat),
+            2 => Ok(MasterMessage::Unregister),
+            3 => Ok(MasterMessage::List(try!(NetEncode::decode(reader)))),
+            4 => {
+                let page = try!(NetEncode::decode(reader));
+                let pages = try!(NetEncode::decode(reader));
+                Ok(MasterMessage::Listing(page, pages, try!(NetEncode::decode(reader))))
+            },
+            _ => Err(BitError::InvalidData)
+        }
+    }
+}
+
+/**
+ * How many bits the length in front of a list of this many items takes up
+ */
+fn length_bits(length: usize) -> usize {
+    let mut writer = BitWriter::new();
+    writer.write_varint(length as u64);
+    writer.bits_written()
+}
+
+/**
+ * Split servers into Listing messages which each fit into a single packet
+ */
+fn paginate(servers: Vec<ListedServer>) -> Vec<MasterMessage> {
+    let mut pages: Vec<Vec<ListedServer>> = vec![vec![]];
+    //Everything on the last page so far, not counting the overhead or its length
+    let mut page_bits = 0;
+    for server in servers.into_iter() {
+        let mut writer = BitWriter::new();
+        match server.encode(&mut writer) {
+            Ok(()) => (),
+            Err(_) => continue
+        }
+        let server_bits = writer.bits_written();
+
+        let fits = {
+            let page = pages.last().unwrap();
+            let total_bits = LISTING_OVERHEAD_BITS + length_bits(page.len() + 1) + page_bits + server_bits;
+            page.len() == 0 || HEADER_SIZE + (total_bits + 7) / 8 <= MAX_PACKET_SIZE
+        };
+        if fits {
+            pages.last_mut().unwrap().push(server);
+            page_bits += server_bits;
+        } else {
+            pages.push(vec![server]);
+            page_bits = server_bits;
+        }
+    }
+    let count = pages.len() as u16;
+    pages.into_iter().enumerate().map(|(page, servers)| MasterMessage::Listing(page as u16, count, servers)).collect()
+}
+
+/**
+ * What changed on a master while polling
+ */
+#[derive(Clone, Show, PartialEq)]
+pub enum MasterEvent {
+    ///A server was listed, or updated its listing
+    Registered(ListedServer),
+    ///A server took its listing down, or disconnected
+    Unregistered(SocketAddr),
+    ///A server stopped heartbeating
+    Expired(SocketAddr),
+    ///A player was sent a list
+    Listed(SocketAddr, usize)
+}
+
+/**
+ * Keeps the list of game servers, and hands it out
+ */
+pub struct MasterServer <N: Transport = UdpSocket> {
+    server: Server<MasterMessage, N>,
+    ///Keyed by the address each game server registered from
+    listings: BTreeMap<String, ListedServer>,
+    list_limiter: RateLimiter
+}
+
+impl MasterServer<UdpSocket> {
+
+    /**
+     * Start a master on a UDP address, forgetting servers which are quiet for longer than expiry
+     */
+    pub fn bind(addr: SocketAddr, expiry: Duration) -> NetResult<MasterServer<UdpSocket>> {
+        let socket = try!(UdpSocket::bind(addr));
+        MasterServer::with_transport(socket, expiry)
+    }
+}
+
+impl <N: Transport> MasterServer<N> {
+
+    /**
+     * Start a master on any Transport
+     */
+    pub fn with_transport(transport: N, expiry: Duration) -> NetResult<MasterServer<N>> {
+        Ok(MasterServer {
+            server: try!(Server::with_transport(transport, ConnectionConfig::with_encoding(MASTER_PROTOCOL_ID, expiry))),
+            listings: BTreeMap::new(),
+            list_limiter: RateLimiter::new(DEFAULT_LISTS_PER_ADDRESS, DEFAULT_LISTS_TOTAL)
+        })
+    }
+
+    /**
+     * Handle everything which has arrived, and drop any listings which have expired
+     */
+    pub fn poll(&mut self) -> Vec<MasterEvent> {
+        let mut events = vec![];
+        loop {
+            match self.server.poll() {
+                Some((PacketOrCommand::UserPacket(message), src)) => {
+                    match message {
+                        MasterMessage::Register(listing) => {
+                            if listing.name.len() <= MAX_LISTING_TEXT && listing.map.len() <= MAX_LISTING_TEXT {
+                                let listed = ListedServer {
+                                    addr: SocketAddr { ip: src.ip, port: listing.port },
+                                    listing: listing
+                                };
+                                self.listings.insert(format!("{}", src), listed.clone());
+                                events.push(MasterEvent::Registered(listed));
+                            }
+                        },
+                        MasterMessage::Heartbeat => {
+                            //Polling has already pushed the expiry back, so just let them know we're here
+                            match self.server.send_to(&MasterMessage::Heartbeat, &src) {
+                                _ => ()
+                            }
+                        },
+                        MasterMessage::Unregister => {
+                            match self.listings.remove(&format!("{}", src)) {
+                                Some(listed) => events.push(MasterEvent::Unregistered(listed.addr)),
+                                None => ()
+                            }
+                        },
+                        MasterMessage::List(_) if !self.list_limiter.allow(&src.ip, precise_time_ns() / 1000000) => (),
+                        MasterMessage::List(filter) => {
+                            let servers: Vec<ListedServer> = self.listings.values().filter(|listed| filter.matches(&listed.listing)).map(|listed| listed.clone()).collect();
+                            let count = servers.len();
+                            for page in paginate(servers).iter() {
+                                match self.server.send_to(page, &src) {
+                                    _ => () //Players can always ask again
+                                }
+                            }
+                            events.push(MasterEvent::Listed(src, count));
+                        },
+                        MasterMessage::Listing(..) => ()
+                    }
+                },
+                Some((PacketOrCommand::Command(PacketType::Disconnect), src)) => {
+                    match self.listings.remove(&format!("{}", src)) {
+                        Some(listed) => events.push(MasterEvent::Unregistered(listed.addr)),
+                        None => ()
+                    }
+                },
+                Some(_) => (),
+                None => break
+            }
+        }
+
+        for addr in self.server.cull().iter() {
+            match self.listings.remove(&format!("{}", addr)) {
+                Some(listed) => events.push(MasterEvent::Expired(listed.addr)),
+                None => ()
+            }
+        }
+        events
+    }
+
+    /**
+     * Change how many lists a second are handed out, for any one address and in total
+     */
+    pub fn set_list_rate(&mut self, per_address: u32, total: u32) {
+        self.list_limiter = RateLimiter::new(per_address, total);
+    }
+
+    /**
+     * Every server currently listed
+     */
+    pub fn listings(&self) -> Vec<ListedServer> {
+        self.listings.values().map(|listed| listed.clone()).collect()
+    }
+
+    /**
+     * Ask the transport which address we're really listening on
+     */
+    pub fn local_addr(&mut self) -> NetResult<SocketAddr> {
+        self.server.local_addr()
+    }
+}
+
+enum RegistrationCommand {
+    Update(ServerListing),
+    Stop
+}
+
+/**
+ * A game server's listing on a master, kept alive in the background until dropped
+ */
+pub struct Registration {
+    commands: Sender<RegistrationCommand>
+}
+
+impl Registration {
+
+    /**
+     * Change what the master lists for us, such as when players join or leave
+     */
+    pub fn update(&self, listing: ServerListing) {
+        match self.commands.send(RegistrationCommand::Update(listing)) {
+            _ => () //If the registration has given up, there's no one left to tell
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        match self.commands.send(RegistrationCommand::Stop) {
+            _ => ()
+        }
+    }
+}
+
+fn master_config(heartbeat: Duration) -> ConnectionConfig<MasterMessage> {
+    //Allow a few missed heartbeat echoes before deciding the master has gone
+    ConnectionConfig::with_encoding(MASTER_PROTOCOL_ID, heartbeat * 3)
+}
+
+/**
+ * Keep heartbeating, reconnecting whenever the master loses track of us
+ */
+fn registration_process<N, F>(mut client: Client<MasterMessage, N>, mut reconnect: F, mut listing: ServerListing, heartbeat: Duration, commands: Receiver<RegistrationCommand>)
+    where N: Transport, F: FnMut() -> Option<NetResult<Client<MasterMessage, N>>> {
+    let mut timer = match Timer::new() {
+        Ok(timer) => timer,
+        Err(_) => return
+    };
+    let interval = heartbeat.num_milliseconds() as u64;
+    let mut next_heartbeat = precise_time_ns() / 1000000 + interval;
+
+    loop {
+        match commands.try_recv() {
+            Ok(RegistrationCommand::Update(updated)) => {
+                listing = updated;
+                match client.send(&MasterMessage::Register(listing.clone())) {
+                    _ => () //Sent again on reconnecting if need be
+                }
+            },
+            Ok(RegistrationCommand::Stop) | Err(TryRecvError::Disconnected) => {
+                match client.send(&MasterMessage::Unregister) {
+                    _ => ()
+                }
+                return;
+            },
+            Err(TryRecvError::Empty) => ()
+        }
+
+        let mut lost = false;
+        loop {
+            match client.poll() {
+                Ok(_) => (),
+                Err(PollFailResult::Empty) => break,
+                Err(PollFailResult::Disconnected) | Err(PollFailResult::Failed(Error::ThreadDied)) => {
+                    lost = true;
+                    break;
+                },
+                //A send going astray doesn't mean the master has forgotten us, and a fresh port would list us twice
+                Err(PollFailResult::Failed(_)) => ()
+            }
+        }
+
+        if lost {
+            match reconnect() {
+                Some(Ok(reconnected)) => {
+                    client = reconnected;
+                    match client.send(&MasterMessage::Register(listing.clone())) {
+                        _ => ()
+                    }
+                },
+                Some(Err(_)) => (), //Try again next time around
+                None => return
+            }
+        }
+
+        let now = precise_time_ns() / 1000000;
+        if now >= next_heartbeat {
+            match client.send(&MasterMessage::Heartbeat) {
+                _ => ()
+            }
+            next_heartbeat = now + interval;
+        }
+        timer.sleep(Duration::milliseconds(50));
+    }
+}
+
+/**
+ * List a game server on a master over UDP, reconnecting if the master forgets us
+ */
+pub fn register(master_addr: SocketAddr, listing: ServerListing) -> NetResult<Registration> {
+    let heartbeat = Duration::seconds(HEARTBEAT_SECONDS);
+    let local = match master_addr.ip {
+        Ipv4Addr(..) => SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 0 },
+        Ipv6Addr(..) => SocketAddr { ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0), port: 0 }
+    };
+    let connect = move || Client::connect(local, master_addr, master_config(heartbeat), ClientConnectionConfig::new(3, Duration::seconds(5)));
+    let client = try!(connect());
+    start_registration(client, move || Some(connect()), listing, heartbeat)
+}
+
+/**
+ * List a game server on a master over any Transport, heartbeating as often as asked
+ *
+ * There's no way to get another transport, so this gives up if the master forgets us.
+ */
+pub fn register_with<N: Transport>(transport: N, master_addr: SocketAddr, listing: ServerListing, heartbeat: Duration) -> NetResult<Registration> {
+    let client = try!(Client::connect_with(transport, master_addr, master_config(heartbeat), ClientConnectionConfig::new(3, Duration::seconds(5))));
+    start_registration(client, || None, listing, heartbeat)
+}
+
+fn start_registration<N, F>(mut client: Client<MasterMessage, N>, reconnect: F, listing: ServerListing, heartbeat: Duration) -> NetResult<Registration>
+    where N: Transport, F: FnMut() -> Option<NetResult<Client<MasterMessage, N>>> + Send {
+    try!(client.send(&MasterMessage::Register(listing.clone())));
+    let (commands, commands_receive) = channel();
+    Thread::spawn(move || {
+        registration_process(client, reconnect, listing, heartbeat, commands_receive);
+    });
+    Ok(Registration { commands: commands })
+}
+
+/**
+ * Ask a master over UDP for the servers matching a filter
+ */
+pub fn fetch_server_list(master_addr: SocketAddr, filter: ListFilter, timeout: Duration) -> NetResult<Vec<ListedServer>> {
+    let local = match master_addr.ip {
+        Ipv4Addr(..) => SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 0 },
+        Ipv6Addr(..) => SocketAddr { ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0), port: 0 }
+    };
+    fetch_server_list_with(try!(UdpSocket::bind(local)), master_addr, filter, timeout)
+}
+
+/**
+ * Ask a master over any Transport for the servers matching a filter
+ *
+ * If some pages of the list go missing, whatever did arrive is returned once the timeout passes.
+ */
+pub fn fetch_server_list_with<N: Transport>(transport: N, master_addr: SocketAddr, filter: ListFilter, timeout: Duration) -> NetResult<Vec<ListedServer>> {
+    let mut client = try!(Client::connect_with(transport, master_addr, master_config(timeout), ClientConnectionConfig::new(3, timeout)));
+    try!(client.send(&MasterMessage::List(filter)));
+
+    let mut timer = try!(Timer::new());
+    let deadline = precise_time_ns() / 1000000 + timeout.num_milliseconds() as u64;
+    let mut pages: BTreeMap<u16, Vec<ListedServer>> = BTreeMap::new();
+    let mut expected = None;
+    while expected.map_or(true, |count| pages.len() < count as usize) && precise_time_ns() / 1000000 < deadline {
+        match client.poll() {
+            Ok(MasterMessage::Listing(page, count, servers)) => {
+                expected = Some(count);
+                pages.insert(page, servers);
+            },
+            Ok(_) => (),
+            Err(PollFailResult::Empty) => timer.sleep(Duration::milliseconds(10)),
+            Err(_) => break
+        }
+    }
+
+    if expected.is_none() {
+        return Err(Error::TimedOut);
+    }
+    Ok(pages.into_iter().flat_map(|(_, servers)| servers.into_iter()).collect())
+}
@@ -0,0 +1,285 @@
+//!
+//! Listening on several addresses at once, such as one IPv4 and one IPv6 socket
+//!
+//! A `MultiTransport` joins several transports into one. Everything received on any of them
+//! comes out of the same `recv_from`, and replies go back out through whichever transport the
+//! peer was last heard on, so a single Server keeps one connection table for every address.
+//! Peers which go quiet for long enough are forgotten, and replies to them go out through the
+//! first address of the same family.
+//!
+//! Streams can be joined with datagram transports. Only a stream's hang ups are passed on, as empty
+//! datagrams, while empty datagrams arriving any other way are ignored.
+//!
+use std::old_io::{IoResult, IoError, TimedOut, EndOfFile, InvalidInput, Timer};
+use std::old_io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::thread::Thread;
+use std::time::duration::Duration;
+use std::collections::BTreeMap;
+use packet::MAX_PACKET_SIZE;
+use transport::Transport;
+use inbox::{Inbox, recv_timeout};
+use clock::{SharedClock, system_clock};
+
+///How long a peer's route is remembered after last hearing from them, in milliseconds
+pub const ROUTE_IDLE_MS: u64 = 60000;
+
+///How many peers' routes are remembered at once
+pub const MAX_ROUTES: usize = 4096;
+
+///How long a receiver waits before trying a transport again after it fails, in milliseconds
+const RETRY_MS: i64 = 100;
+
+struct Route {
+    index: usize,
+    last_heard: u64,
+    ///Tells apart routes last heard from in the same millisecond
+    id: u64
+}
+
+/**
+ * Each peer's route, along with every route in the order they were last heard from
+ */
+struct Routes {
+    by_peer: BTreeMap<String, Route>,
+    by_age: BTreeMap<(u64, u64), String>,
+    next_id: u64
+}
+
+impl Routes {
+    fn forget(&mut self, peer: &String) {
+        match self.by_peer.remove(peer) {
+            Some(route) => { self.by_age.remove(&(route.last_heard, route.id)); },
+            None => ()
+        }
+    }
+
+    /**
+     * Whoever was heard from longest ago, if anyone
+     */
+    fn oldest(&self) -> Option<(u64, String)> {
+        self.by_age.iter().next().map(|(&(last_heard, _), peer)| (last_heard, peer.clone()))
+    }
+}
+
+struct Joined<N> {
+    addrs: Vec<SocketAddr>,
+    ///One handle on each transport for sending, in the same order as addrs
+    senders: Mutex<Vec<N>>,
+    ///Which transport each peer was last heard on
+    routes: Mutex<Routes>,
+    ///Whether any of the transports is a stream
+    streams: bool,
+    clock: SharedClock,
+    inbox: Inbox,
+    handles: AtomicUsize
+}
+
+fn same_family(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (Ipv4Addr(..), Ipv4Addr(..)) => true,
+        (Ipv6Addr(..), Ipv6Addr(..)) => true,
+        _ => false
+    }
+}
+
+impl <N> Joined<N> {
+    /**
+     * Remember which transport a peer was heard on, making room if there are too many to remember
+     */
+    fn heard(&self, src: SocketAddr, index: usize) {
+        let now = self.clock.now_ms();
+        let mut routes = self.routes.lock().unwrap();
+        let key = format!("{}", src);
+        //Idle routes are always the oldest, so clearing them only ever looks at what it removes
+        loop {
+            match routes.oldest() {
+                Some((last_heard, ref peer)) if last_heard + ROUTE_IDLE_MS < now => routes.forget(peer),
+                _ => break
+            }
+        }
+        let known = routes.by_peer.get(&key).map(|route| route.id);
+        let id = match known {
+            Some(id) => id,
+            None => {
+                if routes.by_peer.len() >= MAX_ROUTES {
+                    //Everyone is still talking, so the one heard from longest ago goes
+                    match routes.oldest() {
+                        Some((_, ref peer)) => routes.forget(peer),
+                        None => ()
+                    }
+                }
+                routes.next_id += 1;
+                routes.next_id
+            }
+        };
+        routes.forget(&key);
+        routes.by_age.insert((now, id), key.clone());
+        routes.by_peer.insert(key, Route { index: index, last_heard: now, id: id });
+    }
+
+    /**
+     * Which transport to reply to a peer through, if we still remember them
+     */
+    fn route(&self, addr: SocketAddr) -> Option<usize> {
+        let now = self.clock.now_ms();
+        let mut routes = self.routes.lock().unwrap();
+        let key = format!("{}", addr);
+        let expired = match routes.by_peer.get(&key) {
+            Some(route) if route.last_heard + ROUTE_IDLE_MS >= now => return Some(route.index),
+            Some(_) => true,
+            None => false
+        };
+        if expired {
+            routes.forget(&key);
+        }
+        None
+    }
+}
+
+fn receive_process<N: Transport>(mut transport: N, index: usize, joined: Arc<Joined<N>>, inbox_send: Sender<(Vec<u8>, SocketAddr)>) {
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let mut timer = match Timer::new() {
+        Ok(timer) => timer,
+        Err(_) => return
+    };
+    let stream = transport.is_stream();
+    transport.set_timeout(Some(1000));
+    while joined.handles.load(Ordering::SeqCst) > 0 {
+        match transport.recv_from(&mut buf) {
+            //Only a stream's hang ups are passed on, as anyone can forge an empty datagram
+            Ok((0, _)) if !stream => (),
+            Ok((amt, src)) => {
+                joined.heard(src, index);
+                match inbox_send.send((buf.slice_to(amt).to_vec(), src)) {
+                    Ok(()) => (),
+                    Err(_) => return
+                }
+            },
+            Err(ref e) if e.kind == TimedOut => (),
+            //The transport has closed, so nothing more can ever arrive
+            Err(ref e) if e.kind == EndOfFile => return,
+            //One bad datagram shouldn't stop the others, but a transport which keeps failing shouldn't spin either
+            Err(_) => timer.sleep(Duration::milliseconds(RETRY_MS))
+        }
+    }
+}
+
+/**
+ * A Transport which listens on several other Transports at once
+ */
+pub struct MultiTransport<N> {
+    joined: Arc<Joined<N>>,
+    timeout_ms: Option<u64>
+}
+
+impl <N: Transport> MultiTransport<N> {
+
+    /**
+     * Join transports together, ready to pass to Server::with_transport
+     *
+     * The first transport's address is the one reported by local_addr.
+     */
+    pub fn join(transports: Vec<N>) -> IoResult<MultiTransport<N>> {
+        MultiTransport::join_with_clock(transports, system_clock())
+    }
+
+    /**
+     * Join transports together, forgetting idle peers by the given clock
+     */
+    pub fn join_with_clock(transports: Vec<N>, clock: SharedClock) -> IoResult<MultiTransport<N>> {
+        if transports.len() == 0 {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "No transports to listen on",
+                detail: None
+            });
+        }
+
+        let mut addrs = vec![];
+        let mut receivers = vec![];
+        let mut senders = vec![];
+        let streams = transports.iter().any(|transport| transport.is_stream());
+        for mut transport in transports.into_iter() {
+            addrs.push(try!(transport.local_addr()));
+            receivers.push(try!(transport.try_clone()));
+            senders.push(transport);
+        }
+
+        let (inbox_send, inbox_receive) = channel();
+        let joined = Arc::new(Joined {
+            addrs: addrs,
+            senders: Mutex::new(senders),
+            routes: Mutex::new(Routes { by_peer: BTreeMap::new(), by_age: BTreeMap::new(), next_id: 0 }),
+            streams: streams,
+            clock: clock,
+            inbox: Inbox::new(inbox_receive),
+            handles: AtomicUsize::new(1)
+        });
+
+        for (index, receiver) in receivers.into_iter().enumerate() {
+            let joined = joined.clone();
+            let inbox_send = inbox_send.clone();
+            Thread::spawn(move || {
+                receive_process(receiver, index, joined, inbox_send);
+            });
+        }
+
+        Ok(MultiTransport {
+            joined: joined,
+            timeout_ms: None
+        })
+    }
+
+    /**
+     * Every address we're listening on
+     */
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.joined.addrs.clone()
+    }
+}
+
+impl <N: Transport> Transport for MultiTransport<N> {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        //Reply the way the peer came in, or failing that through the first address of the same family
+        let index = match self.joined.route(addr) {
+            Some(index) => index,
+            None => self.joined.addrs.iter().position(|local| same_family(local.ip, addr.ip)).unwrap_or(0)
+        };
+        self.joined.senders.lock().unwrap()[index].send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        recv_timeout(&self.joined.inbox, self.timeout_ms, buf)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        Ok(self.joined.addrs[0])
+    }
+
+    fn try_clone(&self) -> IoResult<MultiTransport<N>> {
+        self.joined.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(MultiTransport {
+            joined: self.joined.clone(),
+            timeout_ms: self.timeout_ms
+        })
+    }
+
+    fn is_stream(&self) -> bool {
+        //Empty datagrams from anything but a stream never get this far
+        self.joined.streams
+    }
+}
+
+impl <N> Drop for MultiTransport<N> {
+    fn drop(&mut self) {
+        //Once the last handle has gone, the receivers notice at their next timeout and stop
+        self.joined.handles.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -2,6 +2,12 @@ use std::old_io::{IoResult, IoError, OtherIoError};
 use std::old_io::BufReader;
 use std::num::FromPrimitive;
 
+///The largest datagram we'll send or receive, including the header
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+///How many bytes of each packet are taken up by the header
+pub const HEADER_SIZE: usize = 7;
+
 ///Headers for various different built-in message types
 #[derive(FromPrimitive, Clone, Show, PartialEq, Copy)]
 pub enum PacketType {
@@ -9,7 +15,14 @@ pub enum PacketType {
     Accept,
     Reject,
     Disconnect,
-    Message
+    Message,
+    DiscoveryProbe,
+    DiscoveryReply,
+    Query,
+    QueryReply,
+    Introduce,
+    Introduction,
+    Punch
 }
 
 ///The underlying shape for transferring data.
@@ -66,6 +79,15 @@ impl Packet {
         }
     }
 
+    pub fn reject_because(protocol_id: u32, sequence_id: u16, reason: &str) -> Packet {
+        Packet {
+            protocol_id: protocol_id,
+            sequence_id: sequence_id,
+            packet_type: PacketType::Reject,
+            packet_content: if reason.len() > 0 { Some(reason.as_bytes().to_vec()) } else { None }
+        }
+    }
+
     pub fn message(protocol_id: u32, sequence_id: u16, message: Vec<u8>) -> Packet {
         Packet {
             protocol_id: protocol_id,
@@ -75,6 +97,69 @@ impl Packet {
         }
     }
 
+    pub fn discovery_probe(protocol_id: u32, sequence_id: u16, padding: usize) -> Packet {
+        Packet {
+            protocol_id: protocol_id,
+            sequence_id: sequence_id,
+            packet_type: PacketType::DiscoveryProbe,
+            packet_content: if padding > 0 { Some(vec![0; padding]) } else { None }
+        }
+    }
+
+    pub fn discovery_reply(protocol_id: u32, sequence_id: u16, content: Vec<u8>) -> Packet {
+        Packet {
+            protocol_id: protocol_id,
+            sequence_id: sequence_id,
+            packet_type: PacketType::DiscoveryReply,
+            packet_content: Some(content)
+        }
+    }
+
+    pub fn query(protocol_id: u32, sequence_id: u16, padding: usize) -> Packet {
+        Packet {
+            protocol_id: protocol_id,
+            sequence_id: sequence_id,
+            packet_type: PacketType::Query,
+            packet_content: if padding > 0 { Some(vec![0; padding]) } else { None }
+        }
+    }
+
+    pub fn query_reply(protocol_id: u32, sequence_id: u16, info: Vec<u8>) -> Packet {
+        Packet {
+            protocol_id: protocol_id,
+            sequence_id: sequence_id,
+            packet_type: PacketType::QueryReply,
+            packet_content: if info.len() > 0 { Some(info) } else { None }
+        }
+    }
+
+    pub fn introduce(protocol_id: u32, session: &str) -> Packet {
+        Packet {
+            protocol_id: protocol_id,
+            sequence_id: 0,
+            packet_type: PacketType::Introduce,
+            packet_content: Some(session.as_bytes().to_vec())
+        }
+    }
+
+    pub fn introduction(protocol_id: u32, peer: Vec<u8>) -> Packet {
+        Packet {
+            protocol_id: protocol_id,
+            sequence_id: 0,
+            packet_type: PacketType::Introduction,
+            packet_content: Some(peer)
+        }
+    }
+
+    pub fn punch(protocol_id: u32, sequence_id: u16) -> Packet {
+        Packet {
+            protocol_id: protocol_id,
+            sequence_id: sequence_id,
+            packet_type: PacketType::Punch,
+            packet_content: None
+        }
+    }
+
     pub fn deserialize(raw: &[u8]) -> IoResult<Packet> {
         let mut r = BufReader::new(raw);
         let protocol_id = try!(r.read_be_u32());
@@ -0,0 +1,489 @@
+//!
+//! The protocol itself, with no sockets, threads or clocks
+//!
+//! `ClientProtocol` and `ServerProtocol` are state machines. Feed them each datagram along with where
+//! it came from and when it arrived, call `update` now and then so timeouts can happen, and send
+//! whatever `drain_outgoing` hands back. `Client` and `Server` are drivers built around them, moving
+//! datagrams on background threads or when pumped, but a game engine with its own event loop can
+//! drive them directly.
+//!
+use std::old_io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::collections::BTreeMap;
+use std::time::duration::Duration;
+use packet::{Packet, PacketType, MAX_PACKET_SIZE, HEADER_SIZE};
+use shared::SequenceManager;
+use client::ConnectionState;
+use query::within_amplification;
+use error::{Error, NetResult};
+
+/**
+ * Raw bytes, and who they should go to
+ */
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+fn millis(duration: Duration) -> u64 {
+    duration.num_milliseconds() as u64
+}
+
+/**
+ * Something a ClientProtocol noticed
+ */
+#[derive(Clone, PartialEq, Show)]
+pub enum ClientEvent {
+    ///The server accepted us
+    Connected,
+    ///The server turned us away, with its reason, or hung up before accepting us
+    Rejected(String),
+    ///A message payload, in order, ready to be deserialized
+    Message(Vec<u8>),
+    ///The server hung up, or went quiet for too long, once connected
+    Disconnected,
+    ///The server never answered while we were connecting
+    TimedOut
+}
+
+/**
+ * What a connection attempt ended with, as far as the caller is concerned
+ */
+pub fn handshake_result(event: ClientEvent) -> NetResult<()> {
+    match event {
+        ClientEvent::Rejected(reason) => Err(Error::Rejected(reason)),
+        ClientEvent::TimedOut => Err(Error::TimedOut),
+        ClientEvent::Disconnected => Err(Error::NotConnected),
+        _ => Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Handshake {
+    attempts: u32,
+    max_attempts: u32,
+    attempt_timeout: u64,
+    next_attempt: u64
+}
+
+/**
+ * One end of a connection to a server
+ */
+pub struct ClientProtocol {
+    protocol_id: u32,
+    target_addr: SocketAddr,
+    timeout_ms: u64,
+    state: ConnectionState,
+    sequence_manager: SequenceManager,
+    handshake: Option<Handshake>,
+    expires: u64,
+    outgoing: Vec<Datagram>
+}
+
+impl ClientProtocol {
+
+    /**
+     * Create a protocol for talking to a server, not yet connecting
+     */
+    pub fn new(protocol_id: u32, target_addr: SocketAddr, timeout_period: Duration) -> ClientProtocol {
+        ClientProtocol {
+            protocol_id: protocol_id,
+            target_addr: target_addr,
+            timeout_ms: millis(timeout_period),
+            state: ConnectionState::Disconnected,
+            sequence_manager: SequenceManager::new(),
+            handshake: None,
+            expires: 0,
+            outgoing: vec![]
+        }
+    }
+
+    /**
+     * Start asking the server for a connection, trying up to `max_attempts` times
+     */
+    pub fn connect(&mut self, max_attempts: u32, attempt_timeout: Duration, now: u64) -> Option<ClientEvent> {
+        self.state = ConnectionState::Connecting;
+        self.expires = now + self.timeout_ms;
+        self.handshake = Some(Handshake {
+            attempts: 0,
+            max_attempts: max_attempts,
+            attempt_timeout: millis(attempt_timeout),
+            next_attempt: now
+        });
+        self.update(now)
+    }
+
+    /**
+     * Take in a datagram which arrived at `now`
+     */
+    pub fn handle(&mut self, data: &[u8], from: SocketAddr, now: u64) -> Option<ClientEvent> {
+        if from != self.target_addr {
+            return None;
+        }
+        let packet = match Packet::deserialize(data) {
+            Ok(packet) => packet,
+            Err(_) => return None
+        };
+        if packet.protocol_id != self.protocol_id {
+            return None;
+        }
+        self.expires = now + self.timeout_ms;
+
+        match (self.state, packet.packet_type) {
+            (ConnectionState::Connecting, PacketType::Accept) => {
+                self.state = ConnectionState::Connected;
+                self.handshake = None;
+                Some(ClientEvent::Connected)
+            },
+            (ConnectionState::Connecting, PacketType::Reject) => {
+                self.refused(match packet.packet_content {
+                    Some(ref reason) => String::from_utf8_lossy(reason.as_slice()).into_owned(),
+                    None => String::new()
+                })
+            },
+            //Hanging up on us before we're in is as good as turning us away
+            (ConnectionState::Connecting, PacketType::Disconnect) => self.refused(String::new()),
+            (_, PacketType::Disconnect) => self.lost(),
+            (ConnectionState::Connected, PacketType::Message) => {
+                //Are we expecting this packet?
+                if self.sequence_manager.packet_is_newer(packet.sequence_id) {
+                    self.sequence_manager.set_newest_packet(packet.sequence_id);
+                    Some(ClientEvent::Message(packet.packet_content.unwrap_or(vec![])))
+                } else {
+                    None
+                }
+            },
+            _ => None
+        }
+    }
+
+    /**
+     * A stream transport has told us the server hung up
+     *
+     * Only stream transports can say this. Never treat an empty datagram as a hang up, as anyone can forge one.
+     */
+    pub fn hang_up(&mut self, from: SocketAddr) -> Option<ClientEvent> {
+        if from != self.target_addr {
+            return None;
+        }
+        match self.state {
+            ConnectionState::Connecting => self.refused(String::new()),
+            _ => self.lost()
+        }
+    }
+
+    fn refused(&mut self, reason: String) -> Option<ClientEvent> {
+        self.state = ConnectionState::Disconnected;
+        self.handshake = None;
+        Some(ClientEvent::Rejected(reason))
+    }
+
+    fn lost(&mut self) -> Option<ClientEvent> {
+        let event = match self.state {
+            ConnectionState::Connecting => Some(ClientEvent::TimedOut),
+            ConnectionState::Connected => Some(ClientEvent::Disconnected),
+            ConnectionState::Disconnected => None
+        };
+        self.state = ConnectionState::Disconnected;
+        self.handshake = None;
+        event
+    }
+
+    /**
+     * Let time pass, retrying the connection or giving up on a quiet server as needed
+     */
+    pub fn update(&mut self, now: u64) -> Option<ClientEvent> {
+        match self.state {
+            ConnectionState::Disconnected => return None,
+            _ => ()
+        }
+        if now > self.expires {
+            return self.lost();
+        }
+
+        let mut handshake = match self.handshake {
+            Some(handshake) => handshake,
+            None => return None
+        };
+        if now < handshake.next_attempt {
+            return None;
+        }
+        if handshake.attempts >= handshake.max_attempts {
+            return self.lost();
+        }
+        handshake.attempts += 1;
+        handshake.next_attempt = now + handshake.attempt_timeout;
+        self.handshake = Some(handshake);
+        let connect = Packet::connect(self.protocol_id, self.sequence_manager.next_sequence_id());
+        self.queue(connect);
+        None
+    }
+
+    /**
+     * When `update` next needs calling, if anything is waiting on the time
+     */
+    pub fn next_deadline(&self) -> Option<u64> {
+        match (self.state, self.handshake) {
+            (ConnectionState::Disconnected, _) => None,
+            (_, Some(handshake)) if handshake.next_attempt < self.expires => Some(handshake.next_attempt),
+            _ => Some(self.expires + 1)
+        }
+    }
+
+    /**
+     * Queue a message payload for the server
+     */
+    pub fn send(&mut self, payload: Vec<u8>) -> NetResult<()> {
+        match self.state {
+            ConnectionState::Connected => (),
+            _ => return Err(Error::NotConnected)
+        };
+        if payload.len() + HEADER_SIZE > MAX_PACKET_SIZE {
+            return Err(Error::PayloadTooLarge);
+        }
+        let message = Packet::message(self.protocol_id, self.sequence_manager.next_sequence_id(), payload);
+        match message.serialize() {
+            Ok(data) => {
+                self.outgoing.push((data, self.target_addr));
+                Ok(())
+            },
+            Err(_) => Err(Error::Serialization)
+        }
+    }
+
+    /**
+     * Tell the server we're going, if we were connected or connecting
+     */
+    pub fn disconnect(&mut self) {
+        match self.state {
+            ConnectionState::Disconnected => return,
+            _ => ()
+        }
+        let disconnect = Packet::disconnect(self.protocol_id, self.sequence_manager.next_sequence_id());
+        self.queue(disconnect);
+        self.state = ConnectionState::Disconnected;
+        self.handshake = None;
+    }
+
+    fn queue(&mut self, packet: Packet) {
+        match packet.serialize() {
+            Ok(data) => self.outgoing.push((data, self.target_addr)),
+            Err(_) => ()
+        }
+    }
+
+    /**
+     * Take every datagram waiting to be sent
+     */
+    pub fn drain_outgoing(&mut self) -> Vec<Datagram> {
+        let mut drained = vec![];
+        ::std::mem::swap(&mut drained, &mut self.outgoing);
+        drained
+    }
+
+    /**
+     * Where the connection has got to
+     */
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+}
+
+/**
+ * Something a ServerProtocol noticed
+ */
+#[derive(Clone, PartialEq, Show)]
+pub enum ServerEvent {
+    ///A new client has been accepted
+    Connected(SocketAddr),
+    ///A client said goodbye, or its transport hung up
+    Disconnected(SocketAddr),
+    ///A message payload from a client, in order, ready to be deserialized
+    Message(SocketAddr, Vec<u8>),
+    ///Someone who isn't a client asked about us. Holds the sequence ID to echo and the query's size
+    Query(SocketAddr, u16, usize)
+}
+
+//FIXME: Ew ew ew - there must be a nicer way of hashing
+fn hash_sender(address: &SocketAddr) -> String {
+    match address.ip {
+        Ipv4Addr(a, b, c, d) => {
+            format!("{}-{}-{}-{}:{}", a, b, c, d, address.port)
+        },
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            format!("{}-{}-{}-{}-{}-{}-{}-{}:{}", a, b, c, d, e, f, g, h, address.port)
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ClientInstance {
+    addr: SocketAddr,
+    timeout: u64,
+    sequence_manager: SequenceManager
+}
+
+/**
+ * The server's side of every connection
+ */
+pub struct ServerProtocol {
+    protocol_id: u32,
+    timeout_ms: u64,
+    connections: BTreeMap<String, ClientInstance>,
+    outgoing: Vec<Datagram>
+}
+
+impl ServerProtocol {
+
+    /**
+     * Create a protocol with nobody connected
+     */
+    pub fn new(protocol_id: u32, timeout_period: Duration) -> ServerProtocol {
+        ServerProtocol {
+            protocol_id: protocol_id,
+            timeout_ms: millis(timeout_period),
+            connections: BTreeMap::new(),
+            outgoing: vec![]
+        }
+    }
+
+    /**
+     * A stream transport has told us a client hung up
+     *
+     * Only stream transports can say this. Never treat an empty datagram as a hang up, as anyone can forge one.
+     */
+    pub fn hang_up(&mut self, from: SocketAddr) -> Option<ServerEvent> {
+        self.connections.remove(&hash_sender(&from)).map(|_| ServerEvent::Disconnected(from))
+    }
+
+    /**
+     * Take in a datagram which arrived at `now`
+     */
+    pub fn handle(&mut self, data: &[u8], from: SocketAddr, now: u64) -> Option<ServerEvent> {
+        let hash = hash_sender(&from);
+        let packet = match Packet::deserialize(data) {
+            Ok(packet) => packet,
+            Err(_) => return None
+        };
+        if packet.protocol_id != self.protocol_id {
+            return None;
+        }
+
+        match packet.packet_type {
+            PacketType::Connect => {
+                //Don't accept multiple connection attempts from the same client
+                if self.connections.contains_key(&hash) {
+                    return None;
+                }
+                self.connections.insert(hash, ClientInstance {
+                    addr: from,
+                    timeout: now + self.timeout_ms,
+                    sequence_manager: SequenceManager::new()
+                });
+                let accept = Packet::accept(self.protocol_id, 0);
+                self.queue(accept, from);
+                Some(ServerEvent::Connected(from))
+            },
+            PacketType::Disconnect => self.connections.remove(&hash).map(|_| ServerEvent::Disconnected(from)),
+            PacketType::Query => Some(ServerEvent::Query(from, packet.sequence_id, data.len())),
+            PacketType::Message => {
+                let timeout_ms = self.timeout_ms;
+                match self.connections.get_mut(&hash) {
+                    Some(comms) if comms.sequence_manager.packet_is_newer(packet.sequence_id) => {
+                        comms.sequence_manager.set_newest_packet(packet.sequence_id);
+                        comms.timeout = now + timeout_ms;
+                        Some(ServerEvent::Message(from, packet.packet_content.unwrap_or(vec![])))
+                    },
+                    _ => None
+                }
+            },
+            _ => None
+        }
+    }
+
+    /**
+     * Answer a query, unless the answer would be too much larger than the question
+     */
+    pub fn answer_query(&mut self, to: SocketAddr, sequence_id: u16, query_len: usize, info: Vec<u8>) -> bool {
+        if !within_amplification(query_len, HEADER_SIZE + info.len()) {
+            return false;
+        }
+        let reply = Packet::query_reply(self.protocol_id, sequence_id, info);
+        self.queue(reply, to);
+        true
+    }
+
+    /**
+     * Queue a message payload for a connected client
+     */
+    pub fn send(&mut self, payload: Vec<u8>, to: SocketAddr) -> NetResult<()> {
+        if payload.len() + HEADER_SIZE > MAX_PACKET_SIZE {
+            return Err(Error::PayloadTooLarge);
+        }
+        let protocol_id = self.protocol_id;
+        let message = match self.connections.get_mut(&hash_sender(&to)) {
+            Some(comms) => Packet::message(protocol_id, comms.sequence_manager.next_sequence_id(), payload),
+            None => return Err(Error::NotConnected)
+        };
+        match message.serialize() {
+            Ok(data) => {
+                self.outgoing.push((data, to));
+                Ok(())
+            },
+            Err(_) => Err(Error::Serialization)
+        }
+    }
+
+    /**
+     * Disconnect, and return, any clients that have not contacted us by `now`
+     */
+    pub fn cull(&mut self, now: u64) -> Vec<SocketAddr> {
+        let culled_hashes: Vec<String> = self.connections.iter()
+            .filter(|&(_, connection)| connection.timeout < now)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        culled_hashes.iter().filter_map(|hash| self.connections.remove(hash)).map(|connection| connection.addr).collect()
+    }
+
+    /**
+     * Say goodbye to every client, returning who they were
+     */
+    pub fn disconnect_all(&mut self) -> Vec<SocketAddr> {
+        let protocol_id = self.protocol_id;
+        let mut goodbyes = vec![];
+        for connection in self.connections.values_mut() {
+            goodbyes.push((Packet::disconnect(protocol_id, connection.sequence_manager.next_sequence_id()), connection.addr));
+        }
+        self.connections.clear();
+        goodbyes.into_iter().map(|(packet, addr)| {
+            self.queue(packet, addr);
+            addr
+        }).collect()
+    }
+
+    fn queue(&mut self, packet: Packet, to: SocketAddr) {
+        match packet.serialize() {
+            Ok(data) => self.outgoing.push((data, to)),
+            Err(_) => ()
+        }
+    }
+
+    /**
+     * Take every datagram waiting to be sent
+     */
+    pub fn drain_outgoing(&mut self) -> Vec<Datagram> {
+        let mut drained = vec![];
+        ::std::mem::swap(&mut drained, &mut self.outgoing);
+        drained
+    }
+
+    /**
+     * Every connected client
+     */
+    pub fn connections(&self) -> Vec<SocketAddr> {
+        self.connections.values().map(|connection| connection.addr).collect()
+    }
+
+    /**
+     * How many clients are connected
+     */
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+}
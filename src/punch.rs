@@ -0,0 +1,311 @@
+//!
+//! NAT punch-through, so players behind routers can host games for each other
+//!
+//! Both peers send an `Introduce` naming the same session to an `Introducer` everyone can reach.
+//! Once both have arrived, it tells each the other's public address, as it saw them. The peers then
+//! fire `Punch` packets at each other at the same time, which opens a way through both routers.
+//! After that, one side starts a Server and the other connects as normal, over the very same
+//! transport that was punched through.
+//!
+//! The `introducer` binary runs an `Introducer` on its own. Anyone can name a session, so an
+//! introducer only remembers so many at once, and only so many named from any one address.
+//!
+use std::old_io::{IoResult, IoError, TimedOut, InvalidInput};
+use std::old_io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::BufReader;
+use std::collections::BTreeMap;
+use std::time::duration::Duration;
+use packet::{Packet, PacketType, MAX_PACKET_SIZE};
+use error::{Error, NetResult};
+use transport::Transport;
+use clock::{SharedClock, system_clock};
+use time::precise_time_ns;
+
+///The port introducers listen on, unless another is chosen
+pub const INTRODUCER_PORT: u16 = 27960;
+
+///How long an introducer remembers a session nobody has mentioned, in milliseconds
+pub const SESSION_EXPIRY_MS: u64 = 30000;
+
+///The longest session name, in bytes
+pub const MAX_SESSION_NAME: usize = 64;
+
+///How many sessions an introducer remembers at once, unless told otherwise
+pub const DEFAULT_MAX_SESSIONS: usize = 1024;
+
+///How many sessions any one address may name on an introducer, unless told otherwise
+pub const DEFAULT_SESSIONS_PER_ADDRESS: usize = 4;
+
+///How often to ask the introducer, in milliseconds
+const INTRODUCE_INTERVAL_MS: u64 = 250;
+
+///How often to punch, in milliseconds
+const PUNCH_INTERVAL_MS: u64 = 50;
+
+///Punches to keep sending once we've heard the other side, in case ours went missing
+const EXTRA_PUNCHES: u32 = 5;
+
+fn write_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut w = vec![];
+    match addr.ip {
+        Ipv4Addr(a, b, c, d) => {
+            w.push(4);
+            w.push_all(&[a, b, c, d]);
+        },
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            w.push(6);
+            for part in [a, b, c, d, e, f, g, h].iter() {
+                match w.write_be_u16(*part) {
+                    _ => ()
+                }
+            }
+        }
+    }
+    match w.write_be_u16(addr.port) {
+        _ => ()
+    }
+    w
+}
+
+fn read_addr(raw: &[u8]) -> IoResult<SocketAddr> {
+    let mut r = BufReader::new(raw);
+    let ip: IpAddr = match try!(r.read_byte()) {
+        4 => Ipv4Addr(try!(r.read_byte()), try!(r.read_byte()), try!(r.read_byte()), try!(r.read_byte())),
+        6 => {
+            let mut parts = [0u16; 8];
+            for part in parts.iter_mut() {
+                *part = try!(r.read_be_u16());
+            }
+            Ipv6Addr(parts[0], parts[1], parts[2], parts[3], parts[4], parts[5], parts[6], parts[7])
+        },
+        _ => return Err(IoError {
+            kind: InvalidInput,
+            desc: "Unknown address family",
+            detail: None
+        })
+    };
+    Ok(SocketAddr { ip: ip, port: try!(r.read_be_u16()) })
+}
+
+struct Session {
+    peers: Vec<SocketAddr>,
+    last_heard: u64
+}
+
+/**
+ * Introduces pairs of peers who name the same session to each other
+ */
+pub struct Introducer <N: Transport = UdpSocket> {
+    transport: N,
+    protocol_id: u32,
+    clock: SharedClock,
+    max_sessions: usize,
+    sessions_per_address: usize,
+    sessions: BTreeMap<String, Session>
+}
+
+impl Introducer<UdpSocket> {
+
+    /**
+     * Start introducing on a UDP address
+     */
+    pub fn bind(addr: SocketAddr, protocol_id: u32) -> NetResult<Introducer<UdpSocket>> {
+        let socket = try!(UdpSocket::bind(addr));
+        Ok(Introducer::with_transport(socket, protocol_id))
+    }
+}
+
+impl <N: Transport> Introducer<N> {
+
+    /**
+     * Start introducing on any Transport
+     */
+    pub fn with_transport(transport: N, protocol_id: u32) -> Introducer<N> {
+        Introducer {
+            transport: transport,
+            protocol_id: protocol_id,
+            clock: system_clock(),
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            sessions_per_address: DEFAULT_SESSIONS_PER_ADDRESS,
+            sessions: BTreeMap::new()
+        }
+    }
+
+    /**
+     * Wait up to `timeout_ms` for a request, and answer it. Returns any session which was just paired up
+     */
+    pub fn step(&mut self, timeout_ms: u64) -> NetResult<Option<String>> {
+        self.expire();
+        let mut buf = [0; MAX_PACKET_SIZE];
+        self.transport.set_timeout(Some(timeout_ms));
+        let (amt, src) = match self.transport.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind == TimedOut => return Ok(None),
+            Err(e) => return Err(Error::Io(e))
+        };
+
+        let packet = match Packet::deserialize(buf.slice_to(amt)) {
+            Ok(packet) => packet,
+            Err(_) => return Ok(None)
+        };
+        if packet.protocol_id != self.protocol_id || packet.packet_type != PacketType::Introduce {
+            return Ok(None);
+        }
+        let name = match packet.packet_content {
+            Some(ref content) if content.len() <= MAX_SESSION_NAME => String::from_utf8_lossy(content.as_slice()).into_owned(),
+            _ => return Ok(None)
+        };
+
+        let now = self.clock.now_ms();
+        if !self.sessions.contains_key(&name) {
+            //Whoever named a session is always its first peer
+            let named = self.sessions.values().filter(|session| session.peers[0].ip == src.ip).count();
+            if self.sessions.len() >= self.max_sessions || named >= self.sessions_per_address {
+                return Ok(None);
+            }
+            self.sessions.insert(name.clone(), Session { peers: vec![src], last_heard: now });
+        }
+        let (peers, newly_paired) = {
+            let session = self.sessions.get_mut(&name).unwrap();
+            let mut newly_paired = false;
+            if !session.peers.contains(&src) && session.peers.len() < 2 {
+                session.peers.push(src);
+                newly_paired = session.peers.len() == 2;
+            }
+            //Only the peers themselves can keep a session from expiring
+            if session.peers.contains(&src) {
+                session.last_heard = now;
+            }
+            (session.peers.clone(), newly_paired)
+        };
+
+        if peers.len() == 2 && peers.contains(&src) {
+            //Asking again is how a peer finds out its introduction went missing, so always answer both
+            for (i, peer) in peers.iter().enumerate() {
+                let other = peers[1 - i];
+                match Packet::introduction(self.protocol_id, write_addr(&other)).serialize() {
+                    Ok(introduction) => {
+                        match self.transport.send_to(introduction.as_slice(), *peer) {
+                            _ => ()
+                        }
+                    },
+                    Err(_) => ()
+                }
+            }
+        }
+        Ok(if newly_paired { Some(name) } else { None })
+    }
+
+    fn expire(&mut self) {
+        let now = self.clock.now_ms();
+        let expired: Vec<String> = self.sessions.iter()
+            .filter(|&(_, session)| session.last_heard + SESSION_EXPIRY_MS < now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in expired.iter() {
+            self.sessions.remove(name);
+        }
+    }
+
+    /**
+     * Change how many sessions may be remembered at once, and how many any one address may name
+     */
+    pub fn set_session_limits(&mut self, max_sessions: usize, per_address: usize) {
+        self.max_sessions = max_sessions;
+        self.sessions_per_address = per_address;
+    }
+
+    /**
+     * Measure session expiry against another clock
+     */
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+
+    /**
+     * How many sessions are waiting for, or have found, their peers
+     */
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+}
+
+fn now_ms() -> u64 {
+    precise_time_ns() / 1000000
+}
+
+/**
+ * Meet a peer through an introducer, and punch a way through to them
+ *
+ * The transport should be the one the game will then use, as it's that transport's route through
+ * the router which is opened up. Returns the peer's address, ready for Client::connect_with, or for
+ * the peer to connect to a Server on this transport.
+ */
+pub fn punch_through<N: Transport>(transport: &mut N, introducer_addr: SocketAddr, protocol_id: u32, session: &str, timeout: Duration) -> NetResult<SocketAddr> {
+    if session.len() > MAX_SESSION_NAME {
+        return Err(Error::PayloadTooLarge);
+    }
+    let introduce = try!(Packet::introduce(protocol_id, session).serialize().map_err(|_| Error::Serialization));
+    let punch = try!(Packet::punch(protocol_id, 0).serialize().map_err(|_| Error::Serialization));
+    let deadline = now_ms() + timeout.num_milliseconds() as u64;
+    let mut buf = [0; MAX_PACKET_SIZE];
+
+    let mut peer = None;
+    let mut heard_peer = false;
+    let mut extra_punches = 0;
+    let mut next_send = 0;
+
+    loop {
+        let now = now_ms();
+        if now >= deadline {
+            return Err(Error::TimedOut);
+        }
+
+        if now >= next_send {
+            match peer {
+                None => {
+                    try!(transport.send_to(introduce.as_slice(), introducer_addr));
+                    next_send = now + INTRODUCE_INTERVAL_MS;
+                },
+                Some(peer) => {
+                    try!(transport.send_to(punch.as_slice(), peer));
+                    next_send = now + PUNCH_INTERVAL_MS;
+                    if heard_peer {
+                        extra_punches += 1;
+                        if extra_punches >= EXTRA_PUNCHES {
+                            return Ok(peer);
+                        }
+                    }
+                }
+            }
+        }
+
+        transport.set_timeout(Some(if next_send > now { next_send - now } else { 1 }));
+        match transport.recv_from(&mut buf) {
+            Ok((amt, src)) => {
+                match Packet::deserialize(buf.slice_to(amt)) {
+                    Ok(ref packet) if packet.protocol_id == protocol_id => {
+                        match packet.packet_type {
+                            PacketType::Introduction if src == introducer_addr && peer.is_none() => {
+                                match packet.packet_content.as_ref().map(|content| read_addr(content.as_slice())) {
+                                    Some(Ok(addr)) => {
+                                        peer = Some(addr);
+                                        next_send = 0;
+                                    },
+                                    _ => ()
+                                }
+                            },
+                            //Anything else might be an impostor, so wait for our own introduction
+                            PacketType::Punch if peer == Some(src) => heard_peer = true,
+                            _ => ()
+                        }
+                    },
+                    _ => ()
+                }
+            },
+            Err(ref e) if e.kind == TimedOut => (),
+            Err(e) => return Err(Error::Io(e))
+        }
+    }
+}
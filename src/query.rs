@@ -0,0 +1,175 @@
+//!
+//! Asking a server about itself without joining, for server browsers
+//!
+//! A Server with a query handler answers `Query` packets from anyone with whatever the handler
+//! returns, such as the map name and player count, without treating the asker as a client.
+//!
+//! Since the asker's address can't be trusted, answers are limited so a server can't be used to
+//! flood someone else: each address, and the server as a whole, only gets so many answers a
+//! second, and no answer is more than `MAX_AMPLIFICATION` times the size of the query. Queries
+//! from `query_server` are padded so any answer which fits in a packet can be sent.
+//!
+use std::old_io::TimedOut;
+use std::old_io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::old_io::net::udp::UdpSocket;
+use std::collections::BTreeMap;
+use std::time::duration::Duration;
+use packet::{Packet, PacketType, MAX_PACKET_SIZE, HEADER_SIZE};
+use error::{Error, NetResult};
+use transport::Transport;
+use time::precise_time_ns;
+
+///An answer may be at most this many times larger than the query which asked for it
+pub const MAX_AMPLIFICATION: usize = 3;
+
+///Queries are padded to at least this size, so a full packet can be sent back
+pub const MIN_QUERY_SIZE: usize = (MAX_PACKET_SIZE + MAX_AMPLIFICATION - 1) / MAX_AMPLIFICATION;
+
+///How many queries a second are answered for any one address, by default
+pub const DEFAULT_QUERIES_PER_ADDRESS: u32 = 5;
+
+///How many queries a second are answered in total, by default
+pub const DEFAULT_QUERIES_TOTAL: u32 = 200;
+
+/**
+ * What a query handler gets told about the query it's answering
+ */
+pub struct QueryRequest {
+    ///Who asked. This can be forged, so shouldn't be trusted
+    pub from: SocketAddr,
+    ///How many clients are currently connected
+    pub connections: usize
+}
+
+/**
+ * Builds the answer to a query
+ */
+pub type QueryHandler = Box<FnMut(&QueryRequest) -> Vec<u8> + Send>;
+
+/**
+ * A server's answer to a query
+ */
+#[derive(Clone, Show, PartialEq)]
+pub struct ServerInfo {
+    ///Whatever the server's query handler returned
+    pub info: Vec<u8>,
+    ///How long the answer took, in milliseconds
+    pub ping_ms: u64
+}
+
+/**
+ * Counts how often each address has been answered in the current second
+ */
+pub struct RateLimiter {
+    per_address: u32,
+    total: u32,
+    window_start: u64,
+    total_count: u32,
+    counts: BTreeMap<String, u32>
+}
+
+fn ip_key(ip: &IpAddr) -> String {
+    //Ports are free to change, so only the address counts
+    match *ip {
+        Ipv4Addr(a, b, c, d) => format!("{}.{}.{}.{}", a, b, c, d),
+        Ipv6Addr(a, b, c, d, e, f, g, h) => format!("{}:{}:{}:{}:{}:{}:{}:{}", a, b, c, d, e, f, g, h)
+    }
+}
+
+impl RateLimiter {
+
+    /**
+     * Allow up to `per_address` answers a second to any one address, and `total` to everyone
+     */
+    pub fn new(per_address: u32, total: u32) -> RateLimiter {
+        RateLimiter {
+            per_address: per_address,
+            total: total,
+            window_start: 0,
+            total_count: 0,
+            counts: BTreeMap::new()
+        }
+    }
+
+    /**
+     * Should we answer this address at the given time, in milliseconds? Counts the answer if so
+     */
+    pub fn allow(&mut self, ip: &IpAddr, now_ms: u64) -> bool {
+        if now_ms >= self.window_start + 1000 {
+            self.window_start = now_ms;
+            self.total_count = 0;
+            self.counts.clear();
+        }
+        if self.total_count >= self.total {
+            return false;
+        }
+
+        let key = ip_key(ip);
+        let count = self.counts.get(&key).map_or(0, |count| *count);
+        if count >= self.per_address {
+            return false;
+        }
+        self.counts.insert(key, count + 1);
+        self.total_count += 1;
+        true
+    }
+}
+
+/**
+ * Should an answer of `reply_len` bytes be sent for a query of `query_len` bytes?
+ */
+pub fn within_amplification(query_len: usize, reply_len: usize) -> bool {
+    reply_len <= query_len * MAX_AMPLIFICATION && reply_len <= MAX_PACKET_SIZE
+}
+
+/**
+ * Ask a server about itself over UDP, without connecting
+ */
+pub fn query_server(addr: SocketAddr, protocol_id: u32, timeout: Duration) -> NetResult<ServerInfo> {
+    let local = match addr.ip {
+        Ipv4Addr(..) => SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 0 },
+        Ipv6Addr(..) => SocketAddr { ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0), port: 0 }
+    };
+    let socket = try!(UdpSocket::bind(local));
+    query_server_with(socket, addr, protocol_id, timeout)
+}
+
+/**
+ * Ask a server about itself over any Transport, without connecting
+ */
+pub fn query_server_with<N: Transport>(mut transport: N, addr: SocketAddr, protocol_id: u32, timeout: Duration) -> NetResult<ServerInfo> {
+    let sequence_id = (precise_time_ns() & 0xFFFF) as u16;
+    let query = try!(Packet::query(protocol_id, sequence_id, MIN_QUERY_SIZE - HEADER_SIZE).serialize().map_err(|_| Error::Serialization));
+    let sent_at = precise_time_ns() / 1000000;
+    let deadline = sent_at + timeout.num_milliseconds() as u64;
+    try!(transport.send_to(query.as_slice(), addr));
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+        let now = precise_time_ns() / 1000000;
+        if now >= deadline {
+            return Err(Error::TimedOut);
+        }
+        transport.set_timeout(Some(deadline - now));
+        match transport.recv_from(&mut buf) {
+            Ok((amt, src)) => {
+                if src != addr {
+                    continue;
+                }
+                match Packet::deserialize(buf.slice_to(amt)) {
+                    Ok(packet) => {
+                        if packet.protocol_id == protocol_id && packet.packet_type == PacketType::QueryReply && packet.sequence_id == sequence_id {
+                            return Ok(ServerInfo {
+                                info: packet.packet_content.unwrap_or(vec![]),
+                                ping_ms: precise_time_ns() / 1000000 - sent_at
+                            });
+                        }
+                    },
+                    Err(_) => ()
+                }
+            },
+            Err(ref e) if e.kind == TimedOut => return Err(Error::TimedOut),
+            Err(e) => return Err(Error::Io(e))
+        }
+    }
+}
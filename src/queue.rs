@@ -0,0 +1,417 @@
+//!
+//! Bounded queues between Clients or Servers and their workers
+//!
+//! Left unbounded, a stalled game loop lets everything arriving pile up in memory, as does a game
+//! sending faster than the network can carry. A `QueueLimit` caps a queue, and says what to do with
+//! anything that doesn't fit.
+//!
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::sync::mpsc::{Sender, Receiver, TryRecvError, RecvError, channel};
+use error::{Error, NetResult};
+
+/**
+ * What to do when something is queued and the queue is full
+ */
+#[derive(Clone, Copy, Show, PartialEq)]
+pub enum QueuePolicy {
+    ///Throw away whatever has been waiting longest, to make room
+    DropOldest,
+    ///Throw away whatever was just queued
+    DropNewest,
+    ///Wait for room. Anything which is pumped refuses this, as it would only ever be waiting on itself
+    Block,
+    ///Refuse, with a QueueFull error
+    Error
+}
+
+/**
+ * How many things a queue can hold, and what happens beyond that
+ */
+#[derive(Clone, Copy, Show, PartialEq)]
+pub struct QueueLimit {
+    ///At least one
+    pub capacity: usize,
+    pub policy: QueuePolicy
+}
+
+impl QueueLimit {
+
+    /**
+     * Create a new QueueLimit. A capacity of zero is treated as one
+     */
+    pub fn new(capacity: usize, policy: QueuePolicy) -> QueueLimit {
+        QueueLimit {
+            capacity: if capacity == 0 { 1 } else { capacity },
+            policy: policy
+        }
+    }
+}
+
+/**
+ * Refuse a queue set to block, for a Client or Server without threads of its own
+ *
+ * With nothing else to take from the queue, a full one would wait forever.
+ */
+pub fn refuse_block(limit: Option<QueueLimit>, queue_name: &str) -> NetResult<()> {
+    match limit {
+        Some(QueueLimit { policy: QueuePolicy::Block, .. }) => Err(Error::InvalidConfig(format!("The {} queue can't block without threads to make room", queue_name))),
+        _ => Ok(())
+    }
+}
+
+/**
+ * How full a Client or Server's queues are, and how much they've thrown away
+ */
+#[derive(Clone, Copy, Show, PartialEq)]
+pub struct QueueStats {
+    ///Datagrams which have arrived, waiting to be polled
+    pub inbound: usize,
+    ///Datagrams waiting to be sent
+    pub outbound: usize,
+    ///Datagrams which have arrived and been thrown away for lack of room
+    pub inbound_dropped: usize,
+    ///Datagrams which were to be sent and have been thrown away for lack of room
+    pub outbound_dropped: usize
+}
+
+/**
+ * Why something couldn't be queued
+ */
+#[derive(Clone, Copy, Show, PartialEq)]
+pub enum QueueSendError {
+    ///The queue is full, and its policy is to refuse
+    Full,
+    ///Nobody is receiving any more
+    Closed
+}
+
+struct Shared<T> {
+    receiver: Mutex<Receiver<T>>,
+    depth: AtomicUsize,
+    dropped: AtomicUsize,
+    closed: AtomicBool,
+    room_lock: Mutex<()>,
+    room: Condvar
+}
+
+impl <T> Shared<T> {
+
+    fn taken(&self) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+        //Taking the lock means a blocked sender can't miss this between checking and waiting
+        let _room = self.room_lock.lock().unwrap();
+        self.room.notify_all();
+    }
+}
+
+/**
+ * The sending end of a queue
+ */
+pub struct QueueSender<T> {
+    send: Sender<T>,
+    shared: Arc<Shared<T>>,
+    limit: Option<QueueLimit>
+}
+
+/**
+ * The receiving end of a queue
+ */
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>
+}
+
+/**
+ * Create a queue, unbounded unless given a limit
+ */
+pub fn queue<T: Send>(limit: Option<QueueLimit>) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (send, receive) = channel();
+    let shared = Arc::new(Shared {
+        receiver: Mutex::new(receive),
+        depth: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+        room_lock: Mutex::new(()),
+        room: Condvar::new()
+    });
+    (QueueSender { send: send, shared: shared.clone(), limit: limit }, QueueReceiver { shared: shared })
+}
+
+impl <T: Send> QueueSender<T> {
+
+    /**
+     * Queue something, following the queue's policy if it's full
+     *
+     * Anything dropped to make room, or dropped instead, still counts as queued.
+     */
+    pub fn send(&self, item: T) -> Result<(), QueueSendError> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return self.push(item)
+        };
+        if self.shared.depth.load(Ordering::SeqCst) < limit.capacity {
+            return self.push(item);
+        }
+
+        match limit.policy {
+            QueuePolicy::DropNewest => {
+                self.shared.dropped.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+            QueuePolicy::Error => Err(QueueSendError::Full),
+            QueuePolicy::DropOldest => {
+                {
+                    let receiver = self.shared.receiver.lock().unwrap();
+                    match receiver.try_recv() {
+                        Ok(_) => {
+                            self.shared.depth.fetch_sub(1, Ordering::SeqCst);
+                            self.shared.dropped.fetch_add(1, Ordering::SeqCst);
+                        },
+                        Err(_) => () //The receiver got there first
+                    }
+                }
+                self.push(item)
+            },
+            QueuePolicy::Block => {
+                {
+                    let mut room = self.shared.room_lock.lock().unwrap();
+                    while self.shared.depth.load(Ordering::SeqCst) >= limit.capacity {
+                        if self.shared.closed.load(Ordering::SeqCst) {
+                            return Err(QueueSendError::Closed);
+                        }
+                        room = self.shared.room.wait(room).unwrap();
+                    }
+                }
+                self.push(item)
+            }
+        }
+    }
+
+    fn push(&self, item: T) -> Result<(), QueueSendError> {
+        if self.shared.closed.load(Ordering::SeqCst) {
+            return Err(QueueSendError::Closed);
+        }
+        //Counted first, so the receiver can never take more than has been counted
+        self.shared.depth.fetch_add(1, Ordering::SeqCst);
+        match self.send.send(item) {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                self.shared.depth.fetch_sub(1, Ordering::SeqCst);
+                Err(QueueSendError::Closed)
+            }
+        }
+    }
+
+    /**
+     * How many things are waiting
+     */
+    pub fn depth(&self) -> usize {
+        self.shared.depth.load(Ordering::SeqCst)
+    }
+
+    /**
+     * How many things have been thrown away for lack of room
+     */
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::SeqCst)
+    }
+}
+
+impl <T: Send> Clone for QueueSender<T> {
+
+    fn clone(&self) -> QueueSender<T> {
+        QueueSender { send: self.send.clone(), shared: self.shared.clone(), limit: self.limit }
+    }
+}
+
+/**
+ * A sending end which can be handed to other threads, and closed for all of them at once
+ *
+ * Closing it lets the receiver see that nobody is sending any more, however many copies are still held.
+ */
+pub struct SharedSender<T> {
+    sender: Arc<Mutex<Option<QueueSender<T>>>>,
+    shared: Arc<Shared<T>>,
+    order: Arc<Order>
+}
+
+/**
+ * Hands out turns to queue, and says whose turn it is
+ */
+struct Order {
+    ///The next turn to hand out, and the turn now queueing
+    tickets: Mutex<(u64, u64)>,
+    next: Condvar
+}
+
+/**
+ * A place in line to queue through a SharedSender
+ *
+ * Turns queue in the order they were taken, whoever gets there first. Taking one while holding a
+ * lock, and queueing after letting go of it, keeps things in the order they were made under that
+ * lock without anyone else waiting on the queue to get at it.
+ */
+pub struct Turn<'a, T: 'a> {
+    sender: &'a SharedSender<T>,
+    ticket: u64
+}
+
+impl <T: Send> SharedSender<T> {
+
+    /**
+     * Share a sending end
+     */
+    pub fn new(sender: QueueSender<T>) -> SharedSender<T> {
+        SharedSender {
+            shared: sender.shared.clone(),
+            sender: Arc::new(Mutex::new(Some(sender))),
+            order: Arc::new(Order { tickets: Mutex::new((0, 0)), next: Condvar::new() })
+        }
+    }
+
+    /**
+     * Take the next place in line to queue
+     */
+    pub fn turn(&self) -> Turn<T> {
+        let mut tickets = self.order.tickets.lock().unwrap();
+        let ticket = tickets.0;
+        tickets.0 += 1;
+        Turn { sender: self, ticket: ticket }
+    }
+
+    /**
+     * Queue something, failing with Closed once closed
+     */
+    pub fn send(&self, item: T) -> Result<(), QueueSendError> {
+        //Sent from a copy, so a sender waiting for room doesn't stop anyone else getting at this one
+        let sender = match *self.sender.lock().unwrap() {
+            Some(ref sender) => sender.clone(),
+            None => return Err(QueueSendError::Closed)
+        };
+        sender.send(item)
+    }
+
+    /**
+     * Stop anything more being sent through this or any of its clones
+     */
+    pub fn close(&self) {
+        *self.sender.lock().unwrap() = None;
+    }
+
+    /**
+     * How many things are waiting
+     */
+    pub fn depth(&self) -> usize {
+        self.shared.depth.load(Ordering::SeqCst)
+    }
+
+    /**
+     * How many things have been thrown away for lack of room
+     */
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::SeqCst)
+    }
+}
+
+impl <T: Send> Clone for SharedSender<T> {
+
+    fn clone(&self) -> SharedSender<T> {
+        SharedSender { sender: self.sender.clone(), shared: self.shared.clone(), order: self.order.clone() }
+    }
+}
+
+impl <'a, T: Send> Turn<'a, T> {
+
+    /**
+     * Wait for every earlier turn to finish, then queue everything given, stopping at the first failure
+     */
+    pub fn send_all(self, items: Vec<T>) -> Result<(), QueueSendError> {
+        self.wait();
+        for item in items.into_iter() {
+            try!(self.sender.send(item));
+        }
+        Ok(())
+    }
+
+    fn wait(&self) {
+        let mut tickets = self.sender.order.tickets.lock().unwrap();
+        while tickets.1 != self.ticket {
+            tickets = self.sender.order.next.wait(tickets).unwrap();
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl <'a, T: Send> Drop for Turn<'a, T> {
+
+    fn drop(&mut self) {
+        //A turn given up without queueing still has to wait, or later turns could overtake earlier ones
+        self.wait();
+        self.sender.order.tickets.lock().unwrap().1 += 1;
+        self.sender.order.next.notify_all();
+    }
+}
+
+impl <T: Send> QueueReceiver<T> {
+
+    /**
+     * Take the next thing, if there is one
+     */
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let item = try!(self.shared.receiver.lock().unwrap().try_recv());
+        self.shared.taken();
+        Ok(item)
+    }
+
+    /**
+     * Wait for the next thing, failing once every sender has gone
+     */
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let item = try!(self.shared.receiver.lock().unwrap().recv());
+        self.shared.taken();
+        Ok(item)
+    }
+
+    /**
+     * Refuse anything more, and let go of any sender waiting for room, while still holding on to what's queued
+     */
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        let _room = self.shared.room_lock.lock().unwrap();
+        self.shared.room.notify_all();
+    }
+
+    /**
+     * The channel underneath, only for selecting on. Nothing should be taken from it directly
+     */
+    pub fn selectable(&self) -> MutexGuard<Receiver<T>> {
+        self.shared.receiver.lock().unwrap()
+    }
+
+    /**
+     * How many things are waiting
+     */
+    pub fn depth(&self) -> usize {
+        self.shared.depth.load(Ordering::SeqCst)
+    }
+
+    /**
+     * How many things have been thrown away for lack of room
+     */
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::SeqCst)
+    }
+}
+
+#[unsafe_destructor]
+impl <T: Send> Drop for QueueReceiver<T> {
+
+    fn drop(&mut self) {
+        //Senders share the channel with us, so it has to be swapped for a dead one for them to notice we've gone
+        let (_, dead) = channel();
+        ::std::mem::replace(&mut *self.shared.receiver.lock().unwrap(), dead);
+
+        //Anyone waiting for room would wait forever
+        self.close();
+    }
+}
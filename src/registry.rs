@@ -0,0 +1,194 @@
+//!
+//! Carry several distinct message types over a single connection
+//!
+//! Each message type implements `NetMessage`, giving it a numeric id and its own codec.
+//! Connections are then set up as `Client<Message>`/`Server<Message>`, and polling yields a
+//! `Message` envelope which can be matched on its id or downcast back into the original type.
+//!
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::old_io::BufReader;
+use std::old_io::net::ip::SocketAddr;
+use std::time::duration::Duration;
+use shared::ConnectionConfig;
+use client::Client;
+use server::Server;
+use error::{Error, NetResult};
+use transport::Transport;
+
+/**
+ * A message type which can share a connection with other message types
+ */
+pub trait NetMessage: Sized + Send + 'static {
+    /// A numeric id, unique among all the message types on a connection
+    fn message_id() -> u16;
+    /// Turn this message into raw data, or None if it can't be
+    fn encode(&self) -> Option<Vec<u8>>;
+    /// Turn raw data back into a message
+    fn decode(raw: &Vec<u8>) -> Option<Self>;
+}
+
+/**
+ * An encoded message of any registered type
+ */
+#[derive(Clone, PartialEq)]
+pub struct Message {
+    ///Which type of message this is
+    pub message_id: u16,
+    ///The encoded message
+    pub payload: Vec<u8>
+}
+
+impl Message {
+
+    /**
+     * Wrap up a message for sending, if it can be encoded
+     */
+    pub fn new<M: NetMessage>(message: &M) -> Option<Message> {
+        message.encode().map(|payload| Message {
+            message_id: <M as NetMessage>::message_id(),
+            payload: payload
+        })
+    }
+
+    /**
+     * Does this contain a message of the given type?
+     */
+    pub fn is<M: NetMessage>(&self) -> bool {
+        self.message_id == <M as NetMessage>::message_id()
+    }
+
+    /**
+     * Decode the message, if it's of the given type
+     */
+    pub fn downcast<M: NetMessage>(&self) -> Option<M> {
+        if self.is::<M>() {
+            <M as NetMessage>::decode(&self.payload)
+        } else {
+            None
+        }
+    }
+}
+
+/**
+ * Turn raw data into a Message envelope
+ */
+pub fn deserializer(message: &Vec<u8>) -> Option<Message> {
+    let mut r = BufReader::new(message.as_slice());
+    match (r.read_be_u16(), r.read_to_end()) {
+        (Ok(message_id), Ok(payload)) => Some(Message {
+            message_id: message_id,
+            payload: payload
+        }),
+        _ => None
+    }
+}
+
+/**
+ * Turn a Message envelope into raw data
+ */
+pub fn serializer(message: &Message) -> Option<Vec<u8>> {
+    let mut w = Vec::with_capacity(message.payload.len() + 2);
+    w.write_be_u16(message.message_id).ok().expect("Writing to a Vec can't fail");
+    w.push_all(message.payload.as_slice());
+    Some(w)
+}
+
+fn decode_any<M: NetMessage>(raw: &Vec<u8>) -> Option<Box<Any + Send>> {
+    <M as NetMessage>::decode(raw).map(|message| Box::new(message) as Box<Any + Send>)
+}
+
+/**
+ * Keeps track of which message types are in use, so incoming messages can be dispatched dynamically
+ */
+pub struct MessageRegistry {
+    decoders: BTreeMap<u16, fn(&Vec<u8>) -> Option<Box<Any + Send>>>
+}
+
+impl MessageRegistry {
+
+    /**
+     * Create an empty MessageRegistry
+     */
+    pub fn new() -> MessageRegistry {
+        MessageRegistry {
+            decoders: BTreeMap::new()
+        }
+    }
+
+    /**
+     * Register a message type
+     *
+     * This will return false if another type has already registered the same id
+     */
+    pub fn register<M: NetMessage>(&mut self) -> bool {
+        let message_id = <M as NetMessage>::message_id();
+        if self.decoders.contains_key(&message_id) {
+            false
+        } else {
+            self.decoders.insert(message_id, decode_any::<M>);
+            true
+        }
+    }
+
+    /**
+     * Has a message type with this id been registered?
+     */
+    pub fn is_registered(&self, message_id: u16) -> bool {
+        self.decoders.contains_key(&message_id)
+    }
+
+    /**
+     * Decode a message into whichever registered type it belongs to
+     *
+     * Returns None for unregistered or malformed messages
+     */
+    pub fn decode(&self, message: &Message) -> Option<Box<Any + Send>> {
+        match self.decoders.get(&message.message_id) {
+            Some(decoder) => (*decoder)(&message.payload),
+            None => None
+        }
+    }
+}
+
+impl ConnectionConfig <Message> {
+
+    /**
+     * Create a new ConnectionConfig object which carries registered message types
+     */
+    pub fn with_messages(protocol_id: u32, timeout_period: Duration) -> ConnectionConfig<Message> {
+        ConnectionConfig::new(protocol_id, timeout_period, deserializer, serializer)
+    }
+}
+
+impl <N: Transport> Client <Message, N> {
+
+    /**
+     * Send a message of any registered type to the server
+     */
+    pub fn send_message<M: NetMessage>(&mut self, message: &M) -> NetResult<()> {
+        let message = try!(Message::new(message).ok_or(Error::Serialization));
+        self.send(&message)
+    }
+}
+
+impl <N: Transport> Server <Message, N> {
+
+    /**
+     * Send a message of any registered type to a specific address
+     *
+     * This will fail with NotConnected if the given address isn't connected to us
+     */
+    pub fn send_message_to<M: NetMessage>(&mut self, message: &M, addr: &SocketAddr) -> NetResult<()> {
+        let message = try!(Message::new(message).ok_or(Error::Serialization));
+        self.send_to(&message, addr)
+    }
+
+    /**
+     * Send a message of any registered type to every connected client
+     */
+    pub fn send_message_to_all<M: NetMessage>(&mut self, message: &M) -> NetResult<()> {
+        let message = try!(Message::new(message).ok_or(Error::Serialization));
+        self.send_to_all(&message)
+    }
+}
@@ -0,0 +1,343 @@
+//!
+//! Relaying traffic for peers who can't reach each other, even after punching through
+//!
+//! Both peers wrap their transport in a `RelayTransport` with the same session token. Every datagram
+//! goes to the `Relay` with the token in front, and the relay passes it on to the other peer holding
+//! that token without looking inside. As far as the Client and Server are concerned, the relay's
+//! address is the peer they're talking to.
+//!
+//! The `relay` binary runs a `Relay` on its own. Anyone can open a session by sending a token, so a
+//! relay only holds so many sessions at once, and only so many opened from any one address.
+//!
+use std::old_io::{IoResult, TimedOut};
+use std::old_io::net::ip::SocketAddr;
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::{BufReader, BufWriter};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use packet::MAX_PACKET_SIZE;
+use error::{Error, NetResult};
+use transport::Transport;
+use clock::{SharedClock, system_clock};
+
+///The port relays listen on, unless another is chosen
+pub const RELAY_PORT: u16 = 27970;
+
+///Marks a datagram as meant for a relay
+pub const RELAY_MAGIC: u32 = 0x524C4159;
+
+///How many bytes the relay's wrapping adds to each datagram
+pub const RELAY_HEADER_SIZE: usize = 12;
+
+///How long a relay keeps a session nobody is using, in milliseconds
+pub const RELAY_IDLE_MS: u64 = 30000;
+
+///How many bytes a second a session may push through a relay, unless told otherwise
+pub const DEFAULT_SESSION_BANDWIDTH: usize = 64 * 1024;
+
+///How many sessions a relay holds at once, unless told otherwise
+pub const DEFAULT_MAX_SESSIONS: usize = 1024;
+
+///How many sessions any one address may open on a relay, unless told otherwise
+pub const DEFAULT_SESSIONS_PER_ADDRESS: usize = 4;
+
+///How long a RelayTransport stays quiet before reminding the relay it's still there, in milliseconds
+pub const KEEPALIVE_MS: u64 = 5000;
+
+fn wrap(token: u64, buf: &[u8]) -> IoResult<Vec<u8>> {
+    let mut wrapped = vec![0; RELAY_HEADER_SIZE + buf.len()];
+    {
+        let mut w = BufWriter::new(wrapped.as_mut_slice());
+        try!(w.write_be_u32(RELAY_MAGIC));
+        try!(w.write_be_u64(token));
+        try!(w.write(buf));
+    }
+    Ok(wrapped)
+}
+
+/**
+ * The session token a relayed datagram is for, if it is one
+ */
+fn unwrap_token(raw: &[u8]) -> Option<u64> {
+    if raw.len() < RELAY_HEADER_SIZE {
+        return None;
+    }
+    let mut r = BufReader::new(raw);
+    match (r.read_be_u32(), r.read_be_u64()) {
+        (Ok(RELAY_MAGIC), Ok(token)) => Some(token),
+        _ => None
+    }
+}
+
+struct RelaySession {
+    peers: Vec<SocketAddr>,
+    last_heard: u64,
+    window_start: u64,
+    window_bytes: usize
+}
+
+/**
+ * Passes datagrams between pairs of peers holding the same session token
+ */
+pub struct Relay <N: Transport = UdpSocket> {
+    transport: N,
+    clock: SharedClock,
+    bandwidth: usize,
+    max_sessions: usize,
+    sessions_per_address: usize,
+    sessions: BTreeMap<u64, RelaySession>,
+    dropped: u64
+}
+
+impl Relay<UdpSocket> {
+
+    /**
+     * Start relaying on a UDP address
+     */
+    pub fn bind(addr: SocketAddr) -> NetResult<Relay<UdpSocket>> {
+        let socket = try!(UdpSocket::bind(addr));
+        Ok(Relay::with_transport(socket))
+    }
+}
+
+impl <N: Transport> Relay<N> {
+
+    /**
+     * Start relaying on any Transport
+     */
+    pub fn with_transport(transport: N) -> Relay<N> {
+        Relay {
+            transport: transport,
+            clock: system_clock(),
+            bandwidth: DEFAULT_SESSION_BANDWIDTH,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            sessions_per_address: DEFAULT_SESSIONS_PER_ADDRESS,
+            sessions: BTreeMap::new(),
+            dropped: 0
+        }
+    }
+
+    /**
+     * Wait up to `timeout_ms` for a datagram, and pass it on. Returns any session which was just paired up
+     */
+    pub fn step(&mut self, timeout_ms: u64) -> NetResult<Option<u64>> {
+        self.expire();
+        let mut buf = [0; MAX_PACKET_SIZE + RELAY_HEADER_SIZE];
+        self.transport.set_timeout(Some(timeout_ms));
+        let (amt, src) = match self.transport.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind == TimedOut => return Ok(None),
+            Err(e) => return Err(Error::Io(e))
+        };
+        let token = match unwrap_token(buf.slice_to(amt)) {
+            Some(token) => token,
+            None => return Ok(None)
+        };
+
+        let now = self.clock.now_ms();
+        let bandwidth = self.bandwidth;
+        if !self.sessions.contains_key(&token) {
+            //Whoever opened a session is always its first peer
+            let opened = self.sessions.values().filter(|session| session.peers[0].ip == src.ip).count();
+            if self.sessions.len() >= self.max_sessions || opened >= self.sessions_per_address {
+                self.dropped += 1;
+                return Ok(None);
+            }
+            self.sessions.insert(token, RelaySession { peers: vec![src], last_heard: now, window_start: now, window_bytes: 0 });
+        }
+        let session = self.sessions.get_mut(&token).unwrap();
+
+        let mut newly_paired = false;
+        if !session.peers.contains(&src) {
+            if session.peers.len() >= 2 {
+                //Somebody else already holds this token, so they can't listen in
+                self.dropped += 1;
+                return Ok(None);
+            }
+            session.peers.push(src);
+            newly_paired = session.peers.len() == 2;
+        }
+        session.last_heard = now;
+
+        //Datagrams with nothing inside just keep the session open
+        if amt > RELAY_HEADER_SIZE && session.peers.len() == 2 {
+            if now >= session.window_start + 1000 {
+                session.window_start = now;
+                session.window_bytes = 0;
+            }
+            if session.window_bytes + amt > bandwidth {
+                self.dropped += 1;
+            } else {
+                session.window_bytes += amt;
+                let other = if session.peers[0] == src { session.peers[1] } else { session.peers[0] };
+                try!(self.transport.send_to(buf.slice_to(amt), other));
+            }
+        }
+        Ok(if newly_paired { Some(token) } else { None })
+    }
+
+    fn expire(&mut self) {
+        let now = self.clock.now_ms();
+        let expired: Vec<u64> = self.sessions.iter()
+            .filter(|&(_, session)| session.last_heard + RELAY_IDLE_MS < now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired.iter() {
+            self.sessions.remove(token);
+        }
+    }
+
+    /**
+     * Change how many bytes a second each session may send, counting both directions together
+     */
+    pub fn set_bandwidth(&mut self, bytes_per_second: usize) {
+        self.bandwidth = bytes_per_second;
+    }
+
+    /**
+     * Change how many sessions may be open at once, and how many any one address may open
+     */
+    pub fn set_session_limits(&mut self, max_sessions: usize, per_address: usize) {
+        self.max_sessions = max_sessions;
+        self.sessions_per_address = per_address;
+    }
+
+    /**
+     * Measure idle expiry and bandwidth against another clock
+     */
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+
+    /**
+     * How many sessions are waiting for, or have found, their peers
+     */
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /**
+     * How many datagrams have been thrown away, for going over a session's bandwidth, trying to join
+     * a session which already has its pair, or opening a session past the limits
+     */
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/**
+ * A Transport which sends everything by way of a relay
+ *
+ * Whatever address is given to `send_to`, the datagram goes to the relay, and everything received
+ * appears to come from the relay. Clients should connect to the relay's address.
+ */
+pub struct RelayTransport <N: Transport = UdpSocket> {
+    transport: N,
+    relay_addr: SocketAddr,
+    token: u64,
+    clock: SharedClock,
+    last_sent: Arc<Mutex<u64>>
+}
+
+impl <N: Transport> RelayTransport<N> {
+
+    /**
+     * Wrap a transport, and let the relay know we're here
+     */
+    pub fn new(transport: N, relay_addr: SocketAddr, token: u64) -> IoResult<RelayTransport<N>> {
+        RelayTransport::with_clock(transport, relay_addr, token, system_clock())
+    }
+
+    /**
+     * Wrap a transport, timing keepalives against the given clock
+     */
+    pub fn with_clock(transport: N, relay_addr: SocketAddr, token: u64, clock: SharedClock) -> IoResult<RelayTransport<N>> {
+        let mut relayed = RelayTransport {
+            transport: transport,
+            relay_addr: relay_addr,
+            token: token,
+            clock: clock,
+            last_sent: Arc::new(Mutex::new(0))
+        };
+        try!(relayed.send_raw(&[]));
+        Ok(relayed)
+    }
+
+    /**
+     * The address of the relay everything goes through
+     */
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    fn send_raw(&mut self, buf: &[u8]) -> IoResult<()> {
+        let wrapped = try!(wrap(self.token, buf));
+        try!(self.transport.send_to(wrapped.as_slice(), self.relay_addr));
+        *self.last_sent.lock().unwrap() = self.clock.now_ms();
+        Ok(())
+    }
+
+    /**
+     * Remind the relay we're here, if we've been quiet for a while
+     */
+    fn keep_alive(&mut self) {
+        let due = *self.last_sent.lock().unwrap() + KEEPALIVE_MS <= self.clock.now_ms();
+        if due {
+            match self.send_raw(&[]) {
+                _ => () //The next call will try again
+            }
+        }
+    }
+}
+
+impl <N: Transport> Transport for RelayTransport<N> {
+    fn send_to(&mut self, buf: &[u8], _addr: SocketAddr) -> IoResult<()> {
+        self.send_raw(buf)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        let mut raw = [0; MAX_PACKET_SIZE + RELAY_HEADER_SIZE];
+        loop {
+            //A busy peer sending nothing back would otherwise never get a keepalive out
+            self.keep_alive();
+            let (amt, src) = match self.transport.recv_from(&mut raw) {
+                Ok(received) => received,
+                Err(e) => return Err(e)
+            };
+            if amt == 0 {
+                return Ok((0, src));
+            }
+            if src != self.relay_addr || unwrap_token(raw.slice_to(amt)) != Some(self.token) || amt == RELAY_HEADER_SIZE {
+                continue;
+            }
+            let inner = raw.slice(RELAY_HEADER_SIZE, amt);
+            let len = if inner.len() < buf.len() { inner.len() } else { buf.len() };
+            for (to, from) in buf.iter_mut().zip(inner.iter()) {
+                *to = *from;
+            }
+            return Ok((len, self.relay_addr));
+        }
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.transport.set_timeout(timeout_ms);
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    fn try_clone(&self) -> IoResult<RelayTransport<N>> {
+        Ok(RelayTransport {
+            transport: try!(self.transport.try_clone()),
+            relay_addr: self.relay_addr,
+            token: self.token,
+            clock: self.clock.clone(),
+            last_sent: self.last_sent.clone()
+        })
+    }
+
+    fn is_stream(&self) -> bool {
+        self.transport.is_stream()
+    }
+}
@@ -0,0 +1,307 @@
+//!
+//! Handing out what clients sent, fairly
+//!
+//! Taking everything in arrival order lets one chatty client push everyone else to the back of a frame.
+//! A `Scheduler` keeps a backlog for each client and takes turns between them, optionally capping how
+//! much any one client gets each frame. A frame ends whenever there's nothing left to hand out.
+//!
+//! Anything pushed as control, such as a client connecting or leaving, is never dropped to make room.
+//!
+//! Nothing waits on a Scheduler to make room, so when a backlog is full the Block policy takes things in
+//! anyway and says it's `blocked`, leaving whoever feeds it to hold off until it isn't.
+//!
+use std::old_io::net::ip::SocketAddr;
+use std::collections::RingBuf;
+use queue::{QueueLimit, QueuePolicy, QueueSendError};
+
+/**
+ * In what order to hand out what clients have sent
+ */
+#[derive(Clone, Copy, Show, PartialEq)]
+pub enum Scheduling {
+    ///Everything in the order it arrived, however many clients it came from
+    Arrival,
+    ///One from each client in turn
+    RoundRobin,
+    ///In turn, but as many from each client as its priority
+    Weighted
+}
+
+struct Queued<T> {
+    arrival: u64,
+    control: bool,
+    item: T
+}
+
+struct Backlog<T> {
+    addr: SocketAddr,
+    items: RingBuf<Queued<T>>,
+    ///How many items could be dropped to make room, which is everything but control
+    droppable: usize,
+    served: usize
+}
+
+/**
+ * A backlog for each client, and whose turn it is
+ */
+pub struct Scheduler<T> {
+    scheduling: Scheduling,
+    frame_cap: Option<usize>,
+    limit: Option<QueueLimit>,
+    backlogs: Vec<Backlog<T>>,
+    priorities: Vec<(SocketAddr, usize)>,
+    ///Whose turn it is, kept by address as backlogs come and go
+    turn: Option<SocketAddr>,
+    turns_left: usize,
+    arrivals: u64,
+    dropped: usize
+}
+
+impl <T> Scheduler<T> {
+
+    /**
+     * Create a Scheduler handing things out in arrival order, with each client's backlog limited if given a limit
+     */
+    pub fn new(limit: Option<QueueLimit>) -> Scheduler<T> {
+        Scheduler {
+            scheduling: Scheduling::Arrival,
+            frame_cap: None,
+            limit: limit,
+            backlogs: vec![],
+            priorities: vec![],
+            turn: None,
+            turns_left: 0,
+            arrivals: 0,
+            dropped: 0
+        }
+    }
+
+    /**
+     * Change the order things are handed out in
+     */
+    pub fn set_scheduling(&mut self, scheduling: Scheduling) {
+        self.scheduling = scheduling;
+    }
+
+    /**
+     * Cap how much any one client gets each frame, or None for no cap
+     */
+    pub fn set_frame_cap(&mut self, cap: Option<usize>) {
+        self.frame_cap = cap;
+    }
+
+    /**
+     * How many turns in a row a client gets when Weighted. Everyone starts at 1, and 0 is treated as 1
+     */
+    pub fn set_priority(&mut self, addr: SocketAddr, priority: usize) {
+        self.forget(addr);
+        if priority > 1 {
+            self.priorities.push((addr, priority));
+        }
+    }
+
+    /**
+     * Drop a client's priority, such as once they've gone. Anything in their backlog is still handed out
+     */
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.priorities.retain(|&(ref known, _)| *known != addr);
+    }
+
+    fn priority(&self, addr: SocketAddr) -> usize {
+        match self.scheduling {
+            Scheduling::Weighted => {
+                self.priorities.iter().find(|&&(ref known, _)| *known == addr).map(|&(_, priority)| priority).unwrap_or(1)
+            },
+            _ => 1
+        }
+    }
+
+    /**
+     * Add to a client's backlog, following the limit's policy if it's full
+     *
+     * Fails with Full, keeping nothing, if the policy is Error.
+     */
+    pub fn push(&mut self, addr: SocketAddr, item: T) -> Result<(), QueueSendError> {
+        self.queue(addr, item, false)
+    }
+
+    /**
+     * Add to a client's backlog regardless of the limit, for things which mustn't be lost
+     */
+    pub fn push_control(&mut self, addr: SocketAddr, item: T) {
+        match self.queue(addr, item, true) {
+            _ => () //Control is never refused
+        }
+    }
+
+    fn position(&self, addr: SocketAddr) -> Option<usize> {
+        self.backlogs.iter().position(|backlog| backlog.addr == addr)
+    }
+
+    fn queue(&mut self, addr: SocketAddr, item: T, control: bool) -> Result<(), QueueSendError> {
+        let index = match self.position(addr) {
+            Some(index) => index,
+            None => {
+                self.backlogs.push(Backlog { addr: addr, items: RingBuf::new(), droppable: 0, served: 0 });
+                self.backlogs.len() - 1
+            }
+        };
+        let backlog = &mut self.backlogs[index];
+        match self.limit {
+            Some(limit) if !control && backlog.droppable >= limit.capacity => {
+                match limit.policy {
+                    QueuePolicy::DropOldest => {
+                        let oldest = backlog.items.iter().position(|queued| !queued.control);
+                        match oldest {
+                            Some(oldest) => {
+                                backlog.items.remove(oldest);
+                                backlog.droppable -= 1;
+                                self.dropped += 1;
+                            },
+                            None => ()
+                        }
+                    },
+                    QueuePolicy::DropNewest => {
+                        self.dropped += 1;
+                        return Ok(());
+                    },
+                    QueuePolicy::Error => return Err(QueueSendError::Full),
+                    //Taken in anyway, with whoever feeds us expected to hold off while we're blocked
+                    QueuePolicy::Block => ()
+                }
+            },
+            _ => ()
+        }
+        if !control {
+            backlog.droppable += 1;
+        }
+        backlog.items.push_back(Queued { arrival: self.arrivals, control: control, item: item });
+        self.arrivals += 1;
+        Ok(())
+    }
+
+    /**
+     * Whether anything more should wait, because the limit's policy is Block and some client's backlog is full
+     */
+    pub fn blocked(&self) -> bool {
+        match self.limit {
+            Some(QueueLimit { capacity, policy: QueuePolicy::Block }) => {
+                self.backlogs.iter().any(|backlog| backlog.droppable >= capacity)
+            },
+            _ => false
+        }
+    }
+
+    fn ready(&self, index: usize) -> bool {
+        let backlog = &self.backlogs[index];
+        backlog.items.len() > 0 && match self.frame_cap {
+            Some(cap) => backlog.served < cap,
+            None => true
+        }
+    }
+
+    fn take(&mut self, index: usize) -> Option<(SocketAddr, T)> {
+        let backlog = &mut self.backlogs[index];
+        let addr = backlog.addr;
+        backlog.served += 1;
+        match backlog.items.pop_front() {
+            Some(queued) => {
+                if !queued.control {
+                    backlog.droppable -= 1;
+                }
+                Some((addr, queued.item))
+            },
+            None => None
+        }
+    }
+
+    /**
+     * Whatever is next, and who it came from
+     *
+     * Returns None once nothing is left that can be handed out this frame, which also starts the next frame.
+     */
+    pub fn next(&mut self) -> Option<(SocketAddr, T)> {
+        let count = self.backlogs.len();
+        let next = match self.scheduling {
+            _ if count == 0 => None,
+            Scheduling::Arrival => {
+                (0..count).filter(|&index| self.ready(index))
+                    .min_by(|&index| self.backlogs[index].items.front().map(|queued| queued.arrival))
+            },
+            _ => {
+                let (turn, turns_left) = (self.turn, self.turns_left);
+                let current = turn.and_then(|addr| self.position(addr));
+                let mut index = match current {
+                    Some(index) => index,
+                    None => {
+                        self.turns_left = self.priority(self.backlogs[0].addr);
+                        0
+                    }
+                };
+                let mut next = None;
+                //Going all the way round, and back to where we started, covers everyone
+                for _ in (0..count + 1) {
+                    if self.turns_left > 0 && self.ready(index) {
+                        self.turns_left -= 1;
+                        next = Some(index);
+                        break;
+                    }
+                    index = (index + 1) % count;
+                    self.turns_left = self.priority(self.backlogs[index].addr);
+                }
+                match next {
+                    Some(index) => self.turn = Some(self.backlogs[index].addr),
+                    //Everyone is done for the frame, so whoever's turn it was carries on next frame
+                    None => {
+                        self.turn = turn;
+                        self.turns_left = turns_left;
+                    }
+                }
+                next
+            }
+        };
+
+        match next {
+            Some(index) => self.take(index),
+            None => {
+                self.end_frame();
+                None
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        for backlog in self.backlogs.iter_mut() {
+            backlog.served = 0;
+        }
+        //If whoever's turn it was has nothing left, the turn goes to the next client who does
+        let current = self.turn.and_then(|addr| self.position(addr));
+        match current {
+            Some(index) if self.backlogs[index].items.len() == 0 => {
+                let count = self.backlogs.len();
+                let waiting = (1..count).map(|step| (index + step) % count).find(|&other| self.backlogs[other].items.len() > 0);
+                self.turn = waiting.map(|other| self.backlogs[other].addr);
+                self.turns_left = match self.turn {
+                    Some(addr) => self.priority(addr),
+                    None => 0
+                };
+            },
+            _ => ()
+        }
+        self.backlogs.retain(|backlog| backlog.items.len() > 0);
+    }
+
+    /**
+     * How much is waiting, across every client
+     */
+    pub fn depth(&self) -> usize {
+        self.backlogs.iter().map(|backlog| backlog.items.len()).fold(0, |total, len| total + len)
+    }
+
+    /**
+     * How much has been thrown away for lack of room
+     */
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
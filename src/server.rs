@@ -1,46 +1,26 @@
 use std::old_io::net::udp::UdpSocket;
-use std::old_io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
-use std::old_io::{IoResult, TimedOut};
+use std::old_io::net::ip::{SocketAddr, Ipv4Addr};
+use std::old_io::{TimedOut, EndOfFile};
 use std::sync::mpsc::{Sender, Receiver, TryRecvError, channel, Select};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::Thread;
-use std::collections::BTreeMap;
-use packet::{Packet, PacketType, TaskCommand};
-use shared::{ConnectionConfig, SequenceManager};
-use time::now;
-
-
-//FIXME: Ew ew ew - there must be a nicer way of hashing
-fn hash_sender(address: &SocketAddr) -> String {
-    match address.ip {
-        Ipv4Addr(a, b, c, d) => {
-            format!("{}-{}-{}-{}:{}", a, b, c, d, address.port)
-        },
-        Ipv6Addr(a, b, c, d, e, f, g, h) => {
-            format!("{}-{}-{}-{}-{}-{}-{}-{}:{}", a, b, c, d, e, f, g, h, address.port)
-        }
-    }
-}
+use packet::{PacketType, TaskCommand, MAX_PACKET_SIZE};
+use shared::ConnectionConfig;
+use error::{Error, NetResult};
+use transport::Transport;
+use conditioner::LinkConditioner;
+use multi::MultiTransport;
+use relay::RelayTransport;
+use discovery::{Responder, DISCOVERY_PORT};
+use query::{QueryHandler, QueryRequest, RateLimiter, DEFAULT_QUERIES_PER_ADDRESS, DEFAULT_QUERIES_TOTAL};
+use clock::SharedClock;
+use protocol::{ServerProtocol, ServerEvent, Datagram};
+use queue::{QueueSender, QueueReceiver, QueueSendError, QueueStats, SharedSender, queue, refuse_block};
+use schedule::{Scheduler, Scheduling};
+use worker::{WorkerStep, Waker, wait_for, woken, deadline_after, conditioned, READ_TIMEOUT_MS, WAKE_CHECK_MS};
+use time::precise_time_ns;
+use std::time::duration::Duration;
 
-#[derive(Clone)]
-struct ClientInstance {
-    addr: SocketAddr,
-    timeout: i64,
-    sequence_manager: SequenceManager
-}
-
-impl ClientInstance {
-    pub fn new(addr: SocketAddr, timeout: i64) -> ClientInstance {
-        ClientInstance {
-            addr: addr,
-            timeout: timeout,
-            sequence_manager: SequenceManager::new()
-        }
-    }
-
-    pub fn next_sequence_id(&mut self) -> u16 {
-        self.sequence_manager.next_sequence_id()
-    }
-}
 
 /**
  * Types of packet we can receive as a server
@@ -49,241 +29,806 @@ pub enum PacketOrCommand <T> {
     ///A message packet, containing whichever type we're set up to handle
     UserPacket(T),
     ///An internal control packet
-    Command(PacketType)
+    Command(PacketType),
+    ///Something went wrong in the background, such as a failed send to the given address
+    Failed(Error)
 }
 
-fn reader_process(mut reader: UdpSocket, reader_sub_out: Sender<(Packet, SocketAddr)>, reader_sub_in: Receiver<TaskCommand>, protocol_id: u32) {
-    let mut buf = [0; 1024];
-    reader.set_timeout(Some(1000));
-    loop {
-        match reader.recv_from(&mut buf) {
+/**
+ * The receiving half of a server, run on its own thread or pumped by hand
+ *
+ * It only moves datagrams, stamped with when they arrived. Making sense of them is up to the protocol.
+ */
+struct Reader<N> {
+    transport: N,
+    out: QueueSender<(Vec<u8>, SocketAddr, u64)>,
+    errors: Sender<(Error, SocketAddr)>,
+    commands: Receiver<TaskCommand>,
+    ///Hung up when this worker goes, however it goes
+    finished: Sender<()>,
+    clock: SharedClock,
+    local_addr: SocketAddr
+}
+
+impl <N: Transport> Reader<N> {
+
+    /**
+     * Pass on a single datagram, or notice there's nothing to pass on
+     */
+    fn step(&mut self) -> WorkerStep {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        match self.transport.recv_from(&mut buf) {
+            //An empty datagram only means a hang up on a stream. From anything else it could be forged
+            Ok((0, _)) if !self.transport.is_stream() => WorkerStep::Busy,
             Ok((amt, src)) => {
-                match Packet::deserialize(buf.slice_to(amt)) {
-                    Ok(packet) => {
-                        if packet.protocol_id == protocol_id {
-                            reader_sub_out.send((packet, src));
+                match self.out.send((buf.slice_to(amt).to_vec(), src, self.clock.now_ms())) {
+                    Ok(()) => WorkerStep::Busy,
+                    Err(QueueSendError::Full) => {
+                        match self.errors.send((Error::QueueFull, self.local_addr)) {
+                            Ok(()) => WorkerStep::Busy,
+                            Err(_) => WorkerStep::Stop
                         }
                     },
-                    Err(_) => ()
+                    Err(QueueSendError::Closed) => WorkerStep::Stop
                 }
             },
             Err(e) => {
                 match e.kind {
                     TimedOut => {
-                        match reader_sub_in.try_recv() {
-                            Ok(TaskCommand::Disconnect) => {
-                                break;
-                            },
-                            Err(TryRecvError::Disconnected) => {
-                                break;
-                            },
-                            Err(TryRecvError::Empty) => ()
+                        match self.commands.try_recv() {
+                            Ok(TaskCommand::Disconnect) => WorkerStep::Stop,
+                            Err(TryRecvError::Disconnected) => WorkerStep::Stop,
+                            Err(TryRecvError::Empty) => WorkerStep::Idle
                         }
                     },
-                    _ => ()
+                    //The transport has closed, so nothing more can ever arrive
+                    EndOfFile => WorkerStep::Stop,
+                    _ => {
+                        match self.errors.send((Error::Io(e), self.local_addr)) {
+                            Ok(()) => WorkerStep::Idle,
+                            Err(_) => WorkerStep::Stop
+                        }
+                    }
                 }
             }
         }
     }
 }
 
-fn writer_process(mut writer: UdpSocket, _writer_sub_out: Sender<TaskCommand>, writer_sub_in: Receiver<(Packet, SocketAddr)>) {
-    for (msg, target_addr) in writer_sub_in.iter() {
-        match msg.serialize() {
-            Ok(msg) => {
-                match writer.send_to(msg.as_slice(), target_addr) {
-                    Ok(()) => (),
-                    Err(e) => println!("Error sending data - {}", e)
-                }
-            },
-            Err(_) => ()
+fn reader_process<N: Transport>(mut reader: Reader<N>) {
+    reader.transport.set_timeout(Some(READ_TIMEOUT_MS));
+    while reader.step() != WorkerStep::Stop {}
+}
+
+/**
+ * The sending half of a server, run on its own thread or pumped by hand
+ */
+struct Writer<N> {
+    transport: N,
+    errors: Sender<(Error, SocketAddr)>,
+    recv: QueueReceiver<Datagram>,
+    finished: Sender<()>
+}
+
+impl <N: Transport> Writer<N> {
+
+    /**
+     * Put a datagram on the wire, returning false if nobody is listening for our errors any more
+     */
+    fn write(&mut self, (data, target_addr): Datagram) -> bool {
+        match self.transport.send_to(data.as_slice(), target_addr) {
+            Ok(()) => true,
+            Err(e) => self.errors.send((Error::Io(e), target_addr)).is_ok()
+        }
+    }
+
+    /**
+     * Send a single queued datagram, if there is one
+     */
+    fn step(&mut self) -> WorkerStep {
+        match self.recv.try_recv() {
+            Ok(datagram) => if self.write(datagram) { WorkerStep::Busy } else { WorkerStep::Stop },
+            Err(TryRecvError::Empty) => WorkerStep::Idle,
+            Err(TryRecvError::Disconnected) => WorkerStep::Stop
+        }
+    }
+}
+
+fn writer_process<N: Transport>(mut writer: Writer<N>) {
+    loop {
+        match writer.recv.recv() {
+            Ok(datagram) => if !writer.write(datagram) { break },
+            Err(_) => break
         }
     }
 }
 
 /**
- * A UDP server, which manages multiple clients
+ * A server which manages multiple clients, over UDP unless another Transport is given
+ *
+ * This drives a `ServerProtocol`, which keeps track of the clients themselves.
  */
-pub struct Server <T> {
+pub struct Server <T, N: Transport = UdpSocket> {
     ///Which address to listen on
     pub addr: SocketAddr,
     ///Basic configuration for the server
     pub config: ConnectionConfig<T>,
 
     reader_send: Sender<TaskCommand>,
-    reader_receive: Receiver<(Packet, SocketAddr)>,
-    writer_send: Sender<(Packet, SocketAddr)>,
+    reader_receive: QueueReceiver<(Vec<u8>, SocketAddr, u64)>,
+    writer_send: SharedSender<Datagram>,
+    error_receive: Receiver<(Error, SocketAddr)>,
+    reader_died: bool,
+    thread_died_reported: bool,
+    transport: N,
+    pumped_reader: Option<Reader<LinkConditioner<N>>>,
+    pumped_writer: Option<Writer<LinkConditioner<N>>>,
+    discovery: Option<Responder>,
+    query_handler: Option<QueryHandler>,
+    query_limiter: RateLimiter,
+    waker: Waker,
+    wakes: Receiver<()>,
+    finished: Receiver<()>,
+    shut_down: bool,
+    inbound: Scheduler<ServerEvent>,
 
-    connections: BTreeMap<String, ClientInstance>
+    protocol: Arc<Mutex<ServerProtocol>>
 }
 
-impl <T> Server <T> {
+impl <T> Server <T, UdpSocket> {
     /**
      * Start listening on a given socket
      */
-    pub fn new(addr: SocketAddr, config: ConnectionConfig<T>) -> IoResult<Server<T>> {
-        match UdpSocket::bind(addr) {
-            Ok(reader) => {
-                let writer = reader.clone();
-                let (reader_out, reader_sub_in) = channel();
-                let (reader_sub_out, reader_in) = channel();
-
-                let protocol_id = config.protocol_id;
-
-                Thread::spawn(move || {
-                    reader_process(reader, reader_sub_out, reader_sub_in, protocol_id);
-                });
-
-                let (writer_out, writer_sub_in) = channel();
-                let (writer_sub_out, _) = channel();
-
-                Thread::spawn(move || {
-                    writer_process(writer, writer_sub_out, writer_sub_in);
-                });
-                
-                Ok(Server {
-                    addr: addr,
-                    config: config,
-                    reader_send: reader_out,
-                    reader_receive: reader_in,
-                    writer_send: writer_out,
-                    connections: BTreeMap::new()
-                })
-            }
-            Err(e) => Err(e)
+    pub fn new(addr: SocketAddr, config: ConnectionConfig<T>) -> NetResult<Server<T, UdpSocket>> {
+        let socket = try!(UdpSocket::bind(addr));
+        Server::with_transport(socket, config)
+    }
+
+    /**
+     * Start listening on a given socket, without any background threads.
+     *
+     * The socket never blocks, and nothing is sent or received until `pump` is called, so a game
+     * loop can do all of its networking on its own thread, at a time of its choosing.
+     */
+    pub fn new_nonblocking(addr: SocketAddr, config: ConnectionConfig<T>) -> NetResult<Server<T, UdpSocket>> {
+        let socket = try!(UdpSocket::bind(addr));
+        Server::with_transport_unthreaded(socket, config)
+    }
+}
+
+impl <T> Server <T, MultiTransport<UdpSocket>> {
+    /**
+     * Start listening on several sockets at once, such as 0.0.0.0 and ::, with one set of clients
+     *
+     * Some systems also deliver IPv4 to a socket bound to ::, in which case binding both on the
+     * same port will fail as already in use.
+     */
+    pub fn bind_all(addrs: &[SocketAddr], config: ConnectionConfig<T>) -> NetResult<Server<T, MultiTransport<UdpSocket>>> {
+        let mut sockets = vec![];
+        for addr in addrs.iter() {
+            sockets.push(try!(UdpSocket::bind(*addr)));
+        }
+        let transport = try!(MultiTransport::join_with_clock(sockets, config.clock.clone()));
+        Server::with_transport(transport, config)
+    }
+}
+
+impl <T> Server <T, RelayTransport<UdpSocket>> {
+    /**
+     * Host through a relay, for a client which connects with the same session token
+     */
+    pub fn via_relay(addr: SocketAddr, relay_addr: SocketAddr, token: u64, config: ConnectionConfig<T>) -> NetResult<Server<T, RelayTransport<UdpSocket>>> {
+        let socket = try!(UdpSocket::bind(addr));
+        let transport = try!(RelayTransport::with_clock(socket, relay_addr, token, config.clock.clone()));
+        Server::with_transport(transport, config)
+    }
+}
+
+impl <T, N: Transport> Server <T, MultiTransport<N>> {
+    /**
+     * Every address we're listening on
+     */
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.transport.local_addrs()
+    }
+}
+
+impl <T, N: Transport> Server <T, N> {
+    /**
+     * Start listening on any Transport
+     */
+    pub fn with_transport(transport: N, config: ConnectionConfig<T>) -> NetResult<Server<T, N>> {
+        let (server, reader, writer) = try!(Server::build(transport, config));
+
+        Thread::spawn(move || {
+            reader_process(reader);
+        });
+
+        Thread::spawn(move || {
+            writer_process(writer);
+        });
+
+        Ok(server)
+    }
+
+    /**
+     * Start listening on any Transport, without any background threads.
+     *
+     * Nothing is sent or received until `pump` is called. Neither queue may be set to block.
+     */
+    pub fn with_transport_unthreaded(transport: N, config: ConnectionConfig<T>) -> NetResult<Server<T, N>> {
+        try!(refuse_block(config.inbound_queue, "inbound"));
+        try!(refuse_block(config.outbound_queue, "outbound"));
+        let (mut server, mut reader, writer) = try!(Server::build(transport, config));
+        reader.transport.set_timeout(Some(0));
+        server.pumped_reader = Some(reader);
+        server.pumped_writer = Some(writer);
+        Ok(server)
+    }
+
+    /**
+     * Set up a Server and the workers which will carry its traffic
+     */
+    fn build(mut transport: N, config: ConnectionConfig<T>) -> NetResult<(Server<T, N>, Reader<LinkConditioner<N>>, Writer<LinkConditioner<N>>)> {
+        let addr = try!(transport.local_addr());
+        let (reader_out, reader_sub_in) = channel();
+        let (reader_sub_out, reader_in) = queue(config.inbound_queue);
+        let (writer_sub_out, error_in) = channel();
+        let (writer_out, writer_sub_in) = queue(config.outbound_queue);
+        let (waker, wakes) = Waker::new();
+        let (finished_send, finished) = channel();
+
+        let reader = Reader {
+            transport: try!(conditioned(&transport, &config, &finished_send)),
+            out: reader_sub_out,
+            errors: writer_sub_out.clone(),
+            commands: reader_sub_in,
+            finished: finished_send.clone(),
+            clock: config.clock.clone(),
+            local_addr: addr
+        };
+
+        let writer = Writer {
+            transport: try!(conditioned(&transport, &config, &finished_send)),
+            errors: writer_sub_out,
+            recv: writer_sub_in,
+            finished: finished_send
+        };
+
+        let server = Server {
+            addr: addr,
+            reader_send: reader_out,
+            reader_receive: reader_in,
+            writer_send: SharedSender::new(writer_out),
+            error_receive: error_in,
+            reader_died: false,
+            thread_died_reported: false,
+            transport: transport,
+            pumped_reader: None,
+            pumped_writer: None,
+            discovery: None,
+            query_handler: None,
+            query_limiter: RateLimiter::new(DEFAULT_QUERIES_PER_ADDRESS, DEFAULT_QUERIES_TOTAL),
+            waker: waker,
+            wakes: wakes,
+            finished: finished,
+            shut_down: false,
+            inbound: Scheduler::new(config.inbound_queue),
+            protocol: Arc::new(Mutex::new(ServerProtocol::new(config.protocol_id, config.timeout_period))),
+            config: config
+        };
+        Ok((server, reader, writer))
+    }
+
+    /**
+     * Do any waiting network work on the calling thread, for Servers made with with_transport_unthreaded
+     * or new_nonblocking. Servers with their own threads have nothing to do here.
+     *
+     * Everything queued is sent, and everything which has arrived is read, ready for `poll`.
+     */
+    pub fn pump(&mut self) {
+        self.pump_writer();
+        let stopped = match self.pumped_reader {
+            Some(ref mut reader) => {
+                let mut step = reader.step();
+                while step == WorkerStep::Busy {
+                    step = reader.step();
+                }
+                step == WorkerStep::Stop
+            },
+            None => false
+        };
+        if stopped {
+            //Just as if a reader thread had finished
+            self.pumped_reader = None;
+        }
+    }
+
+    fn pump_writer(&mut self) {
+        match self.pumped_writer {
+            Some(ref mut writer) => {
+                while writer.step() == WorkerStep::Busy {}
+            },
+            None => ()
         }
     }
 
     /**
      * Pump any messages that have been sent to us
      *
-     * Note that this internally accepts connections and disconnects, with the original packets being returned
+     * Note that this internally accepts connections and disconnects, with the original packets being returned.
+     * Messages are handed out in the order set by `set_scheduling`, at most `set_frame_cap` per client.
      */
     pub fn poll(&mut self) -> Option<(PacketOrCommand<T>, SocketAddr)> {
-        let mut out = None;
-        loop {
+        match self.error_receive.try_recv() {
+            Ok((e, addr)) => return Some((PacketOrCommand::Failed(e), addr)),
+            Err(_) => ()
+        };
+
+        //Take in everything waiting, so it can be handed out fairly, unless a full backlog says to hold off
+        while !self.inbound.blocked() {
             match self.reader_receive.try_recv() {
-                Ok((packet, src)) => {
-                    //Handle any new connections
-                    match packet.packet_type {
-                        PacketType::Connect => {
-                            let hash = hash_sender(&src);
-                            //Don't accept multiple connection attempts from the same client
-                            if self.connections.contains_key(&hash) == false {
-                                self.connections.insert(hash_sender(&src), ClientInstance::new(src, now().to_timespec().sec + self.config.timeout_period.num_seconds()));
-                                self.writer_send.send((Packet::accept(self.config.protocol_id, 0), src));
-                                out = Some((PacketOrCommand::Command(PacketType::Connect), src));
-                            }
-                            break
-                        },
-                        PacketType::Disconnect => {
-                            let hash = hash_sender(&src);
-                            if self.connections.contains_key(&hash) {
-                                out = Some((PacketOrCommand::Command(PacketType::Disconnect), src));
-                                self.connections.remove(&hash);
-                                break
-                            }
+                Ok((data, src, received)) => {
+                    //The reader only passes on empty datagrams from streams, to tell us they've hung up
+                    let event = if data.len() == 0 {
+                        self.protocol().hang_up(src)
+                    } else {
+                        self.protocol().handle(data.as_slice(), src, received)
+                    };
+                    match event {
+                        Some(ServerEvent::Query(addr, sequence_id, query_len)) => {
+                            //Answered without the asker becoming a client
+                            self.answer_query(addr, sequence_id, query_len);
                         },
-                        PacketType::Message => {
-                            let hash = hash_sender(&src);
-                            match self.connections.get_mut(&hash) {
-                                Some(comms) => {
-                                    if comms.sequence_manager.packet_is_newer(packet.sequence_id) {
-                                        comms.sequence_manager.set_newest_packet(packet.sequence_id);
-                                        match (self.config.packet_deserializer)(&packet.packet_content.unwrap()) {
-                                            Some(deserialized) => {
-                                                out = Some((PacketOrCommand::UserPacket(deserialized), src));
-                                                //Update our timeout
-                                                comms.timeout = now().to_timespec().sec + self.config.timeout_period.num_seconds();
-                                                break
-                                            },
-                                            _ => ()
-                                        }
-                                    }
-                                },
-                                None => ()
-                            }
+                        //Connections coming and going mustn't be lost to a full backlog
+                        Some(event @ ServerEvent::Connected(_)) => self.inbound.push_control(src, event),
+                        Some(event @ ServerEvent::Disconnected(_)) => self.inbound.push_control(src, event),
+                        Some(event) => match self.inbound.push(src, event) {
+                            Ok(()) => (),
+                            Err(_) => return Some((PacketOrCommand::Failed(Error::QueueFull), src))
                         },
-                        _ => ()
-                    };
+                        None => ()
+                    }
                 },
-                _ => {
+                Err(TryRecvError::Disconnected) => {
+                    self.reader_died = true;
+                    break
+                },
+                Err(TryRecvError::Empty) => {
                     break
                 }
             };
         };
-        out
+        match self.flush() {
+            Ok(()) => (),
+            Err(e) => return Some((PacketOrCommand::Failed(e), self.addr))
+        }
+
+        loop {
+            match self.inbound.next() {
+                Some((addr, ServerEvent::Connected(_))) => return Some((PacketOrCommand::Command(PacketType::Connect), addr)),
+                Some((addr, ServerEvent::Disconnected(_))) => {
+                    self.inbound.forget(addr);
+                    return Some((PacketOrCommand::Command(PacketType::Disconnect), addr));
+                },
+                Some((addr, ServerEvent::Message(_, payload))) => {
+                    match (self.config.packet_deserializer)(&payload) {
+                        Some(deserialized) => return Some((PacketOrCommand::UserPacket(deserialized), addr)),
+                        None => ()
+                    }
+                },
+                Some(_) => (),
+                None => break
+            }
+        }
+        //Only reported once everything before it has been handed out, and only the once, so callers draining the queue don't spin forever
+        if self.reader_died && !self.thread_died_reported {
+            self.thread_died_reported = true;
+            Some((PacketOrCommand::Failed(Error::ThreadDied), self.addr))
+        } else {
+            None
+        }
     }
 
     /**
-     * Disconnect, and return, any sockets that have not contacted us for our timeout duration
+     * Change the order messages from different clients are handed out by `poll`. Defaults to Arrival
      */
-    pub fn cull(&mut self) -> Vec<SocketAddr> {
-        let mut culled_hashes = vec![];
-        let mut culled = vec![];
+    pub fn set_scheduling(&mut self, scheduling: Scheduling) {
+        self.inbound.set_scheduling(scheduling);
+    }
+
+    /**
+     * How many messages in a row a client gets when scheduling is Weighted. Everyone starts at 1
+     */
+    pub fn set_priority(&mut self, addr: SocketAddr, priority: usize) {
+        self.inbound.set_priority(addr, priority);
+    }
+
+    /**
+     * Cap how much `poll` hands out from any one client each frame, or None for no cap
+     *
+     * A frame ends whenever `poll` returns None, so anything held back comes out on the next round of polling.
+     */
+    pub fn set_frame_cap(&mut self, cap: Option<usize>) {
+        self.inbound.set_frame_cap(cap);
+    }
+
+    /**
+     * Like `poll`, but if nothing is waiting, block until something arrives or the timeout passes
+     *
+     * Returns None if the timeout passes, or if we're woken through a `Waker`. Servers made to be
+     * pumped are pumped while waiting.
+     */
+    pub fn poll_timeout(&mut self, timeout: Duration) -> Option<(PacketOrCommand<T>, SocketAddr)> {
+        self.poll_until(Some(deadline_after(timeout)))
+    }
 
-        let now = now().to_timespec().sec;
+    /**
+     * Like `poll`, but if nothing is waiting, block until something arrives or we're woken through a `Waker`
+     */
+    pub fn wait(&mut self) -> Option<(PacketOrCommand<T>, SocketAddr)> {
+        self.poll_until(None)
+    }
+
+    /**
+     * Something which can end a `wait` or `poll_timeout` early, from another thread
+     */
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
 
-        for (hash, connection) in self.connections.iter() {
-            if connection.timeout < now {
-                culled.push(connection.addr);
-                culled_hashes.push(hash.clone()); //FIXME: Shouldn't be cloning here
+    fn poll_until(&mut self, deadline: Option<u64>) -> Option<(PacketOrCommand<T>, SocketAddr)> {
+        loop {
+            match self.poll() {
+                None => (),
+                out => return out
+            }
+            //Anything held back by the frame cap can come out now, and once the reader has gone nothing more is coming
+            if self.inbound.depth() > 0 {
+                continue;
+            }
+            if self.reader_died {
+                return None;
+            }
+
+            let now = precise_time_ns();
+            let wait_ms = match deadline {
+                Some(deadline) if deadline <= now => return None,
+                Some(deadline) => Some((deadline - now) / 1000000 + 1),
+                None => None
+            };
+            match self.block(wait_ms) {
+                Ok(true) => return None,
+                Ok(false) => (),
+                Err(e) => return Some((PacketOrCommand::Failed(e), self.addr))
             }
+        }
+    }
+
+    /**
+     * Wait for the reader, or the transport itself if we're pumped. Returns true if we were woken
+     */
+    fn block(&mut self, wait_ms: Option<u64>) -> NetResult<bool> {
+        //Anything waiting to go out shouldn't have to wait with us
+        self.pump_writer();
+        let stopped = match self.pumped_reader {
+            Some(ref mut reader) => {
+                //Nothing can wake the transport, so it's waited on a little at a time, looking for wakes in between
+                let slice = match wait_ms {
+                    Some(ms) if ms < WAKE_CHECK_MS => ms,
+                    _ => WAKE_CHECK_MS
+                };
+                reader.transport.set_timeout(Some(slice));
+                let step = reader.step();
+                reader.transport.set_timeout(Some(0));
+                step == WorkerStep::Stop
+            },
+            None => return wait_for(&*self.reader_receive.selectable(), &self.error_receive, &self.wakes, wait_ms).map_err(|e| Error::Io(e))
         };
+        if stopped {
+            self.pumped_reader = None;
+        }
+        self.pump();
+        Ok(woken(&self.wakes))
+    }
+
+    fn protocol(&self) -> MutexGuard<ServerProtocol> {
+        self.protocol.lock().unwrap()
+    }
 
-        for hash in culled_hashes.iter() {
-            self.connections.remove(hash);
+    /**
+     * Hand everything the protocol wants sent to the writer
+     */
+    fn flush(&mut self) -> NetResult<()> {
+        //A turn taken before letting go of the protocol, so a ServerHandle can't get in between
+        let (turn, datagrams) = {
+            let mut protocol = self.protocol();
+            (self.writer_send.turn(), protocol.drain_outgoing())
+        };
+        try!(turn.send_all(datagrams));
+        Ok(())
+    }
+
+    fn answer_query(&mut self, src: SocketAddr, sequence_id: u16, query_len: usize) {
+        if self.query_handler.is_none() || !self.query_limiter.allow(&src.ip, self.config.clock.now_ms()) {
+            return;
         }
+        let request = QueryRequest {
+            from: src,
+            connections: self.protocol().connection_count()
+        };
+        let info = match self.query_handler {
+            Some(ref mut handler) => (*handler)(&request),
+            None => return
+        };
+        //Anyone who wants an answer can ask again, so a refusal needs no more than this
+        self.protocol().answer_query(src, sequence_id, query_len, info);
+    }
 
+    /**
+     * Disconnect, and return, any sockets that have not contacted us for our timeout duration
+     */
+    pub fn cull(&mut self) -> Vec<SocketAddr> {
+        let culled = self.protocol().cull(self.config.clock.now_ms());
+        for addr in culled.iter() {
+            self.inbound.forget(*addr);
+        }
         culled
     }
 
     /**
      * Send a packet to a specific address
      *
-     * This will return false if the given address isn't connected to us
-     */
-    pub fn send_to(&mut self, packet: &T, addr: &SocketAddr) -> bool {
-        let hashed = hash_sender(addr);
-        match self.connections.get_mut(&hashed) {
-            Some(comms) => {
-                self.writer_send.send((Packet::message(self.config.protocol_id, comms.next_sequence_id(), (self.config.packet_serializer)(packet)), addr.clone()));
-                true
-            },
-            None => false
-        }
+     * This will fail with NotConnected if the given address isn't connected to us
+     */
+    pub fn send_to(&mut self, packet: &T, addr: &SocketAddr) -> NetResult<()> {
+        let payload = try!((self.config.packet_serializer)(packet).ok_or(Error::Serialization));
+        self.send_payload_to(payload, addr)
+    }
+
+    fn send_payload_to(&mut self, payload: Vec<u8>, addr: &SocketAddr) -> NetResult<()> {
+        try!(self.protocol().send(payload, *addr));
+        self.flush()
     }
 
     /**
      * Send a packet to multiple addresses
+     *
+     * Every address is tried, with the first failure being returned
      */
-    pub fn send_to_many(&mut self, packet: &T, addrs: &Vec<SocketAddr>) {
+    pub fn send_to_many(&mut self, packet: &T, addrs: &Vec<SocketAddr>) -> NetResult<()> {
+        let payload = try!((self.config.packet_serializer)(packet).ok_or(Error::Serialization));
+        let mut result = Ok(());
         for addr in addrs.iter() {
-            self.send_to(packet, addr);
+            let sent = self.send_payload_to(payload.clone(), addr);
+            if result.is_ok() {
+                result = sent;
+            }
         }
+        result
     }
 
     /**
      * Send a packet to every connected client
      */
-    pub fn send_to_all(&mut self, packet: &T) {
-        for addr in self.all_connections().iter() {
-            self.send_to(packet, addr);
-        }
+    pub fn send_to_all(&mut self, packet: &T) -> NetResult<()> {
+        let addrs = self.all_connections();
+        self.send_to_many(packet, &addrs)
     }
 
     /**
      * List all of our current connections
      */
     pub fn all_connections(&self) -> Vec<SocketAddr> {
-        self.connections.values().map(|client_instance| { client_instance.addr }).collect()
+        self.protocol().connections()
+    }
+
+    /**
+     * Something which can send to our clients from any thread, while this Server carries on polling
+     */
+    pub fn handle(&self) -> ServerHandle<T> {
+        ServerHandle {
+            protocol: self.protocol.clone(),
+            writer_send: self.writer_send.clone(),
+            packet_serializer: self.config.packet_serializer
+        }
+    }
+
+    /**
+     * Answer LAN discovery probes on DISCOVERY_PORT, handing out some information about this server
+     */
+    pub fn enable_discovery(&mut self, info: Vec<u8>) -> NetResult<()> {
+        let socket = try!(UdpSocket::bind(SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: DISCOVERY_PORT }));
+        self.enable_discovery_with(socket, info)
+    }
+
+    /**
+     * Answer discovery probes arriving on any Transport, such as one joined to a multicast group
+     */
+    pub fn enable_discovery_with<D: Transport>(&mut self, transport: D, info: Vec<u8>) -> NetResult<()> {
+        let port = try!(self.local_addr()).port;
+        self.discovery = Some(try!(Responder::start(transport, self.config.protocol_id, port, info)));
+        Ok(())
+    }
+
+    /**
+     * Change the information handed out to discovery probes. Does nothing if discovery isn't enabled
+     */
+    pub fn set_discovery_info(&mut self, info: Vec<u8>) -> NetResult<()> {
+        match self.discovery {
+            Some(ref responder) => responder.set_info(info),
+            None => Ok(())
+        }
+    }
+
+    /**
+     * Stop answering discovery probes
+     */
+    pub fn disable_discovery(&mut self) {
+        self.discovery = None;
+    }
+
+    /**
+     * Answer info queries from anyone, with whatever the handler returns
+     */
+    pub fn enable_queries(&mut self, handler: QueryHandler) {
+        self.query_handler = Some(handler);
+    }
+
+    /**
+     * Stop answering info queries
+     */
+    pub fn disable_queries(&mut self) {
+        self.query_handler = None;
+    }
+
+    /**
+     * Change how many queries a second are answered, for any one address and in total
+     */
+    pub fn set_query_rate(&mut self, per_address: u32, total: u32) {
+        self.query_limiter = RateLimiter::new(per_address, total);
+    }
+
+    /**
+     * Say goodbye to every client, send everything still queued, and stop our threads
+     *
+     * Returns the first error any of that ran into. A reader thread can take up to a second to notice
+     * it should stop, so this can block for that long. Dropping a Server says goodbye too, but doesn't
+     * wait for anything or report what went wrong.
+     */
+    pub fn shutdown(mut self) -> NetResult<()> {
+        self.shut_down = true;
+        self.discovery = None;
+        self.protocol().disconnect_all();
+        let flushed = self.flush();
+        match self.reader_send.send(TaskCommand::Disconnect) {
+            _ => () //The reader may have gone already
+        }
+
+        //With nobody left to queue anything, the writer stops once everything queued has gone out
+        self.writer_send.close();
+        //A reader waiting for room in a full inbound queue would never notice it should stop
+        self.reader_receive.close();
+        self.pump_writer();
+        self.pumped_reader = None;
+        self.pumped_writer = None;
+        while self.finished.recv().is_ok() {}
+
+        try!(flushed);
+        match self.error_receive.try_recv() {
+            Ok((e, _)) => Err(e),
+            Err(_) => Ok(())
+        }
+    }
+
+    /**
+     * How full our queues are, and how much they've had to throw away
+     */
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            inbound: self.reader_receive.depth() + self.inbound.depth(),
+            outbound: self.writer_send.depth(),
+            inbound_dropped: self.reader_receive.dropped() + self.inbound.dropped(),
+            outbound_dropped: self.writer_send.dropped()
+        }
+    }
+
+    /**
+     * Ask the transport which address we're really listening on
+     *
+     * This can differ from addr, such as when binding to port 0
+     */
+    pub fn local_addr(&mut self) -> NetResult<SocketAddr> {
+        self.transport.local_addr().map_err(|e| Error::Io(e))
     }
 }
 
 #[unsafe_destructor]
-impl <T> Drop for Server <T> {
+impl <T, N: Transport> Drop for Server <T, N> {
 
     fn drop(&mut self) {
-        self.reader_send.send(TaskCommand::Disconnect);
+        if self.shut_down {
+            return;
+        }
+        self.protocol().disconnect_all();
+        match self.flush() {
+            _ => () //Nothing more can be done if the writer has already gone
+        }
+        self.pump_writer();
+        match self.reader_send.send(TaskCommand::Disconnect) {
+            _ => () //Nothing more can be done if the reader has already gone
+        }
+    }
+}
+
+/**
+ * Sends to a Server's clients from any thread, and can be cloned for as many threads as need it
+ *
+ * A Server made to be pumped only puts anything on the wire when it's pumped.
+ */
+pub struct ServerHandle<T> {
+    protocol: Arc<Mutex<ServerProtocol>>,
+    writer_send: SharedSender<Datagram>,
+    packet_serializer: fn(&T) -> Option<Vec<u8>>
+}
+
+impl <T> ServerHandle<T> {
+
+    /**
+     * Send a packet to a specific address
+     *
+     * This will fail with NotConnected if the given address isn't connected to us
+     */
+    pub fn send_to(&self, packet: &T, addr: &SocketAddr) -> NetResult<()> {
+        let payload = try!((self.packet_serializer)(packet).ok_or(Error::Serialization));
+        let (turn, datagrams) = {
+            let mut protocol = self.protocol.lock().unwrap();
+            try!(protocol.send(payload, *addr));
+            (self.writer_send.turn(), protocol.drain_outgoing())
+        };
+        try!(turn.send_all(datagrams));
+        Ok(())
+    }
+
+    /**
+     * Send a packet to every connected client
+     *
+     * Every client is tried, with the first failure being returned
+     */
+    pub fn send_to_all(&self, packet: &T) -> NetResult<()> {
+        let payload = try!((self.packet_serializer)(packet).ok_or(Error::Serialization));
+        let mut result = Ok(());
+        let (turn, datagrams) = {
+            let mut protocol = self.protocol.lock().unwrap();
+            for addr in protocol.connections().into_iter() {
+                let sent = protocol.send(payload.clone(), addr);
+                if result.is_ok() {
+                    result = sent;
+                }
+            }
+            (self.writer_send.turn(), protocol.drain_outgoing())
+        };
+        let queued = turn.send_all(datagrams);
+        try!(result);
+        try!(queued);
+        Ok(())
+    }
+
+    /**
+     * List all of the Server's current connections
+     */
+    pub fn all_connections(&self) -> Vec<SocketAddr> {
+        self.protocol.lock().unwrap().connections()
+    }
+}
+
+impl <T> Clone for ServerHandle<T> {
+
+    fn clone(&self) -> ServerHandle<T> {
+        ServerHandle {
+            protocol: self.protocol.clone(),
+            writer_send: self.writer_send.clone(),
+            packet_serializer: self.packet_serializer
+        }
     }
 }
@@ -1,5 +1,8 @@
 use std::time::duration::Duration;
 use std::u16;
+use conditioner::LinkConditions;
+use queue::QueueLimit;
+use clock::{SharedClock, system_clock};
 /**
  * General configuration for a connection
  */
@@ -10,8 +13,16 @@ pub struct ConnectionConfig<T> {
     pub timeout_period: Duration,
     /// A function to turn raw data into our packet format
     pub packet_deserializer: fn(&Vec<u8>) -> Option<T>,
-    /// A function to turn a packet into raw data
-    pub packet_serializer: fn(&T) -> Vec<u8>
+    /// A function to turn a packet into raw data, or None if it can't be
+    pub packet_serializer: fn(&T) -> Option<Vec<u8>>,
+    /// Simulate a bad network for testing, if set
+    pub link_conditions: Option<LinkConditions>,
+    /// Where timeouts get the time from
+    pub clock: SharedClock,
+    /// How much can arrive without being polled, if limited. Servers also limit each client's backlog to this
+    pub inbound_queue: Option<QueueLimit>,
+    /// How much can wait to be sent, if limited
+    pub outbound_queue: Option<QueueLimit>
 }
 
 impl <T> ConnectionConfig <T> {
@@ -19,12 +30,16 @@ impl <T> ConnectionConfig <T> {
     /**
      * Create a new ConnectionConfig object
      */
-    pub fn new(protocol_id: u32, timeout_period: Duration, packet_deserializer: fn(&Vec<u8>) -> Option<T>, packet_serializer: fn(&T) -> Vec<u8>) -> ConnectionConfig<T> {
+    pub fn new(protocol_id: u32, timeout_period: Duration, packet_deserializer: fn(&Vec<u8>) -> Option<T>, packet_serializer: fn(&T) -> Option<Vec<u8>>) -> ConnectionConfig<T> {
         ConnectionConfig {
             protocol_id: protocol_id,
             timeout_period: timeout_period,
             packet_deserializer: packet_deserializer,
-            packet_serializer: packet_serializer
+            packet_serializer: packet_serializer,
+            link_conditions: None,
+            clock: system_clock(),
+            inbound_queue: None,
+            outbound_queue: None
         }
     }
 }
@@ -0,0 +1,272 @@
+//!
+//! A deterministic simulation, for testing connections without waiting on real time
+//!
+//! A `Simulation` owns a `ManualClock` and a virtual network. Clients and Servers made from it
+//! run without background threads and read the time from the simulation's clock, so a test moves
+//! them along tick by tick: advance the clock, `pump` every endpoint, then `poll`. Timeouts which
+//! would take seconds happen in as many milliseconds, and with seeded `LinkConditions` every run
+//! loses and reorders exactly the same packets.
+//!
+//! `SimTransport`s never block, so they should only be used with the unthreaded constructors.
+//!
+use std::old_io::{IoResult, IoError, OtherIoError, TimedOut};
+use std::old_io::net::ip::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use clock::{Clock, ManualClock};
+use conditioner::{LinkConditions, LinkModel};
+use shared::ConnectionConfig;
+use client::{Client, ClientConnectionConfig};
+use server::Server;
+use error::NetResult;
+use transport::Transport;
+
+///The first port handed out when binding to port 0
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/**
+ * One bound address, and what is on its way to it
+ */
+struct Endpoint {
+    ///Applied to everything this endpoint sends
+    link: Option<LinkModel>,
+    ///Packets in flight, with when they arrive and the order they were sent in
+    inbox: Vec<(u64, u64, Vec<u8>, SocketAddr)>
+}
+
+struct SimNetwork {
+    endpoints: BTreeMap<String, Endpoint>,
+    next_port: u16,
+    sent: u64
+}
+
+/**
+ * A virtual network and clock, which only move when told to
+ */
+pub struct Simulation {
+    clock: ManualClock,
+    network: Arc<Mutex<SimNetwork>>,
+    tick_ms: u64
+}
+
+impl Simulation {
+
+    /**
+     * Create an empty simulation, starting at time 0, which moves `tick_ms` at a time
+     */
+    pub fn new(tick_ms: u64) -> Simulation {
+        Simulation {
+            clock: ManualClock::new(0),
+            network: Arc::new(Mutex::new(SimNetwork {
+                endpoints: BTreeMap::new(),
+                next_port: FIRST_EPHEMERAL_PORT,
+                sent: 0
+            })),
+            tick_ms: tick_ms
+        }
+    }
+
+    /**
+     * The clock everything in this simulation runs on
+     */
+    pub fn clock(&self) -> ManualClock {
+        self.clock.clone()
+    }
+
+    /**
+     * The current simulated time, in milliseconds
+     */
+    pub fn now_ms(&self) -> u64 {
+        self.clock.now_ms()
+    }
+
+    /**
+     * Bind a transport to a virtual address, with conditions for what it sends. Port 0 picks any free port
+     */
+    pub fn bind(&self, addr: SocketAddr, conditions: Option<LinkConditions>) -> IoResult<SimTransport> {
+        let mut network = self.network.lock().unwrap();
+
+        let addr = if addr.port == 0 {
+            let mut candidate = SocketAddr { ip: addr.ip, port: network.next_port };
+            while network.endpoints.contains_key(&format!("{}", candidate)) {
+                candidate.port = if candidate.port == 65535 { FIRST_EPHEMERAL_PORT } else { candidate.port + 1 };
+            }
+            network.next_port = if candidate.port == 65535 { FIRST_EPHEMERAL_PORT } else { candidate.port + 1 };
+            candidate
+        } else {
+            addr
+        };
+
+        let key = format!("{}", addr);
+        if network.endpoints.contains_key(&key) {
+            return Err(IoError {
+                kind: OtherIoError,
+                desc: "Address already in use",
+                detail: Some(key)
+            });
+        }
+
+        network.endpoints.insert(key, Endpoint {
+            link: conditions.map(|conditions| LinkModel::new(conditions, conditions.seed)),
+            inbox: vec![]
+        });
+
+        Ok(SimTransport {
+            network: self.network.clone(),
+            clock: self.clock.clone(),
+            addr: addr,
+            handles: Arc::new(AtomicUsize::new(1))
+        })
+    }
+
+    /**
+     * Start a Server in the simulation
+     *
+     * The config's clock is replaced with the simulation's, and its link conditions are applied
+     * by the simulated network rather than a LinkConditioner.
+     */
+    pub fn server<T>(&self, addr: SocketAddr, mut config: ConnectionConfig<T>) -> NetResult<Server<T, SimTransport>> {
+        let transport = try!(self.bind(addr, config.link_conditions));
+        config.link_conditions = None;
+        config.clock = self.clock.shared();
+        Server::with_transport_unthreaded(transport, config)
+    }
+
+    /**
+     * Start connecting a Client in the simulation. It will be Connecting until pumped
+     *
+     * The config is adjusted as for `server`.
+     */
+    pub fn client<T>(&self, addr: SocketAddr, target_addr: SocketAddr, mut config: ConnectionConfig<T>, client_connection_config: ClientConnectionConfig) -> NetResult<Client<T, SimTransport>> {
+        let transport = try!(self.bind(addr, config.link_conditions));
+        config.link_conditions = None;
+        config.clock = self.clock.shared();
+        Client::connect_unthreaded(transport, target_addr, config, client_connection_config)
+    }
+
+    /**
+     * Move time forwards by a single tick, then call `tick` with the new time
+     */
+    pub fn step<F: FnMut(u64)>(&self, mut tick: F) {
+        self.clock.advance(self.tick_ms);
+        tick(self.now_ms());
+    }
+
+    /**
+     * Step until at least `ms` milliseconds have passed
+     */
+    pub fn run_for<F: FnMut(u64)>(&self, ms: u64, mut tick: F) {
+        let until = self.now_ms() + ms;
+        while self.now_ms() < until {
+            self.step(|now| tick(now));
+        }
+    }
+
+    /**
+     * How many packets are still in flight
+     */
+    pub fn in_flight(&self) -> usize {
+        self.network.lock().unwrap().endpoints.values().map(|endpoint| endpoint.inbox.len()).sum()
+    }
+}
+
+/**
+ * One endpoint on a Simulation's network
+ */
+pub struct SimTransport {
+    network: Arc<Mutex<SimNetwork>>,
+    clock: ManualClock,
+    addr: SocketAddr,
+    handles: Arc<AtomicUsize>
+}
+
+impl Transport for SimTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        let mut network = self.network.lock().unwrap();
+        let now = self.clock.now_ms();
+        let dues = match network.endpoints.get_mut(&format!("{}", self.addr)) {
+            Some(endpoint) => {
+                match endpoint.link {
+                    Some(ref mut link) => link.schedule(buf.len(), now),
+                    None => vec![now]
+                }
+            },
+            None => vec![]
+        };
+
+        for due in dues.into_iter() {
+            let order = network.sent;
+            network.sent += 1;
+            match network.endpoints.get_mut(&format!("{}", addr)) {
+                Some(target) => target.inbox.push((due, order, buf.to_vec(), self.addr)),
+                None => () //Like UDP, nobody listening is no concern of the sender
+            }
+        }
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        let mut network = self.network.lock().unwrap();
+        let now = self.clock.now_ms();
+        let endpoint = match network.endpoints.get_mut(&format!("{}", self.addr)) {
+            Some(endpoint) => endpoint,
+            None => return Err(IoError {
+                kind: OtherIoError,
+                desc: "Not bound",
+                detail: None
+            })
+        };
+
+        //Take the earliest packet which has arrived, keeping packets due at the same time in order
+        let mut earliest = None;
+        for (i, &(due, order, _, _)) in endpoint.inbox.iter().enumerate() {
+            if due <= now && earliest.map_or(true, |(_, best)| (due, order) < best) {
+                earliest = Some((i, (due, order)));
+            }
+        }
+        match earliest {
+            Some((i, _)) => {
+                let (_, _, data, src) = endpoint.inbox.remove(i);
+                let mut amt = 0;
+                for (dst, byte) in buf.iter_mut().zip(data.iter()) {
+                    *dst = *byte;
+                    amt += 1;
+                }
+                Ok((amt, src))
+            },
+            None => Err(IoError {
+                kind: TimedOut,
+                desc: "Timed out",
+                detail: None
+            })
+        }
+    }
+
+    fn set_timeout(&mut self, _timeout_ms: Option<u64>) {
+        //Waiting would never end, as time only moves between pumps
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn try_clone(&self) -> IoResult<SimTransport> {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(SimTransport {
+            network: self.network.clone(),
+            clock: self.clock.clone(),
+            addr: self.addr,
+            handles: self.handles.clone()
+        })
+    }
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        //The last handle frees up the address
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.network.lock().unwrap().endpoints.remove(&format!("{}", self.addr));
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//!
+//! A TCP Transport, for networks which block UDP
+//!
+//! Every packet is framed with a big-endian u16 length, so the stream carries exactly the same
+//! packets as a UDP socket would. Clients still go through the usual Connect/Accept handshake once
+//! the stream is open. These are stream transports, so a closed stream is reported as a zero-length
+//! datagram, which Clients and Servers treat as a Disconnect.
+//!
+use std::old_io::{IoResult, IoError, NotConnected, InvalidInput, TimedOut};
+use std::old_io::{Listener, Acceptor};
+use std::old_io::net::tcp::{TcpStream, TcpListener, TcpAcceptor};
+use std::old_io::net::ip::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::thread::Thread;
+use std::collections::BTreeMap;
+use packet::MAX_PACKET_SIZE;
+use transport::Transport;
+use inbox::{Inbox, recv_timeout};
+
+///How long the server waits to write to a client before giving up on them
+const WRITE_TIMEOUT_MS: u64 = 500;
+
+fn not_connected() -> IoError {
+    IoError {
+        kind: NotConnected,
+        desc: "Not connected to that address",
+        detail: None
+    }
+}
+
+/**
+ * Prefix a packet with its length
+ */
+fn frame(buf: &[u8]) -> IoResult<Vec<u8>> {
+    if buf.len() == 0 || buf.len() > MAX_PACKET_SIZE {
+        return Err(IoError {
+            kind: InvalidInput,
+            desc: "Packet size out of range",
+            detail: None
+        });
+    }
+    let mut w = Vec::with_capacity(buf.len() + 2);
+    try!(w.write_be_u16(buf.len() as u16));
+    try!(w.write(buf));
+    Ok(w)
+}
+
+/**
+ * Split a stream back into packets until it closes, then report the hang up
+ */
+fn read_frames(mut stream: TcpStream, addr: SocketAddr, out: Sender<(Vec<u8>, SocketAddr)>) {
+    loop {
+        let data = match stream.read_be_u16() {
+            Ok(0) => break,
+            Ok(len) => {
+                match stream.read_exact(len as usize) {
+                    Ok(data) => data,
+                    Err(_) => break
+                }
+            },
+            Err(_) => break
+        };
+        match out.send((data, addr)) {
+            Ok(()) => (),
+            Err(_) => return
+        }
+    }
+    match out.send((vec![], addr)) {
+        _ => () //Nobody may be listening any more
+    }
+}
+
+/**
+ * The client end of a TCP connection
+ */
+pub struct TcpClientTransport {
+    stream: TcpStream,
+    peer: SocketAddr,
+    inbox: Arc<Inbox>,
+    handles: Arc<AtomicUsize>,
+    timeout_ms: Option<u64>
+}
+
+impl TcpClientTransport {
+
+    /**
+     * Open a stream to a TcpServerTransport, ready to pass to Client::connect_with
+     */
+    pub fn connect(addr: SocketAddr) -> IoResult<TcpClientTransport> {
+        let stream = try!(TcpStream::connect(addr));
+        let reader = stream.clone();
+        let (inbox_send, inbox_receive) = channel();
+
+        Thread::spawn(move || {
+            read_frames(reader, addr, inbox_send);
+        });
+
+        Ok(TcpClientTransport {
+            stream: stream,
+            peer: addr,
+            inbox: Arc::new(Inbox::new(inbox_receive)),
+            handles: Arc::new(AtomicUsize::new(1)),
+            timeout_ms: None
+        })
+    }
+}
+
+impl Transport for TcpClientTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        if addr != self.peer {
+            return Err(not_connected());
+        }
+        let framed = try!(frame(buf));
+        self.stream.write(framed.as_slice())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        recv_timeout(&*self.inbox, self.timeout_ms, buf)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.stream.socket_name()
+    }
+
+    fn try_clone(&self) -> IoResult<TcpClientTransport> {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(TcpClientTransport {
+            stream: self.stream.clone(),
+            peer: self.peer,
+            inbox: self.inbox.clone(),
+            handles: self.handles.clone(),
+            timeout_ms: self.timeout_ms
+        })
+    }
+
+    fn is_stream(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for TcpClientTransport {
+    fn drop(&mut self) {
+        //The last handle closes the stream, which also stops the frame reader
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            match (self.stream.close_read(), self.stream.close_write()) {
+                _ => ()
+            }
+        }
+    }
+}
+
+struct Listening {
+    addr: SocketAddr,
+    streams: Mutex<BTreeMap<String, TcpStream>>,
+    inbox: Inbox,
+    handles: AtomicUsize
+}
+
+fn accept_process(mut acceptor: TcpAcceptor, listening: Arc<Listening>, inbox_send: Sender<(Vec<u8>, SocketAddr)>) {
+    while listening.handles.load(Ordering::SeqCst) > 0 {
+        //Acceptor timeouts are deadlines, so need resetting every time around
+        acceptor.set_timeout(Some(1000));
+        match acceptor.accept() {
+            Ok(mut stream) => {
+                let addr = match stream.peer_name() {
+                    Ok(addr) => addr,
+                    Err(_) => continue
+                };
+                let reader = stream.clone();
+                listening.streams.lock().unwrap().insert(format!("{}", addr), stream);
+
+                let inbox_send = inbox_send.clone();
+                let listening = listening.clone();
+                Thread::spawn(move || {
+                    read_frames(reader, addr, inbox_send);
+                    listening.streams.lock().unwrap().remove(&format!("{}", addr));
+                });
+            },
+            Err(_) => ()
+        }
+    }
+}
+
+/**
+ * The server end of TCP, accepting any number of client streams
+ */
+pub struct TcpServerTransport {
+    listening: Arc<Listening>,
+    timeout_ms: Option<u64>
+}
+
+impl TcpServerTransport {
+
+    /**
+     * Start accepting streams on a given address, ready to pass to Server::with_transport
+     */
+    pub fn bind(addr: SocketAddr) -> IoResult<TcpServerTransport> {
+        let mut listener = try!(TcpListener::bind(addr));
+        let addr = try!(listener.socket_name());
+        let acceptor = try!(listener.listen());
+        let (inbox_send, inbox_receive) = channel();
+
+        let listening = Arc::new(Listening {
+            addr: addr,
+            streams: Mutex::new(BTreeMap::new()),
+            inbox: Inbox::new(inbox_receive),
+            handles: AtomicUsize::new(1)
+        });
+
+        let accept_listening = listening.clone();
+        Thread::spawn(move || {
+            accept_process(acceptor, accept_listening, inbox_send);
+        });
+
+        Ok(TcpServerTransport {
+            listening: listening,
+            timeout_ms: None
+        })
+    }
+}
+
+impl Transport for TcpServerTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        let framed = try!(frame(buf));
+        //Written outside the lock, so a client with a full window only holds up other writes until the timeout
+        let stream = self.listening.streams.lock().unwrap().get(&format!("{}", addr)).map(|stream| stream.clone());
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => return Err(not_connected())
+        };
+        //Timeouts are deadlines, and belong to this copy of the stream alone
+        stream.set_write_timeout(Some(WRITE_TIMEOUT_MS));
+        let written = stream.write(framed.as_slice());
+        match written {
+            Err(ref e) if e.kind == TimedOut => {
+                //Part of a frame may have gone, so the stream can't be trusted again. Hanging up also
+                //stops its frame reader, which reports the hang up
+                self.listening.streams.lock().unwrap().remove(&format!("{}", addr));
+                match (stream.close_read(), stream.close_write()) {
+                    _ => ()
+                }
+            },
+            _ => ()
+        }
+        written
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        recv_timeout(&self.listening.inbox, self.timeout_ms, buf)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        Ok(self.listening.addr)
+    }
+
+    fn try_clone(&self) -> IoResult<TcpServerTransport> {
+        self.listening.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(TcpServerTransport {
+            listening: self.listening.clone(),
+            timeout_ms: self.timeout_ms
+        })
+    }
+
+    fn is_stream(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for TcpServerTransport {
+    fn drop(&mut self) {
+        //The last handle hangs up on everyone, and lets the acceptor wind down
+        if self.listening.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            for (_, stream) in self.listening.streams.lock().unwrap().iter_mut() {
+                match (stream.close_read(), stream.close_write()) {
+                    _ => ()
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use shared::ConnectionConfig;
+use bincode_codec;
+use tests::test_shared;
+
+use std::time::duration::Duration;
+use rustc_serialize::{Encodable, Decodable};
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone)]
+enum Command {
+    Move { x: i32, y: i32 },
+    Chat(String),
+    Quit
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone)]
+struct PlayerState {
+    id: u32,
+    name: String,
+    position: (f32, f32),
+    inventory: Vec<u16>,
+    target: Option<u32>
+}
+
+fn bincode_settings<T>() -> ConnectionConfig<T> where T: Encodable + Decodable {
+    ConnectionConfig::with_bincode(121, Duration::seconds(10))
+}
+
+/**
+ * Send some packets through an echo server, and return what comes back
+ */
+fn round_trip<T>(packets: &Vec<T>) -> Vec<T> where T: Encodable + Decodable + Send + 'static {
+    test_shared::echo_round_trip(bincode_settings::<T>, packets.len(), |client| {
+        for packet in packets.iter() {
+            client.send(packet).ok().expect("Couldn't send a packet");
+        }
+    })
+}
+
+/**
+ * The codec functions should be inverses of each other
+ */
+#[test]
+fn codec_round_trip() {
+    let command = Command::Move { x: -5, y: 12 };
+    let raw = bincode_codec::serializer(&command).unwrap();
+    assert!(bincode_codec::deserializer::<Command>(&raw) == Some(command));
+}
+
+/**
+ * Garbage shouldn't deserialize into anything
+ */
+#[test]
+fn codec_rejects_garbage() {
+    assert!(bincode_codec::deserializer::<PlayerState>(&vec![255, 1]).is_none());
+}
+
+/**
+ * Enums should survive a trip across a connection
+ */
+#[test]
+fn enum_across_connection() {
+    let packets = vec![
+        Command::Move { x: 1, y: -1 },
+        Command::Chat("Hello".to_string()),
+        Command::Quit
+    ];
+    let received = round_trip(&packets);
+    assert!(received == packets);
+}
+
+/**
+ * Structs should survive a trip across a connection
+ */
+#[test]
+fn struct_across_connection() {
+    let packets = vec![
+        PlayerState { id: 1, name: "Alice".to_string(), position: (1.5, -2.0), inventory: vec![1, 2, 3], target: None },
+        PlayerState { id: 2, name: "Bob".to_string(), position: (0.0, 100.25), inventory: vec![], target: Some(1) }
+    ];
+    let received = round_trip(&packets);
+    assert!(received == packets);
+}
@@ -0,0 +1,187 @@
+use bits::{BitWriter, BitReader, BitError, bits_for_range, quantize, dequantize};
+use std::{i64, u64};
+
+/**
+ * Bits should come back out in the order they went in
+ */
+#[test]
+fn bits_round_trip() {
+    let mut writer = BitWriter::new();
+    writer.write_bits(5, 3);
+    writer.write_bool(true);
+    writer.write_bits(1000, 10);
+    assert!(writer.bits_written() == 14);
+
+    let bytes = writer.into_bytes();
+    assert!(bytes.len() == 2);
+
+    let mut reader = BitReader::new(bytes.as_slice());
+    assert!(reader.read_bits(3) == Ok(5));
+    assert!(reader.read_bool() == Ok(true));
+    assert!(reader.read_bits(10) == Ok(1000));
+    assert!(reader.bits_remaining() == 2);
+    assert!(reader.finish() == Ok(()));
+    assert!(reader.read_bits(3) == Err(BitError::EndOfData));
+}
+
+/**
+ * Bounded integers should only use as many bits as their range needs
+ */
+#[test]
+fn ranged() {
+    assert!(bits_for_range(0, 0) == 0);
+    assert!(bits_for_range(0, 1) == 1);
+    assert!(bits_for_range(-8, 7) == 4);
+    assert!(bits_for_range(100, 355) == 8);
+    assert!(bits_for_range(i64::MIN, i64::MAX) == 64);
+
+    let mut writer = BitWriter::new();
+    assert!(writer.write_ranged(-3, -8, 7) == Ok(()));
+    assert!(writer.write_ranged(355, 100, 355) == Ok(()));
+    assert!(writer.write_ranged(i64::MIN, i64::MIN, i64::MAX) == Ok(()));
+    assert!(writer.write_ranged(8, -8, 7) == Err(BitError::OutOfRange));
+    assert!(writer.write_ranged(99, 100, 355) == Err(BitError::OutOfRange));
+    assert!(writer.bits_written() == 76);
+
+    let bytes = writer.into_bytes();
+    let mut reader = BitReader::new(bytes.as_slice());
+    assert!(reader.read_ranged(-8, 7) == Ok(-3));
+    assert!(reader.read_ranged(100, 355) == Ok(355));
+    assert!(reader.read_ranged(i64::MIN, i64::MAX) == Ok(i64::MIN));
+}
+
+/**
+ * Values that don't fit their range should be reported, not wrapped around
+ */
+#[test]
+fn ranged_out_of_range() {
+    //0..4 takes 3 bits, so 7 can be sent but isn't valid
+    let mut reader = BitReader::new(&[0b1110_0000]);
+    assert!(reader.read_ranged(0, 4) == Err(BitError::OutOfRange));
+}
+
+/**
+ * Quantizing should be reversible at the ends of the range, and clamp outside of it
+ */
+#[test]
+fn quantized() {
+    assert!(quantize(-1.0, -1.0, 1.0, 8) == 0);
+    assert!(quantize(1.0, -1.0, 1.0, 8) == 255);
+    assert!(quantize(5.0, -1.0, 1.0, 8) == 255);
+    assert!(dequantize(0, -1.0, 1.0, 8) == -1.0);
+    assert!(dequantize(255, -1.0, 1.0, 8) == 1.0);
+
+    let mut writer = BitWriter::new();
+    writer.write_quantized(0.3, 0.0, 1.0, 10);
+    let bytes = writer.into_bytes();
+    let value = BitReader::new(bytes.as_slice()).read_quantized(0.0, 1.0, 10).unwrap();
+    assert!(value > 0.299 && value < 0.301);
+}
+
+/**
+ * Varints should be small for small values, and still hold large ones
+ */
+#[test]
+fn varints() {
+    let values = [0u64, 1, 127, 128, 300, 1 << 40, u64::MAX];
+    let mut writer = BitWriter::new();
+    for value in values.iter() {
+        writer.write_varint(*value);
+    }
+    writer.write_signed_varint(-1);
+    writer.write_signed_varint(i64::MIN);
+
+    let bytes = writer.into_bytes();
+    let mut reader = BitReader::new(bytes.as_slice());
+    for value in values.iter() {
+        assert!(reader.read_varint() == Ok(*value));
+    }
+    assert!(reader.read_signed_varint() == Ok(-1));
+    assert!(reader.read_signed_varint() == Ok(i64::MIN));
+    assert!(reader.finish() == Ok(()));
+
+    let mut small = BitWriter::new();
+    small.write_varint(127);
+    small.write_signed_varint(-64);
+    assert!(small.bits_written() == 16);
+}
+
+/**
+ * Varints which keep going past 64 bits should be rejected
+ */
+#[test]
+fn varint_overflow() {
+    let bytes = [0xFFu8; 11];
+    assert!(BitReader::new(&bytes).read_varint() == Err(BitError::VarintOverflow));
+
+    //Ten groups, but the last one has bits set past the 64th
+    let mut too_big = [0xFFu8; 10];
+    too_big[9] = 0x02;
+    assert!(BitReader::new(&too_big).read_varint() == Err(BitError::VarintOverflow));
+}
+
+/**
+ * Truncated data should be reported as such
+ */
+#[test]
+fn truncated() {
+    assert!(BitReader::new(&[]).read_bool() == Err(BitError::EndOfData));
+    assert!(BitReader::new(&[0x80]).read_varint() == Err(BitError::EndOfData));
+}
+
+/**
+ * Leftover data, or junk in the padding, should be noticed
+ */
+#[test]
+fn trailing_data() {
+    let mut reader = BitReader::new(&[0xFF, 0x00]);
+    reader.read_bits(4).unwrap();
+    assert!(reader.finish() == Err(BitError::TrailingData));
+    reader.read_bits(8).unwrap();
+    assert!(reader.finish() == Ok(()));
+
+    let mut junk = BitReader::new(&[0b1000_0001]);
+    junk.read_bool().unwrap();
+    assert!(junk.finish() == Err(BitError::TrailingData));
+}
+
+/**
+ * Checked writes should refuse anything that doesn't fit, rather than cutting it down
+ */
+#[test]
+fn checked_widths() {
+    let mut writer = BitWriter::new();
+    assert!(writer.write_unsigned(63, 6) == Ok(()));
+    assert!(writer.write_unsigned(64, 6) == Err(BitError::OutOfRange));
+    assert!(writer.write_signed(-8, 4) == Ok(()));
+    assert!(writer.write_signed(7, 4) == Ok(()));
+    assert!(writer.write_signed(8, 4) == Err(BitError::OutOfRange));
+    assert!(writer.write_signed(-9, 4) == Err(BitError::OutOfRange));
+    assert!(writer.write_unsigned(u64::MAX, 64) == Ok(()));
+    assert!(writer.bits_written() == 78);
+
+    let bytes = writer.into_bytes();
+    let mut reader = BitReader::new(bytes.as_slice());
+    assert!(reader.read_bits(6) == Ok(63));
+    assert!(reader.read_signed(4) == Ok(-8));
+    assert!(reader.read_signed(4) == Ok(7));
+    assert!(reader.read_bits(64) == Ok(u64::MAX));
+}
+
+/**
+ * Widths a value can't be written with should fail, rather than panicking or wrapping around
+ */
+#[test]
+fn impossible_widths() {
+    let mut writer = BitWriter::new();
+    assert!(writer.write_signed(0, 0) == Err(BitError::OutOfRange));
+    assert!(writer.write_signed(-1, 65) == Err(BitError::OutOfRange));
+    assert!(writer.write_unsigned(1, 65) == Err(BitError::OutOfRange));
+    assert!(writer.bits_written() == 0);
+
+    let bytes = vec![0; 16];
+    let mut reader = BitReader::new(bytes.as_slice());
+    assert!(reader.read_signed(0) == Err(BitError::OutOfRange));
+    assert!(reader.read_signed(65) == Err(BitError::OutOfRange));
+    assert!(reader.bits_remaining() == 128);
+}
@@ -1,31 +1,43 @@
 use shared::ConnectionConfig;
 use client::{ClientConnectionConfig, Client, PollFailResult};
-use packet::{Packet, PacketType};
+use packet::{Packet, PacketType, MAX_PACKET_SIZE};
+use error::Error;
+use queue::{QueueLimit, QueuePolicy};
+use clock::ManualClock;
+
+use loopback::{LoopbackNetwork, LoopbackTransport};
+use transport::Transport;
+use error::NetResult;
 
 use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
-use std::old_io::net::udp::UdpSocket;
 use std::old_io::Timer;
 use std::time::duration::Duration;
 use tests::test_shared;
 use std::thread::Thread;
-use std::sync::mpsc::{channel};
+use std::sync::mpsc::{channel, Select};
+use time::precise_time_ns;
 
-fn generate_settings(port: u16, protocol_id: u32) -> (SocketAddr, SocketAddr, ConnectionConfig<Vec<u8>>, ClientConnectionConfig) {
+fn generate_settings(port: u16, protocol_id: u32) -> (LoopbackNetwork, SocketAddr, SocketAddr, ConnectionConfig<Vec<u8>>, ClientConnectionConfig) {
     let my_addr = SocketAddr{ ip: Ipv4Addr(0, 0, 0, 0), port: 0 };
     let target_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: port };
     let settings = ConnectionConfig::new(protocol_id, Duration::seconds(10), test_shared::deserializer, test_shared::serializer);
     let client_settings = ClientConnectionConfig::new(3, Duration::seconds(5));
-    (my_addr, target_addr, settings, client_settings)
+    (LoopbackNetwork::new(), my_addr, target_addr, settings, client_settings)
+}
+
+fn connect(network: &LoopbackNetwork, my_addr: SocketAddr, target_addr: SocketAddr, settings: ConnectionConfig<Vec<u8>>, client_settings: ClientConnectionConfig) -> NetResult<Client<Vec<u8>, LoopbackTransport>> {
+    Client::connect_with(try!(network.bind(my_addr)), target_addr, settings, client_settings)
 }
 
 macro_rules! with_bound_socket {
-    ($socket:ident, ($variable:ident)$code:block) => (
-        Thread::spawn(move || {
-            match UdpSocket::bind($socket) {
-                Ok(mut $variable) => $code,
-                Err(e) => panic!(e)
-            }
-        });
+    ($network:ident, $socket:ident, ($variable:ident)$code:block) => (
+        //Bind before spawning, so the client can't beat us to it
+        match $network.bind($socket) {
+            Ok(mut $variable) => {
+                Thread::spawn(move || $code);
+            },
+            Err(e) => panic!(e)
+        }
     )
 }
 
@@ -36,12 +48,12 @@ macro_rules! with_bound_socket {
 #[test]
 fn connection_ignored() {
     let port = 65000;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(_) => panic!("Reported connected when there is no server!"),
         Err(e) => {
-            assert!(e.desc == "Failed to connect")
+            assert!(e == Error::TimedOut)
         }
     };
 }
@@ -52,15 +64,15 @@ fn connection_ignored() {
 #[test]
 fn standard_connection() {
     let port = 65001;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(1000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Failed to send accept packet");
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(_) => {
             //Success!
         },
@@ -74,18 +86,18 @@ fn standard_connection() {
 #[test]
 fn connection_different_protocol_id() {
     let port = 65002;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(1000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(122, 0).serialize().unwrap().as_slice(), src).ok().expect("Failed to send accept packet");
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(_) => panic!("Connected to a server with a different protocol ID!"),
         Err(e) => {
-            assert!(e.desc == "Failed to connect")
+            assert!(e == Error::TimedOut)
         }
     };
 }
@@ -96,18 +108,18 @@ fn connection_different_protocol_id() {
 #[test]
 fn connection_rejected() {
     let port = 65003;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(1000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::reject(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Failed to send reject packet");
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(_) => panic!("Connected to a server that rejected us!"),
         Err(e) => {
-            assert!(e.desc == "Failed to connect")
+            assert!(e == Error::Rejected(String::new()))
         }
     };
 }
@@ -119,12 +131,12 @@ fn connection_rejected() {
 #[test]
 fn different_retry_count() {
     let port = 65004;
-    let (my_addr, target_addr, settings, mut client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, mut client_settings) = generate_settings(port, 121);
     client_settings.max_connect_retries = 6;
     client_settings.connect_attempt_timeout = Duration::milliseconds(100);
 
     let (tx, rx) = channel();
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let mut attempts = 0u8;
         while attempts < 3 {
@@ -135,7 +147,7 @@ fn different_retry_count() {
         tx.send(attempts);
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(_) => (),
         Err(_) => ()
     };
@@ -151,15 +163,15 @@ fn different_retry_count() {
 #[test]
 fn empty_polling() {
     let port = 65005;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(1000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Failed to send accept packet");
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(ref mut client) => {
             assert!(match client.poll() { Err(PollFailResult::Empty) => true, _ => false});
         },
@@ -173,16 +185,16 @@ fn empty_polling() {
 #[test]
 fn single_item_polling() {
     let port = 65006;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
         socket.send_to(Packet::message(121, 1, vec![1]).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(ref mut client) => {
             //May have to wait a bit
             //FIXME: There must be a better way of doing this
@@ -204,9 +216,9 @@ fn single_item_polling() {
 #[test]
 fn multiple_item_polling() {
     let port = 65007;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
@@ -217,7 +229,7 @@ fn multiple_item_polling() {
 
     let mut packets: Vec<Vec<u8>> = vec![];
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(ref mut client) => {
             //FIXME: There must be a better way of doing this
             Timer::new().unwrap().sleep(Duration::seconds(1));
@@ -244,9 +256,9 @@ fn multiple_item_polling() {
 #[test]
 fn ignore_bad_queue_items_polling() {
     let port = 65008;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
@@ -257,7 +269,7 @@ fn ignore_bad_queue_items_polling() {
 
     let mut packets: Vec<Vec<u8>> = vec![];
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(ref mut client) => {
             //FIXME: There must be a better way of doing this
             Timer::new().unwrap().sleep(Duration::seconds(1));
@@ -283,16 +295,16 @@ fn ignore_bad_queue_items_polling() {
 #[test]
 fn disconnection() {
     let port = 65008;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
         socket.send_to(Packet::disconnect(121, 1).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(ref mut client) => {
             //FIXME: There must be a better way of doing this
             Timer::new().unwrap().sleep(Duration::seconds(1));
@@ -313,26 +325,23 @@ fn disconnection() {
 #[test]
 fn timeout() {
     let port = 65009;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, mut settings, client_settings) = generate_settings(port, 121);
+    let clock = ManualClock::new(0);
+    settings.clock = clock.shared();
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
         //Don't send any more data
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(ref mut client) => {
-            //FIXME: There must be a better way of doing this
-            Timer::new().unwrap().sleep(Duration::seconds(1));
-            loop {
-                match client.poll() { 
-                    Err(PollFailResult::Disconnected) => break,
-                    Err(PollFailResult::Empty) => (),
-                    _ => panic!("Unexpected result")
-                };
-            }
+            clock.advance(9999);
+            assert!(match client.poll() { Err(PollFailResult::Empty) => true, _ => false });
+            clock.advance(2);
+            assert!(match client.poll() { Err(PollFailResult::Disconnected) => true, _ => false });
         },
         Err(e) => panic!("{}", e)
     };
@@ -344,11 +353,11 @@ fn timeout() {
 #[test]
 fn send_correct_handshake() {
     let port = 65010;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
     let (tx, rx) = channel();
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (msg, src) = test_shared::get_message(&mut socket);
         //Check what's been sent
@@ -357,7 +366,7 @@ fn send_correct_handshake() {
         tx.send(packet);
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(_) => (),
         Err(_) => ()
     };
@@ -374,11 +383,11 @@ fn send_correct_handshake() {
 #[test]
 fn send_data() {
     let port = 65011;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
     let (tx, rx) = channel();
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
@@ -388,9 +397,9 @@ fn send_data() {
         tx.send(packet);
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(ref mut socket) => {
-            socket.send(&vec![1, 2, 3]);
+            socket.send(&vec![1, 2, 3]).ok().expect("Couldn't send a message");
         },
         Err(_) => ()
     };
@@ -407,11 +416,11 @@ fn send_data() {
 #[test]
 fn client_disconnect() {
     let port = 65012;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
     let (tx, rx) = channel();
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
@@ -421,7 +430,7 @@ fn client_disconnect() {
         tx.send(packet);
     });
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(_) => (),
         Err(_) => ()
     };
@@ -438,9 +447,9 @@ fn client_disconnect() {
 #[test]
 fn out_of_sequence() {
     let port = 65013;
-    let (my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
 
-    with_bound_socket!(target_addr, (socket) {
+    with_bound_socket!(network, target_addr, (socket) {
         socket.set_timeout(Some(10000));
         let (_, src) = test_shared::get_message(&mut socket);
         socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
@@ -451,7 +460,7 @@ fn out_of_sequence() {
 
     let mut packets: Vec<Vec<u8>> = vec![];
 
-    match Client::connect(my_addr, target_addr, settings, client_settings) {
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
         Ok(ref mut client) => {
             //FIXME: There must be a better way of doing this
             Timer::new().unwrap().sleep(Duration::seconds(1));
@@ -470,3 +479,302 @@ fn out_of_sequence() {
     assert!(packets[0] == vec![1]);
     assert!(packets[1] == vec![3]);
 }
+
+/**
+ * A server's reason for rejecting us should be passed along
+ */
+#[test]
+fn connection_rejected_with_reason() {
+    let port = 65014;
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(1000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::reject_because(121, 0, "Server full").serialize().unwrap().as_slice(), src).ok().expect("Failed to send reject packet");
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(_) => panic!("Connected to a server that rejected us!"),
+        Err(e) => {
+            assert!(e == Error::Rejected("Server full".to_string()))
+        }
+    };
+}
+
+/**
+ * Packets which can't fit into a datagram shouldn't be sent
+ */
+#[test]
+fn payload_too_large() {
+    let port = 65015;
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(1000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Failed to send accept packet");
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(ref mut client) => {
+            assert!(client.send(&vec![0; MAX_PACKET_SIZE]) == Err(Error::PayloadTooLarge));
+        },
+        Err(e) => panic!(e)
+    };
+}
+
+/**
+ * Once the server has hung up, we shouldn't be able to send any more
+ */
+#[test]
+fn send_after_disconnection() {
+    let port = 65016;
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(10000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        socket.send_to(Packet::disconnect(121, 1).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(ref mut client) => {
+            //FIXME: There must be a better way of doing this
+            Timer::new().unwrap().sleep(Duration::seconds(1));
+            assert!(match client.poll() { Err(PollFailResult::Disconnected) => true, _ => false });
+            assert!(client.send(&vec![1]) == Err(Error::NotConnected));
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * Waiting should return as soon as a message arrives, and another thread should be able to end a wait
+ */
+#[test]
+fn poll_timeout_and_wake() {
+    let port = 65017;
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(10000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        Timer::new().unwrap().sleep(Duration::milliseconds(300));
+        socket.send_to(Packet::message(121, 1, vec![1]).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(ref mut client) => {
+            let started = precise_time_ns();
+            assert!(match client.poll_timeout(Duration::milliseconds(100)) { Err(PollFailResult::Empty) => true, _ => false });
+            assert!(precise_time_ns() - started >= 100000000);
+
+            match client.poll_timeout(Duration::seconds(5)) {
+                Ok(packet) => assert!(packet == vec![1]),
+                Err(_) => panic!("Couldn't match a polled message!")
+            };
+            assert!(precise_time_ns() - started < 2000000000);
+
+            let waker = client.waker();
+            Thread::spawn(move || {
+                Timer::new().unwrap().sleep(Duration::milliseconds(100));
+                waker.wake();
+            });
+            let started = precise_time_ns();
+            assert!(match client.wait() { Err(PollFailResult::Empty) => true, _ => false });
+            assert!(precise_time_ns() - started < 2000000000);
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * Shutting down should send everything queued, then say goodbye, before returning
+ */
+#[test]
+fn shutdown() {
+    let port = 65018;
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (tx, rx) = channel();
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(10000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        let (message, _) = test_shared::get_message(&mut socket);
+        let (goodbye, _) = test_shared::get_message(&mut socket);
+        tx.send((Packet::deserialize(message.as_slice()).unwrap().packet_type, Packet::deserialize(goodbye.as_slice()).unwrap().packet_type)).unwrap();
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(mut client) => {
+            client.send(&vec![1]).ok().expect("Couldn't send");
+            assert!(client.shutdown() == Ok(()));
+            assert!(rx.recv().unwrap() == (PacketType::Message, PacketType::Disconnect));
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * Anything arriving beyond the inbound limit should be dropped and counted
+ */
+#[test]
+fn bounded_inbound_queue() {
+    let port = 65019;
+    let (network, my_addr, target_addr, mut settings, client_settings) = generate_settings(port, 121);
+    settings.inbound_queue = Some(QueueLimit::new(1, QueuePolicy::DropNewest));
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(10000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        //Give the accept time to be taken, so it doesn't take up the queue's only place
+        Timer::new().unwrap().sleep(Duration::milliseconds(200));
+        for i in (1..4) {
+            socket.send_to(Packet::message(121, i, vec![i as u8]).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        }
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(ref mut client) => {
+            //FIXME: There must be a better way of doing this
+            Timer::new().unwrap().sleep(Duration::seconds(1));
+            let stats = client.queue_stats();
+            assert!(stats.inbound == 1 && stats.inbound_dropped == 2);
+            assert!(match client.poll() { Ok(packet) => packet == vec![1], _ => false });
+            assert!(client.queue_stats().inbound == 0);
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * Senders split off a Client should share its sequence numbers, from whichever thread they're used
+ */
+#[test]
+fn split() {
+    let port = 65020;
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+    let (tx, rx) = channel();
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(10000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        let mut sequence_ids = vec![];
+        for _ in (0..2) {
+            let (message, _) = test_shared::get_message(&mut socket);
+            sequence_ids.push(Packet::deserialize(message.as_slice()).unwrap().sequence_id);
+        }
+        socket.send_to(Packet::message(121, 1, vec![7]).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        tx.send(sequence_ids).unwrap();
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(client) => {
+            let (sender, mut receiver) = client.split();
+            let other = sender.clone();
+            let (sent_tx, sent_rx) = channel();
+            Thread::spawn(move || {
+                sent_tx.send(other.send(&vec![1])).unwrap();
+            });
+            assert!(sent_rx.recv().unwrap() == Ok(()));
+            sender.send(&vec![2]).ok().expect("Couldn't send");
+
+            let mut sequence_ids = rx.recv().unwrap();
+            sequence_ids.sort();
+            assert!(sequence_ids[0] != sequence_ids[1]);
+            assert!(match receiver.poll_timeout(Duration::seconds(5)) { Ok(packet) => packet == vec![7], _ => false });
+
+            assert!(receiver.shutdown() == Ok(()));
+            assert!(sender.send(&vec![3]).is_err());
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * Shutting down shouldn't wait forever on a reader blocked by a full inbound queue
+ */
+#[test]
+fn shutdown_with_full_blocking_queue() {
+    let port = 65021;
+    let (network, my_addr, target_addr, mut settings, client_settings) = generate_settings(port, 121);
+    settings.inbound_queue = Some(QueueLimit::new(1, QueuePolicy::Block));
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(10000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        //Give the accept time to be taken, so the messages fill the queue
+        Timer::new().unwrap().sleep(Duration::milliseconds(200));
+        for i in (1..4) {
+            socket.send_to(Packet::message(121, i, vec![i as u8]).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        }
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(client) => {
+            //FIXME: There must be a better way of doing this
+            Timer::new().unwrap().sleep(Duration::seconds(1));
+            assert!(client.queue_stats().inbound == 1);
+            let (tx, rx) = channel();
+            Thread::spawn(move || {
+                tx.send(client.shutdown()).unwrap();
+            });
+            let mut timer = Timer::new().unwrap();
+            let timeout = timer.oneshot(Duration::seconds(5));
+            let sel = Select::new();
+            let mut finished = sel.handle(&rx);
+            let mut timed_out = sel.handle(&timeout);
+            unsafe { finished.add(); timed_out.add(); }
+            assert!(sel.wait() == finished.id());
+            assert!(rx.recv().unwrap() == Ok(()));
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * An empty datagram could come from anyone, so shouldn't be taken as the server hanging up
+ */
+#[test]
+fn empty_datagram_not_hang_up() {
+    let port = 65022;
+    let (network, my_addr, target_addr, settings, client_settings) = generate_settings(port, 121);
+
+    with_bound_socket!(network, target_addr, (socket) {
+        socket.set_timeout(Some(10000));
+        let (_, src) = test_shared::get_message(&mut socket);
+        socket.send_to(Packet::accept(121, 0).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+        socket.send_to(&[], src).ok().expect("Couldn't send a message");
+        socket.send_to(Packet::message(121, 1, vec![1]).serialize().unwrap().as_slice(), src).ok().expect("Couldn't send a message");
+    });
+
+    match connect(&network, my_addr, target_addr, settings, client_settings) {
+        Ok(ref mut client) => {
+            assert!(match client.poll_timeout(Duration::seconds(5)) { Ok(packet) => packet == vec![1], _ => false });
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * Without threads of its own, a Client would wait forever on a full queue set to block
+ */
+#[test]
+fn unthreaded_refuses_blocking_queues() {
+    let port = 65023;
+    let (network, my_addr, target_addr, mut settings, client_settings) = generate_settings(port, 121);
+    settings.outbound_queue = Some(QueueLimit::new(1, QueuePolicy::Block));
+
+    match Client::connect_unthreaded(network.bind(my_addr).unwrap(), target_addr, settings, client_settings) {
+        Err(Error::InvalidConfig(_)) => (),
+        Ok(_) => panic!("Pumped a blocking queue"),
+        Err(e) => panic!("{}", e)
+    };
+}
@@ -0,0 +1,205 @@
+use conditioner::{LinkConditions, LinkConditioner, LinkModel};
+use loopback::{LoopbackNetwork, LoopbackTransport};
+use transport::Transport;
+use clock::ManualClock;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::sync::mpsc::channel;
+
+/**
+ * A sender and receiver on their own network
+ */
+fn link() -> (LoopbackTransport, LoopbackTransport, SocketAddr) {
+    let network = LoopbackNetwork::new();
+    let any_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let sender = network.bind(any_addr).ok().expect("Couldn't bind");
+    let mut receiver = network.bind(any_addr).ok().expect("Couldn't bind");
+    let receiver_addr = receiver.local_addr().unwrap();
+    (sender, receiver, receiver_addr)
+}
+
+/**
+ * Send numbered packets, and see which come out of the other end
+ */
+fn send_through(conditions: LinkConditions, count: u8, wait_ms: u64) -> Vec<u8> {
+    let (sender, receiver, receiver_addr) = link();
+    let mut sender = LinkConditioner::new(sender, Some(conditions));
+    let mut receiver = LinkConditioner::new(receiver, None);
+
+    for i in (0..count) {
+        sender.send_to(&[i], receiver_addr).ok().expect("Couldn't send");
+    }
+
+    let mut received = vec![];
+    let mut buf = [0; 16];
+    receiver.set_timeout(Some(wait_ms));
+    loop {
+        match receiver.recv_from(&mut buf) {
+            Ok((_, _)) => received.push(buf[0]),
+            Err(_) => break
+        }
+    }
+    received
+}
+
+/**
+ * Without conditions, nothing should change
+ */
+#[test]
+fn passthrough() {
+    assert!(send_through(LinkConditions::perfect(), 10, 50) == (0..10).collect::<Vec<u8>>());
+}
+
+/**
+ * Total loss should lose everything
+ */
+#[test]
+fn total_loss() {
+    let conditions = LinkConditions { loss: 1.0, ..LinkConditions::perfect() };
+    assert!(send_through(conditions, 10, 50).len() == 0);
+}
+
+/**
+ * A burst should take out packets in a row
+ */
+#[test]
+fn burst_loss() {
+    let conditions = LinkConditions { burst_chance: 1.0, burst_length: 3, ..LinkConditions::perfect() };
+    //Every packet either starts a burst or is part of one
+    assert!(send_through(conditions, 9, 50).len() == 0);
+}
+
+/**
+ * Duplication should deliver everything twice
+ */
+#[test]
+fn duplication() {
+    let conditions = LinkConditions { duplicate: 1.0, ..LinkConditions::perfect() };
+    assert!(send_through(conditions, 5, 50) == vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4]);
+}
+
+/**
+ * Packets shouldn't arrive before the latency has passed
+ */
+#[test]
+fn latency() {
+    let (sender, receiver, receiver_addr) = link();
+    let mut sender = LinkConditioner::new(sender, Some(LinkConditions { latency_ms: 300, ..LinkConditions::perfect() }));
+    let mut receiver = receiver;
+
+    sender.send_to(&[1], receiver_addr).ok().expect("Couldn't send");
+    receiver.set_timeout(Some(100));
+    assert!(receiver.recv_from(&mut [0; 16]).is_err());
+    receiver.set_timeout(Some(1000));
+    assert!(receiver.recv_from(&mut [0; 16]).is_ok());
+}
+
+/**
+ * Latency should be timed by the clock we're given, not the real time
+ */
+#[test]
+fn latency_by_clock() {
+    let (sender, receiver, receiver_addr) = link();
+    let clock = ManualClock::new(0);
+    let mut sender = LinkConditioner::with_clock(sender, Some(LinkConditions { latency_ms: 100, ..LinkConditions::perfect() }), clock.shared());
+    let mut receiver = receiver;
+
+    sender.send_to(&[1], receiver_addr).ok().expect("Couldn't send");
+    receiver.set_timeout(Some(300));
+    assert!(receiver.recv_from(&mut [0; 16]).is_err());
+    clock.advance(100);
+    receiver.set_timeout(Some(1000));
+    assert!(receiver.recv_from(&mut [0; 16]).is_ok());
+}
+
+/**
+ * Once the conditioner has gone, waiting on `finished` should wait for everything delayed to go out
+ */
+#[test]
+fn delay_thread_finishes() {
+    let (sender, receiver, receiver_addr) = link();
+    let mut sender = LinkConditioner::new(sender, Some(LinkConditions { latency_ms: 100, ..LinkConditions::perfect() }));
+    let mut receiver = receiver;
+    let (finished_send, finished) = channel::<()>();
+    sender.set_finished(finished_send);
+
+    sender.send_to(&[1], receiver_addr).ok().expect("Couldn't send");
+    drop(sender);
+    assert!(finished.recv().is_err());
+    receiver.set_timeout(Some(0));
+    assert!(receiver.recv_from(&mut [0; 16]).is_ok());
+}
+
+/**
+ * Conditions on the receiving side should apply to incoming packets too
+ */
+#[test]
+fn incoming_conditions() {
+    let (mut sender, receiver, receiver_addr) = link();
+    let mut receiver = LinkConditioner::new(receiver, Some(LinkConditions { duplicate: 1.0, ..LinkConditions::perfect() }));
+
+    sender.send_to(&[7], receiver_addr).ok().expect("Couldn't send");
+    receiver.set_timeout(Some(100));
+    assert!(receiver.recv_from(&mut [0; 16]).is_ok());
+    assert!(receiver.recv_from(&mut [0; 16]).is_ok());
+    assert!(receiver.recv_from(&mut [0; 16]).is_err());
+}
+
+/**
+ * Reordered packets should be overtaken by later ones
+ */
+#[test]
+fn reordering() {
+    let conditions = LinkConditions { reorder: 1.0, reorder_delay_ms: 100, ..LinkConditions::perfect() };
+    let (sender, receiver, receiver_addr) = link();
+    let mut plain = sender.try_clone().ok().expect("Couldn't clone");
+    let mut sender = LinkConditioner::new(sender, Some(conditions));
+    let mut receiver = receiver;
+    let mut buf = [0; 16];
+
+    sender.send_to(&[1], receiver_addr).ok().expect("Couldn't send");
+    plain.send_to(&[2], receiver_addr).ok().expect("Couldn't send");
+    receiver.set_timeout(Some(1000));
+    receiver.recv_from(&mut buf).ok().expect("Nothing arrived");
+    assert!(buf[0] == 2);
+    receiver.recv_from(&mut buf).ok().expect("Nothing arrived");
+    assert!(buf[0] == 1);
+}
+
+/**
+ * The same seed should always give the same results
+ */
+#[test]
+fn reproducible() {
+    let conditions = LinkConditions { loss: 0.5, seed: 1234, ..LinkConditions::perfect() };
+    let first = send_through(conditions, 100, 50);
+    let second = send_through(conditions, 100, 50);
+    assert!(first.len() > 0 && first.len() < 100);
+    assert!(first == second);
+    assert!(send_through(LinkConditions { seed: 4321, ..conditions }, 100, 50) != first);
+}
+
+/**
+ * Every preset should be available by name
+ */
+#[test]
+fn presets() {
+    for name in ["perfect", "lan", "wifi", "bad_wifi", "bad_4g"].iter() {
+        assert!(LinkConditions::preset(*name).is_some());
+    }
+    assert!(LinkConditions::preset("carrier_pigeon").is_none());
+}
+
+/**
+ * Packets too small to take a millisecond each should still add up on a slow link
+ */
+#[test]
+fn small_packets_share_bandwidth() {
+    let mut model = LinkModel::new(LinkConditions { bandwidth: Some(10000), ..LinkConditions::perfect() }, 0);
+    let mut last = 0;
+    for _ in (0..100) {
+        last = model.schedule(5, 0)[0];
+    }
+    //500 bytes at 10000 bytes a second take 50ms to get through
+    assert!(last == 50);
+}
@@ -0,0 +1,126 @@
+use shared::ConnectionConfig;
+use server::Server;
+use discovery::{discover_with, DiscoveredServer, DISCOVERY_PORT, MAX_DISCOVERY_INFO};
+use loopback::{LoopbackNetwork, LoopbackTransport};
+use packet::{Packet, PacketType, MAX_PACKET_SIZE, HEADER_SIZE};
+use query::{MIN_QUERY_SIZE, DEFAULT_QUERIES_PER_ADDRESS};
+use transport::Transport;
+use error::Error;
+use tests::test_shared;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::time::duration::Duration;
+
+fn serve(network: &LoopbackNetwork, host: u8, protocol_id: u32, info: &str) -> Server<Vec<u8>, LoopbackTransport> {
+    let settings = ConnectionConfig::new(protocol_id, Duration::seconds(10), test_shared::deserializer, test_shared::serializer);
+    let transport = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, host), port: 7000 }).ok().expect("Couldn't bind the server");
+    let mut server = Server::with_transport(transport, settings).ok().expect("Couldn't start the server");
+    let responder = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, host), port: DISCOVERY_PORT }).ok().expect("Couldn't bind the responder");
+    server.enable_discovery_with(responder, info.as_bytes().to_vec()).ok().expect("Couldn't enable discovery");
+    server
+}
+
+fn search(network: &LoopbackNetwork) -> Vec<DiscoveredServer> {
+    let transport = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 99), port: 0 }).ok().expect("Couldn't bind");
+    let mut found = discover_with(transport, SocketAddr{ ip: Ipv4Addr(255, 255, 255, 255), port: DISCOVERY_PORT }, 121, Duration::milliseconds(200)).ok().expect("Couldn't search");
+    found.sort_by(|a, b| a.info.cmp(&b.info));
+    found
+}
+
+/**
+ * Every server with our protocol should answer, pointing us at its game port
+ */
+#[test]
+fn finds_servers() {
+    let network = LoopbackNetwork::new();
+    let _first = serve(&network, 1, 121, "alpha");
+    let _second = serve(&network, 2, 121, "bravo");
+    let _other_game = serve(&network, 3, 999, "charlie");
+
+    let found = search(&network);
+    assert!(found.len() == 2);
+    assert!(found[0].addr == SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7000 });
+    assert!(found[0].info == b"alpha".to_vec());
+    assert!(found[1].addr == SocketAddr{ ip: Ipv4Addr(127, 0, 0, 2), port: 7000 });
+    assert!(found[1].info == b"bravo".to_vec());
+    assert!(found[0].ping_ms < 200);
+}
+
+/**
+ * Nobody answering is not a failure
+ */
+#[test]
+fn finds_nothing() {
+    let network = LoopbackNetwork::new();
+    assert!(search(&network).len() == 0);
+}
+
+/**
+ * Changed info should be handed out straight away, and disabling should stop the answers
+ */
+#[test]
+fn info_and_disable() {
+    let network = LoopbackNetwork::new();
+    let mut server = serve(&network, 1, 121, "empty");
+
+    server.set_discovery_info(b"full".to_vec()).ok().expect("Couldn't change the info");
+    assert!(search(&network)[0].info == b"full".to_vec());
+
+    server.disable_discovery();
+    assert!(search(&network).len() == 0);
+}
+
+/**
+ * Info has to fit into a single packet
+ */
+#[test]
+fn info_too_large() {
+    let network = LoopbackNetwork::new();
+    let mut server = serve(&network, 1, 121, "");
+    assert!(server.set_discovery_info(vec![0; MAX_DISCOVERY_INFO + 1]) == Err(Error::PayloadTooLarge));
+    assert!(server.set_discovery_info(vec![0; MAX_DISCOVERY_INFO]).is_ok());
+}
+
+/**
+ * Send raw probes to a responder, counting the answers
+ */
+fn probe(network: &LoopbackNetwork, padding: usize, count: usize) -> usize {
+    let mut transport = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 98), port: 0 }).ok().expect("Couldn't bind");
+    let responder = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: DISCOVERY_PORT };
+    for i in (0..count) {
+        transport.send_to(Packet::discovery_probe(121, i as u16, padding).serialize().unwrap().as_slice(), responder).ok().expect("Couldn't send");
+    }
+    transport.set_timeout(Some(200));
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let mut answers = 0;
+    loop {
+        match transport.recv_from(&mut buf) {
+            Ok((amt, _)) => {
+                assert!(Packet::deserialize(buf.slice_to(amt)).unwrap().packet_type == PacketType::DiscoveryReply);
+                answers += 1;
+            },
+            Err(_) => return answers
+        }
+    }
+}
+
+/**
+ * A probe too small for the answer shouldn't get one, so the responder can't be used to flood anyone
+ */
+#[test]
+fn small_probe_ignored() {
+    let network = LoopbackNetwork::new();
+    let _server = serve(&network, 1, 121, "alpha");
+    assert!(probe(&network, 0, 1) == 0);
+    assert!(probe(&network, MIN_QUERY_SIZE - HEADER_SIZE, 1) == 1);
+}
+
+/**
+ * Any one address should only get so many answers a second
+ */
+#[test]
+fn probes_rate_limited() {
+    let network = LoopbackNetwork::new();
+    let _server = serve(&network, 1, 121, "alpha");
+    assert!(probe(&network, MIN_QUERY_SIZE - HEADER_SIZE, 10) == DEFAULT_QUERIES_PER_ADDRESS as usize);
+}
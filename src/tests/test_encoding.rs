@@ -0,0 +1,73 @@
+use encoding::{NetEncode, serializer, deserializer};
+use bits::{BitWriter, BitReader, BitResult};
+
+/**
+ * A type which takes up no room at all
+ */
+#[derive(PartialEq, Show)]
+struct Nothing;
+
+impl NetEncode for Nothing {
+    fn encode(&self, _: &mut BitWriter) -> BitResult<()> {
+        Ok(())
+    }
+
+    fn decode(_: &mut BitReader) -> BitResult<Nothing> {
+        Ok(Nothing)
+    }
+}
+
+fn round_trip<T: NetEncode>(value: &T) -> Option<T> {
+    serializer(value).and_then(|raw| deserializer(&raw))
+}
+
+/**
+ * Built in types should survive encoding
+ */
+#[test]
+fn primitives_round_trip() {
+    assert!(round_trip(&200u8) == Some(200u8));
+    assert!(round_trip(&-12345i16) == Some(-12345i16));
+    assert!(round_trip(&0xDEADBEEFu32) == Some(0xDEADBEEFu32));
+    assert!(round_trip(&-1i64) == Some(-1i64));
+    assert!(round_trip(&1.5f32) == Some(1.5f32));
+    assert!(round_trip(&-0.25f64) == Some(-0.25f64));
+    assert!(round_trip(&true) == Some(true));
+    assert!(round_trip(&"Hello".to_string()) == Some("Hello".to_string()));
+    assert!(round_trip(&vec![1u16, 2, 3]) == Some(vec![1u16, 2, 3]));
+    assert!(round_trip(&Some(4u8)) == Some(Some(4u8)));
+    assert!(round_trip(&None::<u8>) == Some(None::<u8>));
+}
+
+/**
+ * Options and bools should only take up a single bit
+ */
+#[test]
+fn compact_flags() {
+    assert!(serializer(&(None::<u32>)).unwrap().len() == 1);
+    //An 8 bit length, then 3 bits of data
+    assert!(serializer(&vec![true, false, true]).unwrap().len() == 2);
+}
+
+/**
+ * Truncated or oversized data shouldn't decode
+ */
+#[test]
+fn malformed_data() {
+    assert!(deserializer::<u32>(&vec![1, 2]).is_none());
+    assert!(deserializer::<u16>(&vec![1, 2, 3]).is_none());
+    assert!(deserializer::<String>(&vec![2, 0xFF, 0xFF]).is_none());
+    //A length far longer than the data available
+    assert!(deserializer::<Vec<u8>>(&vec![0xFF, 0xFF, 0x7F]).is_none());
+}
+
+/**
+ * A list of things taking no bits can be longer than the data left, but not without limit
+ */
+#[test]
+fn zero_bit_items() {
+    assert!(round_trip(&vec![Nothing, Nothing, Nothing]) == Some(vec![Nothing, Nothing, Nothing]));
+    let many: Vec<Nothing> = (0..1000).map(|_| Nothing).collect();
+    assert!(round_trip(&many).map(|items| items.len()) == Some(1000));
+    assert!(deserializer::<Vec<Nothing>>(&vec![0xFF, 0xFF, 0x7F]).is_none());
+}
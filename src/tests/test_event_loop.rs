@@ -0,0 +1,148 @@
+use event_loop::EventLoop;
+use client::{ClientConnectionConfig, PollFailResult, ConnectionState};
+use server::PacketOrCommand;
+use queue::{QueueLimit, QueuePolicy};
+use transport::Transport;
+use tests::test_shared;
+
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use std::sync::mpsc::channel;
+use time::precise_time_ns;
+
+/**
+ * A Server and Client sharing one loop should handshake and echo
+ */
+#[test]
+fn shared_loop_echo() {
+    let ev = EventLoop::new().ok().expect("Couldn't start the loop");
+    let any_port = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let mut server = ev.server(any_port, test_shared::settings()).ok().expect("Couldn't bind the server");
+    let target_addr = server.addr;
+    let mut client = ev.client(any_port, target_addr, test_shared::settings(), ClientConnectionConfig::new(3, Duration::seconds(1))).ok().expect("Couldn't bind the client");
+    assert!(client.connection_state == ConnectionState::Connecting);
+
+    let mut echoed = None;
+    for _ in (0..200) {
+        client.pump().ok().expect("Gave up connecting");
+        server.pump();
+        loop {
+            match server.poll() {
+                Some((PacketOrCommand::UserPacket(packet), src)) => {
+                    server.send_to(&packet, &src).ok().expect("Couldn't echo a packet");
+                },
+                Some(_) => (),
+                None => break
+            }
+        }
+        if client.connection_state == ConnectionState::Connected {
+            match client.poll() {
+                Ok(packet) => {
+                    echoed = Some(packet);
+                    break;
+                },
+                Err(PollFailResult::Empty) => (),
+                Err(_) => panic!("Unexpected failure")
+            }
+            if echoed.is_none() {
+                match client.send(&vec![4, 5, 6]) {
+                    _ => ()
+                }
+            }
+        }
+        ev.wait(Duration::milliseconds(50));
+    }
+    assert!(echoed == Some(vec![4, 5, 6]));
+}
+
+/**
+ * Waiting should end as soon as something arrives, rather than at the timeout
+ */
+#[test]
+fn wait_wakes() {
+    let ev = EventLoop::new().ok().expect("Couldn't start the loop");
+    let mut transport = ev.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }, None).ok().expect("Couldn't bind");
+    let addr = transport.local_addr().unwrap();
+
+    Thread::spawn(move || {
+        let mut timer = Timer::new().unwrap();
+        timer.sleep(Duration::milliseconds(100));
+        let mut socket = UdpSocket::bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).ok().expect("Couldn't bind the sender");
+        socket.send_to(&[1, 2, 3], addr).ok().expect("Couldn't send");
+    });
+
+    let started = precise_time_ns();
+    assert!(ev.wait(Duration::seconds(5)));
+    assert!(precise_time_ns() - started < 2000000000);
+
+    let mut buf = [0; 16];
+    transport.set_timeout(Some(1000));
+    let (amt, _) = transport.recv_from(&mut buf).ok().expect("Nothing was passed on");
+    assert!(buf.slice_to(amt) == [1, 2, 3]);
+
+    //Nothing new has arrived, so this should time out
+    assert!(!ev.wait(Duration::milliseconds(10)));
+}
+
+/**
+ * Stopping the loop should fail anyone blocked receiving, promptly
+ */
+#[test]
+fn shutdown_fails_receivers() {
+    let ev = EventLoop::new().ok().expect("Couldn't start the loop");
+    let mut transport = ev.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }, None).ok().expect("Couldn't bind");
+    transport.set_timeout(None);
+
+    let (tx, rx) = channel();
+    Thread::spawn(move || {
+        let mut buf = [0; 16];
+        tx.send(transport.recv_from(&mut buf).is_err()).unwrap();
+    });
+
+    let mut timer = Timer::new().unwrap();
+    timer.sleep(Duration::milliseconds(50));
+    let started = precise_time_ns();
+    drop(ev);
+    assert!(rx.recv().unwrap());
+    assert!(precise_time_ns() - started < 2000000000);
+}
+
+/**
+ * Datagrams nobody has picked up should be kept to the inbound limit, throwing away the oldest
+ */
+#[test]
+fn bounded_inbox() {
+    let ev = EventLoop::new().ok().expect("Couldn't start the loop");
+    let mut transport = ev.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }, Some(QueueLimit::new(1, QueuePolicy::DropOldest))).ok().expect("Couldn't bind");
+    let addr = transport.local_addr().unwrap();
+
+    let mut socket = UdpSocket::bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).ok().expect("Couldn't bind the sender");
+    for i in (1..4) {
+        socket.send_to(&[i], addr).ok().expect("Couldn't send");
+    }
+    for _ in (0..100) {
+        if transport.dropped() == 2 {
+            break;
+        }
+        ev.wait(Duration::milliseconds(50));
+    }
+    assert!(transport.dropped() == 2);
+
+    let mut buf = [0; 16];
+    transport.set_timeout(Some(0));
+    let (amt, _) = transport.recv_from(&mut buf).ok().expect("Nothing was passed on");
+    assert!(buf.slice_to(amt) == [3]);
+    assert!(transport.recv_from(&mut buf).is_err());
+}
+
+/**
+ * The loop can't wait for room in an inbox, so shouldn't be asked to
+ */
+#[test]
+fn refuses_blocking_inbox() {
+    let ev = EventLoop::new().ok().expect("Couldn't start the loop");
+    assert!(ev.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }, Some(QueueLimit::new(1, QueuePolicy::Block))).is_err());
+}
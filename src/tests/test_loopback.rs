@@ -0,0 +1,105 @@
+use client::{ClientConnectionConfig, Client, PollFailResult};
+use server::{Server, PacketOrCommand};
+use loopback::LoopbackNetwork;
+use transport::Transport;
+use tests::test_shared;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+
+/**
+ * Port 0 should pick distinct ports, and bound addresses can't be reused until freed
+ */
+#[test]
+fn binding() {
+    let network = LoopbackNetwork::new();
+    let any_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let fixed_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 6666 };
+
+    let mut first = network.bind(any_addr).ok().expect("Couldn't bind");
+    let mut second = network.bind(any_addr).ok().expect("Couldn't bind");
+    assert!(first.local_addr().unwrap() != second.local_addr().unwrap());
+
+    {
+        let _fixed = network.bind(fixed_addr).ok().expect("Couldn't bind");
+        assert!(network.bind(fixed_addr).is_err());
+        assert!(network.is_bound(&fixed_addr));
+    }
+    assert!(!network.is_bound(&fixed_addr));
+}
+
+/**
+ * Datagrams should go to whoever is bound, and nowhere else
+ */
+#[test]
+fn datagrams() {
+    let network = LoopbackNetwork::new();
+    let any_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let nowhere = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 1 };
+
+    let mut first = network.bind(any_addr).ok().expect("Couldn't bind");
+    let mut second = network.bind(any_addr).ok().expect("Couldn't bind");
+    let second_addr = second.local_addr().unwrap();
+
+    first.send_to(&[1, 2, 3], nowhere).ok().expect("Sending to nobody should be silently dropped");
+    first.send_to(&[4, 5], second_addr).ok().expect("Couldn't send");
+
+    let (message, src) = test_shared::get_message(&mut second);
+    assert!(message == vec![4, 5]);
+    assert!(src == first.local_addr().unwrap());
+
+    second.set_timeout(Some(10));
+    assert!(second.recv_from(&mut [0; 16]).is_err());
+}
+
+/**
+ * A Client and Server should be able to talk in the same process without any sockets
+ */
+#[test]
+fn in_process_game() {
+    let network = LoopbackNetwork::new();
+    let server_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 6666 };
+    let server_transport = network.bind(server_addr).ok().expect("Couldn't bind the server");
+
+    Thread::spawn(move || {
+        match Server::with_transport(server_transport, test_shared::settings()) {
+            Ok(ref mut server) => {
+                let mut timer = Timer::new().unwrap();
+                loop {
+                    match server.poll() {
+                        Some((PacketOrCommand::UserPacket(packet), src)) => {
+                            server.send_to(&packet, &src).ok().expect("Couldn't echo a packet");
+                            break;
+                        },
+                        Some(_) => (),
+                        None => timer.sleep(Duration::milliseconds(10))
+                    }
+                }
+            },
+            Err(e) => panic!("Failed to create a server - {}", e)
+        }
+    });
+
+    let client_transport = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).ok().expect("Couldn't bind the client");
+    match Client::connect_with(client_transport, server_addr, test_shared::settings(), ClientConnectionConfig::new(3, Duration::seconds(5))) {
+        Ok(ref mut client) => {
+            client.send(&vec![9]).ok().expect("Couldn't send a packet");
+            let mut timer = Timer::new().unwrap();
+            let mut received = None;
+            for _ in (0..100) {
+                match client.poll() {
+                    Ok(packet) => {
+                        received = Some(packet);
+                        break;
+                    },
+                    Err(PollFailResult::Empty) => timer.sleep(Duration::milliseconds(10)),
+                    Err(_) => panic!("Unexpected failure")
+                }
+            }
+            assert!(received == Some(vec![9]));
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
@@ -0,0 +1,215 @@
+use master::{MasterServer, MasterMessage, MasterEvent, ServerListing, ListedServer, ListFilter, register_with, fetch_server_list_with, MASTER_PROTOCOL_ID};
+use encoding::{serializer, deserializer};
+use shared::ConnectionConfig;
+use client::{Client, ClientConnectionConfig};
+use loopback::{LoopbackNetwork, LoopbackTransport};
+
+use std::old_io::net::ip::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use std::sync::mpsc::{Sender, channel};
+
+fn master_addr() -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 27950 }
+}
+
+fn listing(name: &str, map: &str, players: u16) -> ServerListing {
+    ServerListing {
+        protocol_id: 121,
+        port: 7000,
+        name: name.to_string(),
+        map: map.to_string(),
+        players: players,
+        max_players: 8
+    }
+}
+
+fn bind(network: &LoopbackNetwork, host: u8) -> LoopbackTransport {
+    network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, host), port: 0 }).ok().expect("Couldn't bind")
+}
+
+/**
+ * Run a master in the background for a while, reporting everything that happened
+ */
+fn run_master(network: &LoopbackNetwork, expiry: Duration, run_for: Duration, events: Sender<MasterEvent>) {
+    let mut master = MasterServer::with_transport(network.bind(master_addr()).unwrap(), expiry).ok().expect("Couldn't start the master");
+    Thread::spawn(move || {
+        let mut timer = Timer::new().unwrap();
+        for _ in (0..run_for.num_milliseconds() / 10) {
+            for event in master.poll().into_iter() {
+                match events.send(event) {
+                    _ => ()
+                }
+            }
+            timer.sleep(Duration::milliseconds(10));
+        }
+    });
+}
+
+fn fetch(network: &LoopbackNetwork, filter: ListFilter) -> Vec<ListedServer> {
+    let mut found = fetch_server_list_with(bind(network, 50), master_addr(), filter, Duration::seconds(1)).ok().expect("Couldn't fetch the list");
+    found.sort_by(|a, b| a.listing.name.cmp(&b.listing.name));
+    found
+}
+
+/**
+ * Every message should survive the trip
+ */
+#[test]
+fn messages_round_trip() {
+    let messages = vec![
+        MasterMessage::Register(listing("alpha", "dm_arena", 3)),
+        MasterMessage::Heartbeat,
+        MasterMessage::Unregister,
+        MasterMessage::List(ListFilter { map: Some("ctf_2fort".to_string()), not_full: true, ..ListFilter::new(121) }),
+        MasterMessage::Listing(1, 2, vec![
+            ListedServer { addr: SocketAddr{ ip: Ipv4Addr(10, 1, 2, 3), port: 7000 }, listing: listing("alpha", "dm_arena", 3) },
+            ListedServer { addr: SocketAddr{ ip: Ipv6Addr(0xfe80, 0, 0, 0, 0, 0, 0, 1), port: 7001 }, listing: listing("bravo", "ctf_2fort", 8) }
+        ])
+    ];
+    for message in messages.iter() {
+        assert!(deserializer::<MasterMessage>(&serializer(message).unwrap()) == Some(message.clone()));
+    }
+}
+
+/**
+ * Filters should pick out the servers asked for
+ */
+#[test]
+fn filters() {
+    let full = listing("full", "dm_arena", 8);
+    let empty = listing("empty", "ctf_2fort", 0);
+    assert!(ListFilter::new(121).matches(&full));
+    assert!(!ListFilter::new(999).matches(&full));
+    assert!(!ListFilter { not_full: true, ..ListFilter::new(121) }.matches(&full));
+    assert!(!ListFilter { not_empty: true, ..ListFilter::new(121) }.matches(&empty));
+    assert!(ListFilter { map: Some("ctf_2fort".to_string()), ..ListFilter::new(121) }.matches(&empty));
+    assert!(!ListFilter { map: Some("ctf_2fort".to_string()), ..ListFilter::new(121) }.matches(&full));
+}
+
+/**
+ * Registered servers should be listed where they registered from, and unlisted when they go
+ */
+#[test]
+fn register_and_list() {
+    let network = LoopbackNetwork::new();
+    let (events_send, events) = channel();
+    run_master(&network, Duration::seconds(10), Duration::seconds(3), events_send);
+
+    let alpha = register_with(bind(&network, 1), master_addr(), listing("alpha", "dm_arena", 3), Duration::seconds(1)).ok().expect("Couldn't register");
+    let _bravo = register_with(bind(&network, 2), master_addr(), listing("bravo", "ctf_2fort", 8), Duration::seconds(1)).ok().expect("Couldn't register");
+    let _other_game = register_with(bind(&network, 3), master_addr(), ServerListing { protocol_id: 999, ..listing("charlie", "dm_arena", 1) }, Duration::seconds(1)).ok().expect("Couldn't register");
+    for _ in (0..3) {
+        match events.recv().unwrap() {
+            MasterEvent::Registered(_) => (),
+            _ => panic!("Expected a registration")
+        }
+    }
+
+    let found = fetch(&network, ListFilter::new(121));
+    assert!(found.len() == 2);
+    assert!(found[0].addr == SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7000 });
+    assert!(found[0].listing.name.as_slice() == "alpha");
+    assert!(found[1].addr == SocketAddr{ ip: Ipv4Addr(127, 0, 0, 2), port: 7000 });
+
+    let found = fetch(&network, ListFilter { not_full: true, ..ListFilter::new(121) });
+    assert!(found.len() == 1 && found[0].listing.name.as_slice() == "alpha");
+
+    alpha.update(listing("alpha", "dm_arena", 8));
+    let mut timer = Timer::new().unwrap();
+    timer.sleep(Duration::milliseconds(200));
+    assert!(fetch(&network, ListFilter { not_full: true, ..ListFilter::new(121) }).len() == 0);
+
+    drop(alpha);
+    timer.sleep(Duration::milliseconds(200));
+    assert!(fetch(&network, ListFilter::new(121)).len() == 1);
+}
+
+/**
+ * Servers which stop heartbeating should drop off the list
+ */
+#[test]
+fn expiry() {
+    let network = LoopbackNetwork::new();
+    let (events_send, events) = channel();
+    run_master(&network, Duration::milliseconds(300), Duration::seconds(2), events_send);
+
+    //A server which registers, then never says anything again
+    let config = ConnectionConfig::with_encoding(MASTER_PROTOCOL_ID, Duration::seconds(10));
+    let mut silent: Client<MasterMessage, LoopbackTransport> = Client::connect_with(bind(&network, 1), master_addr(), config, ClientConnectionConfig::new(3, Duration::seconds(1))).ok().expect("Couldn't connect");
+    silent.send(&MasterMessage::Register(listing("silent", "dm_arena", 0))).ok().expect("Couldn't register");
+
+    //And one which keeps heartbeating
+    let _chatty = register_with(bind(&network, 2), master_addr(), listing("chatty", "dm_arena", 0), Duration::milliseconds(100)).ok().expect("Couldn't register");
+
+    let mut expired = vec![];
+    for _ in (0..10) {
+        match events.recv() {
+            Ok(MasterEvent::Expired(addr)) => {
+                expired.push(addr);
+                break;
+            },
+            Ok(_) => (),
+            Err(_) => break
+        }
+    }
+    assert!(expired == vec![SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7000 }]);
+    let found = fetch(&network, ListFilter::new(121));
+    assert!(found.len() == 1 && found[0].listing.name.as_slice() == "chatty");
+}
+
+/**
+ * A long list should be split over several packets, and put back together
+ */
+#[test]
+fn long_list() {
+    let network = LoopbackNetwork::new();
+    let (events_send, events) = channel();
+    run_master(&network, Duration::seconds(10), Duration::seconds(5), events_send);
+
+    let mut registrations = vec![];
+    for i in (0..40u8) {
+        let name = format!("server {:02} with a fairly long name to fill up the packets", i);
+        registrations.push(register_with(bind(&network, 10 + i), master_addr(), listing(name.as_slice(), "dm_arena", 1), Duration::seconds(1)).ok().expect("Couldn't register"));
+    }
+    for _ in (0..40) {
+        events.recv().unwrap();
+    }
+
+    let found = fetch(&network, ListFilter::new(121));
+    assert!(found.len() == 40);
+}
+
+/**
+ * Lists should only be handed out so often to any one address
+ */
+#[test]
+fn lists_rate_limited() {
+    let network = LoopbackNetwork::new();
+    let mut master = MasterServer::with_transport(network.bind(master_addr()).unwrap(), Duration::seconds(10)).ok().expect("Couldn't start the master");
+    master.set_list_rate(1, 100);
+    let client_network = network.clone();
+    Thread::spawn(move || {
+        let config = ConnectionConfig::with_encoding(MASTER_PROTOCOL_ID, Duration::seconds(10));
+        let mut player: Client<MasterMessage, LoopbackTransport> = Client::connect_with(bind(&client_network, 1), master_addr(), config, ClientConnectionConfig::new(3, Duration::seconds(1))).ok().expect("Couldn't connect");
+        for _ in (0..3) {
+            player.send(&MasterMessage::List(ListFilter::new(121))).ok().expect("Couldn't ask for a list");
+        }
+        //Stay connected until the master is done
+        Timer::new().unwrap().sleep(Duration::seconds(2));
+    });
+
+    let mut timer = Timer::new().unwrap();
+    let mut listed = 0;
+    for _ in (0..100) {
+        for event in master.poll().into_iter() {
+            match event {
+                MasterEvent::Listed(..) => listed += 1,
+                _ => ()
+            }
+        }
+        timer.sleep(Duration::milliseconds(10));
+    }
+    assert!(listed == 1);
+}
@@ -0,0 +1,164 @@
+use server::{Server, PacketOrCommand};
+use client::{Client, ClientConnectionConfig, PollFailResult};
+use multi::{MultiTransport, ROUTE_IDLE_MS};
+use clock::ManualClock;
+use loopback::{LoopbackNetwork, LoopbackTransport};
+use transport::Transport;
+use tests::test_shared;
+
+use std::old_io::net::ip::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use std::sync::mpsc::{Sender, channel};
+
+/**
+ * Echo messages back until `echoes` have been sent, then report how many clients are connected
+ */
+fn echo_server<N: Transport>(mut server: Server<Vec<u8>, N>, echoes: usize, done: Sender<usize>) {
+    let mut timer = Timer::new().unwrap();
+    let mut sent = 0;
+    while sent < echoes {
+        match server.poll() {
+            Some((PacketOrCommand::UserPacket(packet), src)) => {
+                server.send_to(&packet, &src).ok().expect("Couldn't echo a packet");
+                sent += 1;
+            },
+            Some(_) => (),
+            None => timer.sleep(Duration::milliseconds(10))
+        }
+    }
+    done.send(server.all_connections().len()).ok().expect("Nobody waiting");
+}
+
+/**
+ * Send a message and wait for it to come back
+ */
+fn echo<N: Transport>(client: &mut Client<Vec<u8>, N>, message: u8) -> Option<Vec<u8>> {
+    client.send(&vec![message]).ok().expect("Couldn't send a packet");
+    let mut timer = Timer::new().unwrap();
+    for _ in (0..100) {
+        match client.poll() {
+            Ok(packet) => return Some(packet),
+            Err(PollFailResult::Empty) => timer.sleep(Duration::milliseconds(10)),
+            Err(_) => panic!("Unexpected failure")
+        }
+    }
+    None
+}
+
+/**
+ * There has to be something to listen on
+ */
+#[test]
+fn join_nothing() {
+    let transports: Vec<LoopbackTransport> = vec![];
+    assert!(MultiTransport::join(transports).is_err());
+}
+
+/**
+ * Every address should be reported, with the first as the main one
+ */
+#[test]
+fn local_addrs() {
+    let network = LoopbackNetwork::new();
+    let v4 = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7000 };
+    let v6 = SocketAddr{ ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1), port: 7000 };
+    let mut joined = MultiTransport::join(vec![network.bind(v4).unwrap(), network.bind(v6).unwrap()]).ok().expect("Couldn't join");
+    assert!(joined.local_addrs() == vec![v4, v6]);
+    assert!(joined.local_addr().unwrap() == v4);
+}
+
+/**
+ * Replies should leave through the address the peer sent to
+ */
+#[test]
+fn reply_route() {
+    let network = LoopbackNetwork::new();
+    let first = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7000 };
+    let second = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7001 };
+    let mut joined = MultiTransport::join(vec![network.bind(first).unwrap(), network.bind(second).unwrap()]).ok().expect("Couldn't join");
+    let mut peer = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).unwrap();
+    let mut buf = [0; 16];
+
+    peer.send_to(&[1], second).ok().expect("Couldn't send");
+    joined.set_timeout(Some(1000));
+    let (_, src) = joined.recv_from(&mut buf).ok().expect("Nothing arrived");
+    joined.send_to(&[2], src).ok().expect("Couldn't reply");
+
+    peer.set_timeout(Some(1000));
+    let (_, from) = peer.recv_from(&mut buf).ok().expect("No reply");
+    assert!(buf[0] == 2);
+    assert!(from == second);
+}
+
+/**
+ * Once a peer has been quiet long enough by the given clock, replies should go out through the first address again
+ */
+#[test]
+fn idle_route_forgotten() {
+    let network = LoopbackNetwork::new();
+    let clock = ManualClock::new(0);
+    let first = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7000 };
+    let second = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7001 };
+    let transports = vec![network.bind(first).unwrap(), network.bind(second).unwrap()];
+    let mut joined = MultiTransport::join_with_clock(transports, clock.shared()).ok().expect("Couldn't join");
+    let mut peer = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).unwrap();
+    let mut buf = [0; 16];
+
+    peer.send_to(&[1], second).ok().expect("Couldn't send");
+    joined.set_timeout(Some(1000));
+    let (_, src) = joined.recv_from(&mut buf).ok().expect("Nothing arrived");
+    clock.advance(ROUTE_IDLE_MS + 1);
+    joined.send_to(&[2], src).ok().expect("Couldn't reply");
+
+    peer.set_timeout(Some(1000));
+    let (_, from) = peer.recv_from(&mut buf).ok().expect("No reply");
+    assert!(from == first);
+}
+
+/**
+ * One server should hold IPv4 and IPv6 clients in the same connection table
+ */
+#[test]
+fn both_families() {
+    let network = LoopbackNetwork::new();
+    let v4 = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7000 };
+    let v6 = SocketAddr{ ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1), port: 7000 };
+    let joined = MultiTransport::join(vec![network.bind(v4).unwrap(), network.bind(v6).unwrap()]).ok().expect("Couldn't join");
+    let server = Server::with_transport(joined, test_shared::settings()).ok().expect("Couldn't start the server");
+    let (done_send, done) = channel();
+    Thread::spawn(move || echo_server(server, 2, done_send));
+
+    let v4_transport = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).unwrap();
+    let mut v4_client = Client::connect_with(v4_transport, v4, test_shared::settings(), ClientConnectionConfig::new(3, Duration::seconds(5))).ok().expect("IPv4 client couldn't connect");
+    let v6_transport = network.bind(SocketAddr{ ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1), port: 0 }).unwrap();
+    let mut v6_client = Client::connect_with(v6_transport, v6, test_shared::settings(), ClientConnectionConfig::new(3, Duration::seconds(5))).ok().expect("IPv6 client couldn't connect");
+
+    assert!(echo(&mut v4_client, 4) == Some(vec![4]));
+    assert!(echo(&mut v6_client, 6) == Some(vec![6]));
+    assert!(done.recv().unwrap() == 2);
+}
+
+/**
+ * The same again over real sockets
+ */
+#[test]
+fn both_families_udp() {
+    let any_v4 = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let any_v6 = SocketAddr{ ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1), port: 0 };
+    let server: Server<Vec<u8>, MultiTransport<UdpSocket>> = Server::bind_all(&[any_v4, any_v6], test_shared::settings()).ok().expect("Couldn't start the server");
+    let addrs = server.local_addrs();
+    let (v4, v6) = (addrs[0], addrs[1]);
+    assert!(v4.ip == any_v4.ip && v6.ip == any_v6.ip);
+    let (done_send, done) = channel();
+    Thread::spawn(move || echo_server(server, 2, done_send));
+
+    let mut v4_client = Client::connect(any_v4, v4, test_shared::settings(), ClientConnectionConfig::new(3, Duration::seconds(5))).ok().expect("IPv4 client couldn't connect");
+    let mut v6_client = Client::connect(any_v6, v6, test_shared::settings(), ClientConnectionConfig::new(3, Duration::seconds(5))).ok().expect("IPv6 client couldn't connect");
+
+    assert!(echo(&mut v4_client, 4) == Some(vec![4]));
+    assert!(echo(&mut v6_client, 6) == Some(vec![6]));
+    assert!(done.recv().unwrap() == 2);
+}
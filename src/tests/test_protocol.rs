@@ -0,0 +1,226 @@
+use protocol::{ClientProtocol, ServerProtocol, ClientEvent, ServerEvent, handshake_result};
+use client::ConnectionState;
+use packet::{Packet, PacketType};
+use error::Error;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::time::duration::Duration;
+
+fn server_addr() -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(10, 0, 0, 1), port: 7000 }
+}
+
+fn client_addr() -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(10, 0, 0, 2), port: 7001 }
+}
+
+/**
+ * Carry everything the client wants sent over to the server, returning what the server made of it
+ */
+fn client_to_server(client: &mut ClientProtocol, server: &mut ServerProtocol, now: u64) -> Vec<ServerEvent> {
+    let mut events = vec![];
+    for (data, addr) in client.drain_outgoing().into_iter() {
+        assert!(addr == server_addr());
+        match server.handle(data.as_slice(), client_addr(), now) {
+            Some(event) => events.push(event),
+            None => ()
+        }
+    }
+    events
+}
+
+fn server_to_client(server: &mut ServerProtocol, client: &mut ClientProtocol, now: u64) -> Vec<ClientEvent> {
+    let mut events = vec![];
+    for (data, addr) in server.drain_outgoing().into_iter() {
+        assert!(addr == client_addr());
+        match client.handle(data.as_slice(), server_addr(), now) {
+            Some(event) => events.push(event),
+            None => ()
+        }
+    }
+    events
+}
+
+fn connected_pair() -> (ClientProtocol, ServerProtocol) {
+    let mut client = ClientProtocol::new(121, server_addr(), Duration::seconds(10));
+    let mut server = ServerProtocol::new(121, Duration::seconds(10));
+    assert!(client.connect(3, Duration::milliseconds(500), 0).is_none());
+    assert!(client_to_server(&mut client, &mut server, 10) == vec![ServerEvent::Connected(client_addr())]);
+    assert!(server_to_client(&mut server, &mut client, 20) == vec![ClientEvent::Connected]);
+    (client, server)
+}
+
+/**
+ * A handshake should need nothing more than passing datagrams back and forth
+ */
+#[test]
+fn handshake() {
+    let (client, server) = connected_pair();
+    assert!(client.state() == ConnectionState::Connected);
+    assert!(server.connections() == vec![client_addr()]);
+}
+
+/**
+ * Connection attempts should go out on time, then be given up on
+ */
+#[test]
+fn handshake_retries() {
+    let mut client = ClientProtocol::new(121, server_addr(), Duration::seconds(10));
+    client.connect(3, Duration::milliseconds(500), 0);
+    assert!(client.drain_outgoing().len() == 1);
+    assert!(client.next_deadline() == Some(500));
+    assert!(client.update(499).is_none());
+    assert!(client.drain_outgoing().len() == 0);
+    assert!(client.update(500).is_none());
+    assert!(client.update(1000).is_none());
+    assert!(client.drain_outgoing().len() == 2);
+    assert!(client.update(1500) == Some(ClientEvent::TimedOut));
+    assert!(client.state() == ConnectionState::Disconnected);
+    assert!(client.next_deadline() == None);
+}
+
+/**
+ * A rejection should come with its reason
+ */
+#[test]
+fn rejected() {
+    let mut client = ClientProtocol::new(121, server_addr(), Duration::seconds(10));
+    client.connect(3, Duration::milliseconds(500), 0);
+    let reject = Packet::reject_because(121, 0, "Server full").serialize().unwrap();
+    assert!(client.handle(reject.as_slice(), server_addr(), 10) == Some(ClientEvent::Rejected("Server full".to_string())));
+    assert!(client.state() == ConnectionState::Disconnected);
+}
+
+/**
+ * A server hanging up before letting us in has turned us away, rather than never answered
+ */
+#[test]
+fn hung_up_while_connecting() {
+    let mut client = ClientProtocol::new(121, server_addr(), Duration::seconds(10));
+    client.connect(3, Duration::milliseconds(500), 0);
+    let disconnect = Packet::disconnect(121, 0).serialize().unwrap();
+    let event = client.handle(disconnect.as_slice(), server_addr(), 10);
+    assert!(event == Some(ClientEvent::Rejected(String::new())));
+    assert!(client.state() == ConnectionState::Disconnected);
+    assert!(match handshake_result(event.unwrap()) { Err(Error::Rejected(_)) => true, _ => false });
+
+    let mut client = ClientProtocol::new(121, server_addr(), Duration::seconds(10));
+    client.connect(3, Duration::milliseconds(500), 0);
+    assert!(client.hang_up(server_addr()) == Some(ClientEvent::Rejected(String::new())));
+    assert!(match handshake_result(ClientEvent::TimedOut) { Err(Error::TimedOut) => true, _ => false });
+}
+
+/**
+ * Strangers and other games should be ignored
+ */
+#[test]
+fn strangers_ignored() {
+    let mut client = ClientProtocol::new(121, server_addr(), Duration::seconds(10));
+    client.connect(3, Duration::milliseconds(500), 0);
+    let accept = Packet::accept(121, 0).serialize().unwrap();
+    let other_game = Packet::accept(999, 0).serialize().unwrap();
+    assert!(client.handle(accept.as_slice(), client_addr(), 10).is_none());
+    assert!(client.handle(other_game.as_slice(), server_addr(), 10).is_none());
+    assert!(client.handle(&[1, 2, 3], server_addr(), 10).is_none());
+    assert!(client.state() == ConnectionState::Connecting);
+
+    let mut server = ServerProtocol::new(121, Duration::seconds(10));
+    let connect = Packet::connect(999, 0).serialize().unwrap();
+    assert!(server.handle(connect.as_slice(), client_addr(), 10).is_none());
+    assert!(server.connection_count() == 0);
+    assert!(server.drain_outgoing().len() == 0);
+}
+
+/**
+ * Messages should arrive both ways, with stale ones dropped
+ */
+#[test]
+fn messages() {
+    let (mut client, mut server) = connected_pair();
+    client.send(vec![1]).ok().expect("Couldn't send");
+    assert!(client_to_server(&mut client, &mut server, 30) == vec![ServerEvent::Message(client_addr(), vec![1])]);
+
+    server.send(vec![2], client_addr()).ok().expect("Couldn't send");
+    server.send(vec![3], client_addr()).ok().expect("Couldn't send");
+    let mut outgoing = server.drain_outgoing();
+    outgoing.reverse();
+    let mut events = vec![];
+    for (data, _) in outgoing.into_iter() {
+        events.push(client.handle(data.as_slice(), server_addr(), 40));
+    }
+    assert!(events == vec![Some(ClientEvent::Message(vec![3])), None]);
+
+    assert!(server.send(vec![4], SocketAddr{ ip: Ipv4Addr(10, 0, 0, 3), port: 7001 }) == Err(Error::NotConnected));
+    assert!(client.send(vec![0; 2000]) == Err(Error::PayloadTooLarge));
+}
+
+/**
+ * A quiet server should be given up on, and a quiet client culled
+ */
+#[test]
+fn timeouts() {
+    let (mut client, mut server) = connected_pair();
+    assert!(client.update(10020).is_none());
+    assert!(client.update(10021) == Some(ClientEvent::Disconnected));
+
+    assert!(server.cull(10010).len() == 0);
+    assert!(server.cull(10011) == vec![client_addr()]);
+    assert!(server.connection_count() == 0);
+}
+
+/**
+ * Saying goodbye, or the transport hanging up, should end the connection on both sides
+ */
+#[test]
+fn disconnects() {
+    let (mut client, mut server) = connected_pair();
+    client.disconnect();
+    assert!(client.state() == ConnectionState::Disconnected);
+    assert!(client_to_server(&mut client, &mut server, 30) == vec![ServerEvent::Disconnected(client_addr())]);
+
+    let (mut client, mut server) = connected_pair();
+    assert!(server.hang_up(client_addr()) == Some(ServerEvent::Disconnected(client_addr())));
+    assert!(client.hang_up(server_addr()) == Some(ClientEvent::Disconnected));
+}
+
+/**
+ * An empty datagram is easily forged, so shouldn't end anything
+ */
+#[test]
+fn empty_datagram_ignored() {
+    let (mut client, mut server) = connected_pair();
+    assert!(server.handle(&[], client_addr(), 30) == None);
+    assert!(client.handle(&[], server_addr(), 30) == None);
+    assert!(server.connections().len() == 1);
+    assert!(client.state() == ConnectionState::Connected);
+}
+
+/**
+ * Queries should be reported, and only answered if the answer isn't too large
+ */
+#[test]
+fn queries() {
+    let mut server = ServerProtocol::new(121, Duration::seconds(10));
+    let query = Packet::query(121, 5, 50).serialize().unwrap();
+    let query_len = query.len();
+    assert!(server.handle(query.as_slice(), client_addr(), 0) == Some(ServerEvent::Query(client_addr(), 5, query_len)));
+    assert!(server.connection_count() == 0);
+
+    assert!(!server.answer_query(client_addr(), 5, query_len, vec![0; query_len * 3]));
+    assert!(server.answer_query(client_addr(), 5, query_len, vec![7; 10]));
+    let outgoing = server.drain_outgoing();
+    assert!(outgoing.len() == 1);
+    let reply = Packet::deserialize(outgoing[0].0.as_slice()).unwrap();
+    assert!(reply.packet_type == PacketType::QueryReply && reply.sequence_id == 5);
+}
+
+/**
+ * Shutting a server down should say goodbye to everyone
+ */
+#[test]
+fn disconnect_all() {
+    let (mut client, mut server) = connected_pair();
+    assert!(server.disconnect_all() == vec![client_addr()]);
+    assert!(server.connection_count() == 0);
+    assert!(server_to_client(&mut server, &mut client, 30) == vec![ClientEvent::Disconnected]);
+}
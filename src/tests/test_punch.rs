@@ -0,0 +1,284 @@
+use punch::{Introducer, punch_through, SESSION_EXPIRY_MS, MAX_SESSION_NAME};
+use server::{Server, PacketOrCommand};
+use client::{Client, ClientConnectionConfig, PollFailResult};
+use packet::Packet;
+use clock::ManualClock;
+use loopback::{LoopbackNetwork, LoopbackTransport};
+use transport::Transport;
+use error::Error;
+use tests::test_shared;
+
+use std::old_io::{IoResult, TimedOut};
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+
+/**
+ * Behaves like a router doing NAT: only lets in packets from addresses we've sent to first
+ */
+struct NatTransport {
+    inner: LoopbackTransport,
+    opened: Arc<Mutex<Vec<SocketAddr>>>
+}
+
+impl NatTransport {
+    fn bind(network: &LoopbackNetwork, host: u8) -> NatTransport {
+        NatTransport {
+            inner: network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, host), port: 0 }).unwrap(),
+            opened: Arc::new(Mutex::new(vec![]))
+        }
+    }
+}
+
+impl Transport for NatTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        let mut opened = self.opened.lock().unwrap();
+        if !opened.contains(&addr) {
+            opened.push(addr);
+        }
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        loop {
+            let (amt, src) = try!(self.inner.recv_from(buf));
+            if self.opened.lock().unwrap().contains(&src) {
+                return Ok((amt, src));
+            }
+        }
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.inner.set_timeout(timeout_ms);
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn try_clone(&self) -> IoResult<NatTransport> {
+        Ok(NatTransport {
+            inner: try!(self.inner.try_clone()),
+            opened: self.opened.clone()
+        })
+    }
+}
+
+fn introducer_addr() -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 27960 }
+}
+
+fn run_introducer(network: &LoopbackNetwork, steps: u32) {
+    let mut introducer = Introducer::with_transport(network.bind(introducer_addr()).unwrap(), 121);
+    Thread::spawn(move || {
+        for _ in (0..steps) {
+            match introducer.step(10) {
+                _ => ()
+            }
+        }
+    });
+}
+
+/**
+ * Two peers behind NAT should meet, punch through, and then play as normal
+ */
+#[test]
+fn introduced_and_connected() {
+    let network = LoopbackNetwork::new();
+    run_introducer(&network, 300);
+
+    let mut host = NatTransport::bind(&network, 2);
+    let host_addr = host.local_addr().unwrap();
+    let mut joiner = NatTransport::bind(&network, 3);
+    let joiner_addr = joiner.local_addr().unwrap();
+    let (found_send, found) = channel();
+
+    Thread::spawn(move || {
+        let peer = punch_through(&mut host, introducer_addr(), 121, "friday night", Duration::seconds(3)).ok().expect("Host couldn't punch through");
+        found_send.send(peer).unwrap();
+        let mut server = Server::with_transport(host, test_shared::settings()).ok().expect("Couldn't start the server");
+        let mut timer = Timer::new().unwrap();
+        for _ in (0..300) {
+            match server.poll() {
+                Some((PacketOrCommand::UserPacket(packet), src)) => {
+                    server.send_to(&packet, &src).ok().expect("Couldn't echo");
+                },
+                Some(_) => (),
+                None => timer.sleep(Duration::milliseconds(10))
+            }
+        }
+    });
+
+    let peer = punch_through(&mut joiner, introducer_addr(), 121, "friday night", Duration::seconds(3)).ok().expect("Joiner couldn't punch through");
+    assert!(peer == host_addr);
+    assert!(found.recv().unwrap() == joiner_addr);
+
+    let mut client = Client::connect_with(joiner, host_addr, test_shared::settings(), ClientConnectionConfig::new(5, Duration::milliseconds(200))).ok().expect("Couldn't connect after punching");
+    client.send(&vec![5]).ok().expect("Couldn't send");
+    let mut timer = Timer::new().unwrap();
+    let mut echoed = None;
+    for _ in (0..100) {
+        match client.poll() {
+            Ok(packet) => {
+                echoed = Some(packet);
+                break;
+            },
+            Err(PollFailResult::Empty) => timer.sleep(Duration::milliseconds(10)),
+            Err(_) => panic!("Unexpected failure")
+        }
+    }
+    assert!(echoed == Some(vec![5]));
+}
+
+/**
+ * Without punching, the host's NAT should turn the connection away
+ */
+#[test]
+fn blocked_without_punching() {
+    let network = LoopbackNetwork::new();
+    let mut host = NatTransport::bind(&network, 2);
+    let host_addr = host.local_addr().unwrap();
+    let _server = Server::with_transport(host, test_shared::settings()).ok().expect("Couldn't start the server");
+
+    let joiner = NatTransport::bind(&network, 3);
+    match Client::connect_with(joiner, host_addr, test_shared::settings(), ClientConnectionConfig::new(2, Duration::milliseconds(100))) {
+        Err(e) => assert!(e == Error::TimedOut),
+        Ok(_) => panic!("Got through a closed NAT")
+    }
+}
+
+/**
+ * Nobody else turning up should time out
+ */
+#[test]
+fn nobody_to_meet() {
+    let network = LoopbackNetwork::new();
+    run_introducer(&network, 30);
+    let mut lonely = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 2), port: 0 }).unwrap();
+    assert!(punch_through(&mut lonely, introducer_addr(), 121, "empty room", Duration::milliseconds(200)) == Err(Error::TimedOut));
+}
+
+/**
+ * Session names have to fit
+ */
+#[test]
+fn session_too_long() {
+    let network = LoopbackNetwork::new();
+    let mut transport = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 2), port: 0 }).unwrap();
+    let name: String = (0..MAX_SESSION_NAME + 1).map(|_| 'x').collect();
+    assert!(punch_through(&mut transport, introducer_addr(), 121, name.as_slice(), Duration::milliseconds(50)) == Err(Error::PayloadTooLarge));
+}
+
+/**
+ * Sessions nobody mentions should be forgotten
+ */
+#[test]
+fn sessions_expire() {
+    let network = LoopbackNetwork::new();
+    let clock = ManualClock::new(0);
+    let mut introducer = Introducer::with_transport(network.bind(introducer_addr()).unwrap(), 121);
+    introducer.set_clock(clock.shared());
+    let mut peer = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 2), port: 0 }).unwrap();
+
+    peer.send_to(Packet::introduce(121, "stale").serialize().unwrap().as_slice(), introducer_addr()).unwrap();
+    assert!(introducer.step(100) == Ok(None));
+    assert!(introducer.session_count() == 1);
+
+    clock.advance(SESSION_EXPIRY_MS + 1);
+    match introducer.step(10) {
+        Err(ref e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+    assert!(introducer.session_count() == 0);
+}
+
+/**
+ * The second peer to arrive should complete the pair
+ */
+#[test]
+fn pairs_reported() {
+    let network = LoopbackNetwork::new();
+    let mut introducer = Introducer::with_transport(network.bind(introducer_addr()).unwrap(), 121);
+    let mut first = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 2), port: 0 }).unwrap();
+    let mut second = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 3), port: 0 }).unwrap();
+    let introduce = Packet::introduce(121, "pair").serialize().unwrap();
+
+    first.send_to(introduce.as_slice(), introducer_addr()).unwrap();
+    assert!(introducer.step(100) == Ok(None));
+    second.send_to(introduce.as_slice(), introducer_addr()).unwrap();
+    assert!(introducer.step(100) == Ok(Some("pair".to_string())));
+
+    first.set_timeout(Some(100));
+    assert!(first.recv_from(&mut [0; 64]).is_ok());
+    match first.recv_from(&mut [0; 64]) {
+        Err(ref e) => assert!(e.kind == TimedOut),
+        Ok(_) => panic!("Introduced twice")
+    }
+}
+
+fn introduce(introducer: &mut Introducer<LoopbackTransport>, peer: &mut LoopbackTransport, name: &str) -> Option<String> {
+    peer.send_to(Packet::introduce(121, name).serialize().unwrap().as_slice(), introducer_addr()).unwrap();
+    introducer.step(100).unwrap()
+}
+
+/**
+ * Nobody should be able to fill an introducer with sessions, or keep someone else's alive
+ */
+#[test]
+fn session_limits() {
+    let network = LoopbackNetwork::new();
+    let clock = ManualClock::new(0);
+    let mut introducer = Introducer::with_transport(network.bind(introducer_addr()).unwrap(), 121);
+    introducer.set_clock(clock.shared());
+    introducer.set_session_limits(2, 1);
+    let mut peers: Vec<LoopbackTransport> = (2..6).map(|host| network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, host), port: 0 }).unwrap()).collect();
+
+    assert!(introduce(&mut introducer, &mut peers[0], "one") == None);
+    assert!(introduce(&mut introducer, &mut peers[0], "greedy") == None);
+    assert!(introduce(&mut introducer, &mut peers[1], "one") == Some("one".to_string()));
+    assert!(introduce(&mut introducer, &mut peers[2], "three") == None);
+    assert!(introduce(&mut introducer, &mut peers[3], "four") == None);
+    assert!(introducer.session_count() == 2);
+
+    //The session already has its pair, so a third party mentioning it isn't it being used
+    clock.advance(SESSION_EXPIRY_MS);
+    assert!(introduce(&mut introducer, &mut peers[2], "one") == None);
+    clock.advance(1);
+    assert!(introduce(&mut introducer, &mut peers[3], "four") == None);
+    assert!(introducer.session_count() == 1);
+}
+
+/**
+ * An introduction with an address of no family we know should be ignored, rather than misread
+ */
+#[test]
+fn unknown_address_family() {
+    let network = LoopbackNetwork::new();
+    let mut introducer = network.bind(introducer_addr()).unwrap();
+    let mut peer = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 3), port: 0 }).unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let (tx, rx) = channel();
+    let mut joiner = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 2), port: 0 }).unwrap();
+    Thread::spawn(move || {
+        tx.send(punch_through(&mut joiner, introducer_addr(), 121, "families", Duration::seconds(2))).unwrap();
+    });
+
+    let (_, joiner_addr) = test_shared::get_message(&mut introducer);
+    //Long enough to pass for an IPv6 address
+    let mut bogus = vec![9];
+    bogus.push_all(&[0; 18]);
+    introducer.send_to(Packet::introduction(121, bogus).serialize().unwrap().as_slice(), joiner_addr).unwrap();
+    let real = vec![4, 127, 0, 0, 3, (peer_addr.port >> 8) as u8, peer_addr.port as u8];
+    introducer.send_to(Packet::introduction(121, real).serialize().unwrap().as_slice(), joiner_addr).unwrap();
+
+    let punch = Packet::punch(121, 0).serialize().unwrap();
+    let mut timer = Timer::new().unwrap();
+    for _ in (0..40) {
+        peer.send_to(punch.as_slice(), joiner_addr).unwrap();
+        timer.sleep(Duration::milliseconds(20));
+    }
+    assert!(rx.recv().unwrap() == Ok(peer_addr));
+}
@@ -0,0 +1,138 @@
+use server::Server;
+use packet::{Packet, PacketType, MAX_PACKET_SIZE, HEADER_SIZE};
+use query::{query_server_with, QueryRequest, RateLimiter, MIN_QUERY_SIZE};
+use loopback::LoopbackNetwork;
+use sim::{Simulation, SimTransport};
+use transport::Transport;
+use error::Error;
+use tests::test_shared;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use std::sync::mpsc::channel;
+
+fn server_addr() -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(10, 0, 0, 1), port: 7000 }
+}
+
+/**
+ * Send a raw query through a simulated server, returning any answer
+ */
+fn ask(sim: &Simulation, server: &mut Server<Vec<u8>, SimTransport>, asker: &mut SimTransport, padding: usize) -> Option<Packet> {
+    asker.send_to(Packet::query(121, 5, padding).serialize().unwrap().as_slice(), server_addr()).ok().expect("Couldn't send");
+    sim.step(|_| {
+        server.pump();
+        while server.poll().is_some() {}
+        server.pump();
+    });
+    let mut buf = [0; MAX_PACKET_SIZE];
+    match asker.recv_from(&mut buf) {
+        Ok((amt, _)) => Some(Packet::deserialize(buf.slice_to(amt)).unwrap()),
+        Err(_) => None
+    }
+}
+
+/**
+ * A query should be answered with the handler's info, without anyone joining
+ */
+#[test]
+fn query_answered() {
+    let network = LoopbackNetwork::new();
+    let addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 7000 };
+    let mut server = Server::with_transport(network.bind(addr).unwrap(), test_shared::settings()).ok().expect("Couldn't start the server");
+    server.enable_queries(Box::new(|request: &QueryRequest| format!("dm_arena {}/8", request.connections).into_bytes()));
+    let (done_send, done) = channel();
+
+    Thread::spawn(move || {
+        let mut timer = Timer::new().unwrap();
+        for _ in (0..50) {
+            while server.poll().is_some() {}
+            timer.sleep(Duration::milliseconds(10));
+        }
+        done_send.send(server.all_connections().len()).unwrap();
+    });
+
+    let asker = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).unwrap();
+    let info = query_server_with(asker, addr, 121, Duration::seconds(1)).ok().expect("No answer");
+    assert!(info.info == b"dm_arena 0/8".to_vec());
+    assert!(info.ping_ms < 1000);
+    assert!(done.recv().unwrap() == 0);
+}
+
+/**
+ * Servers without a handler keep quiet
+ */
+#[test]
+fn query_ignored() {
+    let sim = Simulation::new(10);
+    let mut server = sim.server(server_addr(), test_shared::settings()).unwrap();
+    let mut asker = sim.bind(SocketAddr{ ip: Ipv4Addr(10, 0, 0, 2), port: 0 }, None).unwrap();
+    assert!(ask(&sim, &mut server, &mut asker, MIN_QUERY_SIZE).is_none());
+}
+
+/**
+ * Nobody answering should time out
+ */
+#[test]
+fn query_timeout() {
+    let network = LoopbackNetwork::new();
+    let asker = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).unwrap();
+    assert!(query_server_with(asker, server_addr(), 121, Duration::milliseconds(50)) == Err(Error::TimedOut));
+}
+
+/**
+ * Small queries shouldn't get large answers
+ */
+#[test]
+fn amplification() {
+    let sim = Simulation::new(10);
+    let mut server = sim.server(server_addr(), test_shared::settings()).unwrap();
+    server.enable_queries(Box::new(|_: &QueryRequest| vec![7; 100]));
+    let mut asker = sim.bind(SocketAddr{ ip: Ipv4Addr(10, 0, 0, 2), port: 0 }, None).unwrap();
+
+    assert!(ask(&sim, &mut server, &mut asker, 0).is_none());
+    match ask(&sim, &mut server, &mut asker, MIN_QUERY_SIZE - HEADER_SIZE) {
+        Some(packet) => {
+            assert!(packet.packet_type == PacketType::QueryReply);
+            assert!(packet.sequence_id == 5);
+            assert!(packet.packet_content == Some(vec![7; 100]));
+        },
+        None => panic!("A padded query should be answered")
+    }
+}
+
+/**
+ * Askers should only get so many answers a second
+ */
+#[test]
+fn rate_limited() {
+    let sim = Simulation::new(10);
+    let mut server = sim.server(server_addr(), test_shared::settings()).unwrap();
+    server.enable_queries(Box::new(|_: &QueryRequest| vec![1]));
+    server.set_query_rate(2, 100);
+    let mut asker = sim.bind(SocketAddr{ ip: Ipv4Addr(10, 0, 0, 2), port: 0 }, None).unwrap();
+    let mut other = sim.bind(SocketAddr{ ip: Ipv4Addr(10, 0, 0, 3), port: 0 }, None).unwrap();
+
+    assert!(ask(&sim, &mut server, &mut asker, MIN_QUERY_SIZE).is_some());
+    assert!(ask(&sim, &mut server, &mut asker, MIN_QUERY_SIZE).is_some());
+    assert!(ask(&sim, &mut server, &mut asker, MIN_QUERY_SIZE).is_none());
+    assert!(ask(&sim, &mut server, &mut other, MIN_QUERY_SIZE).is_some());
+
+    sim.run_for(1000, |_| ());
+    assert!(ask(&sim, &mut server, &mut asker, MIN_QUERY_SIZE).is_some());
+}
+
+/**
+ * The total limit should hold however many addresses ask
+ */
+#[test]
+fn rate_limited_total() {
+    let mut limiter = RateLimiter::new(5, 3);
+    for i in (0..3u8) {
+        assert!(limiter.allow(&Ipv4Addr(10, 0, 0, i), 0));
+    }
+    assert!(!limiter.allow(&Ipv4Addr(10, 0, 0, 4), 500));
+    assert!(limiter.allow(&Ipv4Addr(10, 0, 0, 4), 1000));
+}
@@ -0,0 +1,158 @@
+use queue::{QueuePolicy, QueueLimit, QueueSendError, SharedSender, queue};
+
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use std::sync::mpsc::channel;
+
+/**
+ * Without a limit, anything can be queued
+ */
+#[test]
+fn unbounded() {
+    let (send, receive) = queue(None);
+    for i in (0..100) {
+        assert!(send.send(i) == Ok(()));
+    }
+    assert!(send.depth() == 100);
+    assert!(receive.try_recv() == Ok(0));
+    assert!(receive.depth() == 99);
+    assert!(receive.dropped() == 0);
+}
+
+/**
+ * The oldest should make way for the newest
+ */
+#[test]
+fn drop_oldest() {
+    let (send, receive) = queue(Some(QueueLimit::new(2, QueuePolicy::DropOldest)));
+    for i in (0..5) {
+        assert!(send.send(i) == Ok(()));
+    }
+    assert!(receive.depth() == 2);
+    assert!(receive.dropped() == 3);
+    assert!(receive.try_recv() == Ok(3));
+    assert!(receive.try_recv() == Ok(4));
+    assert!(receive.try_recv().is_err());
+}
+
+/**
+ * The newest should be thrown away
+ */
+#[test]
+fn drop_newest() {
+    let (send, receive) = queue(Some(QueueLimit::new(2, QueuePolicy::DropNewest)));
+    for i in (0..5) {
+        assert!(send.send(i) == Ok(()));
+    }
+    assert!(receive.dropped() == 3);
+    assert!(receive.try_recv() == Ok(0));
+    assert!(receive.try_recv() == Ok(1));
+    assert!(receive.try_recv().is_err());
+}
+
+/**
+ * A full queue should refuse, until there's room again
+ */
+#[test]
+fn error() {
+    let (send, receive) = queue(Some(QueueLimit::new(1, QueuePolicy::Error)));
+    assert!(send.send(1) == Ok(()));
+    assert!(send.send(2) == Err(QueueSendError::Full));
+    assert!(receive.try_recv() == Ok(1));
+    assert!(send.send(3) == Ok(()));
+    assert!(receive.dropped() == 0);
+}
+
+/**
+ * A full queue should hold the sender up until there's room, or nobody is receiving
+ */
+#[test]
+fn block() {
+    let (send, receive) = queue(Some(QueueLimit::new(1, QueuePolicy::Block)));
+    let (tx, rx) = channel();
+    assert!(send.send(1) == Ok(()));
+    Thread::spawn(move || {
+        tx.send(send.send(2)).unwrap();
+        tx.send(send.send(3)).unwrap();
+    });
+
+    Timer::new().unwrap().sleep(Duration::milliseconds(100));
+    assert!(rx.try_recv().is_err());
+    assert!(receive.recv() == Ok(1));
+    assert!(rx.recv().unwrap() == Ok(()));
+
+    drop(receive);
+    assert!(rx.recv().unwrap() == Err(QueueSendError::Closed));
+}
+
+/**
+ * Closing a shared sender should close every copy of it, so the receiver sees nobody sending
+ */
+#[test]
+fn shared_close() {
+    let (send, receive) = queue(None);
+    let shared = SharedSender::new(send);
+    let copy = shared.clone();
+    assert!(copy.send(1) == Ok(()));
+    assert!(shared.depth() == 1);
+
+    shared.close();
+    assert!(copy.send(2) == Err(QueueSendError::Closed));
+    assert!(receive.recv() == Ok(1));
+    assert!(receive.recv().is_err());
+}
+
+/**
+ * Closing the receiving end should let go of a sender waiting for room, and refuse anything more
+ */
+#[test]
+fn close_releases_blocked_sender() {
+    let (send, receive) = queue(Some(QueueLimit::new(1, QueuePolicy::Block)));
+    let (tx, rx) = channel();
+    assert!(send.send(1) == Ok(()));
+    Thread::spawn(move || {
+        tx.send(send.send(2)).unwrap();
+    });
+
+    Timer::new().unwrap().sleep(Duration::milliseconds(100));
+    assert!(rx.try_recv().is_err());
+    receive.close();
+    assert!(rx.recv().unwrap() == Err(QueueSendError::Closed));
+    assert!(receive.try_recv() == Ok(1));
+}
+
+/**
+ * Turns should queue in the order they were taken, even when a later one gets there first
+ */
+#[test]
+fn turns_keep_order() {
+    let (send, receive) = queue(None);
+    let shared = SharedSender::new(send);
+    let copy = shared.clone();
+    let first = shared.turn();
+    let (tx, rx) = channel();
+    Thread::spawn(move || {
+        tx.send(copy.turn().send_all(vec![2, 3])).unwrap();
+    });
+
+    Timer::new().unwrap().sleep(Duration::milliseconds(100));
+    assert!(shared.depth() == 0);
+    assert!(first.send_all(vec![1]) == Ok(()));
+    assert!(rx.recv().unwrap() == Ok(()));
+    assert!(receive.recv() == Ok(1));
+    assert!(receive.recv() == Ok(2));
+    assert!(receive.recv() == Ok(3));
+}
+
+/**
+ * A turn given up without queueing anything shouldn't hold up the ones after it
+ */
+#[test]
+fn abandoned_turn() {
+    let (send, receive) = queue(None);
+    let shared = SharedSender::new(send);
+    drop(shared.turn());
+    assert!(shared.turn().send_all(vec![1]) == Ok(()));
+    assert!(receive.try_recv() == Ok(1));
+}
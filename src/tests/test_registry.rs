@@ -0,0 +1,120 @@
+use shared::ConnectionConfig;
+use registry::{NetMessage, Message, MessageRegistry};
+use registry;
+use tests::test_shared;
+
+use std::time::duration::Duration;
+
+#[derive(PartialEq, Clone)]
+struct Chat {
+    text: String
+}
+
+impl NetMessage for Chat {
+    fn message_id() -> u16 { 1 }
+
+    fn encode(&self) -> Option<Vec<u8>> {
+        Some(self.text.clone().into_bytes())
+    }
+
+    fn decode(raw: &Vec<u8>) -> Option<Chat> {
+        String::from_utf8(raw.clone()).ok().map(|text| Chat { text: text })
+    }
+}
+
+#[derive(PartialEq, Clone)]
+struct Jump {
+    height: u8
+}
+
+impl NetMessage for Jump {
+    fn message_id() -> u16 { 2 }
+
+    fn encode(&self) -> Option<Vec<u8>> {
+        Some(vec![self.height])
+    }
+
+    fn decode(raw: &Vec<u8>) -> Option<Jump> {
+        if raw.len() == 1 { Some(Jump { height: raw[0] }) } else { None }
+    }
+}
+
+///Deliberately clashes with Jump
+struct Crouch;
+
+impl NetMessage for Crouch {
+    fn message_id() -> u16 { 2 }
+    fn encode(&self) -> Option<Vec<u8>> { Some(vec![]) }
+    fn decode(_: &Vec<u8>) -> Option<Crouch> { Some(Crouch) }
+}
+
+/**
+ * An envelope should survive being turned into raw data and back
+ */
+#[test]
+fn envelope_round_trip() {
+    let message = Message::new(&Jump { height: 3 }).unwrap();
+    let raw = registry::serializer(&message).unwrap();
+    assert!(registry::deserializer(&raw) == Some(message));
+}
+
+/**
+ * Envelopes should only downcast into the type they were made from
+ */
+#[test]
+fn downcast() {
+    let message = Message::new(&Chat { text: "Hello".to_string() }).unwrap();
+    assert!(message.is::<Chat>());
+    assert!(!message.is::<Jump>());
+    assert!(message.downcast::<Chat>() == Some(Chat { text: "Hello".to_string() }));
+    assert!(message.downcast::<Jump>().is_none());
+}
+
+/**
+ * Two types can't share the same id
+ */
+#[test]
+fn duplicate_registration() {
+    let mut registry = MessageRegistry::new();
+    assert!(registry.register::<Chat>());
+    assert!(registry.register::<Jump>());
+    assert!(!registry.register::<Crouch>());
+    assert!(registry.is_registered(1));
+    assert!(registry.is_registered(2));
+    assert!(!registry.is_registered(3));
+}
+
+/**
+ * The registry should decode messages into their own types
+ */
+#[test]
+fn dynamic_decode() {
+    let mut registry = MessageRegistry::new();
+    registry.register::<Chat>();
+    registry.register::<Jump>();
+
+    let decoded = registry.decode(&Message::new(&Jump { height: 7 }).unwrap()).expect("Couldn't decode a registered message");
+    assert!(decoded.downcast_ref::<Jump>() == Some(&Jump { height: 7 }));
+    assert!(decoded.downcast_ref::<Chat>().is_none());
+
+    assert!(registry.decode(&Message { message_id: 3, payload: vec![] }).is_none());
+}
+
+fn message_settings() -> ConnectionConfig<Message> {
+    ConnectionConfig::with_messages(121, Duration::seconds(10))
+}
+
+/**
+ * Different message types should be able to share a connection
+ */
+#[test]
+fn mixed_types_across_connection() {
+    let received = test_shared::echo_round_trip(message_settings, 2, |client| {
+        client.send_message(&Chat { text: "Hi".to_string() }).ok().expect("Couldn't send a message");
+        client.send_message(&Jump { height: 4 }).ok().expect("Couldn't send a message");
+    });
+
+    assert!(received.len() == 2);
+    assert!(received[0].downcast::<Chat>() == Some(Chat { text: "Hi".to_string() }));
+    assert!(received[1].downcast::<Jump>() == Some(Jump { height: 4 }));
+}
@@ -0,0 +1,213 @@
+use relay::{Relay, RelayTransport, RELAY_IDLE_MS, RELAY_HEADER_SIZE, KEEPALIVE_MS};
+use server::{Server, PacketOrCommand};
+use client::{Client, ClientConnectionConfig, PollFailResult};
+use clock::ManualClock;
+use loopback::{LoopbackNetwork, LoopbackTransport};
+use transport::Transport;
+use tests::test_shared;
+
+use std::old_io::TimedOut;
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+
+fn relay_addr() -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 27970 }
+}
+
+fn relayed(network: &LoopbackNetwork, host: u8, token: u64) -> RelayTransport<LoopbackTransport> {
+    let transport = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, host), port: 0 }).unwrap();
+    RelayTransport::new(transport, relay_addr(), token).ok().expect("Couldn't reach the relay")
+}
+
+/**
+ * Wait briefly for something to come through, returning it if it does
+ */
+fn receive(transport: &mut RelayTransport<LoopbackTransport>) -> Option<Vec<u8>> {
+    let mut buf = [0; 1024];
+    transport.set_timeout(Some(50));
+    match transport.recv_from(&mut buf) {
+        Ok((amt, src)) => {
+            assert!(src == relay_addr());
+            Some(buf.slice_to(amt).to_vec())
+        },
+        Err(ref e) if e.kind == TimedOut => None,
+        Err(e) => panic!("{}", e)
+    }
+}
+
+/**
+ * A Client and Server should play as normal through a relay
+ */
+#[test]
+fn connect_through_relay() {
+    let network = LoopbackNetwork::new();
+    let mut relay = Relay::with_transport(network.bind(relay_addr()).unwrap());
+    Thread::spawn(move || {
+        for _ in (0..300) {
+            match relay.step(10) {
+                _ => ()
+            }
+        }
+    });
+
+    let mut server = Server::with_transport(relayed(&network, 2, 42), test_shared::settings()).ok().expect("Couldn't start the server");
+    Thread::spawn(move || {
+        let mut timer = Timer::new().unwrap();
+        for _ in (0..300) {
+            match server.poll() {
+                Some((PacketOrCommand::UserPacket(packet), src)) => {
+                    server.send_to(&packet, &src).ok().expect("Couldn't echo");
+                },
+                Some(_) => (),
+                None => timer.sleep(Duration::milliseconds(10))
+            }
+        }
+    });
+
+    let mut client = Client::connect_with(relayed(&network, 3, 42), relay_addr(), test_shared::settings(), ClientConnectionConfig::new(5, Duration::milliseconds(200))).ok().expect("Couldn't connect through the relay");
+    client.send(&vec![9, 8, 7]).ok().expect("Couldn't send");
+    let mut timer = Timer::new().unwrap();
+    let mut echoed = None;
+    for _ in (0..100) {
+        match client.poll() {
+            Ok(packet) => {
+                echoed = Some(packet);
+                break;
+            },
+            Err(PollFailResult::Empty) => timer.sleep(Duration::milliseconds(10)),
+            Err(_) => panic!("Unexpected failure")
+        }
+    }
+    assert!(echoed == Some(vec![9, 8, 7]));
+}
+
+/**
+ * Only the first two holders of a token should be paired, and nobody else should hear them
+ */
+#[test]
+fn sessions_kept_apart() {
+    let network = LoopbackNetwork::new();
+    let mut relay = Relay::with_transport(network.bind(relay_addr()).unwrap());
+    let mut first = relayed(&network, 2, 1);
+    assert!(relay.step(50) == Ok(None));
+    let mut second = relayed(&network, 3, 1);
+    assert!(relay.step(50) == Ok(Some(1)));
+    let mut latecomer = relayed(&network, 4, 1);
+    assert!(relay.step(50) == Ok(None));
+    let mut elsewhere = relayed(&network, 5, 2);
+    assert!(relay.step(50) == Ok(None));
+    assert!(relay.session_count() == 2);
+
+    second.send_to(&[1, 2, 3], relay_addr()).unwrap();
+    relay.step(50).unwrap();
+    assert!(receive(&mut first) == Some(vec![1, 2, 3]));
+    assert!(receive(&mut latecomer) == None);
+    assert!(receive(&mut elsewhere) == None);
+
+    latecomer.send_to(&[4], relay_addr()).unwrap();
+    relay.step(50).unwrap();
+    elsewhere.send_to(&[5], relay_addr()).unwrap();
+    relay.step(50).unwrap();
+    assert!(receive(&mut first) == None);
+    assert!(receive(&mut second) == None);
+}
+
+/**
+ * Sessions going over their bandwidth should lose datagrams until the next second
+ */
+#[test]
+fn bandwidth_capped() {
+    let network = LoopbackNetwork::new();
+    let clock = ManualClock::new(0);
+    let mut relay = Relay::with_transport(network.bind(relay_addr()).unwrap());
+    relay.set_clock(clock.shared());
+    relay.set_bandwidth(100);
+    let mut first = relayed(&network, 2, 1);
+    let mut second = relayed(&network, 3, 1);
+    relay.step(50).unwrap();
+    relay.step(50).unwrap();
+
+    let payload = [0; 60 - RELAY_HEADER_SIZE];
+    for _ in (0..3) {
+        first.send_to(&payload, relay_addr()).unwrap();
+        relay.step(50).unwrap();
+    }
+    assert!(receive(&mut second).is_some());
+    assert!(receive(&mut second).is_none());
+    assert!(relay.dropped() == 2);
+
+    clock.advance(1000);
+    first.send_to(&payload, relay_addr()).unwrap();
+    relay.step(50).unwrap();
+    assert!(receive(&mut second).is_some());
+}
+
+/**
+ * Sessions nobody uses should be forgotten
+ */
+#[test]
+fn idle_sessions_expire() {
+    let network = LoopbackNetwork::new();
+    let clock = ManualClock::new(0);
+    let mut relay = Relay::with_transport(network.bind(relay_addr()).unwrap());
+    relay.set_clock(clock.shared());
+    let _first = relayed(&network, 2, 1);
+    relay.step(50).unwrap();
+    assert!(relay.session_count() == 1);
+
+    clock.advance(RELAY_IDLE_MS + 1);
+    relay.step(10).unwrap();
+    assert!(relay.session_count() == 0);
+}
+
+/**
+ * Nobody should be able to fill a relay with sessions, or take up every session from one address
+ */
+#[test]
+fn session_limits() {
+    let network = LoopbackNetwork::new();
+    let mut relay = Relay::with_transport(network.bind(relay_addr()).unwrap());
+    relay.set_session_limits(2, 1);
+    let _first = relayed(&network, 2, 1);
+    assert!(relay.step(50) == Ok(None));
+    let _greedy = relayed(&network, 2, 2);
+    assert!(relay.step(50) == Ok(None));
+    assert!(relay.session_count() == 1);
+    assert!(relay.dropped() == 1);
+
+    let _second = relayed(&network, 3, 3);
+    assert!(relay.step(50) == Ok(None));
+    let _third = relayed(&network, 4, 4);
+    assert!(relay.step(50) == Ok(None));
+    assert!(relay.session_count() == 2);
+    assert!(relay.dropped() == 2);
+
+    //Joining a session someone else opened is still fine
+    let _joiner = relayed(&network, 4, 1);
+    assert!(relay.step(50) == Ok(Some(1)));
+}
+
+/**
+ * A quiet RelayTransport should remind the relay it's there, even while datagrams keep arriving
+ */
+#[test]
+fn keepalive_while_receiving() {
+    let network = LoopbackNetwork::new();
+    let clock = ManualClock::new(0);
+    let mut relay = network.bind(relay_addr()).unwrap();
+    let transport = network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 2), port: 0 }).unwrap();
+    let mut relayed = RelayTransport::with_clock(transport, relay_addr(), 42, clock.shared()).ok().expect("Couldn't reach the relay");
+    let (hello, peer) = test_shared::get_message(&mut relay);
+    assert!(hello.len() == RELAY_HEADER_SIZE);
+
+    clock.advance(KEEPALIVE_MS);
+    let wrapped = [0x52, 0x4C, 0x41, 0x59, 0, 0, 0, 0, 0, 0, 0, 42, 7];
+    relay.send_to(&wrapped, peer).unwrap();
+    let mut buf = [0; 16];
+    relayed.set_timeout(Some(1000));
+    assert!(relayed.recv_from(&mut buf) == Ok((1, relay_addr())));
+    let (keepalive, _) = test_shared::get_message(&mut relay);
+    assert!(keepalive.len() == RELAY_HEADER_SIZE);
+}
@@ -0,0 +1,159 @@
+use schedule::{Scheduler, Scheduling};
+use queue::{QueueLimit, QueuePolicy, QueueSendError};
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(10, 0, 0, 1), port: port }
+}
+
+/**
+ * Everything the chatty client 1 and quiet client 2 sent, with 1 getting in first
+ */
+fn chatty(scheduling: Scheduling) -> Scheduler<u32> {
+    let mut scheduler = Scheduler::new(None);
+    scheduler.set_scheduling(scheduling);
+    for i in (0..4) {
+        scheduler.push(addr(1), i).unwrap();
+    }
+    scheduler.push(addr(2), 10).unwrap();
+    scheduler.push(addr(2), 11).unwrap();
+    scheduler
+}
+
+fn drain(scheduler: &mut Scheduler<u32>) -> Vec<u32> {
+    let mut out = vec![];
+    loop {
+        match scheduler.next() {
+            Some((_, item)) => out.push(item),
+            None => return out
+        }
+    }
+}
+
+/**
+ * By default, everything should come out as it went in
+ */
+#[test]
+fn arrival() {
+    let mut scheduler = chatty(Scheduling::Arrival);
+    assert!(drain(&mut scheduler) == vec![0, 1, 2, 3, 10, 11]);
+    assert!(scheduler.depth() == 0);
+}
+
+/**
+ * Clients should take turns
+ */
+#[test]
+fn round_robin() {
+    let mut scheduler = chatty(Scheduling::RoundRobin);
+    assert!(drain(&mut scheduler) == vec![0, 10, 1, 11, 2, 3]);
+}
+
+/**
+ * A turn should stay with the client it belongs to when someone before them runs out
+ */
+#[test]
+fn turns_follow_clients() {
+    let mut scheduler = Scheduler::new(None);
+    scheduler.set_scheduling(Scheduling::RoundRobin);
+    scheduler.set_frame_cap(Some(1));
+    scheduler.push(addr(1), 0).unwrap();
+    for i in (0..2) {
+        scheduler.push(addr(2), 10 + i).unwrap();
+        scheduler.push(addr(3), 20 + i).unwrap();
+    }
+    assert!(drain(&mut scheduler) == vec![0, 10, 20]);
+    assert!(drain(&mut scheduler) == vec![11, 21]);
+}
+
+/**
+ * A client with a higher priority should get that many turns in a row
+ */
+#[test]
+fn weighted() {
+    let mut scheduler = Scheduler::new(None);
+    scheduler.set_scheduling(Scheduling::Weighted);
+    scheduler.set_priority(addr(1), 3);
+    for i in (0..6) {
+        scheduler.push(addr(1), i).unwrap();
+        scheduler.push(addr(2), 10 + i).unwrap();
+    }
+    let out: Vec<SocketAddr> = (0..8).map(|_| scheduler.next().unwrap().0).collect();
+    let from_chatty = out.iter().filter(|&from| *from == addr(1)).count();
+    assert!(from_chatty == 6);
+}
+
+/**
+ * Nobody should get more than the cap in a frame, with the rest coming out next frame
+ */
+#[test]
+fn frame_cap() {
+    let mut scheduler = chatty(Scheduling::Arrival);
+    scheduler.set_frame_cap(Some(2));
+    assert!(drain(&mut scheduler) == vec![0, 1, 10, 11]);
+    assert!(scheduler.depth() == 2);
+    assert!(drain(&mut scheduler) == vec![2, 3]);
+}
+
+/**
+ * Each client's backlog should be limited on its own
+ */
+#[test]
+fn limited() {
+    let mut scheduler = Scheduler::new(Some(QueueLimit::new(2, QueuePolicy::DropOldest)));
+    for i in (0..4) {
+        scheduler.push(addr(1), i).unwrap();
+    }
+    scheduler.push(addr(2), 10).unwrap();
+    assert!(scheduler.dropped() == 2);
+    assert!(drain(&mut scheduler) == vec![2, 3, 10]);
+
+    let mut scheduler = Scheduler::new(Some(QueueLimit::new(2, QueuePolicy::DropNewest)));
+    for i in (0..4) {
+        scheduler.push(addr(1), i).unwrap();
+    }
+    assert!(drain(&mut scheduler) == vec![0, 1]);
+}
+
+/**
+ * Control items, such as a client coming or going, should never be dropped to make room
+ */
+#[test]
+fn control_never_dropped() {
+    let mut scheduler = Scheduler::new(Some(QueueLimit::new(1, QueuePolicy::DropOldest)));
+    scheduler.push_control(addr(1), 100);
+    for i in (0..3) {
+        scheduler.push(addr(1), i).unwrap();
+    }
+    assert!(scheduler.dropped() == 2);
+    assert!(drain(&mut scheduler) == vec![100, 2]);
+
+    let mut scheduler = Scheduler::new(Some(QueueLimit::new(1, QueuePolicy::DropNewest)));
+    for i in (0..3) {
+        scheduler.push(addr(1), i).unwrap();
+    }
+    scheduler.push_control(addr(1), 100);
+    assert!(drain(&mut scheduler) == vec![0, 100]);
+}
+
+/**
+ * A full backlog should refuse with the Error policy, and say it's blocked with the Block policy, without dropping either way
+ */
+#[test]
+fn refuse_and_block() {
+    let mut scheduler = Scheduler::new(Some(QueueLimit::new(1, QueuePolicy::Error)));
+    assert!(scheduler.push(addr(1), 0) == Ok(()));
+    assert!(scheduler.push(addr(1), 1) == Err(QueueSendError::Full));
+    assert!(scheduler.push(addr(2), 10) == Ok(()));
+    assert!(!scheduler.blocked());
+    assert!(scheduler.dropped() == 0);
+    assert!(drain(&mut scheduler) == vec![0, 10]);
+
+    let mut scheduler = Scheduler::new(Some(QueueLimit::new(1, QueuePolicy::Block)));
+    assert!(scheduler.push(addr(1), 0) == Ok(()));
+    assert!(scheduler.blocked());
+    assert!(scheduler.dropped() == 0);
+    assert!(drain(&mut scheduler) == vec![0]);
+    assert!(!scheduler.blocked());
+}
@@ -1,30 +1,41 @@
 use shared::ConnectionConfig;
 use server::Server;
-use packet::{Packet, PacketType};
+use packet::{Packet, PacketType, MAX_PACKET_SIZE};
 use server::PacketOrCommand;
+use schedule::Scheduling;
+use queue::{QueueLimit, QueuePolicy};
+use clock::ManualClock;
+use error::Error;
+use loopback::{LoopbackNetwork, LoopbackTransport};
+use transport::Transport;
+use error::NetResult;
 use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
 use std::time::duration::Duration;
-use std::old_io::net::udp::UdpSocket;
 use std::old_io::Timer;
 use tests::test_shared;
 use std::thread::Thread;
 use std::sync::mpsc::{channel};
+use time::precise_time_ns;
 
 macro_rules! with_bound_socket {
-    (($variable:ident)$code:block) => (
-        Thread::spawn(move || {
-            match UdpSocket::bind(SocketAddr{ ip: Ipv4Addr(0, 0, 0, 0), port: 0 }) {
-                Ok(mut $variable) => $code,
-                Err(e) => panic!(e)
-            }
-        });
+    ($network:ident, ($variable:ident)$code:block) => (
+        match $network.bind(SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 }) {
+            Ok(mut $variable) => {
+                Thread::spawn(move || $code);
+            },
+            Err(e) => panic!(e)
+        }
     )
 }
 
-fn generate_settings(port: u16, protocol_id: u32) -> (SocketAddr, ConnectionConfig<Vec<u8>>) {
+fn generate_settings(port: u16, protocol_id: u32) -> (LoopbackNetwork, SocketAddr, ConnectionConfig<Vec<u8>>) {
     let my_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: port };
     let settings = ConnectionConfig::new(protocol_id, Duration::seconds(10), test_shared::deserializer, test_shared::serializer);
-    (my_addr, settings)
+    (LoopbackNetwork::new(), my_addr, settings)
+}
+
+fn serve(network: &LoopbackNetwork, my_addr: SocketAddr, settings: ConnectionConfig<Vec<u8>>) -> NetResult<Server<Vec<u8>, LoopbackTransport>> {
+    Server::with_transport(try!(network.bind(my_addr)), settings)
 }
 
 /**
@@ -33,8 +44,8 @@ fn generate_settings(port: u16, protocol_id: u32) -> (SocketAddr, ConnectionConf
 #[test]
 fn create_server() {
     let socket = 64000;
-    let (my_addr, settings) = generate_settings(socket, 121);
-    match Server::new(my_addr, settings) {
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+    match serve(&network, my_addr, settings) {
         Ok(_) => (), //passed
         Err(t) => panic!("Failed to create a server - {}", t)
     };
@@ -46,8 +57,8 @@ fn create_server() {
 #[test]
 fn empty_poll() {
     let socket = 64001;
-    let (my_addr, settings) = generate_settings(socket, 121);
-    match Server::new(my_addr, settings) {
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
             assert!(server.poll().is_none())
         },
@@ -61,12 +72,12 @@ fn empty_poll() {
 #[test]
 fn bad_client_attempt() {
     let socket = 64002;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
     let (tx, rx) = channel();
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.send_to(Packet::connect(122, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 tx.send(());
             });
@@ -85,12 +96,12 @@ fn bad_client_attempt() {
 #[test]
 fn single_client() {
     let socket = 64003;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
     let (tx, rx) = channel();
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 tx.send(());
             });
@@ -113,17 +124,17 @@ fn single_client() {
 #[test]
 fn multiple_clients() {
     let socket = 64004;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
     let (tx, rx) = channel();
     let tx2 = tx.clone();
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 tx.send(());
             });
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 tx2.send(());
             });
@@ -152,35 +163,28 @@ fn multiple_clients() {
 #[test]
 fn cull() {
     let socket = 64005;
-    let (my_addr, mut settings) = generate_settings(socket, 121);
-    settings.timeout_period = Duration::seconds(0);
-    let (tx, rx) = channel();
-    let tx2 = tx.clone();
+    let (network, my_addr, mut settings) = generate_settings(socket, 121);
+    let clock = ManualClock::new(0);
+    settings.clock = clock.shared();
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
-                tx.send(());
             });
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
-                tx2.send(());
             });
-            rx.recv();
-            rx.recv();
-            Timer::new().unwrap().sleep(Duration::seconds(1));
-            match server.poll() {
-                Some((PacketOrCommand::Command(PacketType::Connect), _))=> (),
-                None => panic!("No result found"),
-                _ => panic!("Unexpected poll result")
-            };
-            match server.poll() {
-                Some((PacketOrCommand::Command(PacketType::Connect), _))=> (),
-                None => panic!("No result found"),
-                _ => panic!("Unexpected poll result")
+            for _ in (0..2) {
+                match server.poll_timeout(Duration::seconds(5)) {
+                    Some((PacketOrCommand::Command(PacketType::Connect), _))=> (),
+                    None => panic!("No result found"),
+                    _ => panic!("Unexpected poll result")
+                };
             }
-            Timer::new().unwrap().sleep(Duration::seconds(1));
+            clock.advance(10000);
+            assert!(server.cull().len() == 0);
+            clock.advance(1);
             assert!(server.all_connections().len() == 2);
             assert!(server.cull().len() == 2);
             assert!(server.all_connections().len() == 0);
@@ -195,12 +199,12 @@ fn cull() {
 #[test]
 fn send_to_one() {
     let socket = 64006;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
     let (tx, rx) = channel();
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -215,7 +219,7 @@ fn send_to_one() {
                 _ => panic!("Unexpected poll result")
             };
             let message_out = vec![1,2];
-            assert!(server.send_to(&message_out, &source) == true);
+            assert!(server.send_to(&message_out, &source).is_ok());
             let message = rx.recv().unwrap();
             assert!(message.packet_type == PacketType::Message);
             assert!(message.packet_content.unwrap() == message_out);
@@ -230,11 +234,11 @@ fn send_to_one() {
 #[test]
 fn send_to_disconnected() {
     let socket = 64007;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            assert!(server.send_to(&vec![1], &my_addr) == false);
+            assert!(server.send_to(&vec![1], &my_addr) == Err(Error::NotConnected));
         },
         Err(t) => panic!("Failed to create a server - {}", t)
     };
@@ -246,13 +250,13 @@ fn send_to_disconnected() {
 #[test]
 fn send_to_many() {
     let socket = 64008;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
     let (tx, rx) = channel();
     let tx2 = tx.clone();
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -260,7 +264,7 @@ fn send_to_many() {
 
                 tx.send(Packet::deserialize(message.as_slice()).ok().expect("Couldn't deserialize a message"));
             });
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -280,7 +284,7 @@ fn send_to_many() {
                 _ => panic!("Unexpected poll result")
             };
             let message_out = vec![1,2];
-            server.send_to_many(&message_out, &vec![source, source2]);
+            server.send_to_many(&message_out, &vec![source, source2]).ok().expect("Couldn't send to many");
             let message1 = rx.recv().unwrap();
             let message2 = rx.recv().unwrap();
             assert!(message1.packet_content.unwrap() == message2.packet_content.unwrap());
@@ -295,13 +299,13 @@ fn send_to_many() {
 #[test]
 fn send_to_all() {
     let socket = 64009;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
     let (tx, rx) = channel();
     let tx2 = tx.clone();
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -309,7 +313,7 @@ fn send_to_all() {
 
                 tx.send(Packet::deserialize(message.as_slice()).ok().expect("Couldn't deserialize a message"));
             });
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -329,7 +333,7 @@ fn send_to_all() {
                 _ => panic!("Unexpected poll result")
             };
             let message_out = vec![1,2];
-            server.send_to_all(&message_out);
+            server.send_to_all(&message_out).ok().expect("Couldn't send to all");
             let message1 = rx.recv().unwrap();
             let message2 = rx.recv().unwrap();
             assert!(message1.packet_content.unwrap() == message2.packet_content.unwrap());
@@ -344,11 +348,11 @@ fn send_to_all() {
 #[test]
 fn receive() {
     let socket = 64010;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -378,11 +382,11 @@ fn receive() {
 #[test]
 fn client_disconnect() {
     let socket = 64011;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -413,11 +417,11 @@ fn client_disconnect() {
 #[test]
 fn client_tries_multiple_connect() {
     let socket = 64012;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -447,11 +451,11 @@ fn client_tries_multiple_connect() {
 #[test]
 fn out_of_sequence_packets() {
     let socket = 64013;
-    let (my_addr, settings) = generate_settings(socket, 121);
+    let (network, my_addr, settings) = generate_settings(socket, 121);
 
-    match Server::new(my_addr, settings) {
+    match serve(&network, my_addr, settings) {
         Ok(ref mut server) => {
-            with_bound_socket!((socket) {
+            with_bound_socket!(network, (socket) {
                 socket.set_timeout(Some(5000));
                 socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
                 test_shared::get_message(&mut socket); //Should be the Accept message
@@ -482,3 +486,252 @@ fn out_of_sequence_packets() {
         Err(t) => panic!("Failed to create a server - {}", t)
     };
 }
+
+/**
+ * Packets which can't fit into a datagram shouldn't be sent
+ */
+#[test]
+fn payload_too_large() {
+    let socket = 64014;
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+
+    match serve(&network, my_addr, settings) {
+        Ok(ref mut server) => {
+            with_bound_socket!(network, (socket) {
+                socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
+            });
+            Timer::new().unwrap().sleep(Duration::seconds(1));
+            let source = match server.poll() {
+                Some((PacketOrCommand::Command(PacketType::Connect), source)) => source,
+                None => panic!("No result found"),
+                _ => panic!("Unexpected poll result")
+            };
+            assert!(server.send_to(&vec![0; MAX_PACKET_SIZE], &source) == Err(Error::PayloadTooLarge));
+            assert!(server.send_to_all(&vec![0; MAX_PACKET_SIZE]) == Err(Error::PayloadTooLarge));
+        },
+        Err(t) => panic!("Failed to create a server - {}", t)
+    };
+}
+
+/**
+ * Waiting should return as soon as something arrives, or once the timeout has passed
+ */
+#[test]
+fn poll_timeout() {
+    let socket = 64015;
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+
+    match serve(&network, my_addr, settings) {
+        Ok(ref mut server) => {
+            let started = precise_time_ns();
+            assert!(server.poll_timeout(Duration::milliseconds(200)).is_none());
+            assert!(precise_time_ns() - started >= 200000000);
+
+            with_bound_socket!(network, (socket) {
+                Timer::new().unwrap().sleep(Duration::milliseconds(100));
+                socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
+            });
+            let started = precise_time_ns();
+            match server.poll_timeout(Duration::seconds(5)) {
+                Some((PacketOrCommand::Command(PacketType::Connect), _)) => (),
+                _ => panic!("Unexpected poll result")
+            };
+            assert!(precise_time_ns() - started < 2000000000);
+        },
+        Err(t) => panic!("Failed to create a server - {}", t)
+    };
+}
+
+/**
+ * Another thread should be able to end a wait
+ */
+#[test]
+fn wake() {
+    let socket = 64016;
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+
+    match serve(&network, my_addr, settings) {
+        Ok(ref mut server) => {
+            let waker = server.waker();
+            Thread::spawn(move || {
+                Timer::new().unwrap().sleep(Duration::milliseconds(100));
+                waker.wake();
+            });
+            let started = precise_time_ns();
+            assert!(server.wait().is_none());
+            assert!(precise_time_ns() - started < 2000000000);
+        },
+        Err(t) => panic!("Failed to create a server - {}", t)
+    };
+}
+
+/**
+ * A pumped server should be woken too, and a timeout too long to count in nanoseconds should still wait
+ */
+#[test]
+fn wake_pumped() {
+    let socket = 64021;
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+
+    match Server::with_transport_unthreaded(network.bind(my_addr).unwrap(), settings) {
+        Ok(ref mut server) => {
+            let waker = server.waker();
+            Thread::spawn(move || {
+                Timer::new().unwrap().sleep(Duration::milliseconds(100));
+                waker.wake();
+            });
+            let started = precise_time_ns();
+            assert!(server.poll_timeout(Duration::weeks(100000)).is_none());
+            let waited = precise_time_ns() - started;
+            assert!(waited >= 50000000 && waited < 2000000000);
+        },
+        Err(t) => panic!("Failed to create a server - {}", t)
+    };
+}
+
+/**
+ * Shutting down should send everything queued, then say goodbye to every client
+ */
+#[test]
+fn shutdown() {
+    let socket = 64017;
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+    let (tx, rx) = channel();
+
+    match serve(&network, my_addr, settings) {
+        Ok(mut server) => {
+            with_bound_socket!(network, (socket) {
+                socket.set_timeout(Some(10000));
+                socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
+                let mut received = vec![];
+                for _ in (0..3) {
+                    let (data, _) = test_shared::get_message(&mut socket);
+                    received.push(Packet::deserialize(data.as_slice()).unwrap().packet_type);
+                }
+                tx.send(received).unwrap();
+            });
+            let source = match server.poll_timeout(Duration::seconds(5)) {
+                Some((PacketOrCommand::Command(PacketType::Connect), source)) => source,
+                _ => panic!("Unexpected poll result")
+            };
+            server.send_to(&vec![1], &source).ok().expect("Couldn't send");
+            assert!(server.shutdown() == Ok(()));
+            assert!(rx.recv().unwrap() == vec![PacketType::Accept, PacketType::Message, PacketType::Disconnect]);
+        },
+        Err(t) => panic!("Failed to create a server - {}", t)
+    };
+}
+
+/**
+ * A chatty client shouldn't push a quiet one to the back, or get more than its share of a frame
+ */
+#[test]
+fn fair_polling() {
+    let socket = 64018;
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+
+    match serve(&network, my_addr, settings) {
+        Ok(ref mut server) => {
+            server.set_scheduling(Scheduling::RoundRobin);
+            with_bound_socket!(network, (socket) {
+                socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
+                Timer::new().unwrap().sleep(Duration::milliseconds(300));
+                for i in (1..4) {
+                    socket.send_to(Packet::message(121, i, vec![1]).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
+                }
+            });
+            with_bound_socket!(network, (socket) {
+                socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
+                Timer::new().unwrap().sleep(Duration::milliseconds(400));
+                socket.send_to(Packet::message(121, 1, vec![2]).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
+            });
+            for _ in (0..2) {
+                match server.poll_timeout(Duration::seconds(5)) {
+                    Some((PacketOrCommand::Command(PacketType::Connect), _)) => (),
+                    _ => panic!("Unexpected poll result")
+                }
+            }
+            Timer::new().unwrap().sleep(Duration::seconds(1));
+
+            server.set_frame_cap(Some(1));
+            let mut frame = || {
+                let mut received = vec![];
+                loop {
+                    match server.poll() {
+                        Some((PacketOrCommand::UserPacket(packet), _)) => received.push(packet[0]),
+                        None => return received,
+                        _ => panic!("Unexpected poll result")
+                    }
+                }
+            };
+            let mut first = frame();
+            first.sort();
+            assert!(first == vec![1, 2]);
+            assert!(frame() == vec![1]);
+            assert!(frame() == vec![1]);
+            assert!(frame() == vec![]);
+        },
+        Err(t) => panic!("Failed to create a server - {}", t)
+    };
+}
+
+/**
+ * A handle should send to clients from another thread while the Server carries on polling
+ */
+#[test]
+fn handle() {
+    let socket = 64019;
+    let (network, my_addr, settings) = generate_settings(socket, 121);
+    let (tx, rx) = channel();
+
+    match serve(&network, my_addr, settings) {
+        Ok(ref mut server) => {
+            with_bound_socket!(network, (socket) {
+                socket.set_timeout(Some(10000));
+                socket.send_to(Packet::connect(121, 0).serialize().unwrap().as_slice(), my_addr).ok().expect("Couldn't send a message");
+                let mut received = vec![];
+                for _ in (0..3) {
+                    let (data, _) = test_shared::get_message(&mut socket);
+                    received.push(Packet::deserialize(data.as_slice()).unwrap());
+                }
+                tx.send(received).unwrap();
+            });
+            let source = match server.poll_timeout(Duration::seconds(5)) {
+                Some((PacketOrCommand::Command(PacketType::Connect), source)) => source,
+                _ => panic!("Unexpected poll result")
+            };
+
+            let handle = server.handle();
+            let (sent_tx, sent_rx) = channel();
+            Thread::spawn(move || {
+                assert!(handle.all_connections() == vec![source]);
+                sent_tx.send((handle.send_to(&vec![1], &source), handle.send_to_all(&vec![2]))).unwrap();
+            });
+            assert!(sent_rx.recv().unwrap() == (Ok(()), Ok(())));
+            assert!(server.poll().is_none());
+
+            let received = rx.recv().unwrap();
+            assert!(received[0].packet_type == PacketType::Accept);
+            assert!(received[1].packet_content == Some(vec![1]));
+            assert!(received[2].packet_content == Some(vec![2]));
+            assert!(received[1].sequence_id != received[2].sequence_id);
+        },
+        Err(t) => panic!("Failed to create a server - {}", t)
+    };
+}
+
+/**
+ * Without threads of its own, a Server would wait forever on a full queue set to block
+ */
+#[test]
+fn unthreaded_refuses_blocking_queues() {
+    let socket = 64020;
+    let (network, my_addr, mut settings) = generate_settings(socket, 121);
+    settings.inbound_queue = Some(QueueLimit::new(1, QueuePolicy::Block));
+
+    match Server::with_transport_unthreaded(network.bind(my_addr).unwrap(), settings) {
+        Err(Error::InvalidConfig(_)) => (),
+        Ok(_) => panic!("Pumped a blocking queue"),
+        Err(t) => panic!("Failed to create a server - {}", t)
+    };
+}
@@ -1,7 +1,13 @@
-use std::old_io::net::ip::SocketAddr;
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
 use std::old_io::net::udp::UdpSocket;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use transport::Transport;
+use shared::ConnectionConfig;
+use client::{Client, ClientConnectionConfig, PollFailResult};
+use server::{Server, PacketOrCommand};
 
-pub fn get_message(socket: &mut UdpSocket) -> (Vec<u8>, SocketAddr) {
+pub fn get_message<N: Transport>(socket: &mut N) -> (Vec<u8>, SocketAddr) {
     let mut buf = [0; 256];
     match socket.recv_from(&mut buf) {
         Ok((amt, src)) => (buf.slice_to(amt).to_vec(), src),
@@ -13,6 +19,56 @@ pub fn deserializer(message: &Vec<u8>) -> Option<Vec<u8>> {
     Some(message.clone())
 }
 
-pub fn serializer(packet: &Vec<u8>) -> Vec<u8> {
-    packet.clone()
+pub fn serializer(packet: &Vec<u8>) -> Option<Vec<u8>> {
+    Some(packet.clone())
+}
+
+/**
+ * Settings for sending byte vectors back and forth, shared by the tests which don't care about encoding
+ */
+pub fn settings() -> ConnectionConfig<Vec<u8>> {
+    ConnectionConfig::new(121, Duration::seconds(10), deserializer, serializer)
+}
+
+/**
+ * Connect to a server which echoes back whatever it gets, over UDP on any free port, and wait for
+ * `replies` packets to come back after `send` has had its go with the client
+ */
+pub fn echo_round_trip<T, F>(config: fn() -> ConnectionConfig<T>, replies: usize, send: F) -> Vec<T>
+    where T: Send + 'static, F: FnOnce(&mut Client<T, UdpSocket>) {
+    let any_port = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let mut socket = UdpSocket::bind(any_port).ok().expect("Couldn't bind the server");
+    let server_addr = socket.socket_name().unwrap();
+
+    Thread::spawn(move || {
+        let mut server = Server::with_transport(socket, config()).ok().expect("Failed to create a server");
+        let mut echoed = 0;
+        while echoed < replies {
+            match server.poll_timeout(Duration::seconds(5)) {
+                Some((PacketOrCommand::UserPacket(packet), src)) => {
+                    server.send_to(&packet, &src).ok().expect("Couldn't echo a packet");
+                    echoed += 1;
+                },
+                Some(_) => (),
+                None => break //The client will notice what's missing
+            }
+        }
+        server.shutdown().ok().expect("Couldn't flush the echoes");
+    });
+
+    let mut received = vec![];
+    match Client::connect(any_port, server_addr, config(), ClientConnectionConfig::new(3, Duration::seconds(5))) {
+        Ok(ref mut client) => {
+            send(client);
+            while received.len() < replies {
+                match client.poll_timeout(Duration::seconds(5)) {
+                    Ok(packet) => received.push(packet),
+                    Err(PollFailResult::Empty) => break,
+                    Err(_) => panic!("Unexpected failure")
+                }
+            }
+        },
+        Err(e) => panic!("{}", e)
+    };
+    received
 }
@@ -0,0 +1,239 @@
+use client::{ClientConnectionConfig, Client, PollFailResult, ConnectionState};
+use server::{Server, PacketOrCommand};
+use conditioner::LinkConditions;
+use error::Error;
+use sim::{Simulation, SimTransport};
+use transport::Transport;
+use tests::test_shared;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::time::duration::Duration;
+
+fn server_addr() -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(10, 0, 0, 1), port: 7000 }
+}
+
+fn client_addr() -> SocketAddr {
+    SocketAddr{ ip: Ipv4Addr(10, 0, 0, 2), port: 0 }
+}
+
+/**
+ * Move everything along by one tick, returning whatever the server heard about
+ */
+fn tick(client: &mut Client<Vec<u8>, SimTransport>, server: &mut Server<Vec<u8>, SimTransport>) -> Vec<PacketOrCommand<Vec<u8>>> {
+    let mut heard = vec![];
+    match client.pump() { _ => () }
+    server.pump();
+    loop {
+        match server.poll() {
+            Some((event, _)) => heard.push(event),
+            None => break
+        }
+    }
+    server.pump();
+    heard
+}
+
+/**
+ * A client and server connected over a simulated network, after the handshake
+ */
+fn connected(sim: &Simulation, conditions: Option<LinkConditions>) -> (Client<Vec<u8>, SimTransport>, Server<Vec<u8>, SimTransport>) {
+    let mut server_settings = test_shared::settings();
+    server_settings.link_conditions = conditions;
+    let mut server = sim.server(server_addr(), server_settings).ok().expect("Couldn't start the server");
+    let mut client_settings = test_shared::settings();
+    client_settings.link_conditions = conditions;
+    let mut client = sim.client(client_addr(), server_addr(), client_settings, ClientConnectionConfig::new(10, Duration::milliseconds(200))).ok().expect("Couldn't start the client");
+
+    let mut ticks = 0;
+    while client.connection_state != ConnectionState::Connected {
+        sim.step(|_| { tick(&mut client, &mut server); });
+        ticks += 1;
+        assert!(ticks < 1000, "Never connected");
+    }
+    (client, server)
+}
+
+/**
+ * Nothing should move until the clock does
+ */
+#[test]
+fn time_stands_still() {
+    let sim = Simulation::new(10);
+    let mut sender = sim.bind(client_addr(), Some(LinkConditions { latency_ms: 50, ..LinkConditions::perfect() })).ok().expect("Couldn't bind");
+    let mut receiver = sim.bind(server_addr(), None).ok().expect("Couldn't bind");
+
+    sender.send_to(&[1], server_addr()).ok().expect("Couldn't send");
+    assert!(receiver.recv_from(&mut [0; 16]).is_err());
+    sim.run_for(40, |_| ());
+    assert!(receiver.recv_from(&mut [0; 16]).is_err());
+    sim.run_for(10, |_| ());
+    assert!(receiver.recv_from(&mut [0; 16]).is_ok());
+    assert!(sim.in_flight() == 0);
+}
+
+/**
+ * A client and server should be able to connect and talk
+ */
+#[test]
+fn connect_and_send() {
+    let sim = Simulation::new(10);
+    let (mut client, mut server) = connected(&sim, None);
+
+    client.send(&vec![1, 2, 3]).ok().expect("Couldn't send");
+    let mut heard = vec![];
+    sim.run_for(20, |_| heard.extend(tick(&mut client, &mut server).into_iter()));
+    assert!(heard.len() == 1);
+    match heard[0] {
+        PacketOrCommand::UserPacket(ref data) => assert!(*data == vec![1, 2, 3]),
+        _ => panic!("Expected a message")
+    }
+}
+
+/**
+ * A client which hears nothing should give up after exactly its timeout period
+ */
+#[test]
+fn client_timeout() {
+    let sim = Simulation::new(10);
+    let (mut client, server) = connected(&sim, None);
+    drop(server);
+    let connected_at = sim.now_ms();
+
+    let mut disconnected_at = None;
+    sim.run_for(11000, |now| {
+        match client.pump() { _ => () }
+        match client.poll() {
+            Err(PollFailResult::Disconnected) if disconnected_at.is_none() => disconnected_at = Some(now),
+            _ => ()
+        }
+    });
+    let waited = disconnected_at.expect("Never timed out") - connected_at;
+    assert!(waited > 10000 && waited <= 10010);
+}
+
+/**
+ * Connection attempts should be retried on time, then given up on
+ */
+#[test]
+fn connect_retries() {
+    let sim = Simulation::new(10);
+    let mut listener = sim.bind(server_addr(), None).ok().expect("Couldn't bind");
+    let mut client = sim.client(client_addr(), server_addr(), test_shared::settings(), ClientConnectionConfig::new(3, Duration::milliseconds(500))).ok().expect("Couldn't start the client");
+
+    let mut attempts = vec![];
+    let mut failed_at = None;
+    sim.run_for(2000, |now| {
+        match client.pump() {
+            Err(e) => {
+                assert!(e == Error::TimedOut);
+                if failed_at.is_none() {
+                    failed_at = Some(now);
+                }
+            },
+            Ok(()) => ()
+        }
+        while listener.recv_from(&mut [0; 64]).is_ok() {
+            attempts.push(now);
+        }
+    });
+
+    //The first attempt goes out as soon as the client is made, and is read on the first tick
+    assert!(attempts == vec![10, 500, 1000]);
+    assert!(failed_at == Some(1500));
+    assert!(client.connection_state == ConnectionState::Disconnected);
+}
+
+/**
+ * A server speaking another protocol should be no better than no server at all
+ */
+#[test]
+fn connect_wrong_protocol() {
+    let sim = Simulation::new(10);
+    let mut server_settings = test_shared::settings();
+    server_settings.protocol_id = 999;
+    let mut server = sim.server(server_addr(), server_settings).ok().expect("Couldn't start the server");
+    let mut client = sim.client(client_addr(), server_addr(), test_shared::settings(), ClientConnectionConfig::new(3, Duration::milliseconds(500))).ok().expect("Couldn't start the client");
+
+    let mut result = Ok(());
+    sim.run_for(2000, |_| {
+        server.pump();
+        while server.poll().is_some() {}
+        if result.is_ok() {
+            result = client.pump();
+        }
+    });
+    assert!(result == Err(Error::TimedOut));
+}
+
+/**
+ * The server should cull silent clients once the timeout has passed on its clock
+ */
+#[test]
+fn cull() {
+    let sim = Simulation::new(10);
+    let (client, mut server) = connected(&sim, None);
+    let client_addr = client.addr;
+    drop(client);
+
+    sim.run_for(9900, |_| server.pump());
+    assert!(server.cull().len() == 0);
+    sim.run_for(200, |_| server.pump());
+    assert!(server.cull() == vec![client_addr]);
+}
+
+/**
+ * Reordered messages should be dropped as stale, and the same seed should drop the same ones
+ */
+#[test]
+fn reordering() {
+    fn run(seed: u64) -> Vec<Vec<u8>> {
+        let sim = Simulation::new(5);
+        let conditions = LinkConditions { latency_ms: 20, jitter_ms: 15, seed: seed, ..LinkConditions::perfect() };
+        let (mut client, mut server) = connected(&sim, Some(conditions));
+
+        let mut heard = vec![];
+        for i in (0..50u8) {
+            client.send(&vec![i]).ok().expect("Couldn't send");
+            sim.step(|_| heard.extend(tick(&mut client, &mut server).into_iter()));
+        }
+        sim.run_for(100, |_| heard.extend(tick(&mut client, &mut server).into_iter()));
+        heard.into_iter().filter_map(|event| match event {
+            PacketOrCommand::UserPacket(data) => Some(data),
+            _ => None
+        }).collect()
+    }
+
+    let first = run(42);
+    //Something was overtaken, and nothing older came after anything newer
+    assert!(first.len() > 0 && first.len() < 50);
+    for pair in first.windows(2) {
+        assert!(pair[0][0] < pair[1][0]);
+    }
+    assert!(run(42) == first);
+}
+
+/**
+ * Lost packets should be lost the same way every run
+ */
+#[test]
+fn loss() {
+    fn run() -> usize {
+        let sim = Simulation::new(10);
+        let mut sender = sim.bind(client_addr(), Some(LinkConditions { loss: 0.5, seed: 7, ..LinkConditions::perfect() })).ok().expect("Couldn't bind");
+        let mut receiver = sim.bind(server_addr(), None).ok().expect("Couldn't bind");
+        for i in (0..100u8) {
+            sender.send_to(&[i], server_addr()).ok().expect("Couldn't send");
+        }
+        sim.step(|_| ());
+        let mut received = 0;
+        while receiver.recv_from(&mut [0; 16]).is_ok() {
+            received += 1;
+        }
+        received
+    }
+
+    let received = run();
+    assert!(received > 0 && received < 100);
+    assert!(run() == received);
+}
@@ -0,0 +1,156 @@
+use client::{ClientConnectionConfig, Client, PollFailResult};
+use server::{Server, PacketOrCommand};
+use packet::PacketType;
+use tcp::{TcpClientTransport, TcpServerTransport};
+use transport::Transport;
+use tests::test_shared;
+
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::{Timer, EndOfFile, NotConnected, TimedOut};
+use std::old_io::net::tcp::TcpStream;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use std::sync::mpsc::channel;
+
+/**
+ * Packets should survive a round trip over TCP, in order
+ */
+#[test]
+fn echo_over_tcp() {
+    let port = 63030;
+    let target_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: port };
+    let (tx, rx) = channel();
+
+    Thread::spawn(move || {
+        let transport = TcpServerTransport::bind(target_addr).ok().expect("Couldn't listen");
+        match Server::with_transport(transport, test_shared::settings()) {
+            Ok(ref mut server) => {
+                tx.send(()).ok().expect("Test has gone away");
+                let mut echoed = 0;
+                let mut timer = Timer::new().unwrap();
+                while echoed < 2 {
+                    match server.poll() {
+                        Some((PacketOrCommand::UserPacket(packet), src)) => {
+                            server.send_to(&packet, &src).ok().expect("Couldn't echo a packet");
+                            echoed += 1;
+                        },
+                        Some(_) => (),
+                        None => timer.sleep(Duration::milliseconds(10))
+                    }
+                }
+            },
+            Err(e) => panic!("Failed to create a server - {}", e)
+        }
+    });
+    rx.recv().ok().expect("Server didn't start");
+
+    let transport = TcpClientTransport::connect(target_addr).ok().expect("Couldn't connect");
+    match Client::connect_with(transport, target_addr, test_shared::settings(), ClientConnectionConfig::new(3, Duration::seconds(5))) {
+        Ok(ref mut client) => {
+            client.send(&vec![1, 2, 3]).ok().expect("Couldn't send a packet");
+            client.send(&vec![4, 5]).ok().expect("Couldn't send a packet");
+            //FIXME: There must be a better way of doing this
+            Timer::new().unwrap().sleep(Duration::seconds(1));
+            assert!(match client.poll() { Ok(packet) => packet == vec![1, 2, 3], _ => false });
+            assert!(match client.poll() { Ok(packet) => packet == vec![4, 5], _ => false });
+            assert!(match client.poll() { Err(PollFailResult::Empty) => true, _ => false });
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * Closing the stream should look like a Disconnect to the server
+ */
+#[test]
+fn close_is_disconnect() {
+    let port = 63031;
+    let target_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: port };
+    let transport = TcpServerTransport::bind(target_addr).ok().expect("Couldn't listen");
+
+    match Server::with_transport(transport, test_shared::settings()) {
+        Ok(ref mut server) => {
+            Thread::spawn(move || {
+                let transport = TcpClientTransport::connect(target_addr).ok().expect("Couldn't connect");
+                match Client::connect_with(transport, target_addr, test_shared::settings(), ClientConnectionConfig::new(3, Duration::seconds(5))) {
+                    Ok(_) => (), //Dropping the client hangs up
+                    Err(e) => panic!("{}", e)
+                }
+            });
+
+            let mut timer = Timer::new().unwrap();
+            let mut events = vec![];
+            for _ in (0..300) {
+                match server.poll() {
+                    Some((PacketOrCommand::Command(command), _)) => {
+                        events.push(command);
+                        if command == PacketType::Disconnect {
+                            break;
+                        }
+                    },
+                    Some(_) => (),
+                    None => timer.sleep(Duration::milliseconds(10))
+                }
+            }
+            assert!(events == vec![PacketType::Connect, PacketType::Disconnect]);
+            assert!(server.all_connections().len() == 0);
+        },
+        Err(e) => panic!("Failed to create a server - {}", e)
+    };
+}
+
+/**
+ * Once the stream has closed and the hang up has been passed on, the transport should stay closed
+ */
+#[test]
+fn closed_stays_closed() {
+    let port = 63032;
+    let target_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: port };
+    let mut server = TcpServerTransport::bind(target_addr).ok().expect("Couldn't listen");
+    let mut client = TcpClientTransport::connect(target_addr).ok().expect("Couldn't connect");
+
+    client.send_to(&[1], target_addr).ok().expect("Couldn't send");
+    server.set_timeout(Some(5000));
+    assert!(server.recv_from(&mut [0; 16]).is_ok());
+    drop(server);
+
+    let mut buf = [0; 16];
+    client.set_timeout(Some(5000));
+    assert!(client.recv_from(&mut buf) == Ok((0, target_addr)));
+    assert!(match client.recv_from(&mut buf) { Err(e) => e.kind == EndOfFile, _ => false });
+    client.set_timeout(Some(0));
+    assert!(match client.recv_from(&mut buf) { Err(e) => e.kind == EndOfFile, _ => false });
+}
+
+/**
+ * A client which stops reading should be hung up on once a write to it times out, rather than holding up every send
+ */
+#[test]
+fn stalled_client_dropped() {
+    let port = 63033;
+    let target_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: port };
+    let mut server = TcpServerTransport::bind(target_addr).ok().expect("Couldn't listen");
+    let mut stalled = TcpStream::connect(target_addr).ok().expect("Couldn't connect");
+    let stalled_addr = stalled.socket_name().unwrap();
+
+    //One frame in, so we know the server has the stream
+    stalled.write_be_u16(1).unwrap();
+    stalled.write(&[1]).unwrap();
+    server.set_timeout(Some(5000));
+    assert!(server.recv_from(&mut [0; 16]) == Ok((1, stalled_addr)));
+
+    let packet = [0; 1000];
+    let mut failed = None;
+    for _ in (0..100000) {
+        match server.send_to(&packet, stalled_addr) {
+            Ok(()) => (),
+            Err(e) => {
+                failed = Some(e.kind);
+                break;
+            }
+        }
+    }
+    assert!(failed == Some(TimedOut));
+    assert!(server.recv_from(&mut [0; 16]) == Ok((0, stalled_addr)));
+    assert!(match server.send_to(&[1], stalled_addr) { Err(e) => e.kind == NotConnected, _ => false });
+}
@@ -0,0 +1,154 @@
+use shared::ConnectionConfig;
+use client::{ClientConnectionConfig, Client, PollFailResult, ConnectionState};
+use server::{Server, PacketOrCommand};
+use transport::Transport;
+use tests::test_shared;
+
+use std::old_io::IoResult;
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::net::ip::{Ipv4Addr, SocketAddr};
+use std::old_io::Timer;
+use std::time::duration::Duration;
+use std::thread::Thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use time::precise_time_ns;
+
+/**
+ * A transport which counts everything it sends, to prove the Client really goes through it
+ */
+struct CountingTransport {
+    socket: UdpSocket,
+    sent: Arc<AtomicUsize>
+}
+
+impl Transport for CountingTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        self.socket.send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.socket.set_timeout(timeout_ms)
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket.socket_name()
+    }
+
+    fn try_clone(&self) -> IoResult<CountingTransport> {
+        Ok(CountingTransport {
+            socket: self.socket.clone(),
+            sent: self.sent.clone()
+        })
+    }
+}
+
+/**
+ * A Client and Server should be able to run over a transport other than the default
+ */
+#[test]
+fn custom_transport() {
+    let port = 63020;
+    let target_addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: port };
+
+    Thread::spawn(move || {
+        let socket = UdpSocket::bind(target_addr).ok().expect("Couldn't bind the server");
+        let transport = CountingTransport { socket: socket, sent: Arc::new(AtomicUsize::new(0)) };
+        let settings = ConnectionConfig::new(121, Duration::seconds(10), test_shared::deserializer, test_shared::serializer);
+        match Server::with_transport(transport, settings) {
+            Ok(ref mut server) => {
+                let mut timer = Timer::new().unwrap();
+                loop {
+                    match server.poll() {
+                        Some((PacketOrCommand::UserPacket(packet), src)) => {
+                            server.send_to(&packet, &src).ok().expect("Couldn't echo a packet");
+                            break;
+                        },
+                        Some(_) => (),
+                        None => timer.sleep(Duration::milliseconds(10))
+                    }
+                }
+            },
+            Err(e) => panic!("Failed to create a server - {}", e)
+        }
+    });
+
+    let sent = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind(SocketAddr{ ip: Ipv4Addr(0, 0, 0, 0), port: 0 }).ok().expect("Couldn't bind the client");
+    let transport = CountingTransport { socket: socket, sent: sent.clone() };
+    let settings = ConnectionConfig::new(121, Duration::seconds(10), test_shared::deserializer, test_shared::serializer);
+
+    match Client::connect_with(transport, target_addr, settings, ClientConnectionConfig::new(3, Duration::seconds(5))) {
+        Ok(ref mut client) => {
+            client.send(&vec![1, 2, 3]).ok().expect("Couldn't send a packet");
+            //FIXME: There must be a better way of doing this
+            Timer::new().unwrap().sleep(Duration::seconds(1));
+            match client.poll() {
+                Ok(packet) => assert!(packet == vec![1, 2, 3]),
+                Err(PollFailResult::Empty) => panic!("Nothing came back"),
+                Err(_) => panic!("Unexpected failure")
+            };
+            //A Connect and a Message
+            assert!(sent.load(Ordering::SeqCst) == 2);
+            assert!(client.local_addr().ok().expect("Couldn't find our address").port != 0);
+        },
+        Err(e) => panic!("{}", e)
+    };
+}
+
+/**
+ * A Client and Server should be able to share one thread over real sockets, without blocking it
+ */
+#[test]
+fn nonblocking_udp() {
+    let any_port = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+    let settings = ConnectionConfig::new(121, Duration::seconds(10), test_shared::deserializer, test_shared::serializer);
+    let mut server = Server::new_nonblocking(any_port, settings).ok().expect("Couldn't bind the server");
+    let target_addr = server.local_addr().ok().expect("Couldn't find the server");
+    let settings = ConnectionConfig::new(121, Duration::seconds(10), test_shared::deserializer, test_shared::serializer);
+    let mut client = Client::connect_nonblocking(any_port, target_addr, settings, ClientConnectionConfig::new(3, Duration::seconds(1))).ok().expect("Couldn't bind the client");
+    assert!(client.connection_state == ConnectionState::Connecting);
+
+    //Nothing is waiting, so a pump should come straight back
+    let started = precise_time_ns();
+    server.pump();
+    assert!(precise_time_ns() - started < 100000000);
+
+    let mut timer = Timer::new().unwrap();
+    let mut echoed = None;
+    for _ in (0..200) {
+        client.pump().ok().expect("Gave up connecting");
+        server.pump();
+        loop {
+            match server.poll() {
+                Some((PacketOrCommand::UserPacket(packet), src)) => {
+                    server.send_to(&packet, &src).ok().expect("Couldn't echo a packet");
+                },
+                Some(_) => (),
+                None => break
+            }
+        }
+        if client.connection_state == ConnectionState::Connected {
+            match client.poll() {
+                Ok(packet) => {
+                    echoed = Some(packet);
+                    break;
+                },
+                Err(PollFailResult::Empty) => (),
+                Err(_) => panic!("Unexpected failure")
+            }
+            if echoed.is_none() {
+                match client.send(&vec![4, 5, 6]) {
+                    _ => ()
+                }
+            }
+        }
+        timer.sleep(Duration::milliseconds(5));
+    }
+    assert!(echoed == Some(vec![4, 5, 6]));
+}
@@ -0,0 +1,56 @@
+//!
+//! The layer which actually moves datagrams between peers
+//!
+//! `Client` and `Server` only ever talk to a `Transport`, so anything which can send a datagram to an
+//! address and wait a while for one to arrive can stand in for a real socket.
+//!
+use std::old_io::IoResult;
+use std::old_io::net::udp::UdpSocket;
+use std::old_io::net::ip::SocketAddr;
+
+/**
+ * Something which can carry datagrams to and from peers
+ *
+ * Clients and Servers run a reader and a writer thread, each with its own handle from `try_clone`,
+ * so every handle must refer to the same underlying endpoint.
+ */
+pub trait Transport: Send + Sized + 'static {
+    /// Send a single datagram to a peer
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()>;
+    /// Wait for a datagram, failing with TimedOut if nothing arrives before the timeout,
+    /// and with EndOfFile once nothing more can ever arrive
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)>;
+    /// How long, in milliseconds, recv_from should wait. None waits forever
+    fn set_timeout(&mut self, timeout_ms: Option<u64>);
+    /// The address peers can reach us on
+    fn local_addr(&mut self) -> IoResult<SocketAddr>;
+    /// Create another handle to the same endpoint
+    fn try_clone(&self) -> IoResult<Self>;
+    /// Whether this carries streams, which report a peer hanging up as a zero-length datagram.
+    /// Anyone can send an empty UDP datagram from a forged address, so for anything else they're ignored
+    fn is_stream(&self) -> bool {
+        false
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        UdpSocket::set_timeout(self, timeout_ms)
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket_name()
+    }
+
+    fn try_clone(&self) -> IoResult<UdpSocket> {
+        Ok(self.clone())
+    }
+}
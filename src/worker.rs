@@ -0,0 +1,121 @@
+//!
+//! Pieces shared by the Client and Server background workers
+//!
+use std::old_io::{IoResult, Timer};
+use std::sync::mpsc::{Sender, Receiver, channel, Select};
+use std::time::duration::Duration;
+use std::u64;
+use time::precise_time_ns;
+use conditioner::LinkConditioner;
+use shared::ConnectionConfig;
+use transport::Transport;
+
+///How long background readers wait on the transport before checking for commands, in milliseconds
+pub const READ_TIMEOUT_MS: u64 = 1000;
+
+///How long a pumped poller waits on the transport before checking whether it's been woken, in milliseconds
+pub const WAKE_CHECK_MS: u64 = 10;
+
+/**
+ * What happened when a reader or writer took a single step
+ */
+#[derive(Clone, Copy, PartialEq, Show)]
+pub enum WorkerStep {
+    ///Something was handled, so there may be more to do
+    Busy,
+    ///Nothing was waiting
+    Idle,
+    ///The other end has gone away, so the worker should stop
+    Stop
+}
+
+/**
+ * Wakes a Client or Server blocked in `wait` or `poll_timeout`, from any thread
+ *
+ * A wake with nobody waiting is kept, and ends the next wait straight away.
+ */
+#[derive(Clone)]
+pub struct Waker {
+    send: Sender<()>
+}
+
+impl Waker {
+
+    /**
+     * Create a Waker, and the receiver a waiting poller should watch
+     */
+    pub fn new() -> (Waker, Receiver<()>) {
+        let (send, receive) = channel();
+        (Waker { send: send }, receive)
+    }
+
+    /**
+     * End the current or next wait early, such as for a shutdown
+     */
+    pub fn wake(&self) {
+        match self.send.send(()) {
+            _ => () //If the poller has gone, there's nothing left to wake
+        }
+    }
+}
+
+/**
+ * A worker's handle on the transport, conditioned as the config says
+ *
+ * Any thread delaying packets holds on to `finished` too, so shutting down waits for it.
+ */
+pub fn conditioned<N: Transport, T>(transport: &N, config: &ConnectionConfig<T>, finished: &Sender<()>) -> IoResult<LinkConditioner<N>> {
+    let mut conditioned = LinkConditioner::with_clock(try!(transport.try_clone()), config.link_conditions, config.clock.clone());
+    conditioned.set_finished(finished.clone());
+    Ok(conditioned)
+}
+
+/**
+ * Whether we've been asked to wake, taking every wake waiting so none are left over for the next wait
+ */
+pub fn woken(wakes: &Receiver<()>) -> bool {
+    let mut woken = false;
+    while wakes.try_recv().is_ok() {
+        woken = true;
+    }
+    woken
+}
+
+/**
+ * When, by `precise_time_ns`, a timeout starting now runs out
+ *
+ * Timeouts too long to count in nanoseconds run out at the end of time rather than straight away.
+ */
+pub fn deadline_after(timeout: Duration) -> u64 {
+    let now = precise_time_ns();
+    match timeout.num_nanoseconds() {
+        Some(ns) if ns <= 0 => now,
+        Some(ns) if ns as u64 > u64::MAX - now => u64::MAX,
+        Some(ns) => now + ns as u64,
+        None if timeout > Duration::zero() => u64::MAX,
+        None => now
+    }
+}
+
+/**
+ * Block until something arrives, goes wrong, or asks us to wake, or `wait_ms` passes
+ *
+ * Nothing is taken off of `arrivals` or `errors`. Returns true if we were woken.
+ */
+pub fn wait_for<A: Send, B: Send>(arrivals: &Receiver<A>, errors: &Receiver<B>, wakes: &Receiver<()>, wait_ms: Option<u64>) -> IoResult<bool> {
+    let mut timer = try!(Timer::new());
+    let (_never_send, never) = channel();
+    let timeout = match wait_ms {
+        Some(ms) => timer.oneshot(Duration::milliseconds(ms as i64)),
+        None => never
+    };
+
+    //FIXME: Replace with the select! macro when it starts working
+    let sel = Select::new();
+    let mut arrival = sel.handle(arrivals);
+    let mut error = sel.handle(errors);
+    let mut wake = sel.handle(wakes);
+    let mut timeout = sel.handle(&timeout);
+    unsafe { arrival.add(); error.add(); wake.add(); timeout.add(); }
+    Ok(sel.wait() == wake.id() && woken(wakes))
+}
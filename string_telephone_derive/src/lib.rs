@@ -0,0 +1,704 @@
+//!
+//! # String Telephone Derive
+//!
+//! `#[derive(NetMessage)]` generates a compact `NetEncode` implementation for structs and enums,
+//! so the serializer and deserializer for a connection can never disagree with each other.
+//!
+//! This is a compiler plugin, so load it next to string_telephone:
+//!
+//! ```ignore
+//! #![feature(plugin)]
+//!
+//! #[plugin] #[no_link]
+//! extern crate string_telephone_derive;
+//! extern crate string_telephone;
+//! ```
+//!
+//! Fields can be tuned with the `net` attribute:
+//!
+//! * `#[net(bits = 5)]` - send an integer using only the given number of bits, failing to encode any value which doesn't fit
+//! * `#[net(range(min = "-10", max = 10))]` - send an integer using only as many bits as the range needs, failing to encode any value outside of it
+//! * `#[net(quantize(min = "-10.0", max = 10.0, bits = 12))]` - send a float as a fixed-point value
+//! * `#[net(skip)]` - don't send the field at all, and use its `Default` when receiving
+//!
+//! `Option` fields are sent with a single presence bit, and any `bits` or `quantize` attribute
+//! applies to the value inside. Tuple variants can't carry attributes, so use a struct variant
+//! for any fields which need them.
+//!
+//! Putting `#[net(id = 3)]` on the type itself also implements `NetMessage` from the registry, so
+//! it can share a connection with other message types.
+//!
+#![feature(plugin_registrar, rustc_private)]
+#![crate_name = "string_telephone_derive"]
+#![crate_type = "dylib"]
+
+extern crate syntax;
+extern crate rustc;
+
+use rustc::plugin::Registry;
+use syntax::ast;
+use syntax::ast::{Item, MetaItem, Attribute, StructDef, Ty, Generics, Ident};
+use syntax::attr;
+use syntax::codemap::Span;
+use syntax::ext::base::{ExtCtxt, Decorator};
+use syntax::parse;
+use syntax::parse::token;
+use syntax::print::pprust;
+use syntax::ptr::P;
+
+/**
+ * How a single field should be written out
+ */
+enum Encoding {
+    Default,
+    Bits(usize),
+    Range { min: i64, max: i64 },
+    Quantize { min: f64, max: f64, bits: usize },
+    Skip
+}
+
+/**
+ * A field to be sent, as the generated code will see it
+ */
+struct Field {
+    ///What it's called in the type, if it has a name
+    name: Option<String>,
+    ///What it's bound to while encoding and decoding
+    binding: String,
+    ty: P<Ty>,
+    encoding: Encoding
+}
+
+/**
+ * The shape of a struct, or of a single enum variant
+ */
+enum Shape {
+    Named,
+    Unnamed,
+    Unit
+}
+
+#[plugin_registrar]
+pub fn plugin_registrar(reg: &mut Registry) {
+    reg.register_syntax_extension(token::intern("derive_NetMessage"), Decorator(Box::new(expand_net_message)));
+}
+
+fn expand_net_message(cx: &mut ExtCtxt, span: Span, _: &MetaItem, item: &Item, mut push: Box<FnMut(P<Item>)>) {
+    let sources = match expand(cx, item) {
+        Some(sources) => sources,
+        None => return //Already reported
+    };
+    for source in sources.into_iter() {
+        match parse::parse_item_from_source_str("<derive(NetMessage)>".to_string(), source, cx.cfg(), cx.parse_sess()) {
+            Some(generated) => push(generated),
+            None => cx.span_err(span, "derive(NetMessage) generated something which didn't parse")
+        }
+    }
+}
+
+/**
+ * Write out the source of every impl needed for this item
+ */
+fn expand(cx: &mut ExtCtxt, item: &Item) -> Option<Vec<String>> {
+    let name = ident_string(item.ident);
+    let (generics, encode_body, decode_body) = match item.node {
+        ast::ItemStruct(ref def, ref generics) => {
+            let (encode, decode) = match expand_struct(cx, &name, &**def) {
+                Some(bodies) => bodies,
+                None => return None
+            };
+            (generics, encode, decode)
+        },
+        ast::ItemEnum(ref def, ref generics) => {
+            let (encode, decode) = match expand_enum(cx, &name, def) {
+                Some(bodies) => bodies,
+                None => return None
+            };
+            (generics, encode, decode)
+        },
+        _ => {
+            cx.span_err(item.span, "NetMessage can only be derived for structs and enums");
+            return None;
+        }
+    };
+
+    let (impl_generics, ty_generics) = match split_generics(cx, item.span, generics) {
+        Some(split) => split,
+        None => return None
+    };
+    let ty = format!("{}{}", name, ty_generics);
+
+    let mut sources = vec![format!(
+        "impl{impl_generics} ::string_telephone::encoding::NetEncode for {ty} {{
+            #[allow(unused_variables)]
+            fn encode(&self, writer: &mut ::string_telephone::bits::BitWriter) -> ::string_telephone::bits::BitResult<()> {{
+                {encode_body}
+                Ok(())
+            }}
+
+            #[allow(unused_variables)]
+            fn decode(reader: &mut ::string_telephone::bits::BitReader) -> ::string_telephone::bits::BitResult<{ty}> {{
+                {decode_body}
+            }}
+        }}",
+        impl_generics = impl_generics, ty = ty, encode_body = encode_body, decode_body = decode_body)];
+
+    match parse_message_id(cx, &item.attrs) {
+        Ok(Some(message_id)) => sources.push(format!(
+            "impl{impl_generics} ::string_telephone::registry::NetMessage for {ty} {{
+                fn message_id() -> u16 {{
+                    {message_id}
+                }}
+
+                fn encode(&self) -> Option<Vec<u8>> {{
+                    ::string_telephone::encoding::serializer(self)
+                }}
+
+                fn decode(raw: &Vec<u8>) -> Option<{ty}> {{
+                    ::string_telephone::encoding::deserializer(raw)
+                }}
+            }}",
+            impl_generics = impl_generics, ty = ty, message_id = message_id)),
+        Ok(None) => (),
+        Err(()) => return None
+    }
+    Some(sources)
+}
+
+fn expand_struct(cx: &mut ExtCtxt, name: &String, def: &StructDef) -> Option<(String, String)> {
+    let (shape, fields) = match collect_fields(cx, def) {
+        Some(collected) => collected,
+        None => return None
+    };
+
+    let mut encode = String::new();
+    for field in fields.iter() {
+        match encode_value(cx, &field.binding, &*field.ty, &field.encoding) {
+            Some(code) => encode.push_str(code.as_slice()),
+            None => return None
+        }
+    }
+    let encode = format!("match *self {{ {} => {{ {} }} }}", pattern(name, &shape, &fields), encode);
+
+    match decode_fields(cx, name, &shape, &fields) {
+        Some(decode) => Some((encode, decode)),
+        None => None
+    }
+}
+
+fn expand_enum(cx: &mut ExtCtxt, name: &String, def: &ast::EnumDef) -> Option<(String, String)> {
+    let tag_bits = bits_needed(def.variants.len());
+
+    let mut encode_arms = String::new();
+    let mut decode_arms = String::new();
+    for (tag, variant) in def.variants.iter().enumerate() {
+        let variant_name = format!("{}::{}", name, ident_string(variant.node.name));
+        let (shape, fields) = match variant.node.kind {
+            ast::TupleVariantKind(ref args) => {
+                let fields: Vec<Field> = args.iter().enumerate().map(|(index, arg)| Field {
+                    name: None,
+                    binding: format!("__field{}", index),
+                    ty: arg.ty.clone(),
+                    encoding: Encoding::Default
+                }).collect();
+                (if fields.len() == 0 { Shape::Unit } else { Shape::Unnamed }, fields)
+            },
+            ast::StructVariantKind(ref def) => match collect_fields(cx, &**def) {
+                Some(collected) => collected,
+                None => return None
+            }
+        };
+
+        let mut encode_fields = String::new();
+        for field in fields.iter() {
+            match encode_value(cx, &field.binding, &*field.ty, &field.encoding) {
+                Some(code) => encode_fields.push_str(code.as_slice()),
+                None => return None
+            }
+        }
+        encode_arms.push_str(format!("{} => {{ writer.write_bits({}, {}); {} }},\n",
+            pattern(&variant_name, &shape, &fields), tag, tag_bits, encode_fields).as_slice());
+
+        match decode_fields(cx, &variant_name, &shape, &fields) {
+            Some(decode) => decode_arms.push_str(format!("Ok({}) => {{ {} }},\n", tag, decode).as_slice()),
+            None => return None
+        }
+    }
+
+    let encode = format!("match *self {{ {} }}", encode_arms);
+    let decode = format!(
+        "match reader.read_bits({}) {{
+            {}
+            Ok(_) => Err(::string_telephone::bits::BitError::InvalidData),
+            Err(e) => Err(e)
+        }}", tag_bits, decode_arms);
+    Some((encode, decode))
+}
+
+/**
+ * Gather up the fields of a struct or struct variant, along with how each should be sent
+ */
+fn collect_fields(cx: &mut ExtCtxt, def: &StructDef) -> Option<(Shape, Vec<Field>)> {
+    let mut shape = Shape::Unit;
+    let mut fields = vec![];
+    for (index, field) in def.fields.iter().enumerate() {
+        let name = match field.node.kind {
+            ast::NamedField(ident, _) => {
+                shape = Shape::Named;
+                Some(ident_string(ident))
+            },
+            ast::UnnamedField(_) => {
+                shape = Shape::Unnamed;
+                None
+            }
+        };
+        let encoding = match parse_encoding(cx, &field.node.attrs) {
+            Ok(encoding) => encoding,
+            Err(()) => return None
+        };
+        fields.push(Field {
+            name: name,
+            binding: format!("__field{}", index),
+            ty: field.node.ty.clone(),
+            encoding: encoding
+        });
+    }
+    Some((shape, fields))
+}
+
+/**
+ * A pattern binding every field by reference
+ */
+fn pattern(constructor: &String, shape: &Shape, fields: &Vec<Field>) -> String {
+    match *shape {
+        Shape::Named => {
+            let bound: Vec<String> = fields.iter().map(|field| format!("{}: ref {}", field.name.as_ref().unwrap(), field.binding)).collect();
+            format!("{} {{ {} }}", constructor, bound.connect(", "))
+        },
+        Shape::Unnamed => {
+            let bound: Vec<String> = fields.iter().map(|field| format!("ref {}", field.binding)).collect();
+            format!("{}({})", constructor, bound.connect(", "))
+        },
+        Shape::Unit => constructor.clone()
+    }
+}
+
+/**
+ * Read every field in order, then build the value out of them
+ */
+fn decode_fields(cx: &mut ExtCtxt, constructor: &String, shape: &Shape, fields: &Vec<Field>) -> Option<String> {
+    let mut reads = String::new();
+    for field in fields.iter() {
+        match decode_value(cx, &*field.ty, &field.encoding) {
+            Some(value) => reads.push_str(format!("let {}: {} = {};\n", field.binding, pprust::ty_to_string(&*field.ty), value).as_slice()),
+            None => return None
+        }
+    }
+
+    let construct = match *shape {
+        Shape::Named => {
+            let assigned: Vec<String> = fields.iter().map(|field| format!("{}: {}", field.name.as_ref().unwrap(), field.binding)).collect();
+            format!("{} {{ {} }}", constructor, assigned.connect(", "))
+        },
+        Shape::Unnamed => {
+            let bindings: Vec<String> = fields.iter().map(|field| field.binding.clone()).collect();
+            format!("{}({})", constructor, bindings.connect(", "))
+        },
+        Shape::Unit => constructor.clone()
+    };
+    Some(format!("{} Ok({})", reads, construct))
+}
+
+/**
+ * Generate the statements which write out a value, given a binding referencing it
+ */
+fn encode_value(cx: &mut ExtCtxt, value: &String, ty: &Ty, encoding: &Encoding) -> Option<String> {
+    match *encoding {
+        Encoding::Default => return Some(format!(
+            "match ::string_telephone::encoding::NetEncode::encode({}, writer) {{
+                Ok(()) => (),
+                Err(e) => return Err(e)
+            }}", value)),
+        Encoding::Skip => return Some(String::new()),
+        _ => ()
+    }
+
+    match option_inner(ty) {
+        Some(inner_ty) => {
+            let inner = match encode_value(cx, &"inner".to_string(), inner_ty, encoding) {
+                Some(inner) => inner,
+                None => return None
+            };
+            return Some(format!(
+                "match *{} {{
+                    Some(ref inner) => {{
+                        writer.write_bool(true);
+                        {}
+                    }},
+                    None => writer.write_bool(false)
+                }}", value, inner));
+        },
+        None => ()
+    }
+
+    match *encoding {
+        Encoding::Bits(bits) => {
+            let written = match check_integer(cx, ty) {
+                Some(true) => format!("writer.write_signed(*{} as i64, {})", value, bits),
+                Some(false) => format!("writer.write_unsigned(*{} as u64, {})", value, bits),
+                None => return None
+            };
+            Some(format!("match {} {{ Ok(()) => (), Err(e) => return Err(e) }}", written))
+        },
+        Encoding::Range { min, max } => {
+            let written = match check_integer(cx, ty) {
+                Some(true) => format!("writer.write_ranged(*{} as i64, {}, {})", value, min, max),
+                //Past i64::MAX would wrap around to a negative, which could even land inside the range
+                Some(false) => format!(
+                    "if *{value} as u64 > ::std::i64::MAX as u64 {{
+                        Err(::string_telephone::bits::BitError::OutOfRange)
+                    }} else {{
+                        writer.write_ranged(*{value} as i64, {min}, {max})
+                    }}", value = value, min = min, max = max),
+                None => return None
+            };
+            Some(format!("match {} {{ Ok(()) => (), Err(e) => return Err(e) }}", written))
+        },
+        Encoding::Quantize { min, max, bits } => {
+            match check_float(cx, ty) {
+                Some(()) => Some(format!("writer.write_quantized(*{} as f64, {}, {}, {});", value, float_source(min), float_source(max), bits)),
+                None => None
+            }
+        },
+        _ => unreachable!()
+    }
+}
+
+/**
+ * Generate an expression which reads back a value, returning the error from decode on failure
+ */
+fn decode_value(cx: &mut ExtCtxt, ty: &Ty, encoding: &Encoding) -> Option<String> {
+    match *encoding {
+        Encoding::Default => return Some(
+            "match ::string_telephone::encoding::NetEncode::decode(reader) {
+                Ok(value) => value,
+                Err(e) => return Err(e)
+            }".to_string()),
+        Encoding::Skip => return Some("::std::default::Default::default()".to_string()),
+        _ => ()
+    }
+
+    match option_inner(ty) {
+        Some(inner_ty) => {
+            let inner = match decode_value(cx, inner_ty, encoding) {
+                Some(inner) => inner,
+                None => return None
+            };
+            return Some(format!(
+                "match reader.read_bool() {{
+                    Ok(true) => Some({}),
+                    Ok(false) => None,
+                    Err(e) => return Err(e)
+                }}", inner));
+        },
+        None => ()
+    }
+
+    let ty_name = pprust::ty_to_string(ty);
+    match *encoding {
+        Encoding::Bits(bits) => {
+            //Wider than the field is allowed, so anything which doesn't fit back in is malformed
+            let (read, wide) = match check_integer(cx, ty) {
+                Some(true) => (format!("reader.read_signed({})", bits), "i64"),
+                Some(false) => (format!("reader.read_bits({})", bits), "u64"),
+                None => return None
+            };
+            Some(format!(
+                "match {read} {{
+                    Ok(raw) if raw as {ty} as {wide} == raw => raw as {ty},
+                    Ok(_) => return Err(::string_telephone::bits::BitError::OutOfRange),
+                    Err(e) => return Err(e)
+                }}", read = read, ty = ty_name, wide = wide))
+        },
+        Encoding::Range { min, max } => {
+            //The range may reach further than the field can hold
+            let fits = match check_integer(cx, ty) {
+                Some(true) => format!("value as {} as i64 == value", ty_name),
+                Some(false) => format!("value >= 0 && value as {} as i64 == value", ty_name),
+                None => return None
+            };
+            Some(format!(
+                "match reader.read_ranged({min}, {max}) {{
+                    Ok(value) if {fits} => value as {ty},
+                    Ok(_) => return Err(::string_telephone::bits::BitError::OutOfRange),
+                    Err(e) => return Err(e)
+                }}", min = min, max = max, fits = fits, ty = ty_name))
+        },
+        Encoding::Quantize { min, max, bits } => {
+            match check_float(cx, ty) {
+                Some(()) => Some(format!(
+                    "match reader.read_quantized({}, {}, {}) {{
+                        Ok(value) => value as {},
+                        Err(e) => return Err(e)
+                    }}", float_source(min), float_source(max), bits, ty_name)),
+                None => None
+            }
+        },
+        _ => unreachable!()
+    }
+}
+
+/**
+ * The generics to put after `impl`, each type parameter also needing NetEncode, and those to put after the type's name
+ */
+fn split_generics(cx: &mut ExtCtxt, span: Span, generics: &Generics) -> Option<(String, String)> {
+    if generics.where_clause.predicates.len() > 0 {
+        cx.span_err(span, "NetMessage can't be derived for types with where clauses, so put any bounds on the parameters themselves");
+        return None;
+    }
+
+    let mut declared = vec![];
+    let mut used = vec![];
+    for lifetime in generics.lifetimes.iter() {
+        let name = token::get_name(lifetime.lifetime.name).get().to_string();
+        declared.push(name.clone());
+        used.push(name);
+    }
+    for param in generics.ty_params.iter() {
+        let name = ident_string(param.ident);
+        let mut bounds = "::string_telephone::encoding::NetEncode".to_string();
+        if param.bounds.len() > 0 {
+            bounds.push_str(" + ");
+            bounds.push_str(pprust::bounds_to_string(param.bounds.as_slice()).as_slice());
+        }
+        declared.push(format!("{}: {}", name, bounds));
+        used.push(name);
+    }
+
+    if used.len() == 0 {
+        Some((String::new(), String::new()))
+    } else {
+        Some((format!("<{}>", declared.connect(", ")), format!("<{}>", used.connect(", "))))
+    }
+}
+
+/**
+ * How many bits are needed to tell this many variants apart
+ */
+fn bits_needed(count: usize) -> usize {
+    if count <= 1 {
+        0
+    } else {
+        64 - ((count - 1) as u64).leading_zeros() as usize
+    }
+}
+
+fn ident_string(ident: Ident) -> String {
+    token::get_ident(ident).get().to_string()
+}
+
+/**
+ * Written as a cast, so a whole number isn't taken for an integer
+ */
+fn float_source(value: f64) -> String {
+    format!("({} as f64)", value)
+}
+
+/**
+ * If this is an Option, get the type it wraps
+ */
+fn option_inner(ty: &Ty) -> Option<&Ty> {
+    let segment = match ty.node {
+        ast::TyPath(ref path, _) => path.segments.last(),
+        _ => None
+    };
+    match segment {
+        Some(segment) if ident_string(segment.identifier).as_slice() == "Option" => match segment.parameters {
+            ast::AngleBracketedParameters(ref data) if data.types.len() == 1 => data.types.iter().next().map(|inner| &**inner),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+/**
+ * Make sure bit widths are only used on integers, returning whether the integer is signed
+ */
+fn check_integer(cx: &mut ExtCtxt, ty: &Ty) -> Option<bool> {
+    match pprust::ty_to_string(ty).as_slice() {
+        "u8" | "u16" | "u32" | "u64" | "usize" => Some(false),
+        "i8" | "i16" | "i32" | "i64" | "isize" => Some(true),
+        _ => {
+            cx.span_err(ty.span, "`bits` and `range` can only be used on integer fields");
+            None
+        }
+    }
+}
+
+fn check_float(cx: &mut ExtCtxt, ty: &Ty) -> Option<()> {
+    match pprust::ty_to_string(ty).as_slice() {
+        "f32" | "f64" => Some(()),
+        _ => {
+            cx.span_err(ty.span, "`quantize` can only be used on float fields");
+            None
+        }
+    }
+}
+
+/**
+ * Everything inside any `#[net(...)]` attributes
+ */
+fn net_attributes(cx: &mut ExtCtxt, attrs: &Vec<Attribute>) -> Result<Vec<P<MetaItem>>, ()> {
+    let mut nested = vec![];
+    for attribute in attrs.iter() {
+        match attribute.node.value.node {
+            ast::MetaList(ref name, ref items) if name.get() == "net" => {
+                attr::mark_used(attribute);
+                nested.push_all(items.as_slice());
+            },
+            ast::MetaWord(ref name) | ast::MetaNameValue(ref name, _) if name.get() == "net" => {
+                cx.span_err(attribute.span, "expected #[net(...)]");
+                return Err(());
+            },
+            _ => ()
+        }
+    }
+    Ok(nested)
+}
+
+fn parse_message_id(cx: &mut ExtCtxt, attrs: &Vec<Attribute>) -> Result<Option<u16>, ()> {
+    let mut message_id = None;
+    for meta in try!(net_attributes(cx, attrs)).iter() {
+        match meta.node {
+            ast::MetaNameValue(ref name, ref lit) if name.get() == "id" => {
+                match lit_integer(lit) {
+                    Some(id) if id <= std::u16::MAX as i64 && id >= 0 => message_id = Some(id as u16),
+                    _ => {
+                        cx.span_err(lit.span, "message ids must be between 0 and 65535");
+                        return Err(());
+                    }
+                }
+            },
+            _ => {
+                cx.span_err(meta.span, "unknown net attribute, expected `id`");
+                return Err(());
+            }
+        }
+    }
+    Ok(message_id)
+}
+
+fn parse_encoding(cx: &mut ExtCtxt, attrs: &Vec<Attribute>) -> Result<Encoding, ()> {
+    let mut encoding = Encoding::Default;
+    for meta in try!(net_attributes(cx, attrs)).iter() {
+        encoding = match meta.node {
+            ast::MetaWord(ref name) if name.get() == "skip" => Encoding::Skip,
+            ast::MetaNameValue(ref name, ref lit) if name.get() == "bits" => {
+                Encoding::Bits(try!(bit_width(cx, lit, 64)))
+            },
+            ast::MetaList(ref name, ref items) if name.get() == "range" => {
+                let (mut min, mut max) = (None, None);
+                for item in items.iter() {
+                    match item.node {
+                        ast::MetaNameValue(ref name, ref lit) if name.get() == "min" => min = Some(try!(signed(cx, lit))),
+                        ast::MetaNameValue(ref name, ref lit) if name.get() == "max" => max = Some(try!(signed(cx, lit))),
+                        _ => {
+                            cx.span_err(item.span, "expected `min` or `max`");
+                            return Err(());
+                        }
+                    }
+                }
+                match (min, max) {
+                    (Some(min), Some(max)) if min <= max => Encoding::Range { min: min, max: max },
+                    (Some(_), Some(_)) => {
+                        cx.span_err(meta.span, "`min` must not be more than `max`");
+                        return Err(());
+                    },
+                    _ => {
+                        cx.span_err(meta.span, "`range` needs `min` and `max`");
+                        return Err(());
+                    }
+                }
+            },
+            ast::MetaList(ref name, ref items) if name.get() == "quantize" => {
+                let (mut min, mut max, mut bits) = (None, None, None);
+                for item in items.iter() {
+                    match item.node {
+                        ast::MetaNameValue(ref name, ref lit) if name.get() == "min" => min = Some(try!(float(cx, lit))),
+                        ast::MetaNameValue(ref name, ref lit) if name.get() == "max" => max = Some(try!(float(cx, lit))),
+                        ast::MetaNameValue(ref name, ref lit) if name.get() == "bits" => bits = Some(try!(bit_width(cx, lit, 32))),
+                        _ => {
+                            cx.span_err(item.span, "expected `min`, `max` or `bits`");
+                            return Err(());
+                        }
+                    }
+                }
+                match (min, max, bits) {
+                    (Some(min), Some(max), Some(bits)) if min < max => Encoding::Quantize { min: min, max: max, bits: bits },
+                    (Some(_), Some(_), Some(_)) => {
+                        cx.span_err(meta.span, "`min` must be less than `max`");
+                        return Err(());
+                    },
+                    _ => {
+                        cx.span_err(meta.span, "`quantize` needs `min`, `max` and `bits`");
+                        return Err(());
+                    }
+                }
+            },
+            _ => {
+                cx.span_err(meta.span, "unknown net attribute, expected `bits`, `range`, `quantize` or `skip`");
+                return Err(());
+            }
+        }
+    }
+    Ok(encoding)
+}
+
+/**
+ * Integers are accepted as numbers, or as strings so that negative values can be written
+ */
+fn lit_integer(lit: &ast::Lit) -> Option<i64> {
+    match lit.node {
+        ast::LitInt(value, _) if value <= std::i64::MAX as u64 => Some(value as i64),
+        ast::LitStr(ref value, _) => value.get().parse(),
+        _ => None
+    }
+}
+
+fn signed(cx: &mut ExtCtxt, lit: &ast::Lit) -> Result<i64, ()> {
+    match lit_integer(lit) {
+        Some(value) => Ok(value),
+        None => {
+            cx.span_err(lit.span, "expected an integer");
+            Err(())
+        }
+    }
+}
+
+fn bit_width(cx: &mut ExtCtxt, lit: &ast::Lit, max: usize) -> Result<usize, ()> {
+    match lit.node {
+        ast::LitInt(bits, _) if bits > 0 && bits <= max as u64 => Ok(bits as usize),
+        _ => {
+            cx.span_err(lit.span, format!("bit widths must be between 1 and {}", max).as_slice());
+            Err(())
+        }
+    }
+}
+
+/**
+ * Floats are accepted as numbers, or as strings so that negative values can be written
+ */
+fn float(cx: &mut ExtCtxt, lit: &ast::Lit) -> Result<f64, ()> {
+    let parsed = match lit.node {
+        ast::LitFloat(ref value, _) | ast::LitFloatUnsuffixed(ref value) | ast::LitStr(ref value, _) => value.get().parse(),
+        ast::LitInt(value, _) => Some(value as f64),
+        _ => None
+    };
+    match parsed {
+        Some(value) => Ok(value),
+        None => {
+            cx.span_err(lit.span, "expected a number");
+            Err(())
+        }
+    }
+}
@@ -0,0 +1,192 @@
+#![feature(plugin)]
+
+#[plugin] #[no_link]
+extern crate string_telephone_derive;
+extern crate string_telephone;
+
+use string_telephone::encoding::{serializer, deserializer};
+use string_telephone::registry::{NetMessage, Message};
+
+#[derive(NetMessage, PartialEq, Clone)]
+struct Position {
+    #[net(quantize(min = "-128.0", max = 127.0, bits = 8))]
+    x: f32,
+    #[net(quantize(min = "-128.0", max = 127.0, bits = 8))]
+    y: f32
+}
+
+#[derive(NetMessage, PartialEq, Clone)]
+struct PlayerUpdate {
+    #[net(bits = 6)]
+    player: u8,
+    #[net(range(min = "-8", max = 7))]
+    health_change: i8,
+    position: Position,
+    alive: bool,
+    #[net(bits = 10)]
+    target: Option<u16>,
+    name: Option<String>,
+    #[net(skip)]
+    local_only: u32
+}
+
+#[derive(NetMessage, PartialEq, Clone)]
+#[net(id = 7)]
+enum Command {
+    Idle,
+    Move(Position),
+    Say { #[net(bits = 3)] channel: u8, text: String },
+    Fire { #[net(bits = 12)] angle: u16, charged: bool }
+}
+
+#[derive(NetMessage, PartialEq, Clone)]
+struct Wrapper<T>(Vec<T>);
+
+#[derive(NetMessage, PartialEq, Clone)]
+enum Small {
+    A,
+    B,
+    C
+}
+
+fn round_trip<T: string_telephone::encoding::NetEncode>(value: &T) -> Option<T> {
+    serializer(value).and_then(|raw| deserializer(&raw))
+}
+
+#[test]
+fn struct_round_trip() {
+    let update = PlayerUpdate {
+        player: 42,
+        health_change: -3,
+        position: Position { x: 0.0, y: 50.0 },
+        alive: true,
+        target: Some(1000),
+        name: None,
+        local_only: 0
+    };
+    assert!(round_trip(&update) == Some(update));
+}
+
+#[test]
+fn bit_widths_are_respected() {
+    let update = PlayerUpdate {
+        player: 1,
+        health_change: 1,
+        position: Position { x: 0.0, y: 0.0 },
+        alive: false,
+        target: None,
+        name: None,
+        local_only: 0
+    };
+    //6 + 4 + 8 + 8 + 1 + 1 + 1 bits, padded to a whole byte
+    assert!(serializer(&update).unwrap().len() == 4);
+}
+
+#[test]
+fn too_large_for_bits() {
+    let update = PlayerUpdate {
+        player: 64,
+        health_change: 0,
+        position: Position { x: 0.0, y: 0.0 },
+        alive: true,
+        target: None,
+        name: None,
+        local_only: 0
+    };
+    assert!(serializer(&update) == None);
+    assert!(serializer(&Command::Fire { angle: 4096, charged: true }) == None);
+    assert!(serializer(&Command::Fire { angle: 4095, charged: true }).is_some());
+}
+
+#[derive(NetMessage, PartialEq, Clone)]
+struct Counter {
+    #[net(range(min = 0, max = 100))]
+    count: u64
+}
+
+#[test]
+fn outside_of_range() {
+    let update = PlayerUpdate {
+        player: 1,
+        health_change: 8,
+        position: Position { x: 0.0, y: 0.0 },
+        alive: true,
+        target: None,
+        name: None,
+        local_only: 0
+    };
+    assert!(serializer(&update) == None);
+    assert!(serializer(&Counter { count: 101 }) == None);
+    //Would wrap around to a negative if squeezed into an i64
+    assert!(serializer(&Counter { count: ::std::u64::MAX }) == None);
+    assert!(round_trip(&Counter { count: 100 }) == Some(Counter { count: 100 }));
+}
+
+#[test]
+fn skipped_fields_use_default() {
+    let update = PlayerUpdate {
+        player: 1,
+        health_change: 0,
+        position: Position { x: 0.0, y: 0.0 },
+        alive: true,
+        target: None,
+        name: Some("Alice".to_string()),
+        local_only: 99
+    };
+    let decoded = round_trip(&update).unwrap();
+    assert!(decoded.local_only == 0);
+    assert!(decoded.name == Some("Alice".to_string()));
+}
+
+#[test]
+fn quantization_is_close() {
+    let position = Position { x: 12.3, y: -99.9 };
+    let decoded = round_trip(&position).unwrap();
+    assert!((decoded.x - position.x).abs() <= 0.5);
+    assert!((decoded.y - position.y).abs() <= 0.5);
+}
+
+#[test]
+fn quantization_clamps() {
+    let decoded = round_trip(&Position { x: 1000.0, y: -1000.0 }).unwrap();
+    assert!(decoded == Position { x: 127.0, y: -128.0 });
+}
+
+#[test]
+fn enum_round_trip() {
+    let commands = vec![
+        Command::Idle,
+        Command::Move(Position { x: 1.0, y: 1.0 }),
+        Command::Say { channel: 5, text: "Hello".to_string() },
+        Command::Fire { angle: 4095, charged: true }
+    ];
+    for command in commands.iter() {
+        assert!(round_trip(command).as_ref() == Some(command));
+    }
+    //Four variants fit in two bits
+    assert!(serializer(&Command::Idle).unwrap().len() == 1);
+}
+
+#[test]
+fn generic_round_trip() {
+    let wrapper = Wrapper(vec![1u32, 2, 3]);
+    assert!(round_trip(&wrapper) == Some(wrapper));
+}
+
+#[test]
+fn malformed_input() {
+    assert!(deserializer::<Position>(&vec![1]) == None);
+    assert!(deserializer::<Position>(&vec![1, 2, 3, 4]) == None);
+    //A Move with no Position after it
+    assert!(deserializer::<Command>(&vec![0b0100_0000]) == None);
+    //A tag with no variant behind it
+    assert!(deserializer::<Small>(&vec![0b1100_0000]) == None);
+}
+
+#[test]
+fn message_id() {
+    let message = Message::new(&Command::Fire { angle: 1, charged: false }).unwrap();
+    assert!(message.message_id == 7);
+    assert!(<Command as NetMessage>::message_id() == 7);
+    assert!(message.downcast::<Command>() == Some(Command::Fire { angle: 1, charged: false }));
+}